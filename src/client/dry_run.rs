@@ -0,0 +1,273 @@
+//! A look at the swarm of a torrent before we download it: we announce and shake hands with the
+//! peers, but we never request a block and nothing is written to the download directory.
+//! The throughput is a guess from the round trip times: with [`BLOCK_QUEUE_SIZE_MAX`] blocks in
+//! flight a peer sends at most that many blocks per round trip, if it unchokes us at all.
+use std::{
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use futures_util::StreamExt;
+use serde::Serialize;
+use tokio::{net::TcpStream, task::JoinSet};
+use tokio_util::codec::Framed;
+
+use crate::{
+    BLOCK_MAX,
+    client::{Client, ClientError},
+    database::DBConnection,
+    messages::{MessageFramer, PeerMessage},
+    peer::initial_handshake::Handshake,
+    peer_manager::BLOCK_QUEUE_SIZE_MAX,
+    policy::PeerSource,
+    torrent::{InfoHash, Torrent},
+    tracker::{PeerResolver, TrackerRequest, TrackerTiers},
+};
+
+/// how long a peer gets to accept the connection and shake hands
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long we wait for the next message while waiting for the bitfield
+const BITFIELD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What [`Client::dry_run`] found out about the swarm.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DryRunReport {
+    pub pieces: usize,
+    /// the pieces we downloaded before
+    pub pieces_have: usize,
+    /// the peers the trackers told us about
+    pub peers_found: usize,
+    /// the peers that shook hands with us
+    pub peers_reachable: usize,
+    /// the reachable peers with every piece
+    pub seeds: usize,
+    /// the pieces at least one reachable peer or we have
+    pub pieces_available: usize,
+    /// how many complete copies the reachable peers have together, e.g. 1.5 if every piece is
+    /// there once and half of them twice
+    pub distributed_copies: f64,
+    /// bytes per second, see the module docs
+    pub estimated_throughput: u64,
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Peers: {} found, {} reachable, {} seeds",
+            self.peers_found, self.peers_reachable, self.seeds
+        )?;
+        writeln!(
+            f,
+            "Pieces: {} of {} available, {} downloaded before",
+            self.pieces_available, self.pieces, self.pieces_have
+        )?;
+        writeln!(f, "Distributed copies: {:.2}", self.distributed_copies)?;
+        write!(
+            f,
+            "Estimated throughput: up to {} KiB/s",
+            self.estimated_throughput / 1024
+        )
+    }
+}
+
+/// a peer that shook hands with us
+#[derive(Debug, Clone, PartialEq)]
+struct PeerProbe {
+    /// how long the TCP connect took
+    rtt: Duration,
+    has: Vec<bool>,
+}
+
+impl DryRunReport {
+    fn new(have: &[bool], peers_found: usize, probes: &[PeerProbe]) -> Self {
+        let mut counts = vec![0_u32; have.len()];
+        for probe in probes {
+            for (count, _) in counts.iter_mut().zip(&probe.has).filter(|(_, has)| **has) {
+                *count += 1;
+            }
+        }
+        let min = counts.iter().copied().min().unwrap_or(0);
+        let above_min = counts.iter().filter(|count| **count > min).count();
+        let distributed_copies = if counts.is_empty() {
+            0.0
+        } else {
+            min as f64 + above_min as f64 / counts.len() as f64
+        };
+
+        let in_flight = (BLOCK_QUEUE_SIZE_MAX as u32 * BLOCK_MAX) as f64;
+        let estimated_throughput = probes
+            .iter()
+            // a peer without anything we need sends us nothing
+            .filter(|probe| probe.has.iter().zip(have).any(|(has, have)| *has && !have))
+            .map(|probe| in_flight / probe.rtt.max(Duration::from_millis(1)).as_secs_f64())
+            .sum::<f64>() as u64;
+
+        Self {
+            pieces: have.len(),
+            pieces_have: have.iter().filter(|have| **have).count(),
+            peers_found,
+            peers_reachable: probes.len(),
+            seeds: probes
+                .iter()
+                .filter(|probe| probe.has.len() == have.len() && probe.has.iter().all(|h| *h))
+                .count(),
+            pieces_available: counts
+                .iter()
+                .zip(have)
+                .filter(|(count, have)| **count > 0 || **have)
+                .count(),
+            distributed_copies,
+            estimated_throughput,
+        }
+    }
+}
+
+impl Client {
+    /// Announces the torrent and shakes hands with its peers without downloading anything,
+    /// to see whether the swarm can deliver it before it takes up disk space.
+    pub async fn dry_run(&self, torrent: &Torrent) -> Result<DryRunReport, ClientError> {
+        let info_hash = torrent.info.info_hash();
+        let n_pieces = torrent.info.pieces.len();
+        let have = match DBConnection::new(&self.config.paths(), info_hash)
+            .await?
+            .get_entry()
+            .await?
+        {
+            Some(entry) => unpack(&entry.bitfield, n_pieces),
+            None => vec![false; n_pieces],
+        };
+
+        let left = torrent.info.total_size();
+        let tiers = TrackerTiers::from_torrent(torrent);
+        let response = TrackerRequest::new(&info_hash, &self.peer_id, self.port, left)
+            .with_settings(&self.config.announce)
+            .get_response(tiers.urls(), &self.scheduler)
+            .await?;
+        let resolver = PeerResolver::default();
+        let mut addrs = Vec::new();
+        for peer in response.into_peers() {
+            addrs.extend(resolver.resolve(peer.addr).await);
+        }
+        addrs.retain(|addr| self.policy.allows(addr, PeerSource::Tracker));
+
+        let mut probes = JoinSet::new();
+        for addr in addrs.iter().copied() {
+            let peer_id = self.peer_id;
+            probes.spawn(probe(addr, info_hash, peer_id, n_pieces));
+        }
+        let mut reachable = Vec::new();
+        while let Some(probe) = probes.join_next().await {
+            // a peer that fails the handshake panics its task
+            if let Ok(Some(probe)) = probe {
+                reachable.push(probe);
+            }
+        }
+        Ok(DryRunReport::new(&have, addrs.len(), &reachable))
+    }
+}
+
+/// Shakes hands with the peer and waits for its bitfield, None if it's unreachable.
+async fn probe(
+    addr: SocketAddr,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    n_pieces: usize,
+) -> Option<PeerProbe> {
+    let connected = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let started = Instant::now();
+        let mut tcp = TcpStream::connect(addr).await.ok()?;
+        let rtt = started.elapsed();
+        Handshake::new(info_hash, peer_id)
+            .shake_hands(&mut tcp)
+            .await
+            .ok()?;
+        Some((tcp, rtt))
+    });
+    let (tcp, rtt) = connected.await.ok()??;
+
+    let mut framed = Framed::new(tcp, MessageFramer);
+    let mut has = vec![false; n_pieces];
+    // a peer without pieces may not send a bitfield, only the extension handshake
+    while let Ok(Some(Ok(message))) = tokio::time::timeout(BITFIELD_TIMEOUT, framed.next()).await {
+        match message {
+            PeerMessage::Bitfield(bitfield) => {
+                if let Ok(pieces) = bitfield.checked(n_pieces) {
+                    has = pieces;
+                }
+                break;
+            }
+            PeerMessage::Have(have) => {
+                if let Some(has) = has.get_mut(have.piece_index as usize) {
+                    *has = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(PeerProbe { rtt, has })
+}
+
+/// the bitfield of the DB, the first piece is the highest bit of the first byte
+fn unpack(bitfield: &[u8], n_pieces: usize) -> Vec<bool> {
+    (0..n_pieces)
+        .map(|i| {
+            bitfield
+                .get(i / 8)
+                .is_some_and(|byte| byte & (0x80 >> (i % 8)) != 0)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn availability_of_the_probes() {
+        let have = [true, false, false, false];
+        let rtt = Duration::from_millis(125);
+        let probes = [
+            PeerProbe {
+                rtt,
+                has: vec![true; 4],
+            },
+            PeerProbe {
+                rtt,
+                has: vec![false, true, true, false],
+            },
+            // only has what we have
+            PeerProbe {
+                rtt,
+                has: vec![true, false, false, false],
+            },
+        ];
+        let report = DryRunReport::new(&have, 5, &probes);
+        assert_eq!(report.peers_found, 5);
+        assert_eq!(report.peers_reachable, 3);
+        assert_eq!(report.seeds, 1);
+        assert_eq!(report.pieces_have, 1);
+        assert_eq!(report.pieces_available, 4);
+        // every piece once, 3 of 4 more than once
+        assert_eq!(report.distributed_copies, 1.75);
+        assert_eq!(
+            report.estimated_throughput,
+            2 * 8 * BLOCK_QUEUE_SIZE_MAX as u64 * BLOCK_MAX as u64
+        );
+
+        let nobody = DryRunReport::new(&have, 0, &[]);
+        assert_eq!(nobody.pieces_available, 1);
+        assert_eq!(nobody.distributed_copies, 0.0);
+        assert_eq!(nobody.estimated_throughput, 0);
+    }
+
+    #[test]
+    fn unpack_the_db_bitfield() {
+        assert_eq!(
+            unpack(&[0b1010_0000, 0b1000_0000], 9),
+            [true, false, true, false, false, false, false, false, true]
+        );
+        assert_eq!(unpack(&[], 2), [false, false]);
+    }
+}
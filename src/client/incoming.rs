@@ -0,0 +1,207 @@
+//! One listener for the peers of all torrents of the client. A peer that connects to us names the
+//! torrent in its handshake, so we read the handshake before we answer it and hand the peer to the
+//! PeerManager of that torrent. A peer that asks for a torrent we don't run is disconnected.
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use tokio::{net::TcpStream, sync::mpsc};
+
+use super::PeerSetup;
+use crate::{
+    config::SocketOptions,
+    peer::{Peer, error::PeerError, socket},
+    peer_manager::ReqMsgFromPeer,
+    policy::{ConnectionPolicy, PeerSource},
+    sync::Mutex,
+    torrent::InfoHash,
+};
+
+/// where the peers of a torrent go
+#[derive(Debug, Clone)]
+struct Target {
+    peer_manager_tx: mpsc::Sender<ReqMsgFromPeer>,
+    setup: PeerSetup,
+}
+
+/// The torrents that accept peers right now, cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Torrents(Arc<Mutex<HashMap<InfoHash, Target>>>);
+
+impl Torrents {
+    /// The torrent accepts peers until the future is dropped, it never returns.
+    pub(super) async fn serve(
+        &self,
+        info_hash: InfoHash,
+        peer_manager_tx: mpsc::Sender<ReqMsgFromPeer>,
+        setup: PeerSetup,
+    ) {
+        let _registration = Registration {
+            torrents: self.clone(),
+            info_hash,
+            peer_manager_tx: peer_manager_tx.clone(),
+        };
+        self.0.lock().unwrap().insert(
+            info_hash,
+            Target {
+                peer_manager_tx,
+                setup,
+            },
+        );
+        std::future::pending().await
+    }
+
+    fn get(&self, info_hash: &InfoHash) -> Option<Target> {
+        self.0.lock().unwrap().get(info_hash).cloned()
+    }
+
+    fn info_hashes(&self) -> Vec<InfoHash> {
+        self.0.lock().unwrap().keys().copied().collect()
+    }
+
+    /// shakes hands with the peer and runs it until it disconnects
+    async fn attach(
+        &self,
+        tcp: TcpStream,
+        addr: SocketAddr,
+        peer_id: [u8; 20],
+        options: &SocketOptions,
+    ) -> Result<(), PeerError> {
+        let (stream, handshake) = Peer::accept_handshake(tcp, &self.info_hashes(), options).await?;
+        let info_hash = InfoHash(handshake.info_hash);
+        let Some(target) = self.get(&info_hash) else {
+            eprintln!(
+                "Disconnecting the peer {addr}, it asked for the torrent {} we don't run.",
+                hex::encode(info_hash.0)
+            );
+            return Ok(());
+        };
+        let peer = Peer::from_accepted(
+            stream,
+            handshake,
+            peer_id,
+            target.peer_manager_tx,
+            options,
+            target.setup.dht.as_ref(),
+        )
+        .await?;
+        target.setup.apply(peer).run().await
+    }
+}
+
+/// Unregisters the torrent, unless another run of it took its place meanwhile.
+#[derive(Debug)]
+struct Registration {
+    torrents: Torrents,
+    info_hash: InfoHash,
+    peer_manager_tx: mpsc::Sender<ReqMsgFromPeer>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut torrents = self.torrents.0.lock().unwrap();
+        if torrents
+            .get(&self.info_hash)
+            .is_some_and(|target| target.peer_manager_tx.same_channel(&self.peer_manager_tx))
+        {
+            torrents.remove(&self.info_hash);
+        }
+    }
+}
+
+/// accepts the incoming peer connections of all torrents, each one in a task of its own
+pub(super) async fn accept_peers(
+    listener: tokio::net::TcpListener,
+    torrents: Torrents,
+    peer_id: [u8; 20],
+    policy: Arc<dyn ConnectionPolicy>,
+    options: SocketOptions,
+) {
+    loop {
+        let connection = listener.accept().await;
+        let Ok((stream, addr)) = connection else {
+            continue;
+        };
+        let addr = socket::canonical(addr);
+        if !policy.allows(&addr, PeerSource::Incoming) {
+            // dropping the stream closes the connection before the handshake
+            continue;
+        }
+        let torrents = torrents.clone();
+        tokio::spawn(async move {
+            // a peer that never finishes the handshake must not stop us from accepting the others
+            if let Err(e) = torrents.attach(stream, addr, peer_id, &options).await {
+                eprintln!("Failed to accept the connection of {addr}: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{config::Config, peer::initial_handshake::Handshake};
+
+    fn setup() -> PeerSetup {
+        PeerSetup {
+            private: false,
+            listen_port: 6881,
+            metadata_size: None,
+            socket: Config::default().socket,
+            dht: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_go_to_their_torrent() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let torrents = Torrents::default();
+        let policy = Arc::new(Config::default().connection_rules);
+        tokio::spawn(accept_peers(
+            listener,
+            torrents.clone(),
+            [9; 20],
+            policy,
+            Config::default().socket,
+        ));
+        let (first_tx, _first_rx) = mpsc::channel(8);
+        let (second_tx, mut second_rx) = mpsc::channel(8);
+        let served = torrents.clone();
+        tokio::spawn(async move {
+            tokio::join!(
+                served.serve(InfoHash([1; 20]), first_tx, setup()),
+                served.serve(InfoHash([2; 20]), second_tx, setup()),
+            )
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(torrents.info_hashes().len(), 2);
+
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        let ours = Handshake::new(InfoHash([2; 20]), [5; 20]);
+        let theirs = ours.shake_hands(&mut tcp).await.unwrap();
+        assert_eq!(theirs.peer_id, [9; 20]);
+        let connected = second_rx.recv().await.unwrap();
+        assert_eq!(connected.peer_id, [5; 20]);
+
+        // nobody runs this one
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        Handshake::new(InfoHash([3; 20]), [6; 20])
+            .send(&mut tcp)
+            .await
+            .unwrap();
+        assert_eq!(tcp.read(&mut [0; 68]).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn a_stopped_torrent_leaves() {
+        let torrents = Torrents::default();
+        let (tx, _rx) = mpsc::channel(8);
+        let mut serve = Box::pin(torrents.serve(InfoHash([1; 20]), tx, setup()));
+        assert!((&mut serve).now_or_never().is_none());
+        assert!(torrents.get(&InfoHash([1; 20])).is_some());
+        drop(serve);
+        assert!(torrents.info_hashes().is_empty());
+    }
+}
@@ -0,0 +1,745 @@
+//! The entry point for library users: downloads torrent files and magnet links
+//! exactly like the CLI does, from finding peers to stopping the torrent.
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures_util::FutureExt;
+use thiserror::Error;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::{mpsc, watch},
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    config::{Config, SocketOptions},
+    database::{self, CachedPeer, DBConnection, DBError, PeerCacheRecord},
+    dht::Dht,
+    lsd::Lsd,
+    magnet_links::{MagnetLink, MagnetLinkError},
+    peer::{
+        Peer,
+        rate_limit::{Bandwidth, RateLimit},
+        socket,
+        trace::WireTrace,
+    },
+    peer_manager::{
+        ClientStats, DiskBudget, DiskStats, EventLog, PeerCandidates, PeerManager, PeerSlots,
+        PexPolicy, ProgressSnapshot, ReqMsgFromPeer, TorrentControl, error::PeerManagerError,
+    },
+    policy::{ConnectionPolicy, PeerSource},
+    torrent::{InfoHash, Metainfo, Torrent, TorrentError},
+    tracker::{AnnounceScheduler, Announcer, PeerAddr, TrackerRequestError, TrackerTiers},
+};
+
+mod dry_run;
+mod incoming;
+mod queue;
+
+pub use dry_run::DryRunReport;
+use incoming::Torrents;
+use queue::{Activity, QueueEntry, QueueSlot, TorrentQueue};
+
+/// how long we wait for the `stopped` announce when shutting down
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many peers of a torrent are remembered for the next run
+const PEER_CACHE_SIZE: usize = 50;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    PeerManager(#[from] PeerManagerError),
+    #[error(transparent)]
+    Torrent(#[from] TorrentError),
+    #[error(transparent)]
+    MagnetLink(#[from] MagnetLinkError),
+    #[error(transparent)]
+    Tracker(#[from] TrackerRequestError),
+    #[error(transparent)]
+    DB(#[from] DBError),
+    #[error("Failed to listen for peers with the error: `{0}`")]
+    Listen(#[from] std::io::Error),
+    #[error("The torrent stopped before the metadata was downloaded.")]
+    MetadataIncomplete,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    config: Arc<Config>,
+    scheduler: AnnounceScheduler,
+    policy: Arc<dyn ConnectionPolicy>,
+    peer_id: [u8; 20],
+    port: u16,
+    wire_trace: bool,
+    /// see [`Client::with_recheck`]
+    recheck: bool,
+    /// the clients of the peers of every torrent
+    client_stats: ClientStats,
+    /// the recent events of every torrent
+    events: EventLog,
+    /// shared by the torrents of this client, see [`Config::max_disk_rate`]
+    disk: DiskBudget,
+    /// shared by the torrents of this client, see [`Client::bandwidth`]
+    bandwidth: Bandwidth,
+    /// shared by the torrents of this client, see [`Config::max_peers`]
+    peer_slots: PeerSlots,
+    /// shared by the torrents of this client, see [`Config::queue`]
+    queue: TorrentQueue,
+    /// how many peers PEX finds for all torrents are dialed
+    pex: PexPolicy,
+    /// bound when the first torrent starts, None if it's disabled or the port is taken
+    dht: tokio::sync::OnceCell<Option<Dht>>,
+    /// joined when the first torrent starts, None if it's disabled or the group can't be joined
+    lsd: std::sync::OnceLock<Option<Lsd>>,
+    /// see [`Client::with_clock`]
+    clock: Arc<dyn Clock>,
+    /// the torrents the incoming peers are handed to
+    incoming: Torrents,
+    /// where we accept the peers of all torrents, bound when the first torrent starts
+    listen_addr: tokio::sync::OnceCell<SocketAddr>,
+}
+
+/// how a run of a torrent ended
+#[derive(Debug, PartialEq)]
+enum RunEnd {
+    Stopped,
+    /// the download finished but all seeding slots are taken
+    Requeued,
+}
+
+impl Client {
+    /// The connection policy are the rules of the config, see [`Client::with_policy`].
+    pub fn new(config: Arc<Config>, peer_id: [u8; 20], port: u16) -> Result<Self, ClientError> {
+        Ok(Self {
+            scheduler: AnnounceScheduler::new(
+                config.min_announce_gap(),
+                &config.tracker_tls,
+                &config.tracker_compat,
+            )?,
+            policy: Arc::new(config.connection_rules.clone()),
+            queue: TorrentQueue::new(config.queue),
+            disk: DiskBudget::new(config.max_disk_rate),
+            events: EventLog::new(config.event_history),
+            bandwidth: Bandwidth::new(RateLimit {
+                upload: config.max_upload_rate,
+                download: config.max_download_rate,
+            }),
+            peer_slots: PeerSlots::new(config.max_peers),
+            config,
+            peer_id,
+            port,
+            wire_trace: false,
+            recheck: false,
+            client_stats: ClientStats::default(),
+            pex: PexPolicy::default(),
+            dht: tokio::sync::OnceCell::new(),
+            lsd: std::sync::OnceLock::new(),
+            clock: Arc::new(SystemClock),
+            incoming: Torrents::default(),
+            listen_addr: tokio::sync::OnceCell::new(),
+        })
+    }
+
+    pub fn with_policy(mut self, policy: Arc<dyn ConnectionPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// enables the wire trace of every torrent from the start, SIGUSR1 toggles it
+    pub fn with_wire_trace(mut self, enabled: bool) -> Self {
+        self.wire_trace = enabled;
+        self
+    }
+
+    /// hashes the data of every torrent again when it starts instead of trusting the DB
+    pub fn with_recheck(mut self, recheck: bool) -> Self {
+        self.recheck = recheck;
+        self
+    }
+
+    /// the clock of the PeerManager and the announcer of every torrent, e.g. a [`TokioClock`] for
+    /// tests that pause the runtime
+    ///
+    /// [`TokioClock`]: crate::clock::TokioClock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// which clients the peers of all torrents used, see [`PeerManager::client_stats`] for one torrent
+    pub fn client_stats(&self) -> ClientStats {
+        self.client_stats.clone()
+    }
+
+    /// the recent events of all torrents, see [`PeerManager::events`] for one torrent
+    pub fn events(&self) -> EventLog {
+        self.events.clone()
+    }
+
+    /// how busy the disk of all torrents is
+    pub fn disk_stats(&self) -> DiskStats {
+        self.disk.stats()
+    }
+
+    /// The upload and download limit of all torrents, it starts at [`Config::max_upload_rate`] and
+    /// [`Config::max_download_rate`] and changes with [`Bandwidth::set_limit`] while they run.
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.bandwidth.clone()
+    }
+
+    /// the peer connections of all torrents, see [`Config::max_peers`]
+    pub fn peer_slots(&self) -> PeerSlots {
+        self.peer_slots.clone()
+    }
+
+    /// Accepts the peers of all torrents on our port from now on, returns where we listen.
+    async fn listen(&self) -> Result<SocketAddr, ClientError> {
+        let addr = self
+            .listen_addr
+            .get_or_try_init(|| async {
+                let listener = self.config.socket.listen_on_port(self.port)?;
+                let addr = listener.local_addr()?;
+                tokio::spawn(incoming::accept_peers(
+                    listener,
+                    self.incoming.clone(),
+                    self.peer_id,
+                    self.policy.clone(),
+                    self.config.socket,
+                ));
+                Ok::<_, std::io::Error>(addr)
+            })
+            .await?;
+        Ok(*addr)
+    }
+
+    /// the DHT node all torrents share, it starts from the nodes the last run knew
+    pub async fn dht(&self) -> Option<Dht> {
+        self.dht
+            .get_or_init(|| async {
+                if !self.config.dht.enabled {
+                    return None;
+                }
+                let saved = database::load_dht(&self.config.paths())
+                    .await
+                    .inspect_err(|e| eprintln!("Failed to load the DHT nodes of the last run: {e}"))
+                    .ok()
+                    .flatten();
+                Dht::bind(self.port, &self.config.dht, saved)
+                    .await
+                    .inspect_err(|e| eprintln!("Continuing without the DHT: {e}"))
+                    .ok()
+            })
+            .await
+            .clone()
+    }
+
+    /// Hands the torrent the DHT node, the local service discovery and the PEX budget all torrents
+    /// share. The peers each of them finds go to this torrent's dialer only.
+    async fn share_discovery(
+        &self,
+        announcer: Announcer,
+        peer_manager: &mut PeerManager,
+        private: bool,
+    ) -> Announcer {
+        // private trackers ban clients that find the peers of their torrents elsewhere
+        if private {
+            return announcer;
+        }
+        peer_manager.share_pex(&self.pex);
+        announcer.with_dht(self.dht().await).with_lsd(self.lsd())
+    }
+
+    /// the local service discovery all torrents share
+    fn lsd(&self) -> Option<Lsd> {
+        self.lsd
+            .get_or_init(|| {
+                if !self.config.local_discovery {
+                    return None;
+                }
+                Lsd::bind(self.port)
+                    .inspect_err(|e| eprintln!("Continuing without local peer discovery: {e}"))
+                    .ok()
+            })
+            .clone()
+    }
+
+    /// Torrents with a higher `priority` leave the queue first, see [`Config::queue`].
+    pub async fn download_torrent(
+        &self,
+        torrent_path: &PathBuf,
+        output_path: Option<PathBuf>,
+        ratio_group: Option<String>,
+        priority: i32,
+    ) -> Result<(), ClientError> {
+        let torrent = Torrent::read_from_file(torrent_path)?;
+        self.start_download(torrent, output_path, ratio_group, priority)
+            .await
+    }
+
+    pub async fn download_magnet(
+        &self,
+        magnet_link: &str,
+        output_path: Option<PathBuf>,
+        ratio_group: Option<String>,
+        priority: i32,
+    ) -> Result<(), ClientError> {
+        let magnet_link = MagnetLink::from_url(magnet_link)?;
+        let entry = self.queue.enqueue(priority);
+        let torrent = self.fetch_metadata(&magnet_link, entry).await?;
+        self.download(
+            torrent,
+            output_path,
+            ratio_group,
+            entry,
+            magnet_link.get_peer_addrs(),
+        )
+        .await
+    }
+
+    /// Downloads the metadata of the magnet link from its peers and returns the torrent it describes.
+    /// If the metadata was downloaded before, it's read from the DB instead.
+    pub async fn download_metadata(
+        &self,
+        magnet_link: &MagnetLink,
+    ) -> Result<Torrent, ClientError> {
+        self.fetch_metadata(magnet_link, self.queue.enqueue(0))
+            .await
+    }
+
+    /// The torrent of the magnet link if its metadata was downloaded before, the network isn't used.
+    pub async fn cached_metadata(
+        &self,
+        magnet_link: &MagnetLink,
+    ) -> Result<Option<Torrent>, ClientError> {
+        let db_conn = DBConnection::new(&self.config.paths(), magnet_link.info_hash).await?;
+        let Some(entry) = db_conn.get_entry().await? else {
+            return Ok(None);
+        };
+        Ok(Some(magnet_torrent(magnet_link, entry.torrent_info)?))
+    }
+
+    /// like [`Client::download_metadata`], the torrent waits in line as a download
+    async fn fetch_metadata(
+        &self,
+        magnet_link: &MagnetLink,
+        entry: QueueEntry,
+    ) -> Result<Torrent, ClientError> {
+        let (peer_manager_tx, peer_manager_rx) = mpsc::channel(64);
+        let mut peer_manager = PeerManager::init_from_magnet(
+            peer_manager_rx,
+            None,
+            magnet_link.clone(),
+            self.config.clone(),
+        )
+        .await?;
+        let metadata = peer_manager.subscribe_metadata();
+        if metadata.borrow().is_none() {
+            let Some(slot) = self.wait_in_queue(entry, Activity::Downloading).await else {
+                return Err(ClientError::MetadataIncomplete);
+            };
+            peer_manager.stop_after_metadata();
+            let (announcer, announce_handle, new_peers) = Announcer::new(
+                magnet_link.info_hash,
+                self.peer_id,
+                self.port,
+                TrackerTiers::single_tier(magnet_link.get_announce_urls()?),
+                self.scheduler.clone(),
+            );
+            let announcer = announcer
+                .with_cached_peers(self.cached_peers(magnet_link.info_hash).await)
+                .with_peers(magnet_link.get_peer_addrs())
+                .with_settings(self.config.announce);
+            // whether it's private is part of the metadata we don't have yet
+            let announcer = self
+                .share_discovery(announcer, &mut peer_manager, false)
+                .await
+                .with_external_ip(peer_manager.subscribe_external_ip());
+            peer_manager.attach_announcer(announce_handle);
+
+            let candidates = peer_manager.candidates();
+            let setup = PeerSetup {
+                private: false,
+                listen_port: self.port,
+                metadata_size: None,
+                socket: self.config.socket,
+                dht: self.dht().await,
+            };
+            self.listen().await?;
+            let peers = async {
+                tokio::join!(
+                    dial_peers(
+                        new_peers,
+                        magnet_link.info_hash,
+                        self.peer_id,
+                        peer_manager_tx.clone(),
+                        self.policy.clone(),
+                        candidates.clone(),
+                        setup.clone(),
+                    ),
+                    self.incoming
+                        .serve(magnet_link.info_hash, peer_manager_tx, setup),
+                );
+            };
+            self.run_torrent(peer_manager, announcer, peers, slot).await;
+            self.save_peers(magnet_link.info_hash, &candidates).await;
+        }
+
+        let info = metadata
+            .borrow()
+            .clone()
+            .ok_or(ClientError::MetadataIncomplete)?;
+        magnet_torrent(magnet_link, info)
+    }
+
+    /// Downloads the torrent and seeds it until the seeding goal of the ratio group is reached
+    /// or the user presses Ctrl+C.
+    pub async fn start_download(
+        &self,
+        torrent: Torrent,
+        output_path: Option<PathBuf>,
+        ratio_group: Option<String>,
+        priority: i32,
+    ) -> Result<(), ClientError> {
+        let entry = self.queue.enqueue(priority);
+        self.download(torrent, output_path, ratio_group, entry, Vec::new())
+            .await
+    }
+
+    /// `peers` are dialed in addition to the ones the trackers return
+    async fn download(
+        &self,
+        torrent: Torrent,
+        output_path: Option<PathBuf>,
+        ratio_group: Option<String>,
+        entry: QueueEntry,
+        peers: Vec<PeerAddr>,
+    ) -> Result<(), ClientError> {
+        // a finished download stops if there's no seeding slot and starts again once there is one
+        while self
+            .run_queued(
+                torrent.clone(),
+                output_path.clone(),
+                ratio_group.clone(),
+                entry,
+                peers.clone(),
+            )
+            .await?
+            == RunEnd::Requeued
+        {}
+        Ok(())
+    }
+
+    async fn run_queued(
+        &self,
+        torrent: Torrent,
+        output_path: Option<PathBuf>,
+        ratio_group: Option<String>,
+        entry: QueueEntry,
+        peers: Vec<PeerAddr>,
+    ) -> Result<RunEnd, ClientError> {
+        let (peer_manager_tx, peer_manager_rx) = mpsc::channel(64);
+        let info_hash = torrent.info.info_hash();
+        let private = torrent.info.is_private();
+        let setup = PeerSetup {
+            private,
+            listen_port: self.port,
+            metadata_size: Some(torrent.info.to_bytes().len()),
+            socket: self.config.socket,
+            dht: if private { None } else { self.dht().await },
+        };
+        let mut peer_manager = PeerManager::init_from_torrent(
+            peer_manager_rx,
+            output_path,
+            torrent,
+            self.config.clone(),
+        )
+        .await?;
+        if let Some(ratio_group) = ratio_group {
+            peer_manager.assign_ratio_group(ratio_group).await?;
+        }
+        // the trackers of the earlier torrent files and magnet links of it as well
+        let tiers = peer_manager.tracker_tiers();
+        let activity = if peer_manager.is_seeding() {
+            Activity::Seeding
+        } else {
+            Activity::Downloading
+        };
+        let Some(slot) = self.wait_in_queue(entry, activity).await else {
+            return Ok(RunEnd::Stopped);
+        };
+
+        let ipv6 = if self.listen().await?.is_ipv6() {
+            socket::global_ipv6()
+        } else {
+            None
+        };
+        let (announcer, announce_handle, new_peers) = Announcer::new(
+            info_hash,
+            self.peer_id,
+            self.port,
+            tiers,
+            self.scheduler.clone(),
+        );
+        let announcer = announcer
+            .with_cached_peers(self.cached_peers(info_hash).await)
+            .with_peers(peers)
+            .with_settings(self.config.announce)
+            .with_ipv6(ipv6);
+        let announcer = self
+            .share_discovery(announcer, &mut peer_manager, setup.private)
+            .await
+            .with_external_ip(peer_manager.subscribe_external_ip());
+        peer_manager.attach_announcer(announce_handle);
+
+        let candidates = peer_manager.candidates();
+        let peers = async {
+            tokio::join!(
+                dial_peers(
+                    new_peers,
+                    info_hash,
+                    self.peer_id,
+                    peer_manager_tx.clone(),
+                    self.policy.clone(),
+                    candidates.clone(),
+                    setup.clone(),
+                ),
+                self.incoming.serve(info_hash, peer_manager_tx, setup),
+            );
+        };
+        let end = self.run_torrent(peer_manager, announcer, peers, slot).await;
+        self.save_peers(info_hash, &candidates).await;
+        Ok(end)
+    }
+
+    /// returns None if the user pressed Ctrl+C while the torrent was waiting
+    async fn wait_in_queue(&self, entry: QueueEntry, activity: Activity) -> Option<QueueSlot> {
+        let acquire = self.queue.acquire(entry, activity);
+        tokio::pin!(acquire);
+        if let Some(slot) = (&mut acquire).now_or_never() {
+            return Some(slot);
+        }
+        eprintln!("The torrent is queued until another one stops.");
+        tokio::select! {
+            slot = acquire => Some(slot),
+            _ = tokio::signal::ctrl_c() => None,
+        }
+    }
+
+    /// the best peers of the last run of the torrent
+    async fn cached_peers(&self, info_hash: InfoHash) -> Vec<SocketAddr> {
+        database::load_peers(&self.config.paths(), info_hash)
+            .await
+            .inspect_err(|e| eprintln!("Failed to load the peers of the last run: {e}"))
+            .ok()
+            .flatten()
+            .map(|record| record.peers.into_iter().map(|peer| peer.addr).collect())
+            .unwrap_or_default()
+    }
+
+    /// remembers the best peers of this run, the ones that sent us garbage aren't worth it
+    async fn save_peers(&self, info_hash: InfoHash, candidates: &PeerCandidates) {
+        let peers: Vec<_> = candidates
+            .best(PEER_CACHE_SIZE)
+            .into_iter()
+            .filter(|(_, score)| *score > 0.0)
+            .map(|(addr, score)| CachedPeer { addr, score })
+            .collect();
+        if peers.is_empty() {
+            return;
+        }
+        let record = PeerCacheRecord { peers };
+        if let Err(e) = database::save_peers(&self.config.paths(), info_hash, record).await {
+            eprintln!("Failed to save the peers of this run: {e}");
+        }
+    }
+
+    async fn save_dht(&self) {
+        let Some(Some(dht)) = self.dht.get() else {
+            return;
+        };
+        if let Err(e) = database::save_dht(&self.config.paths(), dht.to_record()).await {
+            eprintln!("Failed to save the DHT nodes: {e}");
+        }
+    }
+
+    /// runs the torrent until it stops by itself, the user presses Ctrl+C
+    /// or it has to wait for a seeding slot
+    async fn run_torrent(
+        &self,
+        mut peer_manager: PeerManager,
+        announcer: Announcer,
+        peers: impl Future<Output = ()>,
+        mut slot: QueueSlot,
+    ) -> RunEnd {
+        peer_manager.count_clients_into(&self.client_stats);
+        peer_manager.log_events_into(&self.events);
+        let client_stats = peer_manager.client_stats();
+        peer_manager.share_disk(&self.disk);
+        peer_manager.share_bandwidth(&self.bandwidth);
+        peer_manager.share_peer_slots(&self.peer_slots);
+        peer_manager.set_clock(self.clock.clone());
+        let shutdown = peer_manager.shutdown_token();
+        let finished = peer_manager.subscribe_progress();
+        peer_manager.wire_trace().set_enabled(self.wire_trace);
+        let toggle = tokio::spawn(toggle_wire_trace(peer_manager.wire_trace()));
+        let control = peer_manager.control();
+        if self.recheck && peer_manager.subscribe_metadata().borrow().is_some() {
+            control.recheck();
+        }
+        let reannounce = tokio::spawn(reannounce_on_signal(control));
+        let progress = tokio::spawn(print_progress(peer_manager.subscribe_progress()));
+        let announcer = tokio::spawn(announcer.with_clock(self.clock.clone()).run());
+        let mut peer_manager = tokio::spawn(peer_manager.run());
+
+        let mut end = RunEnd::Stopped;
+        let result = tokio::select! {
+            result = &mut peer_manager => Some(result),
+            _ = peers => None,
+            _ = tokio::signal::ctrl_c() => None,
+            _ = wait_for_seeding_slot(&mut slot, finished) => {
+                eprintln!("The download finished, the torrent is queued for seeding.");
+                end = RunEnd::Requeued;
+                None
+            }
+        };
+        shutdown.cancel();
+        let result = match result {
+            Some(result) => result,
+            None => peer_manager.await,
+        };
+        if let Ok(Err(e)) = result {
+            eprintln!("The torrent failed with the error: {e}");
+        }
+        progress.abort();
+        toggle.abort();
+        reannounce.abort();
+        eprint!("Clients of the peers:\n{}", client_stats.snapshot());
+        eprintln!("Disk: {}", self.disk.stats());
+        self.save_dht().await;
+        // the announcer returns after announcing `stopped`
+        let _ = tokio::time::timeout(STOPPED_ANNOUNCE_TIMEOUT, announcer).await;
+        end
+    }
+}
+
+/// the torrent with the metadata of the magnet link, the trackers and web seeds are the link's
+fn magnet_torrent(magnet_link: &MagnetLink, info: Metainfo) -> Result<Torrent, ClientError> {
+    let announce_urls = magnet_link.get_announce_urls()?;
+    let announce_list = (announce_urls.len() > 1)
+        .then(|| vec![announce_urls.iter().map(url::Url::to_string).collect()]);
+    Ok(Torrent {
+        announce: announce_urls.into_iter().next(),
+        announce_list,
+        url_list: magnet_link
+            .get_web_seeds()
+            .iter()
+            .map(url::Url::to_string)
+            .collect(),
+        info,
+    })
+}
+
+/// Moves the slot to seeding once the download finished.
+/// Returns if all seeding slots are taken, otherwise it never returns.
+async fn wait_for_seeding_slot(
+    slot: &mut QueueSlot,
+    mut progress: watch::Receiver<ProgressSnapshot>,
+) {
+    if slot.activity() == Activity::Downloading {
+        while progress.changed().await.is_ok() {
+            if progress.borrow_and_update().left == Some(0) {
+                if !slot.try_switch(Activity::Seeding) {
+                    return;
+                }
+                break;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// switches the wire trace on and off on every SIGUSR1
+async fn toggle_wire_trace(wire_trace: WireTrace) {
+    let Ok(mut signals) = signal(SignalKind::user_defined1()) else {
+        return;
+    };
+    while signals.recv().await.is_some() {
+        let state = if wire_trace.toggle() { "on" } else { "off" };
+        eprintln!("The wire trace is {state}.");
+    }
+}
+
+async fn reannounce_on_signal(control: TorrentControl) {
+    let Ok(mut signals) = signal(SignalKind::user_defined2()) else {
+        return;
+    };
+    while signals.recv().await.is_some() {
+        eprintln!("Forcing a re-announce.");
+        control.reannounce();
+    }
+}
+
+/// prints the status of the torrent whenever it changes
+async fn print_progress(mut progress: watch::Receiver<ProgressSnapshot>) {
+    while progress.changed().await.is_ok() {
+        eprintln!("{}", *progress.borrow_and_update());
+    }
+}
+
+/// how every peer of a torrent is set up
+#[derive(Debug, Clone)]
+struct PeerSetup {
+    private: bool,
+    /// the port we accept peers on
+    listen_port: u16,
+    /// None while we wait for the metadata
+    metadata_size: Option<usize>,
+    socket: SocketOptions,
+    /// None for private torrents, their peers don't learn about our DHT node
+    dht: Option<Dht>,
+}
+
+impl PeerSetup {
+    fn apply(&self, peer: Peer) -> Peer {
+        peer.with_private(self.private)
+            .with_handshake_info(self.listen_port, self.metadata_size)
+    }
+}
+
+/// connects to every peer the announcer found that we aren't connected to yet
+async fn dial_peers(
+    mut new_peers: mpsc::Receiver<(SocketAddr, PeerSource)>,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    peer_manager_tx: mpsc::Sender<ReqMsgFromPeer>,
+    policy: Arc<dyn ConnectionPolicy>,
+    candidates: PeerCandidates,
+    setup: PeerSetup,
+) {
+    while let Some((addr, source)) = new_peers.recv().await {
+        if !policy.allows(&addr, source) {
+            continue;
+        }
+        let Some(dialing) = candidates.try_dial(addr, source) else {
+            continue;
+        };
+        let peer_manager_tx = peer_manager_tx.clone();
+        let setup = setup.clone();
+        tokio::spawn(async move {
+            let _dialing = dialing;
+            let peer = Peer::connect_from_addr(
+                addr,
+                info_hash,
+                peer_id,
+                peer_manager_tx,
+                &setup.socket,
+                setup.dht.as_ref(),
+            )
+            .await
+            .context("initializing peer")
+            .unwrap();
+            let peer = setup.apply(peer);
+            peer.run().await.unwrap();
+        });
+    }
+}
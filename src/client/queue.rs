@@ -0,0 +1,275 @@
+//! Limits how many torrents download and seed at the same time.
+//! The others wait in line: the highest priority first, on a tie the one that was added first.
+use std::{cmp::Reverse, collections::BTreeSet, sync::Arc};
+
+use tokio::sync::watch;
+
+use crate::config::QueueLimits;
+
+use crate::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Activity {
+    Downloading,
+    Seeding,
+}
+
+/// the place of a torrent in the queue, it keeps it when it moves on to seeding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct QueueEntry {
+    priority: Reverse<i32>,
+    added: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lane {
+    limit: Option<usize>,
+    active: usize,
+    waiting: BTreeSet<QueueEntry>,
+}
+
+impl Lane {
+    fn has_room(&self) -> bool {
+        self.limit.is_none_or(|limit| self.active < limit)
+    }
+
+    /// whether nobody waits in front of the entry
+    fn is_next(&self, entry: &QueueEntry) -> bool {
+        self.waiting.first().is_none_or(|first| first >= entry)
+    }
+}
+
+#[derive(Debug)]
+struct QueueState {
+    downloading: Lane,
+    seeding: Lane,
+    added: u64,
+}
+
+impl QueueState {
+    fn lane(&mut self, activity: Activity) -> &mut Lane {
+        match activity {
+            Activity::Downloading => &mut self.downloading,
+            Activity::Seeding => &mut self.seeding,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: Mutex<QueueState>,
+    /// sent whenever the waiting torrents have to check again whether it's their turn
+    changed: watch::Sender<()>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TorrentQueue(Arc<Inner>);
+
+impl TorrentQueue {
+    pub(crate) fn new(limits: QueueLimits) -> Self {
+        let lane = |limit| Lane {
+            limit,
+            ..Lane::default()
+        };
+        Self(Arc::new(Inner {
+            state: Mutex::new(QueueState {
+                downloading: lane(limits.max_active_downloads),
+                seeding: lane(limits.max_active_seeds),
+                added: 0,
+            }),
+            changed: watch::Sender::new(()),
+        }))
+    }
+
+    /// a higher priority goes first
+    pub(crate) fn enqueue(&self, priority: i32) -> QueueEntry {
+        let mut state = self.lock();
+        state.added += 1;
+        QueueEntry {
+            priority: Reverse(priority),
+            added: state.added,
+        }
+    }
+
+    /// waits until it's the turn of the entry, the slot is freed when it's dropped
+    pub(crate) async fn acquire(&self, entry: QueueEntry, activity: Activity) -> QueueSlot {
+        let mut changed = self.0.changed.subscribe();
+        let _waiting = Waiting {
+            queue: self,
+            entry,
+            activity,
+        };
+        self.lock().lane(activity).waiting.insert(entry);
+        loop {
+            {
+                let mut state = self.lock();
+                let lane = state.lane(activity);
+                if lane.has_room() && lane.is_next(&entry) {
+                    lane.waiting.remove(&entry);
+                    lane.active += 1;
+                    // the next one may fit as well
+                    self.0.changed.send_replace(());
+                    return QueueSlot {
+                        queue: self.clone(),
+                        entry,
+                        activity,
+                    };
+                }
+            }
+            // the sender lives as long as we do
+            let _ = changed.changed().await;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.0.state.lock().unwrap()
+    }
+}
+
+/// removes a waiting entry that gave up, e.g. because the user pressed Ctrl+C
+struct Waiting<'a> {
+    queue: &'a TorrentQueue,
+    entry: QueueEntry,
+    activity: Activity,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self
+            .queue
+            .lock()
+            .lane(self.activity)
+            .waiting
+            .remove(&self.entry)
+        {
+            self.queue.0.changed.send_replace(());
+        }
+    }
+}
+
+/// the right of a torrent to be active
+#[derive(Debug)]
+pub(crate) struct QueueSlot {
+    queue: TorrentQueue,
+    entry: QueueEntry,
+    activity: Activity,
+}
+
+impl QueueSlot {
+    pub(crate) fn activity(&self) -> Activity {
+        self.activity
+    }
+
+    /// Takes a free slot of the other activity without queueing, e.g. when a download finished.
+    /// Returns false if the torrent has to wait in line for it.
+    pub(crate) fn try_switch(&mut self, activity: Activity) -> bool {
+        if activity == self.activity {
+            return true;
+        }
+        let mut state = self.queue.lock();
+        let lane = state.lane(activity);
+        if !lane.has_room() || !lane.is_next(&self.entry) {
+            return false;
+        }
+        lane.active += 1;
+        state.lane(self.activity).active -= 1;
+        self.activity = activity;
+        drop(state);
+        self.queue.0.changed.send_replace(());
+        true
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.queue.lock().lane(self.activity).active -= 1;
+        self.queue.0.changed.send_replace(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn queue(max_active_downloads: usize, max_active_seeds: usize) -> TorrentQueue {
+        TorrentQueue::new(QueueLimits {
+            max_active_downloads: Some(max_active_downloads),
+            max_active_seeds: Some(max_active_seeds),
+        })
+    }
+
+    async fn is_pending(slot: &mut tokio::task::JoinHandle<QueueSlot>) -> bool {
+        tokio::time::timeout(Duration::from_millis(20), slot)
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn higher_priority_goes_first() {
+        let queue = queue(1, 1);
+        let first = queue.acquire(queue.enqueue(0), Activity::Downloading).await;
+
+        let (low, high) = (queue.enqueue(0), queue.enqueue(5));
+        let mut low = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(low, Activity::Downloading).await }
+        });
+        let mut high = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(high, Activity::Downloading).await }
+        });
+        assert!(is_pending(&mut low).await);
+        assert!(is_pending(&mut high).await);
+
+        drop(first);
+        let high = high.await.unwrap();
+        assert!(is_pending(&mut low).await);
+        drop(high);
+        low.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn finished_download_needs_a_seeding_slot() {
+        let queue = queue(2, 1);
+        let mut first = queue.acquire(queue.enqueue(0), Activity::Downloading).await;
+        let mut second = queue.acquire(queue.enqueue(0), Activity::Downloading).await;
+
+        assert!(first.try_switch(Activity::Seeding));
+        assert_eq!(first.activity(), Activity::Seeding);
+        assert!(!second.try_switch(Activity::Seeding));
+        assert_eq!(second.activity(), Activity::Downloading);
+
+        // the freed download slot is usable right away
+        let third = queue.enqueue(0);
+        tokio::time::timeout(
+            Duration::from_millis(20),
+            queue.acquire(third, Activity::Downloading),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn giving_up_leaves_the_queue() {
+        let queue = queue(1, 1);
+        let first = queue.acquire(queue.enqueue(0), Activity::Downloading).await;
+        let impatient = queue.enqueue(10);
+        let gave_up = tokio::time::timeout(
+            Duration::from_millis(20),
+            queue.acquire(impatient, Activity::Downloading),
+        )
+        .await;
+        assert!(gave_up.is_err());
+
+        drop(first);
+        let patient = queue.enqueue(0);
+        tokio::time::timeout(
+            Duration::from_millis(20),
+            queue.acquire(patient, Activity::Downloading),
+        )
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+//! Where the PeerManager and the announcer get the time from, e.g. for the request timeouts, the
+//! choke rounds, the download rate and the announce intervals. The timers are tokio's, with
+//! [`TokioClock`] a test can `tokio::time::pause` the runtime and `advance` it, the timers fire
+//! and the clock jumps along with them, without waiting for real.
+use std::{fmt::Debug, time::Instant};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// the time of the OS, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The time of the tokio runtime. It stands still while the runtime is paused and moves with
+/// `tokio::time::advance` or when every task waits for a timer; otherwise it's the OS time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_clock_jumps() {
+        let start = TokioClock.now();
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        assert_eq!(TokioClock.now() - start, Duration::from_secs(3600));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(TokioClock.now() - start, Duration::from_secs(3605));
+        // the OS didn't wait an hour
+        assert!(SystemClock.now() - start < Duration::from_secs(60));
+    }
+}
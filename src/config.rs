@@ -0,0 +1,516 @@
+//! Settings of the client that the user can change via a JSON config file.
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{paths::Paths, policy::ConnectionRules, torrent::Metainfo};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Named seeding policies (e.g. "private-trackers" or "public") that can be assigned to torrents
+    /// so the limits don't have to be configured for each torrent individually.
+    pub ratio_groups: HashMap<String, RatioGroup>,
+    /// The group used for torrents that were never assigned one.
+    /// If it's None, those torrents are seeded until the program is stopped.
+    pub default_ratio_group: Option<String>,
+    /// The minimum time in milliseconds between two announces to the same tracker host.
+    pub min_announce_gap_ms: u64,
+    /// If set, the buffers of the pieces being downloaded stay within a budget.
+    pub low_memory: Option<LowMemory>,
+    /// Where downloads are written to if no output path is given.
+    pub download_dir: PathBuf,
+    /// Where the database and the other per-torrent state is kept.
+    pub state_dir: PathBuf,
+    /// Torrents exceeding these are rejected before anything is written to disk.
+    pub limits: TorrentLimits,
+    /// Which peers we may connect to and accept connections from.
+    pub connection_rules: ConnectionRules,
+    /// What we ask the trackers for in every announce.
+    pub announce: AnnounceSettings,
+    /// How we verify HTTPS trackers.
+    pub tracker_tls: TrackerTls,
+    /// How we present ourselves to the trackers and the workarounds for the odd ones.
+    pub tracker_compat: TrackerCompat,
+    /// How many torrents may be active at once, the others wait in a queue.
+    pub queue: QueueLimits,
+    /// Finding peers without trackers.
+    pub dht: DhtSettings,
+    /// Finding peers in the local network (BEP 14).
+    pub local_discovery: bool,
+    /// The bytes per second all torrents may read from and write to the disk together.
+    /// If it's None, the disk isn't throttled.
+    pub max_disk_rate: Option<u64>,
+    /// The bytes per second all peers of all torrents may download from us together,
+    /// on top of the limits of single peers. If it's None, the upload isn't throttled.
+    pub max_upload_rate: Option<u64>,
+    /// Like `max_upload_rate` for what the peers send us.
+    pub max_download_rate: Option<u64>,
+    /// How many peers of a torrent may download from us at once, one of them is picked at random.
+    /// With 0 we don't upload at all.
+    pub upload_slots: usize,
+    /// After how many seconds without a verified piece a download with unchoking peers counts as
+    /// hung and is restarted. If it's None, hung downloads are left alone.
+    pub stall_timeout_secs: Option<u64>,
+    /// After how many seconds a block a peer didn't send is requested from another peer.
+    pub block_timeout_secs: u64,
+    /// How the TCP connections to the peers are set up.
+    pub socket: SocketOptions,
+    /// Writes a JSON report of how the data was verified next to it when a download finishes,
+    /// e.g. `movie.mkv.integrity.json`.
+    pub integrity_report: bool,
+    /// When peers that send corrupt data are banned.
+    pub bans: BanSettings,
+    /// Leaves a few pieces out of the bitfield we send and tells the peer about them with Have
+    /// messages later, so we don't look like a seed to ISPs that throttle them.
+    pub lazy_bitfield: bool,
+    /// How many of the recent events are kept per torrent and for all torrents together, so a UI
+    /// that attaches later can catch up.
+    pub event_history: usize,
+    /// How many peers a torrent keeps connections to. When it's full, a new peer replaces the worst
+    /// one. If it's None, there's no limit.
+    pub max_peers_per_torrent: Option<usize>,
+    /// Like `max_peers_per_torrent` for the peers of all torrents together.
+    pub max_peers: Option<usize>,
+    /// Reads the blocks of the torrents we seed from a memory map of the file instead of with a
+    /// syscall per block. Needs the `mmap` feature, without it the reads stay as they are.
+    /// Another program that truncates a seeded file while we read it crashes us with SIGBUS.
+    pub mmap_reads: bool,
+}
+
+/// A peer is blamed for every piece that failed the hash it sent blocks of.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct BanSettings {
+    /// After how many corrupt pieces an IP is banned. If it's None, nobody is banned.
+    pub max_hash_failures: Option<u32>,
+    /// How long a ban lasts, in seconds.
+    pub ban_secs: u64,
+}
+
+impl Default for BanSettings {
+    fn default() -> Self {
+        Self {
+            max_hash_failures: Some(3),
+            ban_secs: 60 * 60,
+        }
+    }
+}
+
+/// Message Stream Encryption, it's RC4 and only hides the traffic from the network,
+/// it doesn't keep a determined attacker out.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    /// We dial without encryption but accept encrypted connections.
+    #[default]
+    PreferPlaintext,
+    /// We dial encrypted and fall back to plaintext if the peer doesn't support it.
+    PreferEncrypted,
+    /// Peers that don't encrypt are disconnected.
+    RequireEncrypted,
+}
+
+/// For the connections we open and the ones we accept.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Sends small messages like requests right away instead of waiting for more data.
+    pub nodelay: bool,
+    /// After how many idle seconds the OS checks whether the peer is still there.
+    /// If it's None, it doesn't.
+    pub keepalive_secs: Option<u64>,
+    /// In bytes. If it's None, the OS picks the size and grows it as needed.
+    /// Larger buffers help on links with a high latency and bandwidth.
+    pub send_buffer: Option<usize>,
+    /// Like `send_buffer`, it decides how much a peer may send before we acknowledge it.
+    pub recv_buffer: Option<usize>,
+    /// Whether we encrypt the connections, some networks throttle BitTorrent traffic they
+    /// recognize.
+    pub encryption: Encryption,
+    /// How long a peer gets to accept our connection.
+    pub connect_timeout_secs: u64,
+    /// How long a peer gets to answer our handshake, the encrypted one included.
+    pub handshake_timeout_secs: u64,
+    /// How long a peer gets after the handshake to send its bitfield, or whatever it sends first.
+    pub bitfield_timeout_secs: u64,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_secs: None,
+            send_buffer: None,
+            recv_buffer: None,
+            encryption: Encryption::default(),
+            connect_timeout_secs: 10,
+            handshake_timeout_secs: 10,
+            bitfield_timeout_secs: 30,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    pub fn bitfield_timeout(&self) -> Duration {
+        Duration::from_secs(self.bitfield_timeout_secs)
+    }
+}
+
+/// The DHT listens on the same port as the peers, but for UDP.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct DhtSettings {
+    pub enabled: bool,
+    /// `host:port` of the nodes we ask first when we don't know any other node.
+    pub bootstrap_nodes: Vec<String>,
+}
+
+impl Default for DhtSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bootstrap_nodes: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+            ],
+        }
+    }
+}
+
+/// None means unlimited.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct QueueLimits {
+    /// Torrents that download, including the ones waiting for the metadata.
+    pub max_active_downloads: Option<usize>,
+    pub max_active_seeds: Option<usize>,
+}
+
+/// For HTTPS trackers whose certificates the system doesn't trust.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct TrackerTls {
+    /// PEM or DER files with certificates we trust in addition to the system's.
+    pub root_certificates: Vec<PathBuf>,
+    /// Accepts any certificate, even expired or self-signed ones.
+    /// Only use this if you don't mind others reading and changing the announces.
+    pub accept_invalid_certs: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct TrackerCompat {
+    /// The `User-Agent` of the HTTP announces and scrapes.
+    /// If it's None, it's the name and version of this crate.
+    pub user_agent: Option<String>,
+    /// Workarounds by tracker host, a host also covers its subdomains.
+    pub quirks: HashMap<String, TrackerQuirks>,
+}
+
+impl TrackerCompat {
+    /// the quirks of the most specific host that matches
+    pub fn quirks_for(&self, host: &str) -> Option<&TrackerQuirks> {
+        self.quirks
+            .iter()
+            .filter(|(key, _)| {
+                host == key.as_str()
+                    || host
+                        .strip_suffix(key.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+            .max_by_key(|(key, _)| key.len())
+            .map(|(_, quirks)| quirks)
+    }
+}
+
+/// What a tracker needs that others don't.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct TrackerQuirks {
+    /// Overrides [`TrackerCompat::user_agent`].
+    pub user_agent: Option<String>,
+    /// Headers sent with every request, e.g. ones a tracker behind a proxy insists on.
+    pub headers: HashMap<String, String>,
+    /// Asks for the non-compact peer list, for trackers that send broken compact lists.
+    pub no_compact: bool,
+    /// Closes the connection after every request, for trackers that drop idle connections
+    /// without telling us.
+    pub no_keepalive: bool,
+}
+
+/// The optional parameters of an announce, some private trackers have rules about them.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct AnnounceSettings {
+    /// How many peers we ask for. If it's None, the tracker decides, most send 50.
+    pub numwant: Option<u32>,
+    /// Whether we ask for the compact peer list.
+    pub compact: bool,
+    /// Asks the tracker to leave out the peer ids of the non-compact peer list.
+    pub no_peer_id: bool,
+    /// Announces to every tracker of every tier at once, each on its own interval, instead of
+    /// only to the first tracker that answers.
+    pub announce_to_all: bool,
+}
+
+impl Default for AnnounceSettings {
+    fn default() -> Self {
+        Self {
+            numwant: None,
+            compact: true,
+            no_peer_id: false,
+            announce_to_all: false,
+        }
+    }
+}
+
+/// Hard caps for new torrents, e.g. so a hostile magnet link can't fill the disk of a daemon.
+/// None means unlimited.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct TorrentLimits {
+    pub max_total_size: Option<u64>,
+    pub max_pieces: Option<usize>,
+    pub max_files: Option<usize>,
+}
+
+impl TorrentLimits {
+    pub fn check(&self, metainfo: &Metainfo) -> Result<(), LimitError> {
+        let size = metainfo.total_size();
+        if let Some(max) = self.max_total_size
+            && size > max
+        {
+            return Err(LimitError::TooLarge { size, max });
+        }
+        let pieces = metainfo.pieces.len();
+        if let Some(max) = self.max_pieces
+            && pieces > max
+        {
+            return Err(LimitError::TooManyPieces { pieces, max });
+        }
+        let files = metainfo.n_files();
+        if let Some(max) = self.max_files
+            && files > max
+        {
+            return Err(LimitError::TooManyFiles { files, max });
+        }
+        Ok(())
+    }
+}
+
+/// Settings for constrained devices. The bookkeeping per piece is packed in any case: what we and
+/// every peer have and the pieces in flight take one bit per piece, the rarity counts a few bits
+/// per piece. What this adds is a bound on the piece buffers.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct LowMemory {
+    /// The maximum amount of bytes the buffers of the pieces we're currently downloading may take up.
+    /// It has to be at least the piece length of the torrent.
+    pub piece_buffer_budget: u64,
+}
+
+/// A seeding policy shared by all torrents assigned to it.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct RatioGroup {
+    /// We stop seeding once we uploaded `ratio` times the size of the torrent.
+    pub ratio: f64,
+}
+
+impl RatioGroup {
+    pub fn is_reached(&self, uploaded: u64, torrent_length: u64) -> bool {
+        // an empty torrent has nothing to seed
+        torrent_length == 0 || uploaded as f64 / torrent_length as f64 >= self.ratio
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let ratio_groups = HashMap::from([
+            ("private-trackers".to_string(), RatioGroup { ratio: 2.0 }),
+            ("public".to_string(), RatioGroup { ratio: 0.5 }),
+        ]);
+        Self {
+            ratio_groups,
+            default_ratio_group: None,
+            min_announce_gap_ms: 1000,
+            low_memory: None,
+            download_dir: PathBuf::from("."),
+            state_dir: PathBuf::from("."),
+            limits: TorrentLimits::default(),
+            connection_rules: ConnectionRules::default(),
+            announce: AnnounceSettings::default(),
+            tracker_tls: TrackerTls::default(),
+            tracker_compat: TrackerCompat::default(),
+            queue: QueueLimits::default(),
+            dht: DhtSettings::default(),
+            local_discovery: true,
+            max_disk_rate: None,
+            max_upload_rate: None,
+            max_download_rate: None,
+            upload_slots: 5,
+            stall_timeout_secs: Some(300),
+            block_timeout_secs: 60,
+            socket: SocketOptions::default(),
+            integrity_report: false,
+            bans: BanSettings::default(),
+            lazy_bitfield: false,
+            event_history: 256,
+            max_peers_per_torrent: Some(50),
+            max_peers: Some(200),
+            mmap_reads: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn read_from_file(path: &PathBuf) -> Result<Self, ConfigError> {
+        let bytes = std::fs::read(path).map_err(|error| ConfigError::IOReadError {
+            error,
+            path: path.clone(),
+        })?;
+        let config = serde_json::from_slice::<Config>(&bytes)?;
+        if let Some(name) = &config.default_ratio_group {
+            config.ratio_group(name)?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn paths(&self) -> Paths {
+        Paths::new(self.download_dir.clone(), self.state_dir.clone())
+    }
+
+    pub fn min_announce_gap(&self) -> Duration {
+        Duration::from_millis(self.min_announce_gap_ms)
+    }
+
+    /// looks up a ratio group by its name
+    pub fn stall_timeout(&self) -> Option<Duration> {
+        self.stall_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn block_timeout(&self) -> Duration {
+        Duration::from_secs(self.block_timeout_secs)
+    }
+
+    pub fn ratio_group(&self, name: &str) -> Result<&RatioGroup, ConfigError> {
+        self.ratio_groups
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownRatioGroup(name.to_string()))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed with error `{error}` to read the config file with path `{path}`")]
+    IOReadError {
+        error: std::io::Error,
+        path: PathBuf,
+    },
+    #[error("Failed to deserialize the config file: `{0}`")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("There is no ratio group with the name `{0}` in the config")]
+    UnknownRatioGroup(String),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LimitError {
+    #[error("The torrent has {size} bytes but at most {max} bytes are allowed")]
+    TooLarge { size: u64, max: u64 },
+    #[error("The torrent has {pieces} pieces but at most {max} pieces are allowed")]
+    TooManyPieces { pieces: usize, max: usize },
+    #[error("The torrent has {files} files but at most {max} files are allowed")]
+    TooManyFiles { files: usize, max: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+
+    #[test]
+    fn ratio_group_is_reached() {
+        let group = RatioGroup { ratio: 2.0 };
+        assert!(!group.is_reached(0, 100));
+        assert!(!group.is_reached(199, 100));
+        assert!(group.is_reached(200, 100));
+        assert!(group.is_reached(0, 0));
+    }
+
+    #[test]
+    fn quirks_of_subdomains() {
+        let config: Config = serde_json::from_str(
+            r#"{"tracker_compat": {"quirks": {
+                "example.org": {"no_compact": true},
+                "tracker.example.org": {"no_keepalive": true}
+            }}}"#,
+        )
+        .unwrap();
+        let compat = &config.tracker_compat;
+        assert!(
+            compat
+                .quirks_for("tracker.example.org")
+                .unwrap()
+                .no_keepalive
+        );
+        assert!(compat.quirks_for("udp.example.org").unwrap().no_compact);
+        assert!(compat.quirks_for("example.org").unwrap().no_compact);
+        assert_eq!(compat.quirks_for("badexample.org"), None);
+    }
+
+    #[test]
+    fn parse_partial_config() {
+        let config: Config =
+            serde_json::from_str(r#"{"ratio_groups": {"archive": {"ratio": 10.0}}}"#).unwrap();
+        assert_eq!(
+            config.ratio_group("archive").unwrap(),
+            &RatioGroup { ratio: 10.0 }
+        );
+        assert!(config.ratio_group("public").is_err());
+        assert_eq!(config.default_ratio_group, None);
+    }
+
+    #[test]
+    fn limits_reject_large_torrents() {
+        // 4 pieces of 16KiB
+        let metainfo = test_fixtures::metainfo(65536, 16384);
+
+        assert_eq!(TorrentLimits::default().check(&metainfo), Ok(()));
+        let limits = TorrentLimits {
+            max_total_size: Some(1 << 16),
+            max_pieces: Some(4),
+            max_files: Some(1),
+        };
+        assert_eq!(limits.check(&metainfo), Ok(()));
+        let limits = TorrentLimits {
+            max_total_size: Some(1000),
+            ..limits
+        };
+        assert_eq!(
+            limits.check(&metainfo),
+            Err(LimitError::TooLarge {
+                size: 65536,
+                max: 1000
+            })
+        );
+        let limits = TorrentLimits {
+            max_total_size: None,
+            max_pieces: Some(3),
+            ..limits
+        };
+        assert_eq!(
+            limits.check(&metainfo),
+            Err(LimitError::TooManyPieces { pieces: 4, max: 3 })
+        );
+    }
+}
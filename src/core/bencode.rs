@@ -0,0 +1,437 @@
+//! A bencode value that keeps dictionaries in the order they were read.
+//! serde_bencode sorts the keys when it writes a dictionary, so a torrent whose keys aren't
+//! sorted would get another info hash after a roundtrip through it.
+//! The decoder only takes the canonical encoding of numbers and lengths,
+//! so encoding a decoded value gives back the exact bytes.
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(Dict),
+}
+
+/// the entries in the order they were read or inserted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dict(pub Vec<(Vec<u8>, Value)>);
+
+impl Dict {
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// replaces the value of the key or appends the entry
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: Value) {
+        let key = key.into();
+        match self.0.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((key, value)),
+        }
+    }
+
+    /// sorts the keys like the spec wants them, all the way down
+    pub fn sort(&mut self) {
+        self.0.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (_, value) in self.0.iter_mut() {
+            value.sort();
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BencodeError {
+    #[error("The bencode ended early")]
+    Eof,
+    #[error("Invalid bencode at byte {at}: {reason}")]
+    Invalid { at: usize, reason: &'static str },
+    #[error("Found {0} bytes after the bencode value")]
+    TrailingBytes(usize),
+}
+
+impl BencodeError {
+    /// where in the input it went wrong
+    pub fn offset(&self, input_len: usize) -> usize {
+        match self {
+            BencodeError::Eof => input_len,
+            BencodeError::Invalid { at, .. } => *at,
+            BencodeError::TrailingBytes(n) => input_len - n,
+        }
+    }
+}
+
+/// A step into a value, e.g. `info.files[3].path` is four of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(Vec<u8>),
+    Index(usize),
+}
+
+/// writes the path the way it'd be written in code
+pub fn path_to_string(path: &[Segment]) -> String {
+    let mut out = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if out.is_empty() => out.push_str(&String::from_utf8_lossy(key)),
+            Segment::Key(key) => {
+                out.push('.');
+                out.push_str(&String::from_utf8_lossy(key));
+            }
+            Segment::Index(i) => out.push_str(&format!("[{i}]")),
+        }
+    }
+    out
+}
+
+impl Value {
+    /// decodes exactly one value, trailing bytes are an error
+    pub fn decode(bytes: &[u8]) -> Result<Self, BencodeError> {
+        Self::decode_traced(bytes).map_err(|(e, _)| e)
+    }
+
+    /// like [`Value::decode`], with the path to the value the error is in
+    pub fn decode_traced(bytes: &[u8]) -> Result<Self, (BencodeError, Vec<Segment>)> {
+        let mut decoder = Decoder::new(bytes);
+        let value = match decoder.value() {
+            Ok(value) => value,
+            Err(e) => {
+                // the segments were pushed on the way out
+                decoder.path.reverse();
+                return Err((e, decoder.path));
+            }
+        };
+        match bytes.len() - decoder.at {
+            0 => Ok(value),
+            n => Err((BencodeError::TrailingBytes(n), Vec::new())),
+        }
+    }
+
+    /// The offset of the value at the path, or of the deepest one on the way that exists,
+    /// e.g. the dictionary that misses the key.
+    pub fn locate(bytes: &[u8], path: &[Segment]) -> usize {
+        let mut decoder = Decoder::new(bytes);
+        for segment in path {
+            let start = decoder.at;
+            let found = match (decoder.peek(), segment) {
+                (Ok(b'd'), Segment::Key(wanted)) => {
+                    decoder.at += 1;
+                    decoder.find(|decoder| Ok(decoder.string()? == *wanted))
+                }
+                (Ok(b'l'), Segment::Index(wanted)) => {
+                    decoder.at += 1;
+                    let mut i = 0;
+                    decoder.find(|_| {
+                        i += 1;
+                        Ok(i - 1 == *wanted)
+                    })
+                }
+                _ => false,
+            };
+            if !found {
+                return start;
+            }
+        }
+        decoder.at
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => out.extend_from_slice(format!("i{i}e").as_bytes()),
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict.0.iter() {
+                    encode_bytes(key, out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// What the `decode` command prints: strings that aren't UTF-8, like the piece hashes, are
+    /// written in hex. The keys of a dictionary keep their order.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.json_into(&mut out);
+        out
+    }
+
+    fn json_into(&self, out: &mut String) {
+        match self {
+            Value::Int(n) => out.push_str(&n.to_string()),
+            Value::Bytes(bytes) => out.push_str(&json_string(bytes)),
+            Value::List(list) => {
+                out.push('[');
+                for (i, value) in list.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    value.json_into(out);
+                }
+                out.push(']');
+            }
+            Value::Dict(dict) => {
+                out.push('{');
+                for (i, (key, value)) in dict.0.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push_str(&json_string(key));
+                    out.push(':');
+                    value.json_into(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    fn sort(&mut self) {
+        match self {
+            Value::List(list) => list.iter_mut().for_each(Value::sort),
+            Value::Dict(dict) => dict.sort(),
+            Value::Int(_) | Value::Bytes(_) => {}
+        }
+    }
+}
+
+fn json_string(bytes: &[u8]) -> String {
+    let string = match std::str::from_utf8(bytes) {
+        Ok(string) => string.to_string(),
+        Err(_) => hex::encode(bytes),
+    };
+    serde_json::Value::String(string).to_string()
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    /// where the error is, innermost first
+    path: Vec<Segment>,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            at: 0,
+            path: Vec::new(),
+        }
+    }
+
+    /// Steps through the entries of a list or dictionary until `is_it` says so, it reads the key
+    /// of a dictionary entry. Leaves the decoder at the value that was found.
+    fn find(&mut self, mut is_it: impl FnMut(&mut Self) -> Result<bool, BencodeError>) -> bool {
+        while self.peek().is_ok_and(|b| b != b'e') {
+            match is_it(self) {
+                Ok(true) => return true,
+                Ok(false) if self.value().is_ok() => {}
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.bytes.get(self.at).copied().ok_or(BencodeError::Eof)
+    }
+
+    fn invalid(&self, reason: &'static str) -> BencodeError {
+        BencodeError::Invalid {
+            at: self.at,
+            reason,
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, BencodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.at += 1;
+                let i = self.number(b'e')?;
+                Ok(Value::Int(i))
+            }
+            b'l' => {
+                self.at += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    let i = list.len();
+                    let value = self
+                        .value()
+                        .inspect_err(|_| self.path.push(Segment::Index(i)))?;
+                    list.push(value);
+                }
+                self.at += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.at += 1;
+                let mut dict = Dict::default();
+                while self.peek()? != b'e' {
+                    let start = self.at;
+                    let key = self.string()?;
+                    if dict.get(&key).is_some() {
+                        return Err(BencodeError::Invalid {
+                            at: start,
+                            reason: "duplicate dictionary key",
+                        });
+                    }
+                    let value = match self.value() {
+                        Ok(value) => value,
+                        Err(e) => {
+                            self.path.push(Segment::Key(key));
+                            return Err(e);
+                        }
+                    };
+                    dict.0.push((key, value));
+                }
+                self.at += 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.string()?)),
+            _ => Err(self.invalid("expected a value")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, BencodeError> {
+        if !self.peek()?.is_ascii_digit() {
+            return Err(self.invalid("expected a byte string"));
+        }
+        let start = self.at;
+        let len = usize::try_from(self.number(b':')?).map_err(|_| BencodeError::Invalid {
+            at: start,
+            reason: "the length doesn't fit",
+        })?;
+        let bytes = self
+            .bytes
+            .get(self.at..self.at.saturating_add(len))
+            .ok_or(BencodeError::Eof)?;
+        self.at += len;
+        Ok(bytes.to_vec())
+    }
+
+    /// reads a number in its only valid form up to the terminator: no leading zeros, no `-0`
+    fn number(&mut self, terminator: u8) -> Result<i64, BencodeError> {
+        let start = self.at;
+        let len = self.bytes[start..]
+            .iter()
+            .position(|b| *b == terminator)
+            .ok_or(BencodeError::Eof)?;
+        let digits = &self.bytes[start..start + len];
+        let invalid = |reason| BencodeError::Invalid { at: start, reason };
+        let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+        if unsigned.is_empty() || !unsigned.iter().all(u8::is_ascii_digit) {
+            return Err(invalid("not a number"));
+        }
+        if (unsigned.len() > 1 && unsigned[0] == b'0') || digits == b"-0" {
+            return Err(invalid("not the canonical form of the number"));
+        }
+        let number = std::str::from_utf8(digits)
+            .expect("the digits are ascii")
+            .parse()
+            .map_err(|_| invalid("the number doesn't fit"))?;
+        self.at = start + len + 1;
+        Ok(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_keeps_the_order() {
+        let bytes = b"d4:name4:test6:lengthi-3e5:filesl3:abce0:dee";
+        let value = Value::decode(bytes).unwrap();
+        assert_eq!(value.encode(), bytes);
+
+        let mut sorted = value;
+        if let Value::Dict(dict) = &mut sorted {
+            dict.sort();
+        }
+        assert_eq!(
+            sorted.encode(),
+            b"d0:de5:filesl3:abce6:lengthi-3e4:name4:teste"
+        );
+    }
+
+    #[test]
+    fn rejects_non_canonical_encodings() {
+        for (bytes, at) in [
+            (&b"i03e"[..], 1),
+            (b"i-0e", 1),
+            (b"01:a", 0),
+            (b"d1:ai1e1:ai2ee", 7),
+        ] {
+            assert!(
+                matches!(Value::decode(bytes), Err(BencodeError::Invalid { at: pos, .. }) if pos == at),
+                "{bytes:?}"
+            );
+        }
+        assert_eq!(Value::decode(b"4:ab"), Err(BencodeError::Eof));
+        assert_eq!(
+            Value::decode(b"i1ei2e"),
+            Err(BencodeError::TrailingBytes(3))
+        );
+    }
+
+    #[test]
+    fn decode_goldens() {
+        use crate::test_fixtures::{TORRENTS, assert_golden, read};
+
+        let inputs = [
+            "5:hello",
+            "0:",
+            "i52e",
+            "i-52e",
+            "l5:helloi52ee",
+            "lli4eei5ee",
+            "d3:foo3:bar5:helloi52ee",
+            "d4:spaml1:a1:bee",
+            "d1:q2:\"\\e",
+        ];
+        let decoded: Vec<_> = inputs
+            .iter()
+            .map(|input| {
+                let value = Value::decode(input.as_bytes()).unwrap();
+                format!("{input}\n{}\n", value.to_json())
+            })
+            .collect();
+        assert_golden("decode.golden", &decoded.concat());
+
+        for name in TORRENTS {
+            let bytes = read(&format!("{name}.torrent"));
+            let json = Value::decode(&bytes).unwrap().to_json();
+            assert_golden(&format!("{name}.json"), &(json + "\n"));
+        }
+    }
+}
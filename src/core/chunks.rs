@@ -0,0 +1,137 @@
+//! Pages of the large values a status API serves, so no response holds megabytes at once.
+//! The info dict of a torrent with 100k pieces alone has 2 MB of piece hashes. A reader asks for
+//! the chunk at `next_offset` until there's none and puts them together with [`MetadataAssembler`].
+//! The piece map is paged the same way, see [`crate::PieceMap::page`].
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::torrent::{Metainfo, TorrentError};
+
+#[derive(Debug, Error)]
+pub enum ChunkError {
+    #[error("Expected the chunk at {expected}, got the one at {found}")]
+    OutOfOrder { expected: u64, found: u64 },
+    #[error("The chunks are of different snapshots, one is {0} long and the other {1}")]
+    LengthChanged(u64, u64),
+    #[error("Only got {received} of {total}")]
+    Incomplete { received: u64, total: u64 },
+    #[error("The data of the chunk isn't hex: `{0}`")]
+    Hex(#[from] hex::FromHexError),
+    #[error("The reassembled metadata is invalid: {0}")]
+    Metainfo(#[from] TorrentError),
+}
+
+/// A slice of the bencoded info dict.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataChunk {
+    pub offset: u64,
+    /// the length of the whole info dict
+    pub total_len: u64,
+    /// hex, JSON has no bytes
+    pub data: String,
+}
+
+impl MetadataChunk {
+    /// at most `max_len` bytes of `bytes` from `offset` on, e.g. of [`Metainfo::to_bytes`]
+    pub fn of(bytes: &[u8], offset: u64, max_len: usize) -> Self {
+        let start = (offset as usize).min(bytes.len());
+        let end = start.saturating_add(max_len.max(1)).min(bytes.len());
+        Self {
+            offset: start as u64,
+            total_len: bytes.len() as u64,
+            data: hex::encode(&bytes[start..end]),
+        }
+    }
+
+    /// where the next chunk starts, None if this is the last one
+    pub fn next_offset(&self) -> Option<u64> {
+        let end = self.offset + self.data.len() as u64 / 2;
+        (end < self.total_len).then_some(end)
+    }
+}
+
+/// Puts the chunks back together, they have to come in order.
+#[derive(Debug, Default)]
+pub struct MetadataAssembler {
+    bytes: Vec<u8>,
+    total_len: Option<u64>,
+}
+
+impl MetadataAssembler {
+    pub fn push(&mut self, chunk: &MetadataChunk) -> Result<(), ChunkError> {
+        let total_len = *self.total_len.get_or_insert(chunk.total_len);
+        if total_len != chunk.total_len {
+            return Err(ChunkError::LengthChanged(total_len, chunk.total_len));
+        }
+        let expected = self.bytes.len() as u64;
+        if chunk.offset != expected {
+            return Err(ChunkError::OutOfOrder {
+                expected,
+                found: chunk.offset,
+            });
+        }
+        self.bytes.extend(hex::decode(&chunk.data)?);
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<Metainfo, ChunkError> {
+        let total = self.total_len.unwrap_or(0);
+        let received = self.bytes.len() as u64;
+        if received != total || self.total_len.is_none() {
+            return Err(ChunkError::Incomplete { received, total });
+        }
+        Ok(Metainfo::from_bytes(&self.bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_roundtrip() {
+        let info = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let metainfo = Metainfo::from_bytes(info).unwrap();
+        let bytes = metainfo.to_bytes();
+
+        let mut assembler = MetadataAssembler::default();
+        let mut offset = Some(0);
+        let mut chunks = 0;
+        while let Some(at) = offset {
+            let chunk = MetadataChunk::of(&bytes, at, 16);
+            assembler.push(&chunk).unwrap();
+            offset = chunk.next_offset();
+            chunks += 1;
+        }
+        assert_eq!(chunks, bytes.len().div_ceil(16));
+        assert_eq!(
+            assembler.finish().unwrap().info_hash(),
+            metainfo.info_hash()
+        );
+    }
+
+    #[test]
+    fn chunks_in_order() {
+        let bytes = [1u8; 40];
+        let mut assembler = MetadataAssembler::default();
+        assert!(matches!(
+            assembler.push(&MetadataChunk::of(&bytes, 16, 16)),
+            Err(ChunkError::OutOfOrder {
+                expected: 0,
+                found: 16
+            })
+        ));
+        assembler.push(&MetadataChunk::of(&bytes, 0, 16)).unwrap();
+        assert!(matches!(
+            assembler.push(&MetadataChunk::of(&[1u8; 50], 16, 16)),
+            Err(ChunkError::LengthChanged(40, 50))
+        ));
+        assert!(matches!(
+            assembler.finish(),
+            Err(ChunkError::Incomplete {
+                received: 16,
+                total: 40
+            })
+        ));
+    }
+}
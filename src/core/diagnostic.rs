@@ -0,0 +1,151 @@
+//! serde_bencode only says what went wrong, e.g. "Invalid Type: integer", not where.
+//! A broken torrent is deserialized once more to find the key path of the failure and the byte
+//! offset it's at, see [`BencodeDiagnostic`].
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::core::bencode::{Segment, Value, path_to_string};
+
+/// the bytes shown before and after the failure
+const CONTEXT: usize = 16;
+
+/// Where a bencode value didn't have the shape we expected.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{message} at byte {offset}{}\n{}", in_path(.path), hexdump(*.snippet_start, .snippet, *.offset))]
+pub struct BencodeDiagnostic {
+    pub message: String,
+    pub offset: usize,
+    /// e.g. `info.files[3].path`, empty for the outermost value
+    pub path: String,
+    /// the bytes around the offset
+    pub snippet: Vec<u8>,
+    pub snippet_start: usize,
+}
+
+impl BencodeDiagnostic {
+    fn new(bytes: &[u8], message: String, offset: usize, path: &[Segment]) -> Self {
+        let snippet_start = offset.saturating_sub(CONTEXT) / CONTEXT * CONTEXT;
+        let end = (offset + CONTEXT).min(bytes.len());
+        Self {
+            message,
+            offset,
+            path: path_to_string(path),
+            snippet: bytes[snippet_start.min(end)..end].to_vec(),
+            snippet_start,
+        }
+    }
+}
+
+/// serde_bencode's `from_bytes` with a [`BencodeDiagnostic`] if it fails
+pub(crate) fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, BencodeDiagnostic> {
+    let deserializer = &mut serde_bencode::Deserializer::new(bytes);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        // a syntax error has its position, the path is the best we get for the others
+        if let Err((syntax, path)) = Value::decode_traced(bytes) {
+            let offset = syntax.offset(bytes.len());
+            return BencodeDiagnostic::new(bytes, syntax.to_string(), offset, &path);
+        }
+        let path = segments(e.path());
+        let offset = Value::locate(bytes, &path);
+        BencodeDiagnostic::new(bytes, e.into_inner().to_string(), offset, &path)
+    })
+}
+
+/// up to the first step that serde can't name, e.g. into a flattened struct
+fn segments(path: &serde_path_to_error::Path) -> Vec<Segment> {
+    use serde_path_to_error::Segment as Step;
+    path.iter()
+        .map_while(|step| match step {
+            Step::Map { key } => Some(Segment::Key(key.as_bytes().to_vec())),
+            Step::Seq { index } => Some(Segment::Index(*index)),
+            Step::Enum { .. } | Step::Unknown => None,
+        })
+        .collect()
+}
+
+fn in_path(path: &str) -> String {
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!(", in `{path}`")
+    }
+}
+
+/// `xxd` style, the line with the offset is marked
+fn hexdump(start: usize, bytes: &[u8], offset: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(CONTEXT).enumerate() {
+        let line_start = start + i * CONTEXT;
+        let marker = if (line_start..line_start + CONTEXT).contains(&offset) {
+            '>'
+        } else {
+            ' '
+        };
+        let hex: Vec<_> = line.iter().map(|b| format!("{b:02x}")).collect();
+        let ascii: String = line
+            .iter()
+            .map(|b| {
+                if b.is_ascii_graphic() {
+                    *b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{marker} {line_start:08x}: {:<47}  {ascii}\n",
+            hex.join(" ")
+        ));
+    }
+    out.trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+
+    #[test]
+    fn type_error_in_a_field() {
+        let bytes = b"d8:announce15:http://tracker/4:infod4:namei3e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let diagnostic = from_bytes::<Torrent>(bytes).unwrap_err();
+        assert_eq!(diagnostic.path, "info.name");
+        assert_eq!(diagnostic.offset, 42);
+        assert_eq!(&bytes[diagnostic.offset..][..3], b"i3e");
+        assert!(diagnostic.message.contains("integer `3`"));
+        let shown = diagnostic.to_string();
+        assert!(shown.contains("at byte 42, in `info.name`"), "{shown}");
+        assert!(shown.contains("> 00000020: "), "{shown}");
+    }
+
+    #[test]
+    fn syntax_error_deep_down() {
+        let bytes = b"d4:infod5:filesld4:pathl1:aeed4:pathl1:bi3xeeeeee";
+        let diagnostic = from_bytes::<serde_bencode::value::Value>(bytes).unwrap_err();
+        assert_eq!(diagnostic.path, "info.files[1].path[1]");
+        assert_eq!(diagnostic.offset, 41);
+        assert!(diagnostic.message.contains("not a number"));
+        // the snippet starts at a line
+        assert_eq!(diagnostic.snippet_start, 16);
+        assert_eq!(diagnostic.snippet, bytes[16..].to_vec());
+    }
+
+    #[test]
+    fn locate_follows_the_path() {
+        let bytes = b"d1:ali1ei2ee1:bd1:ci3eee";
+        let c = [Segment::Key(b"b".to_vec()), Segment::Key(b"c".to_vec())];
+        assert_eq!(Value::locate(bytes, &c), 19);
+        assert_eq!(
+            Value::locate(bytes, &[Segment::Key(b"a".to_vec()), Segment::Index(1)]),
+            8
+        );
+        // there's no `d`, the dictionary it'd be in
+        assert_eq!(
+            Value::locate(
+                bytes,
+                &[Segment::Key(b"b".to_vec()), Segment::Key(b"d".to_vec())]
+            ),
+            15
+        );
+    }
+}
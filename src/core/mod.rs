@@ -1 +1,4 @@
+pub mod bencode;
+pub mod chunks;
+pub mod diagnostic;
 pub mod torrent;
@@ -1,11 +1,17 @@
 use std::path::PathBuf;
 
+use bytes::Bytes;
 pub use hashes::Hashes;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use thiserror::Error;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use crate::core::{
+    bencode::{BencodeError, Value},
+    diagnostic::{self, BencodeDiagnostic},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct InfoHash(pub [u8; 20]);
 
 mod ser_info_hash {
@@ -24,22 +30,52 @@ mod ser_info_hash {
 }
 
 mod hashes {
-    use serde::de::{self, Visitor};
+    use serde::de::{self, SeqAccess, Visitor};
     use serde::ser::{Serialize, Serializer};
     use serde::{Deserialize, Deserializer};
-    use std::fmt;
+    use std::{fmt, sync::Arc};
 
-    #[derive(Debug, Clone)]
-    pub struct Hashes(pub Vec<[u8; 20]>);
+    /// The SHA1 hashes of the pieces, 20 bytes each and all in one allocation.
+    /// Clones share it, the hashes of a large torrent are megabytes.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Hashes(Arc<[u8]>);
     struct HashesVisitor;
 
+    impl Hashes {
+        /// None if the length isn't a multiple of 20
+        pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+            (bytes.len() % 20 == 0).then(|| Self(bytes.into()))
+        }
+
+        /// the number of pieces
+        pub fn len(&self) -> usize {
+            self.0.len() / 20
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        /// Panics if there's no such piece.
+        pub fn hash_of(&self, piece_i: usize) -> &[u8; 20] {
+            self.0[piece_i * 20..piece_i * 20 + 20]
+                .try_into()
+                .expect("the range is 20 bytes")
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = &[u8; 20]> {
+            self.0
+                .chunks_exact(20)
+                .map(|hash| hash.try_into().expect("the chunks are 20 bytes"))
+        }
+    }
+
     impl Serialize for Hashes {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: Serializer,
         {
-            let single_slice = self.0.concat();
-            serializer.serialize_bytes(&single_slice)
+            serializer.serialize_bytes(&self.0)
         }
     }
 
@@ -54,18 +90,24 @@ mod hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
-                Err(de::Error::custom(format!(
+            Hashes::from_bytes(v).ok_or_else(|| {
+                de::Error::custom(format!(
                     "Bytes which length is a multiple of 20. Got {:?}",
                     v.len()
-                )))
-            } else {
-                Ok(Hashes(
-                    v.chunks_exact(20)
-                        .map(|slice_20| slice_20.try_into().expect("slice_20 is 20 bytes"))
-                        .collect(),
                 ))
+            })
+        }
+
+        /// self-describing formats like JSON store bytes as a list of numbers
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element::<u8>()? {
+                bytes.push(byte);
             }
+            self.visit_bytes(&bytes)
         }
     }
 
@@ -82,20 +124,102 @@ mod hashes {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 /// The Metainfo files
 pub struct Torrent {
-    /// The url of the tracker.
-    pub announce: url::Url,
+    /// The url of the tracker, trackerless torrents find their peers via the DHT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<url::Url>,
+    /// The tiers of trackers (BEP 12), if the torrent has more than one.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// The web seeds (BEP 19), HTTP servers that have the whole torrent.
+    /// Some torrents give a single url instead of a list.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
     /// This maps to a dictionary.
     pub info: Metainfo,
 }
 
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    let urls = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    };
+    // an empty string is how some tools say there are none
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
 impl Torrent {
     pub fn read_from_file(path: &PathBuf) -> Result<Self, TorrentError> {
         let bytes = std::fs::read(path).map_err(|error| TorrentError::IOReadError {
             error,
             path: path.clone(),
         })?;
-        let torrent = serde_bencode::from_bytes::<Torrent>(&bytes)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// what the `info` command prints, everything but the piece hashes
+    pub fn summary(&self) -> String {
+        let info = &self.info;
+        let mut summary = format!(
+            "Info Hash: {}\nPiece Length: {}\nName: {}\nPieces: {}\nPrivate: {}\nFiles:\n",
+            hex::encode(info.info_hash().0),
+            info.piece_length,
+            info.name,
+            info.pieces.len(),
+            if info.is_private() { "yes" } else { "no" },
+        );
+        match &info.files {
+            Key::SingleFile { .. } => {
+                let length = info.length.map_or("?".to_string(), |l| l.to_string());
+                summary += &format!("  {} ({length} bytes)\n", info.name);
+            }
+            Key::MultiFile { files, .. } => {
+                for file in files {
+                    summary += &format!("  {} ({} bytes)\n", file.path.join("/"), file.length);
+                }
+            }
+        }
+        // serde_bencode keeps dicts in a HashMap, written out they're sorted
+        let other = serde_bencode::to_bytes(&info.other)
+            .ok()
+            .and_then(|bytes| Value::decode(&bytes).ok());
+        if let Some(Value::Dict(other)) = other
+            && !other.0.is_empty()
+        {
+            summary += "Other Keys:\n";
+            for (key, value) in &other.0 {
+                summary += &format!("  {}: {}\n", String::from_utf8_lossy(key), value.to_json());
+            }
+        }
+        summary
+    }
 
+    /// keeps the info dictionary as it is in the file, so the info hash matches the one of the swarm
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TorrentError> {
+        let mut torrent = diagnostic::from_bytes::<Torrent>(bytes)?;
+        let info = Value::decode(bytes)?
+            .as_dict()
+            .and_then(|torrent| torrent.get(b"info"))
+            .map(Value::encode)
+            .ok_or(TorrentError::NoInfo)?;
+        torrent.info.raw = Some(info.into());
         Ok(torrent)
     }
 }
@@ -119,18 +243,64 @@ pub struct Metainfo {
     pub files: Key,
     #[serde(flatten)]
     pub other: serde_bencode::value::Value,
+    /// the dictionary as we got it, None if it comes from the database
+    #[serde(skip)]
+    raw: Option<Bytes>,
 }
 
 impl Metainfo {
+    /// the metadata exactly as we got it, the bytes we hash and send to peers
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TorrentError> {
+        let mut metainfo: Metainfo = diagnostic::from_bytes(bytes)?;
+        metainfo.raw = Some(Bytes::copy_from_slice(bytes));
+        Ok(metainfo)
+    }
+
+    /// Falls back to serializing the fields if we don't have the original bytes,
+    /// which only matches them if the keys were sorted.
+    pub fn to_bytes(&self) -> Bytes {
+        match &self.raw {
+            Some(raw) => raw.clone(),
+            None => serde_bencode::to_bytes(&self)
+                .expect("If this doesn't work, the &self provided would be invalid")
+                .into(),
+        }
+    }
+
+    /// Private torrents (BEP 27) may only get their peers from the trackers in the torrent.
+    pub fn is_private(&self) -> bool {
+        match &self.other {
+            serde_bencode::value::Value::Dict(dict) => matches!(
+                dict.get(b"private".as_slice()),
+                Some(serde_bencode::value::Value::Int(1))
+            ),
+            _ => false,
+        }
+    }
+
     pub fn info_hash(&self) -> InfoHash {
         let mut hasher = Sha1::new();
-        let bytes = serde_bencode::to_bytes(&self)
-            .expect("If this doesn't work, the &self provided would be invalid");
-        hasher.update(&bytes);
+        hasher.update(self.to_bytes());
         let info_hash = hasher.finalize();
         InfoHash(info_hash.into())
     }
 
+    /// The size of the torrent without trusting the file lengths:
+    /// if there's no single file length, this is the upper bound given by the pieces.
+    pub fn total_size(&self) -> u64 {
+        match self.length {
+            Some(length) => length as u64,
+            None => self.pieces.len() as u64 * self.piece_length as u64,
+        }
+    }
+
+    pub fn n_files(&self) -> usize {
+        match &self.files {
+            Key::SingleFile { .. } => 1,
+            Key::MultiFile { files, .. } => files.len(),
+        }
+    }
+
     pub fn get_length(&self) -> u32 {
         if let Some(length) = self.length {
             length
@@ -141,7 +311,7 @@ impl Metainfo {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum Key {
     /// In the single file case, length maps to the length of the file in bytes.
@@ -156,6 +326,25 @@ pub enum Key {
         md5sum: Option<String>,
     },
 }
+impl<'de> Deserialize<'de> for Key {
+    /// Read as a struct, so `files` doesn't end up in [`Metainfo::other`] as well.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Fields {
+            files: Option<Vec<File>>,
+            md5sum: Option<String>,
+        }
+        let Fields { files, md5sum } = Fields::deserialize(deserializer)?;
+        Ok(match files {
+            Some(files) => Key::MultiFile { files, md5sum },
+            None => Key::SingleFile { md5sum },
+        })
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     /// The length of the file, in bytes.
@@ -172,6 +361,103 @@ pub enum TorrentError {
         error: std::io::Error,
         path: PathBuf,
     },
-    #[error("Failed to deserialize the torrent bencode: `{0}`")]
-    InvalidBencode(#[from] serde_bencode::Error),
+    #[error("Failed to deserialize the torrent bencode: {0}")]
+    InvalidBencode(#[from] BencodeDiagnostic),
+    #[error("Failed to read the torrent bencode: `{0}`")]
+    Bencode(#[from] BencodeError),
+    #[error("The torrent has no info dictionary")]
+    NoInfo,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_hash_of_unsorted_info() {
+        // `name` comes before `length`, serde_bencode would write them the other way around
+        let info =
+            b"d4:name4:test6:lengthi3e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut torrent = b"d8:announce15:http://tracker/4:info".to_vec();
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        let torrent = Torrent::from_bytes(&torrent).unwrap();
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(torrent.info.info_hash(), InfoHash(expected));
+        assert_eq!(torrent.info.to_bytes(), &info[..]);
+    }
+
+    #[test]
+    fn url_list_may_be_a_single_url() {
+        let info =
+            "4:infod6:lengthi3e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let single = format!("d8:url-list16:http://seed/test{info}e");
+        let torrent = Torrent::from_bytes(single.as_bytes()).unwrap();
+        assert_eq!(torrent.url_list, vec!["http://seed/test"]);
+
+        let list = format!("d8:url-listl12:http://a/dir0:e{info}e");
+        let torrent = Torrent::from_bytes(list.as_bytes()).unwrap();
+        assert_eq!(torrent.url_list, vec!["http://a/dir"]);
+
+        let none = format!("d{info}e");
+        assert!(
+            Torrent::from_bytes(none.as_bytes())
+                .unwrap()
+                .url_list
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn hashes_share_one_allocation() {
+        let mut bytes = vec![1; 20];
+        bytes.extend([2; 20]);
+        let hashes = Hashes::from_bytes(&bytes).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes.hash_of(1), &[2; 20]);
+        assert_eq!(hashes.iter().collect::<Vec<_>>(), vec![&[1; 20], &[2; 20]]);
+        assert_eq!(
+            serde_bencode::to_bytes(&hashes.clone()).unwrap()[3..],
+            bytes
+        );
+        assert!(Hashes::from_bytes(&bytes[1..]).is_none());
+    }
+
+    #[test]
+    fn private_flag() {
+        let public =
+            b"d6:lengthi3e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        assert!(!Metainfo::from_bytes(public).unwrap().is_private());
+        let private = b"d6:lengthi3e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+        assert!(Metainfo::from_bytes(private).unwrap().is_private());
+    }
+
+    #[test]
+    fn sample_torrents() {
+        use crate::test_fixtures::{TORRENTS, assert_golden, torrent};
+
+        for name in TORRENTS {
+            let torrent = torrent(name);
+            assert_golden(&format!("{name}.info"), &torrent.summary());
+            // the info dict is hashed as it is in the file
+            let info = torrent.info.to_bytes();
+            assert_eq!(Metainfo::from_bytes(&info).unwrap().to_bytes(), info);
+        }
+        assert!(torrent("private").info.is_private());
+        assert!(!torrent("single_file").info.is_private());
+        assert_eq!(torrent("multi_file").info.total_size(), 3 * 16384);
+        assert_eq!(torrent("multi_file").info.n_files(), 3);
+        // the files aren't among the unknown keys
+        assert!(matches!(
+            &torrent("multi_file").info.other,
+            serde_bencode::value::Value::Dict(other) if other.is_empty()
+        ));
+        assert_eq!(torrent("large_piece").info.pieces.len(), 3);
+        let tiered = torrent("announce_list");
+        assert_eq!(tiered.announce_list.unwrap().len(), 2);
+        assert_eq!(tiered.url_list, ["http://seed.example/files/"]);
+        // only the v1 part of a hybrid is used
+        assert_eq!(torrent("hybrid").info.pieces.len(), 3);
+    }
 }
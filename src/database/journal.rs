@@ -0,0 +1,187 @@
+//! The writes to the DB that failed and wait for another try.
+//! RocksDB may be locked or the disk may hiccup for a moment, that shouldn't lose a finished piece
+//! or stop the torrent. The write is kept here and retried in the background with backoff.
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use surrealdb::opt::PatchOp;
+
+use crate::database::{DBConnection, DBError};
+
+use crate::sync::Mutex;
+
+const BACKOFF_BASE: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+/// after this many failures in a row the retrying stops until the next write or the flush
+const MAX_RETRIES: u32 = 8;
+
+/// a field of the DBEntry we replace
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Patch {
+    Bitfield(Vec<u8>),
+    File(PathBuf),
+    RatioGroup(String),
+    Uploaded(u64),
+    Trackers(Vec<Vec<url::Url>>),
+}
+
+impl Patch {
+    fn is_same_field(&self, other: &Patch) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    pub(super) fn to_op(&self) -> PatchOp {
+        match self {
+            Patch::Bitfield(bitfield) => {
+                PatchOp::replace("/bitfield", serde_bytes::ByteBuf::from(bitfield.clone()))
+            }
+            Patch::File(file) => PatchOp::replace("/file", file),
+            Patch::RatioGroup(ratio_group) => PatchOp::replace("/ratio_group", ratio_group),
+            Patch::Uploaded(uploaded) => PatchOp::replace("/uploaded", uploaded),
+            Patch::Trackers(trackers) => PatchOp::replace("/trackers", trackers),
+        }
+    }
+}
+
+/// Shared by all clones of a DBConnection.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Journal(Arc<Mutex<JournalInner>>);
+
+#[derive(Debug, Default)]
+struct JournalInner {
+    /// at most one patch per field, a newer value replaces the one that's waiting
+    pending: Vec<Patch>,
+    /// whether a task is retrying the pending patches
+    retrying: bool,
+}
+
+impl Journal {
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().pending.is_empty()
+    }
+
+    /// returns true if nobody is retrying yet and the caller has to start it
+    fn push(&self, patch: Patch) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        match inner.pending.iter_mut().find(|p| p.is_same_field(&patch)) {
+            Some(pending) => *pending = patch,
+            None => inner.pending.push(patch),
+        }
+        !std::mem::replace(&mut inner.retrying, true)
+    }
+
+    fn peek(&self) -> Option<Patch> {
+        self.0.lock().unwrap().pending.first().cloned()
+    }
+
+    /// like `peek`, but the retrying stops if there's nothing left
+    fn next_or_stop(&self) -> Option<Patch> {
+        let mut inner = self.0.lock().unwrap();
+        let next = inner.pending.first().cloned();
+        inner.retrying = next.is_some();
+        next
+    }
+
+    fn stop(&self) {
+        self.0.lock().unwrap().retrying = false;
+    }
+
+    /// the patch is written, unless a newer value replaced it in the meantime
+    fn done(&self, patch: &Patch) {
+        self.0.lock().unwrap().pending.retain(|p| p != patch);
+    }
+}
+
+impl DBConnection {
+    /// Writes the field, if that fails it's kept in the journal and retried in the background.
+    pub(super) async fn patch(&self, patch: Patch) -> Result<(), DBError> {
+        // a write must not overtake an older one that's still waiting
+        if self.journal.is_empty() {
+            match self.apply(&patch).await {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("Failed to write to the DB, trying again later: {e}"),
+            }
+        }
+        if self.journal.push(patch) {
+            tokio::spawn(self.clone().retry());
+        }
+        Ok(())
+    }
+
+    async fn retry(self) {
+        let mut failures = 0;
+        while let Some(patch) = self.journal.next_or_stop() {
+            match self.apply(&patch).await {
+                Ok(()) => {
+                    self.journal.done(&patch);
+                    failures = 0;
+                }
+                Err(e) => {
+                    failures += 1;
+                    let Some(delay) = backoff(failures) else {
+                        eprintln!("Giving up on writing to the DB for now: {e}");
+                        self.journal.stop();
+                        return;
+                    };
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Writes what's left in the journal, so no progress is lost when we shut down.
+    pub(crate) async fn flush(&self) -> Result<(), DBError> {
+        let mut failures = 0;
+        while let Some(patch) = self.journal.peek() {
+            match self.apply(&patch).await {
+                Ok(()) => self.journal.done(&patch),
+                Err(e) => {
+                    failures += 1;
+                    tokio::time::sleep(backoff(failures).ok_or(e)?).await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// None once we should give up
+fn backoff(failures: u32) -> Option<Duration> {
+    (failures <= MAX_RETRIES).then(|| {
+        BACKOFF_BASE
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(BACKOFF_MAX)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_values_replace_waiting_ones() {
+        let journal = Journal::default();
+        assert!(journal.push(Patch::Uploaded(1)));
+        assert!(!journal.push(Patch::Bitfield(vec![0x80])));
+        assert!(!journal.push(Patch::Uploaded(2)));
+        assert_eq!(journal.peek(), Some(Patch::Uploaded(2)));
+
+        // the retry wrote the old value while the new one came in
+        journal.done(&Patch::Uploaded(1));
+        assert_eq!(journal.peek(), Some(Patch::Uploaded(2)));
+        journal.done(&Patch::Uploaded(2));
+        assert_eq!(journal.next_or_stop(), Some(Patch::Bitfield(vec![0x80])));
+        journal.done(&Patch::Bitfield(vec![0x80]));
+        assert_eq!(journal.next_or_stop(), None);
+        assert!(journal.is_empty());
+        // the retrying stopped, the next failure has to start it again
+        assert!(journal.push(Patch::Uploaded(3)));
+    }
+
+    #[test]
+    fn bounded_backoff() {
+        assert_eq!(backoff(1), Some(Duration::from_millis(100)));
+        assert_eq!(backoff(3), Some(Duration::from_millis(400)));
+        assert_eq!(backoff(MAX_RETRIES), Some(BACKOFF_MAX));
+        assert_eq!(backoff(MAX_RETRIES + 1), None);
+    }
+}
@@ -0,0 +1,163 @@
+//! Upgrades the records older versions wrote, so a change to DBEntry doesn't break existing installs.
+//! Every outdated layout has a struct here that converts into the next one.
+//! These structs are frozen: never change them, add a new version instead.
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    database::{DBConnection, DBEntry, DBError, SCHEMA_VERSION},
+    torrent::Metainfo,
+};
+
+/// the key and the version of a record that needs a migration
+#[derive(Debug, Deserialize)]
+struct OutdatedRecord {
+    key: String,
+    #[serde(default)]
+    version: u32,
+}
+
+/// Before records were versioned: one bool per piece.
+/// The stats fields were added later on, so they may be missing too.
+#[derive(Debug, Deserialize)]
+struct DBEntryV0 {
+    bitfield: Vec<bool>,
+    file: PathBuf,
+    torrent_info: Metainfo,
+    announce: url::Url,
+    #[serde(default)]
+    ratio_group: Option<String>,
+    #[serde(default)]
+    uploaded: u64,
+}
+
+impl From<DBEntryV0> for DBEntryV1 {
+    fn from(old: DBEntryV0) -> Self {
+        let mut bitfield = vec![0u8; old.bitfield.len().div_ceil(8)];
+        for (i, _) in old.bitfield.iter().enumerate().filter(|(_, have)| **have) {
+            bitfield[i / 8] |= 0x80 >> (i % 8);
+        }
+        Self {
+            bitfield,
+            file: old.file.into(),
+            torrent_info: old.torrent_info,
+            announce: Some(old.announce),
+            ratio_group: old.ratio_group,
+            uploaded: old.uploaded,
+        }
+    }
+}
+
+/// Packed bitfields, but only a single tracker, the other trackers of a magnet link were lost.
+#[derive(Debug, Deserialize)]
+struct DBEntryV1 {
+    #[serde(with = "serde_bytes")]
+    bitfield: Vec<u8>,
+    file: Cow<'static, Path>,
+    torrent_info: Metainfo,
+    #[serde(default)]
+    announce: Option<url::Url>,
+    #[serde(default)]
+    ratio_group: Option<String>,
+    #[serde(default)]
+    uploaded: u64,
+}
+
+impl From<DBEntryV1> for DBEntry {
+    fn from(old: DBEntryV1) -> Self {
+        Self {
+            version: 2,
+            bitfield: old.bitfield,
+            file: old.file,
+            torrent_info: old.torrent_info,
+            trackers: old.announce.into_iter().map(|url| vec![url]).collect(),
+            ratio_group: old.ratio_group,
+            uploaded: old.uploaded,
+        }
+    }
+}
+
+impl DBConnection {
+    /// Upgrades every record that is older than [`SCHEMA_VERSION`].
+    /// Fails if a record is newer than what we understand instead of misreading it.
+    pub(super) async fn migrate(&self) -> Result<(), DBError> {
+        let outdated: Vec<OutdatedRecord> = self
+            .db
+            .query(
+                "SELECT record::id(id) AS key, version FROM files WHERE (version ?? 0) != $version",
+            )
+            .bind(("version", SCHEMA_VERSION))
+            .await?
+            .take(0)?;
+        for record in outdated {
+            if record.version > SCHEMA_VERSION {
+                return Err(DBError::NewerSchema {
+                    key: record.key,
+                    found: record.version,
+                });
+            }
+            let key = ("files", record.key.as_str());
+            let old = if record.version == 0 {
+                let old: Option<DBEntryV0> = self.db.select(key).await?;
+                old.map(DBEntryV1::from)
+            } else {
+                self.db.select(key).await?
+            };
+            let Some(old) = old else {
+                continue;
+            };
+            eprintln!(
+                "Migrating the DB record of {} from schema {} to {SCHEMA_VERSION}.",
+                record.key, record.version
+            );
+            let _: Option<DBEntry> = self
+                .db
+                .update(("files", record.key.as_str()))
+                .content(DBEntry::from(old))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a record as the first versions stored it, with the info dict of `sample.torrent`
+    const V0_FIXTURE: &str = r#"{
+        "bitfield": [true, false, false, true, false, false, false, false, true],
+        "file": "/downloads/sample.txt",
+        "torrent_info": {
+            "name": "sample.txt",
+            "piece length": 32768,
+            "pieces": [],
+            "length": 92063
+        },
+        "announce": "http://bittorrent-test-tracker.codecrafters.io/announce"
+    }"#;
+
+    #[test]
+    fn v0_bitfield_is_packed() {
+        let old: DBEntryV0 = serde_json::from_str(V0_FIXTURE).unwrap();
+        let entry = DBEntry::from(DBEntryV1::from(old));
+        assert_eq!(entry.version, SCHEMA_VERSION);
+        assert_eq!(entry.bitfield, vec![0b1001_0000, 0b1000_0000]);
+        assert_eq!(entry.uploaded, 0);
+        assert_eq!(entry.ratio_group, None);
+        assert_eq!(entry.torrent_info.name, "sample.txt");
+        assert_eq!(
+            entry.trackers,
+            [
+                [
+                    url::Url::parse("http://bittorrent-test-tracker.codecrafters.io/announce")
+                        .unwrap()
+                ]
+            ]
+        );
+    }
+}
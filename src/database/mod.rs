@@ -0,0 +1,283 @@
+use std::borrow::Cow;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use surrealdb::RecordId;
+use surrealdb::Surreal;
+use surrealdb::engine::local::Db;
+
+// For a RocksDB file
+use surrealdb::engine::local::RocksDb;
+use thiserror::Error;
+
+use crate::{
+    database::journal::{Journal, Patch},
+    paths::Paths,
+    torrent::{InfoHash, Metainfo, Torrent},
+    tracker::torrent_trackers,
+};
+
+mod journal;
+mod migrations;
+
+/// The layout of the records in the `files` table.
+/// Bump it and add a migration whenever DBEntry changes.
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+/// the actual data stored in the DB
+/// torrent path is also the key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct DBEntry {
+    /// see [`SCHEMA_VERSION`], records written before it existed are version 0
+    #[serde(default)]
+    pub(crate) version: u32,
+    /// one bit per piece, the first piece is the highest bit of the first byte
+    #[serde(with = "serde_bytes")]
+    pub(crate) bitfield: Vec<u8>,
+    pub(crate) file: Cow<'static, Path>,
+    pub(crate) torrent_info: Metainfo,
+    /// In tiers, like the `announce-list`. It has the trackers of every torrent file or magnet link
+    /// of the info hash that was added, see [`crate::tracker::merge_trackers`].
+    #[serde(default)]
+    pub(crate) trackers: Vec<Vec<url::Url>>,
+    /// the name of the ratio group in the config this torrent is assigned to
+    #[serde(default)]
+    pub(crate) ratio_group: Option<String>,
+    /// the total amount of bytes we have uploaded for this torrent
+    #[serde(default)]
+    pub(crate) uploaded: u64,
+}
+
+impl DBEntry {
+    fn from_new_file(file_path: PathBuf, torrent: Torrent) -> Self {
+        let n_pieces = torrent.info.pieces.len();
+        Self {
+            version: SCHEMA_VERSION,
+            bitfield: vec![0; n_pieces.div_ceil(8)],
+            file: file_path.into(),
+            trackers: torrent_trackers(&torrent),
+            torrent_info: torrent.info,
+            ratio_group: None,
+            uploaded: 0,
+        }
+    }
+}
+
+/// The metadata blocks of a magnet link we have downloaded so far.
+/// It's stored in its own table since there's no DBEntry before we have the metadata.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct PartialMetadata {
+    #[serde(with = "serde_bytes")]
+    pub(crate) bytes: Vec<u8>,
+    /// which of the blocks in `bytes` are finished
+    pub(crate) finished: Vec<bool>,
+}
+
+/// The DHT node we were and the nodes that answered us, there's one for all torrents.
+/// Starting from these spares us bootstrapping from scratch on every run.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct DhtRecord {
+    #[serde(with = "serde_bytes")]
+    pub(crate) id: Vec<u8>,
+    pub(crate) nodes: Vec<DhtNodeRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct DhtNodeRecord {
+    #[serde(with = "serde_bytes")]
+    pub(crate) id: Vec<u8>,
+    pub(crate) addr: SocketAddrV4,
+}
+
+/// The peers of a torrent that were the best the last time, the next run dials them first.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct PeerCacheRecord {
+    pub(crate) peers: Vec<CachedPeer>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct CachedPeer {
+    pub(crate) addr: SocketAddr,
+    /// see [`crate::peer_manager::PeerCandidates::best`]
+    pub(crate) score: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    #[allow(dead_code)]
+    id: RecordId,
+}
+#[derive(Debug, Clone)]
+pub(crate) struct DBConnection {
+    pub(crate) db: Surreal<Db>,
+    pub(crate) info_hash_hex: String,
+    /// the writes that failed and are retried
+    journal: Journal,
+}
+
+impl DBConnection {
+    pub(crate) async fn new(paths: &Paths, info_hash: InfoHash) -> Result<DBConnection, DBError> {
+        let info_hash_hex = hex::encode(info_hash.0);
+        let db_conn = Self {
+            db: open(paths).await?,
+            info_hash_hex,
+            journal: Journal::default(),
+        };
+        db_conn.migrate().await?;
+        Ok(db_conn)
+    }
+
+    pub(crate) async fn get_entry(&self) -> Result<Option<DBEntry>, DBError> {
+        let entry = self.db.select(("files", &self.info_hash_hex)).await?;
+        Ok(entry)
+    }
+
+    pub(crate) async fn set_entry(
+        &self,
+        file_path: PathBuf,
+        torrent: Torrent,
+    ) -> Result<DBEntry, DBError> {
+        let file = DBEntry::from_new_file(file_path, torrent);
+        let entry = self
+            .db
+            .create::<Option<DBEntry>>(("files", &self.info_hash_hex))
+            .content(file)
+            .await?
+            .expect("I'm really curious what the error is here.");
+        Ok(entry)
+    }
+
+    /// `new_bitfield` is packed like [`DBEntry::bitfield`]
+    pub(super) async fn update_bitfields(&mut self, new_bitfield: Vec<u8>) -> Result<(), DBError> {
+        self.patch(Patch::Bitfield(new_bitfield)).await
+    }
+
+    /// the download moved from its `.part` path to the final one
+    pub(super) async fn update_file(&self, file: &Path) -> Result<(), DBError> {
+        self.patch(Patch::File(file.to_path_buf())).await
+    }
+
+    /// `trackers` are all trackers, the new ones merged in
+    pub(crate) async fn update_trackers(&self, trackers: &[Vec<url::Url>]) -> Result<(), DBError> {
+        self.patch(Patch::Trackers(trackers.to_vec())).await
+    }
+
+    pub(super) async fn update_ratio_group(&self, ratio_group: &str) -> Result<(), DBError> {
+        self.patch(Patch::RatioGroup(ratio_group.to_string())).await
+    }
+
+    /// writes one field of the entry, see [`DBConnection::patch`] for the one that retries
+    async fn apply(&self, patch: &Patch) -> Result<(), DBError> {
+        let updated: Option<DBEntry> = self
+            .db
+            .update(("files", &self.info_hash_hex))
+            .patch(patch.to_op())
+            .await?;
+
+        assert!(
+            updated.is_some(),
+            "The record for the torrent was already created if wasn't there."
+        );
+
+        Ok(())
+    }
+
+    pub(crate) async fn get_partial_metadata(&self) -> Result<Option<PartialMetadata>, DBError> {
+        let partial = self.db.select(("metadata", &self.info_hash_hex)).await?;
+        Ok(partial)
+    }
+
+    pub(crate) async fn set_partial_metadata(
+        &self,
+        partial: PartialMetadata,
+    ) -> Result<(), DBError> {
+        let _: Option<PartialMetadata> = self
+            .db
+            .upsert(("metadata", &self.info_hash_hex))
+            .content(partial)
+            .await?;
+        Ok(())
+    }
+
+    /// removes the partial metadata once the full info dict is verified
+    pub(crate) async fn delete_partial_metadata(&self) -> Result<(), DBError> {
+        let _: Option<PartialMetadata> = self.db.delete(("metadata", &self.info_hash_hex)).await?;
+        Ok(())
+    }
+
+    pub(super) async fn update_uploaded(&self, uploaded: u64) -> Result<(), DBError> {
+        self.patch(Patch::Uploaded(uploaded)).await
+    }
+}
+
+async fn open(paths: &Paths) -> Result<Surreal<Db>, DBError> {
+    let db_dir = paths.db_dir();
+    std::fs::create_dir_all(&db_dir).map_err(|error| DBError::CreateDir {
+        path: db_dir.clone(),
+        error,
+    })?;
+    let db = Surreal::new::<RocksDb>(db_dir).await?;
+    db.use_ns("files_ns").use_db("files_db").await?;
+    Ok(db)
+}
+
+pub(crate) async fn load_dht(paths: &Paths) -> Result<Option<DhtRecord>, DBError> {
+    let record = open(paths).await?.select(("dht", "node")).await?;
+    Ok(record)
+}
+
+pub(crate) async fn save_dht(paths: &Paths, record: DhtRecord) -> Result<(), DBError> {
+    let _: Option<DhtRecord> = open(paths)
+        .await?
+        .upsert(("dht", "node"))
+        .content(record)
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn load_peers(
+    paths: &Paths,
+    info_hash: InfoHash,
+) -> Result<Option<PeerCacheRecord>, DBError> {
+    let record = open(paths)
+        .await?
+        .select(("peers", hex::encode(info_hash.0)))
+        .await?;
+    Ok(record)
+}
+
+pub(crate) async fn save_peers(
+    paths: &Paths,
+    info_hash: InfoHash,
+    record: PeerCacheRecord,
+) -> Result<(), DBError> {
+    let _: Option<PeerCacheRecord> = open(paths)
+        .await?
+        .upsert(("peers", hex::encode(info_hash.0)))
+        .content(record)
+        .await?;
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum DBError {
+    #[error("Got error from the local DB: `{0}`")]
+    Surreal(Box<surrealdb::Error>),
+    #[error(
+        "The torrent `{key}` was stored by a newer version (schema {found}, we support up to {SCHEMA_VERSION})"
+    )]
+    NewerSchema { key: String, found: u32 },
+    #[error("Failed to create the database directory `{path}`: `{error}`")]
+    CreateDir {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+impl From<surrealdb::Error> for DBError {
+    fn from(value: surrealdb::Error) -> Self {
+        Self::Surreal(Box::new(value))
+    }
+}
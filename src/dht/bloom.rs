@@ -0,0 +1,79 @@
+//! The bloom filters of a DHT scrape (BEP 33).
+//! A node puts the IPs of the peers it stores into a filter instead of listing them,
+//! the filters of several nodes are merged and the number of set bits gives the swarm size.
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+/// the size of a filter on the wire
+pub(super) const BLOOM_LEN: usize = 256;
+const BITS: usize = BLOOM_LEN * 8;
+/// the number of bits an IP sets
+const HASHES: f64 = 2.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct BloomFilter(pub(super) [u8; BLOOM_LEN]);
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self([0; BLOOM_LEN])
+    }
+}
+
+impl BloomFilter {
+    pub(super) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub(super) fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+            IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+        };
+        for index in [
+            u16::from_le_bytes([hash[0], hash[1]]),
+            u16::from_le_bytes([hash[2], hash[3]]),
+        ] {
+            let index = index as usize % BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    /// the filter of all IPs in either filter
+    pub(super) fn union(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0) {
+            *byte |= other;
+        }
+    }
+
+    /// the number of distinct IPs that went into the filter, roughly
+    pub(super) fn estimate(&self) -> u64 {
+        let zeros = self.0.iter().map(|b| b.count_zeros()).sum::<u32>();
+        // a full filter would give infinity
+        let zeros = zeros.max(1) as f64;
+        let bits = BITS as f64;
+        let estimate = (zeros / bits).ln() / (HASHES * (1.0 - 1.0 / bits).ln());
+        estimate.round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn bep_test_vector() {
+        let mut filter = BloomFilter::default();
+        for i in 0..=255 {
+            filter.insert(Ipv4Addr::new(192, 0, 2, i).into());
+        }
+        for i in 0..1000 {
+            filter.insert(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i).into());
+        }
+        assert_eq!(hex::encode(&filter.0[..8]), "f6c3f5eaa07ffd91");
+        // the BEP gives 1224.93
+        assert_eq!(filter.estimate(), 1225);
+    }
+}
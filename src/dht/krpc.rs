@@ -0,0 +1,390 @@
+//! The KRPC messages of the DHT: bencoded dictionaries sent over UDP.
+//! Every message carries a transaction id that the answer has to repeat.
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+
+use crate::{
+    dht::{bloom::BloomFilter, routing_table::NodeId},
+    torrent::InfoHash,
+};
+
+/// the length of a node in the compact `nodes` string
+const COMPACT_NODE_LEN: usize = 26;
+
+/// `a` of a query and `r` of a response, the fields a message doesn't use are missing
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+struct Body {
+    /// the bloom filter of the downloaders (BEP 33)
+    #[serde(rename = "BFpe", default, skip_serializing_if = "Option::is_none")]
+    bf_pe: Option<ByteBuf>,
+    /// the bloom filter of the seeds (BEP 33)
+    #[serde(rename = "BFsd", default, skip_serializing_if = "Option::is_none")]
+    bf_sd: Option<ByteBuf>,
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scrape: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+/// the fields are in the order bencode wants them
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct Message {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<Body>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+    /// the address we see the other node under (BEP 42)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<Body>,
+    t: ByteBuf,
+    y: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: InfoHash,
+        /// asks for the bloom filters of the swarm too
+        scrape: bool,
+    },
+    AnnouncePeer {
+        info_hash: InfoHash,
+        port: u16,
+        /// the peer listens on the port the query came from
+        implied_port: bool,
+        token: Vec<u8>,
+        /// the peer has the whole torrent
+        seed: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Response {
+    pub(super) id: NodeId,
+    pub(super) nodes: Vec<(NodeId, SocketAddrV4)>,
+    /// the peers of a `get_peers`
+    pub(super) values: Vec<SocketAddr>,
+    pub(super) token: Option<Vec<u8>>,
+    /// the answer to a scrape: the seeds and the downloaders the node knows, boxed since they are
+    /// rare and each one is 256 bytes
+    pub(super) seeds: Option<Box<BloomFilter>>,
+    pub(super) downloaders: Option<Box<BloomFilter>>,
+}
+
+impl Response {
+    pub(super) fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+            seeds: None,
+            downloaders: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Kind {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Krpc {
+    pub(super) transaction_id: Vec<u8>,
+    pub(super) kind: Kind,
+    /// set in answers: the address the query came from
+    pub(super) ip: Option<SocketAddr>,
+}
+
+#[derive(Debug, Error)]
+pub enum KrpcError {
+    #[error("Failed to decode a KRPC message: `{0}`")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("The KRPC message is invalid: {0}")]
+    Invalid(&'static str),
+}
+
+impl Krpc {
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let t = ByteBuf::from(self.transaction_id.clone());
+        let ip = self.ip.as_ref().map(encode_peer);
+        let message = match &self.kind {
+            Kind::Query { id, query } => {
+                let mut body = Body {
+                    id: ByteBuf::from(id.0.to_vec()),
+                    ..Body::default()
+                };
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        body.target = Some(ByteBuf::from(target.0.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash, scrape } => {
+                        body.info_hash = Some(ByteBuf::from(info_hash.0.to_vec()));
+                        body.scrape = scrape.then_some(1);
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                        seed,
+                    } => {
+                        body.info_hash = Some(ByteBuf::from(info_hash.0.to_vec()));
+                        body.port = Some(*port);
+                        body.implied_port = Some(*implied_port as i64);
+                        body.token = Some(ByteBuf::from(token.clone()));
+                        body.seed = seed.then_some(1);
+                        "announce_peer"
+                    }
+                };
+                Message {
+                    a: Some(body),
+                    e: None,
+                    ip,
+                    q: Some(name.to_string()),
+                    r: None,
+                    t,
+                    y: "q".to_string(),
+                }
+            }
+            Kind::Response(response) => Message {
+                a: None,
+                e: None,
+                ip,
+                q: None,
+                r: Some(Body {
+                    bf_pe: response
+                        .downloaders
+                        .as_ref()
+                        .map(|filter| ByteBuf::from(filter.0.to_vec())),
+                    bf_sd: response
+                        .seeds
+                        .as_ref()
+                        .map(|filter| ByteBuf::from(filter.0.to_vec())),
+                    id: ByteBuf::from(response.id.0.to_vec()),
+                    nodes: (!response.nodes.is_empty())
+                        .then(|| ByteBuf::from(encode_nodes(&response.nodes))),
+                    token: response.token.clone().map(ByteBuf::from),
+                    values: (!response.values.is_empty())
+                        .then(|| response.values.iter().map(encode_peer).collect()),
+                    ..Body::default()
+                }),
+                t,
+                y: "r".to_string(),
+            },
+            Kind::Error { code, message } => Message {
+                a: None,
+                e: Some((*code, message.clone())),
+                ip,
+                q: None,
+                r: None,
+                t,
+                y: "e".to_string(),
+            },
+        };
+        serde_bencode::to_bytes(&message).expect("a message always serializes")
+    }
+
+    pub(super) fn decode(bytes: &[u8]) -> Result<Self, KrpcError> {
+        let message: Message = serde_bencode::from_bytes(bytes)?;
+        let kind = match message.y.as_str() {
+            "q" => {
+                let body = message.a.ok_or(KrpcError::Invalid("a query without `a`"))?;
+                let query = match message.q.as_deref() {
+                    Some("ping") => Query::Ping,
+                    Some("find_node") => Query::FindNode {
+                        target: NodeId(hash(body.target.as_ref())?),
+                    },
+                    Some("get_peers") => Query::GetPeers {
+                        info_hash: InfoHash(hash(body.info_hash.as_ref())?),
+                        scrape: body.scrape.unwrap_or(0) != 0,
+                    },
+                    Some("announce_peer") => Query::AnnouncePeer {
+                        info_hash: InfoHash(hash(body.info_hash.as_ref())?),
+                        port: body.port.ok_or(KrpcError::Invalid("no port"))?,
+                        implied_port: body.implied_port.unwrap_or(0) != 0,
+                        token: body.token.ok_or(KrpcError::Invalid("no token"))?.into_vec(),
+                        seed: body.seed.unwrap_or(0) != 0,
+                    },
+                    _ => return Err(KrpcError::Invalid("unknown query")),
+                };
+                Kind::Query {
+                    id: NodeId(hash(Some(&body.id))?),
+                    query,
+                }
+            }
+            "r" => {
+                let body = message
+                    .r
+                    .ok_or(KrpcError::Invalid("a response without `r`"))?;
+                Kind::Response(Response {
+                    id: NodeId(hash(Some(&body.id))?),
+                    nodes: body
+                        .nodes
+                        .as_deref()
+                        .map(|nodes| decode_nodes(nodes))
+                        .unwrap_or_default(),
+                    values: body
+                        .values
+                        .iter()
+                        .flatten()
+                        .filter_map(|peer| decode_peer(peer))
+                        .collect(),
+                    token: body.token.map(ByteBuf::into_vec),
+                    seeds: body
+                        .bf_sd
+                        .and_then(|filter| BloomFilter::from_bytes(&filter))
+                        .map(Box::new),
+                    downloaders: body
+                        .bf_pe
+                        .and_then(|filter| BloomFilter::from_bytes(&filter))
+                        .map(Box::new),
+                })
+            }
+            "e" => {
+                let (code, message) = message
+                    .e
+                    .ok_or(KrpcError::Invalid("an error without `e`"))?;
+                Kind::Error { code, message }
+            }
+            _ => return Err(KrpcError::Invalid("unknown message type")),
+        };
+        Ok(Self {
+            transaction_id: message.t.into_vec(),
+            kind,
+            ip: message.ip.as_deref().and_then(|ip| decode_peer(ip)),
+        })
+    }
+}
+
+fn hash(bytes: Option<&ByteBuf>) -> Result<[u8; 20], KrpcError> {
+    bytes
+        .and_then(|bytes| <[u8; 20]>::try_from(bytes.as_slice()).ok())
+        .ok_or(KrpcError::Invalid("an id or hash isn't 20 bytes long"))
+}
+
+fn encode_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for (id, addr) in nodes {
+        bytes.extend_from_slice(&id.0);
+        bytes.extend_from_slice(&addr.ip().octets());
+        bytes.extend_from_slice(&addr.port().to_be_bytes());
+    }
+    bytes
+}
+
+fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .map(|chunk| {
+            let id = NodeId(chunk[..20].try_into().expect("the chunk is 26 bytes"));
+            let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+            let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+            (id, SocketAddrV4::new(ip, port))
+        })
+        .collect()
+}
+
+fn encode_peer(addr: &SocketAddr) -> ByteBuf {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    ByteBuf::from(bytes)
+}
+
+/// 6 bytes for IPv4 peers, 18 for IPv6 peers
+fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_at_checked(bytes.len().checked_sub(2)?)?;
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_matches_the_bep() {
+        let ping = Krpc {
+            transaction_id: b"aa".to_vec(),
+            kind: Kind::Query {
+                id: NodeId(*b"abcdefghij0123456789"),
+                query: Query::Ping,
+            },
+            ip: None,
+        };
+        let bytes = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(ping.encode(), bytes);
+        assert_eq!(Krpc::decode(bytes).unwrap(), ping);
+    }
+
+    #[test]
+    fn get_peers_response_roundtrip() {
+        let mut response = Response::new(NodeId([1; 20]));
+        response.nodes = vec![(NodeId([2; 20]), "10.0.0.1:6881".parse().unwrap())];
+        response.values = vec!["10.0.0.2:51413".parse().unwrap()];
+        response.token = Some(b"token".to_vec());
+        let mut seeds = BloomFilter::default();
+        seeds.insert("10.0.0.3".parse().unwrap());
+        response.seeds = Some(Box::new(seeds));
+        response.downloaders = Some(Box::default());
+        let message = Krpc {
+            transaction_id: vec![0, 7],
+            kind: Kind::Response(response),
+            ip: Some("203.0.113.9:6881".parse().unwrap()),
+        };
+        assert_eq!(Krpc::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn error_matches_the_bep() {
+        let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let error = Krpc::decode(bytes).unwrap();
+        assert_eq!(
+            error.kind,
+            Kind::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".to_string()
+            }
+        );
+        assert_eq!(error.encode(), bytes);
+    }
+}
@@ -0,0 +1,595 @@
+//! The mainline DHT (BEP 5): finds the peers of a torrent without asking a tracker.
+//! One DHT node is shared by all torrents, its socket listens on the same port as the peers.
+//! Lookups run on the caller's task, a background task answers the queries of other nodes.
+//! Our id follows BEP 42 once the other nodes agree on our external IP.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    mem,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU16, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{StreamExt, future::join_all, stream::FuturesUnordered};
+use thiserror::Error;
+use tokio::{net::UdpSocket, sync::oneshot};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::{
+    config::DhtSettings,
+    database::{DhtNodeRecord, DhtRecord},
+    dht::{
+        bloom::BloomFilter,
+        krpc::{Kind, Krpc, KrpcError, Query, Response},
+        routing_table::{K, RoutingTable},
+        store::{PeerStore, Tokens},
+    },
+    peer_manager::ExternalIpVotes,
+    sync::Mutex,
+    torrent::InfoHash,
+};
+
+mod bloom;
+mod krpc;
+mod routing_table;
+mod secure_id;
+mod store;
+
+pub use routing_table::NodeId;
+
+/// how many queries of a lookup are in flight at once
+const ALPHA: usize = 3;
+/// how long we wait for the answer to a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// a lookup gives up after this many queries even if it could still get closer
+const MAX_LOOKUP_QUERIES: usize = 64;
+/// the error code of a query we can't answer (BEP 5)
+const PROTOCOL_ERROR: i64 = 203;
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+    #[error("The DHT socket failed: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("The DHT node didn't answer")]
+    Timeout,
+    #[error("The DHT node answered with the error {code}: `{message}`")]
+    Remote { code: i64, message: String },
+    #[error(transparent)]
+    Krpc(#[from] KrpcError),
+}
+
+/// the size of a swarm judged by the bloom filters of the nodes closest to it (BEP 33)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwarmEstimate {
+    pub seeds: u64,
+    pub downloaders: u64,
+}
+
+/// what a `get_peers` lookup found
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerLookup {
+    pub peers: Vec<SocketAddr>,
+    pub swarm: SwarmEstimate,
+}
+
+/// This is cheap to clone, all clones are the same node.
+#[derive(Debug, Clone)]
+pub struct Dht(Arc<DhtInner>);
+
+#[derive(Debug)]
+struct DhtInner {
+    /// changes once we know our external IP and the id doesn't match it
+    id: Mutex<NodeId>,
+    /// the addresses the answering nodes saw us under
+    external_ip: Mutex<ExternalIpVotes>,
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+    /// the queries waiting for an answer by their transaction id, with the node we asked
+    pending: Mutex<HashMap<u16, (SocketAddr, oneshot::Sender<Kind>)>>,
+    next_transaction: AtomicU16,
+    /// the peers other nodes announced to us
+    store: Mutex<PeerStore>,
+    tokens: Mutex<Tokens>,
+    /// `host:port` of the nodes we ask first if we don't know any other
+    bootstrap_nodes: Vec<String>,
+    /// stops the background task once the last clone is dropped
+    _receiving: DropGuard,
+}
+
+impl Dht {
+    /// Starts as the node of the last run if there's a record of it, see [`Dht::to_record`].
+    pub(crate) async fn bind(
+        port: u16,
+        settings: &DhtSettings,
+        saved: Option<DhtRecord>,
+    ) -> Result<Self, DhtError> {
+        let socket = Arc::new(UdpSocket::bind(SocketAddrV4::new([0; 4].into(), port)).await?);
+        let stop = CancellationToken::new();
+        let saved_id = saved
+            .as_ref()
+            .and_then(|saved| <[u8; 20]>::try_from(saved.id.as_slice()).ok());
+        let id = saved_id.map_or_else(NodeId::random, NodeId);
+        let mut table = RoutingTable::new(id);
+        if saved_id.is_some() {
+            let now = Instant::now();
+            for node in saved.into_iter().flat_map(|saved| saved.nodes) {
+                if let Ok(node_id) = <[u8; 20]>::try_from(node.id.as_slice()) {
+                    table.insert(NodeId(node_id), node.addr, now);
+                }
+            }
+        }
+        let dht = Self(Arc::new(DhtInner {
+            id: Mutex::new(id),
+            external_ip: Mutex::new(ExternalIpVotes::default()),
+            socket: socket.clone(),
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            store: Mutex::new(PeerStore::default()),
+            tokens: Mutex::new(Tokens::new(Instant::now())),
+            bootstrap_nodes: settings.bootstrap_nodes.clone(),
+            _receiving: stop.clone().drop_guard(),
+        }));
+        tokio::spawn(receive(socket, Arc::downgrade(&dht.0), stop));
+        Ok(dht)
+    }
+
+    fn id(&self) -> NodeId {
+        *self.0.id.lock().unwrap()
+    }
+
+    /// the number of nodes in the routing table
+    pub fn node_count(&self) -> usize {
+        self.0.table.lock().unwrap().len()
+    }
+
+    /// the UDP port of the node, for the Port message to peers
+    pub(crate) fn port(&self) -> Option<u16> {
+        self.0.socket.local_addr().ok().map(|addr| addr.port())
+    }
+
+    /// a node a peer told us about with a Port message, it joins the table if it answers
+    pub(crate) async fn ping(&self, addr: SocketAddr) -> Result<(), DhtError> {
+        self.query(addr, Query::Ping).await.map(|_| ())
+    }
+
+    /// our id and the nodes that answered us, to start from them on the next run
+    pub(crate) fn to_record(&self) -> DhtRecord {
+        let nodes = self.0.table.lock().unwrap().good_nodes();
+        DhtRecord {
+            id: self.id().0.to_vec(),
+            nodes: nodes
+                .into_iter()
+                .map(|(id, addr)| DhtNodeRecord {
+                    id: id.0.to_vec(),
+                    addr,
+                })
+                .collect(),
+        }
+    }
+
+    /// Looks for peers of the torrent and estimates the size of its swarm.
+    /// With a `port` we also tell the closest nodes that we're a peer listening on it,
+    /// `seed` tells them we have the whole torrent.
+    pub async fn get_peers(
+        &self,
+        info_hash: InfoHash,
+        port: Option<u16>,
+        seed: bool,
+    ) -> PeerLookup {
+        let query = Query::GetPeers {
+            info_hash,
+            scrape: true,
+        };
+        let lookup = self.lookup(info_hash.into(), query).await;
+        let (mut seeds, mut downloaders) = (BloomFilter::default(), BloomFilter::default());
+        for (_, response) in lookup.closest.iter() {
+            seeds.union(response.seeds.as_deref().unwrap_or(&BloomFilter::default()));
+            downloaders.union(
+                response
+                    .downloaders
+                    .as_deref()
+                    .unwrap_or(&BloomFilter::default()),
+            );
+        }
+        if let Some(port) = port {
+            let announces = lookup.closest.into_iter().filter_map(|(addr, response)| {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token: response.token?,
+                    seed,
+                };
+                Some(self.query(addr.into(), query))
+            });
+            join_all(announces).await;
+        }
+        PeerLookup {
+            peers: lookup.peers.into_iter().collect(),
+            swarm: SwarmEstimate {
+                seeds: seeds.estimate(),
+                downloaders: downloaders.estimate(),
+            },
+        }
+    }
+
+    /// the size of the swarm without joining it
+    pub async fn scrape(&self, info_hash: InfoHash) -> SwarmEstimate {
+        self.get_peers(info_hash, None, false).await.swarm
+    }
+
+    /// Sends the query to ever closer nodes until the closest ones we know all answered.
+    async fn lookup(&self, target: NodeId, query: Query) -> Lookup {
+        let own_id = self.id();
+        let mut seeds = self.0.table.lock().unwrap().closest(&target, K);
+        // the saved nodes of the last run may all be gone
+        if seeds.is_empty() {
+            seeds = self.bootstrap().await;
+            // the bootstrap nodes that answered are in the table now
+            seeds.extend(self.0.table.lock().unwrap().closest(&target, K));
+        }
+        let mut candidates: BTreeMap<[u8; 20], Candidate> = seeds
+            .into_iter()
+            .filter(|(id, _)| *id != own_id)
+            .map(|(id, addr)| (id.distance(&target), Candidate::new(addr)))
+            .collect();
+        let mut peers = HashSet::new();
+        let mut in_flight = FuturesUnordered::new();
+        let mut queries = 0;
+        loop {
+            while in_flight.len() < ALPHA && queries < MAX_LOOKUP_QUERIES {
+                let next = candidates
+                    .iter_mut()
+                    .filter(|(_, c)| !matches!(c.state, CandidateState::Failed))
+                    .take(K)
+                    .find(|(_, c)| matches!(c.state, CandidateState::New));
+                let Some((distance, candidate)) = next else {
+                    break;
+                };
+                candidate.state = CandidateState::Asked;
+                queries += 1;
+                let (dht, distance, addr, query) =
+                    (self.clone(), *distance, candidate.addr, query.clone());
+                in_flight.push(async move { (distance, dht.query(addr.into(), query).await) });
+            }
+            let Some((distance, result)) = in_flight.next().await else {
+                break;
+            };
+            let Some(candidate) = candidates.get_mut(&distance) else {
+                continue;
+            };
+            match result {
+                Ok(mut response) => {
+                    peers.extend(mem::take(&mut response.values));
+                    let nodes = mem::take(&mut response.nodes);
+                    candidate.state = CandidateState::Answered(response);
+                    for (id, addr) in nodes {
+                        if id != own_id {
+                            candidates
+                                .entry(id.distance(&target))
+                                .or_insert_with(|| Candidate::new(addr));
+                        }
+                    }
+                }
+                Err(_) => candidate.state = CandidateState::Failed,
+            }
+        }
+
+        let closest = candidates
+            .into_values()
+            .filter_map(|c| match c.state {
+                CandidateState::Answered(response) => Some((c.addr, response)),
+                _ => None,
+            })
+            .take(K)
+            .collect();
+        Lookup { peers, closest }
+    }
+
+    /// asks the bootstrap nodes for the nodes close to us
+    async fn bootstrap(&self) -> Vec<(NodeId, SocketAddrV4)> {
+        let mut addrs = Vec::new();
+        for node in self.0.bootstrap_nodes.iter() {
+            match tokio::net::lookup_host(node.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(e) => eprintln!("Failed to resolve the DHT bootstrap node `{node}`: {e}"),
+            }
+        }
+        let target = self.id();
+        let answers = join_all(
+            addrs
+                .into_iter()
+                .map(|addr| self.query(addr, Query::FindNode { target })),
+        )
+        .await;
+        answers
+            .into_iter()
+            .flatten()
+            .flat_map(|response| response.nodes)
+            .collect()
+    }
+
+    /// sends the query and waits for the answer, nodes that answer are added to the routing table
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, DhtError> {
+        let transaction_id = self.0.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .pending
+            .lock()
+            .unwrap()
+            .insert(transaction_id, (addr, tx));
+        let message = Krpc {
+            transaction_id: transaction_id.to_be_bytes().to_vec(),
+            kind: Kind::Query {
+                id: self.id(),
+                query,
+            },
+            ip: None,
+        };
+        let sent = self.0.socket.send_to(&message.encode(), addr).await;
+        let answer = match sent {
+            Ok(_) => tokio::time::timeout(QUERY_TIMEOUT, rx).await.ok(),
+            Err(_) => None,
+        };
+        self.0.pending.lock().unwrap().remove(&transaction_id);
+        sent?;
+
+        let SocketAddr::V4(addr) = addr else {
+            return Err(DhtError::Timeout);
+        };
+        match answer {
+            Some(Ok(Kind::Response(response))) => {
+                if response.id.is_valid_for(*addr.ip()) {
+                    self.0
+                        .table
+                        .lock()
+                        .unwrap()
+                        .insert(response.id, addr, Instant::now());
+                }
+                Ok(response)
+            }
+            Some(Ok(Kind::Error { code, message })) => Err(DhtError::Remote { code, message }),
+            _ => {
+                self.0.table.lock().unwrap().failed(addr);
+                Err(DhtError::Timeout)
+            }
+        }
+    }
+
+    /// hands answers to the waiting queries and answers the queries of other nodes
+    async fn handle_packet(&self, bytes: &[u8], from: SocketAddr) {
+        let Ok(message) = Krpc::decode(bytes) else {
+            return;
+        };
+        let (id, query) = match message.kind {
+            Kind::Query { id, query } => (id, query),
+            answer => {
+                if let (Kind::Response(response), Some(ip)) = (&answer, message.ip) {
+                    self.on_external_ip(response.id, ip.ip());
+                }
+                let Ok(transaction_id) = <[u8; 2]>::try_from(message.transaction_id.as_slice())
+                else {
+                    return;
+                };
+                let mut pending = self.0.pending.lock().unwrap();
+                let transaction_id = u16::from_be_bytes(transaction_id);
+                // someone else can't answer for the node we asked
+                if pending
+                    .get(&transaction_id)
+                    .is_some_and(|(addr, _)| *addr == from)
+                    && let Some((_, tx)) = pending.remove(&transaction_id)
+                {
+                    let _ = tx.send(answer);
+                }
+                return;
+            }
+        };
+        let answer = Krpc {
+            transaction_id: message.transaction_id,
+            kind: self.answer(id, query, from),
+            ip: Some(from),
+        };
+        let _ = self.0.socket.send_to(&answer.encode(), from).await;
+    }
+
+    /// counts the IP a node saw us under and picks a matching id once the nodes agree on it
+    fn on_external_ip(&self, voter: NodeId, ip: IpAddr) {
+        let Some(IpAddr::V4(ip)) = self.0.external_ip.lock().unwrap().vote(voter.0, ip) else {
+            return;
+        };
+        if self.id().is_valid_for(ip) {
+            return;
+        }
+        let id = NodeId::secure(ip);
+        *self.0.id.lock().unwrap() = id;
+        self.0.table.lock().unwrap().set_own_id(id);
+        eprintln!("Our external IP is {ip}, the DHT node id now matches it.");
+    }
+
+    fn answer(&self, id: NodeId, query: Query, from: SocketAddr) -> Kind {
+        let now = Instant::now();
+        let SocketAddr::V4(from_v4) = from else {
+            return protocol_error("only IPv4 is supported");
+        };
+        // BEP 42: a node whose id doesn't match its IP may still ask, but we don't route to it
+        if id.is_valid_for(*from_v4.ip()) {
+            self.0.table.lock().unwrap().insert(id, from_v4, now);
+        }
+        let mut response = Response::new(self.id());
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.0.table.lock().unwrap().closest(&target, K);
+            }
+            Query::GetPeers { info_hash, scrape } => {
+                response.token = Some(self.0.tokens.lock().unwrap().issue(from.ip(), now));
+                let mut store = self.0.store.lock().unwrap();
+                response.values = store.get(&info_hash, now);
+                if scrape {
+                    let (seeds, downloaders) = store.scrape(&info_hash, now);
+                    response.seeds = Some(Box::new(seeds));
+                    response.downloaders = Some(Box::new(downloaders));
+                }
+                drop(store);
+                if response.values.is_empty() {
+                    response.nodes = self.0.table.lock().unwrap().closest(&info_hash.into(), K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                seed,
+            } => {
+                if !self
+                    .0
+                    .tokens
+                    .lock()
+                    .unwrap()
+                    .is_valid(from.ip(), &token, now)
+                {
+                    return protocol_error("bad token");
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.0.store.lock().unwrap().add(
+                    info_hash,
+                    SocketAddr::new(from.ip(), port),
+                    seed,
+                    now,
+                );
+            }
+        }
+        Kind::Response(response)
+    }
+}
+
+fn protocol_error(message: &str) -> Kind {
+    Kind::Error {
+        code: PROTOCOL_ERROR,
+        message: message.to_string(),
+    }
+}
+
+/// runs until the DHT is dropped
+async fn receive(socket: Arc<UdpSocket>, dht: Weak<DhtInner>, stop: CancellationToken) {
+    let mut buf = vec![0; 2048];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = stop.cancelled() => return,
+        };
+        let Ok((len, from)) = received else {
+            continue;
+        };
+        let Some(dht) = dht.upgrade() else {
+            return;
+        };
+        Dht(dht).handle_packet(&buf[..len], from).await;
+    }
+}
+
+#[derive(Debug)]
+struct Lookup {
+    peers: HashSet<SocketAddr>,
+    /// the closest nodes that answered with their answer, without the nodes and peers
+    closest: Vec<(SocketAddrV4, Response)>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    addr: SocketAddrV4,
+    state: CandidateState,
+}
+
+impl Candidate {
+    fn new(addr: SocketAddrV4) -> Self {
+        Self {
+            addr,
+            state: CandidateState::New,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CandidateState {
+    New,
+    Asked,
+    /// with the token and the bloom filters of a `get_peers`
+    Answered(Response),
+    Failed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(bootstrap_nodes: Vec<String>) -> DhtSettings {
+        DhtSettings {
+            enabled: true,
+            bootstrap_nodes,
+        }
+    }
+
+    async fn node(bootstrap_nodes: Vec<String>) -> Dht {
+        Dht::bind(0, &settings(bootstrap_nodes), None)
+            .await
+            .unwrap()
+    }
+
+    fn local_addr(dht: &Dht) -> String {
+        format!("127.0.0.1:{}", dht.port().unwrap())
+    }
+
+    #[tokio::test]
+    async fn pinged_node_joins_the_table() {
+        let peer_node = node(Vec::new()).await;
+        let ours = node(Vec::new()).await;
+        ours.ping(local_addr(&peer_node).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(ours.node_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found() {
+        let router = node(Vec::new()).await;
+        let seeder = node(vec![local_addr(&router)]).await;
+        let leecher = node(vec![local_addr(&router)]).await;
+        let info_hash = InfoHash([7; 20]);
+
+        let lookup = seeder.get_peers(info_hash, Some(6881), true).await;
+        assert!(lookup.peers.is_empty());
+        assert_eq!(seeder.node_count(), 1);
+        let lookup = leecher.get_peers(info_hash, None, false).await;
+        assert_eq!(lookup.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(
+            leecher.scrape(info_hash).await,
+            SwarmEstimate {
+                seeds: 1,
+                downloaders: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_from_the_saved_nodes() {
+        let router = node(Vec::new()).await;
+        let first_run = node(vec![local_addr(&router)]).await;
+        first_run.get_peers(InfoHash([7; 20]), None, false).await;
+        let record = first_run.to_record();
+        assert_eq!(record.nodes.len(), 1);
+        drop(first_run);
+
+        // no bootstrap nodes, the router is only known from the record
+        let second_run = Dht::bind(0, &settings(Vec::new()), Some(record.clone()))
+            .await
+            .unwrap();
+        assert_eq!(second_run.id().0.to_vec(), record.id);
+        assert_eq!(second_run.node_count(), 1);
+    }
+}
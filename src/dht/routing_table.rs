@@ -0,0 +1,191 @@
+//! The nodes we know, sorted into one bucket per bit of XOR distance to our own id.
+//! Far away buckets cover huge parts of the id space but hold as many nodes as the close ones,
+//! so we know our neighbourhood well and the rest of the network roughly.
+use std::{mem, net::SocketAddrV4, time::Instant};
+
+use crate::torrent::InfoHash;
+
+/// the number of nodes in a bucket and the number of closest nodes a lookup looks for
+pub(super) const K: usize = 8;
+/// a node that didn't answer this many queries in a row may be replaced
+const MAX_FAILURES: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub(super) fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// the XOR metric, compare the results to find the closer node
+    pub(super) fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    /// the number of leading bits both ids share, None for the same id
+    fn common_prefix_len(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let byte = distance.iter().position(|b| *b != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+}
+
+impl From<InfoHash> for NodeId {
+    fn from(info_hash: InfoHash) -> Self {
+        Self(info_hash.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    id: NodeId,
+    addr: SocketAddrV4,
+    last_seen: Instant,
+    /// the queries it didn't answer since the last answer
+    failures: u8,
+}
+
+#[derive(Debug)]
+pub(super) struct RoutingTable {
+    own_id: NodeId,
+    /// the bucket at index i holds the nodes that share exactly i leading bits with us
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub(super) fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// sorts the nodes into the buckets of our new id
+    pub(super) fn set_own_id(&mut self, own_id: NodeId) {
+        let nodes: Vec<_> = self.buckets.iter_mut().flat_map(mem::take).collect();
+        self.own_id = own_id;
+        for node in nodes {
+            if let Some(i) = own_id.common_prefix_len(&node.id) {
+                let bucket = &mut self.buckets[i];
+                if bucket.len() < K {
+                    bucket.push(node);
+                }
+            }
+        }
+    }
+
+    /// Adds the node or refreshes it if we know it already.
+    /// A full bucket only takes the node if one of its nodes stopped answering.
+    pub(super) fn insert(&mut self, id: NodeId, addr: SocketAddrV4, now: Instant) {
+        let Some(i) = self.own_id.common_prefix_len(&id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[i];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = now;
+            node.failures = 0;
+            return;
+        }
+        let node = Node {
+            id,
+            addr,
+            last_seen: now,
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(bad) = bucket.iter_mut().find(|node| node.failures >= MAX_FAILURES) {
+            *bad = node;
+        }
+    }
+
+    /// the node at the address didn't answer
+    pub(super) fn failed(&mut self, addr: SocketAddrV4) {
+        for node in self.buckets.iter_mut().flatten() {
+            if node.addr == addr {
+                node.failures = node.failures.saturating_add(1);
+            }
+        }
+    }
+
+    /// the `n` known nodes that are closest to the target, the closest first
+    pub(super) fn closest(&self, target: &NodeId, n: usize) -> Vec<(NodeId, SocketAddrV4)> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.failures < MAX_FAILURES)
+            .map(|node| (node.id, node.addr))
+            .collect();
+        nodes.sort_by_key(|(id, _)| id.distance(target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// the nodes that answered their last query, the ones worth keeping for the next run
+    pub(super) fn good_nodes(&self) -> Vec<(NodeId, SocketAddrV4)> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.failures == 0)
+            .map(|node| (node.id, node.addr))
+            .collect()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first;
+        NodeId(id)
+    }
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, 1].into(), port)
+    }
+
+    #[test]
+    fn closest_are_sorted_by_xor_distance() {
+        let mut table = RoutingTable::new(id(0));
+        let now = Instant::now();
+        for (first, port) in [(0b1000_0000, 1), (0b0100_0000, 2), (0b0110_0000, 3)] {
+            table.insert(id(first), addr(port), now);
+        }
+        let closest = table.closest(&id(0b0111_0000), 2);
+        assert_eq!(
+            closest,
+            vec![(id(0b0110_0000), addr(3)), (id(0b0100_0000), addr(2))]
+        );
+        // we don't store ourselves
+        table.insert(id(0), addr(4), now);
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn full_bucket_replaces_only_failing_nodes() {
+        let mut table = RoutingTable::new(id(0));
+        let now = Instant::now();
+        // all of these share no leading bit with us, so they land in the same bucket
+        for i in 0..K as u8 {
+            table.insert(id(0x80 + i), addr(i as u16), now);
+        }
+        table.insert(id(0xf0), addr(100), now);
+        assert_eq!(table.len(), K);
+        assert!(table.closest(&id(0xf0), 1)[0].0 != id(0xf0));
+
+        for _ in 0..MAX_FAILURES {
+            table.failed(addr(0));
+        }
+        table.insert(id(0xf0), addr(100), now);
+        assert_eq!(table.closest(&id(0xf0), 1), vec![(id(0xf0), addr(100))]);
+    }
+}
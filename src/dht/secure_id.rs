@@ -0,0 +1,87 @@
+//! Node ids that are bound to the IP of the node (BEP 42).
+//! The first 21 bits of the id are a hash of the IP, so a node can't pick an id next to a
+//! target of its choice without controlling an IP that hashes there.
+use std::net::Ipv4Addr;
+
+use crate::dht::routing_table::NodeId;
+
+/// the bits of the IP that go into the hash
+const IPV4_MASK: u32 = 0x030f_3fff;
+/// the reflected Castagnoli polynomial
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+impl NodeId {
+    /// a random id that is valid for our external IP
+    pub(super) fn secure(ip: Ipv4Addr) -> Self {
+        let mut id: [u8; 20] = rand::random();
+        let r = id[19] & 0x07;
+        let crc = ip_hash(ip, r);
+        id[0] = (crc >> 24) as u8;
+        id[1] = (crc >> 16) as u8;
+        id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+        Self(id)
+    }
+
+    /// Whether the id matches the IP the node talks to us from.
+    /// Nodes in local networks can't know their external IP, so any id is fine for them.
+    pub(super) fn is_valid_for(&self, ip: Ipv4Addr) -> bool {
+        if is_exempt(ip) {
+            return true;
+        }
+        let crc = ip_hash(ip, self.0[19] & 0x07);
+        self.0[0] == (crc >> 24) as u8
+            && self.0[1] == (crc >> 16) as u8
+            && self.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+    }
+}
+
+fn ip_hash(ip: Ipv4Addr, r: u8) -> u32 {
+    let masked = (u32::from(ip) & IPV4_MASK) | (r as u32) << 29;
+    crc32c(&masked.to_be_bytes())
+}
+
+fn is_exempt(ip: Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local()
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(hex_id: &str) -> NodeId {
+        NodeId(hex::decode(hex_id).unwrap().try_into().unwrap())
+    }
+
+    #[test]
+    fn bep_test_vectors() {
+        for (ip, node_id) in [
+            ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+            ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+            ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+            ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+            ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+        ] {
+            let ip: Ipv4Addr = ip.parse().unwrap();
+            assert!(id(node_id).is_valid_for(ip), "{ip}");
+            assert!(NodeId::secure(ip).is_valid_for(ip));
+        }
+        let spoofed = id("5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401");
+        assert!(!spoofed.is_valid_for("21.75.31.124".parse().unwrap()));
+        assert!(spoofed.is_valid_for("192.168.1.2".parse().unwrap()));
+    }
+}
@@ -0,0 +1,145 @@
+//! What other nodes announced to us, and the tokens that prove an announcer asked us before.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{dht::bloom::BloomFilter, torrent::InfoHash};
+
+/// announced peers are forgotten after this, the BEP suggests 30 minutes
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// the most peers we put into one `get_peers` response, more don't fit into a UDP packet
+const MAX_VALUES: usize = 50;
+/// a token stays valid for up to two of these
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy)]
+struct Announced {
+    at: Instant,
+    seed: bool,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct PeerStore(HashMap<InfoHash, HashMap<SocketAddr, Announced>>);
+
+impl PeerStore {
+    pub(super) fn add(&mut self, info_hash: InfoHash, peer: SocketAddr, seed: bool, now: Instant) {
+        let announced = Announced { at: now, seed };
+        self.0.entry(info_hash).or_default().insert(peer, announced);
+    }
+
+    pub(super) fn get(&mut self, info_hash: &InfoHash, now: Instant) -> Vec<SocketAddr> {
+        self.fresh(info_hash, now)
+            .map(|(peer, _)| *peer)
+            .take(MAX_VALUES)
+            .collect()
+    }
+
+    /// the bloom filters of the seeds and of the downloaders (BEP 33)
+    pub(super) fn scrape(
+        &mut self,
+        info_hash: &InfoHash,
+        now: Instant,
+    ) -> (BloomFilter, BloomFilter) {
+        let (mut seeds, mut downloaders) = (BloomFilter::default(), BloomFilter::default());
+        for (peer, announced) in self.fresh(info_hash, now) {
+            match announced.seed {
+                true => seeds.insert(peer.ip()),
+                false => downloaders.insert(peer.ip()),
+            }
+        }
+        (seeds, downloaders)
+    }
+
+    /// forgets the peers that didn't announce in a while
+    fn fresh(
+        &mut self,
+        info_hash: &InfoHash,
+        now: Instant,
+    ) -> impl Iterator<Item = (&SocketAddr, &Announced)> {
+        let peers = self.0.get_mut(info_hash).map(|peers| {
+            peers.retain(|_, announced| now.duration_since(announced.at) < PEER_TTL);
+            &*peers
+        });
+        peers.into_iter().flatten()
+    }
+}
+
+/// The token of a `get_peers` response is a hash of the IP that asked and a secret.
+/// The secret changes regularly, the previous one is still accepted.
+#[derive(Debug)]
+pub(super) struct Tokens {
+    secret: [u8; 16],
+    previous: [u8; 16],
+    rotated_at: Instant,
+}
+
+impl Tokens {
+    pub(super) fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated_at: now,
+        }
+    }
+
+    pub(super) fn issue(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        token(ip, &self.secret)
+    }
+
+    pub(super) fn is_valid(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        [self.secret, self.previous]
+            .iter()
+            .any(|secret| self::token(ip, secret) == token)
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated_at) >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated_at = now;
+        }
+    }
+}
+
+fn token(ip: IpAddr, secret: &[u8; 16]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+    hasher.finalize()[..8].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_outlive_one_rotation() {
+        let start = Instant::now();
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let mut tokens = Tokens::new(start);
+        let token = tokens.issue(ip, start);
+        assert!(!tokens.is_valid("198.51.100.2".parse().unwrap(), &token, start));
+        assert!(tokens.is_valid(ip, &token, start + TOKEN_ROTATION));
+        assert!(!tokens.is_valid(ip, &token, start + TOKEN_ROTATION * 2));
+    }
+
+    #[test]
+    fn announced_peers_expire() {
+        let start = Instant::now();
+        let mut store = PeerStore::default();
+        let info_hash = InfoHash([1; 20]);
+        let peer = "198.51.100.1:6881".parse().unwrap();
+        store.add(info_hash, peer, false, start);
+        assert_eq!(store.get(&info_hash, start), vec![peer]);
+        assert!(store.get(&info_hash, start + PEER_TTL).is_empty());
+    }
+}
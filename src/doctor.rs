@@ -0,0 +1,323 @@
+//! Self-test of the environment (`doctor` subcommand).
+//! It catches the usual reasons why a download silently stalls before it's even started.
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_util::time::FutureExt;
+
+use crate::{database::DBConnection, paths::Paths, torrent::InfoHash};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// if we don't know the size of the download, we at least want this much free space
+const MIN_FREE_SPACE: u64 = 1 << 30;
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+/// magic constant of the UDP tracker protocol (BEP 15)
+const UDP_TRACKER_PROTOCOL_ID: u64 = 0x41727101980;
+
+pub struct DoctorOptions {
+    /// the port we listen on for incoming peers
+    pub listen_port: u16,
+    /// decides where the database lives
+    pub paths: Paths,
+    /// the directory the downloaded files are written to
+    pub target_dir: PathBuf,
+    /// how many bytes the download needs, if we know it
+    pub required_space: Option<u64>,
+    /// `host:port` of a HTTP tracker
+    pub tcp_tracker: String,
+    /// `host:port` of a UDP tracker
+    pub udp_tracker: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// not required for downloading but it will be slower
+    Warning,
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct CheckReport {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// what the user can do about a failed check
+    pub hint: Option<&'static str>,
+}
+
+impl CheckReport {
+    fn new(name: &'static str, result: Result<String, String>, hint: &'static str) -> Self {
+        match result {
+            Ok(detail) => Self {
+                name,
+                status: Status::Ok,
+                detail,
+                hint: None,
+            },
+            Err(detail) => Self {
+                name,
+                status: Status::Failed,
+                detail,
+                hint: Some(hint),
+            },
+        }
+    }
+
+    fn downgrade_to_warning(mut self) -> Self {
+        if self.status == Status::Failed {
+            self.status = Status::Warning;
+        }
+        self
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "warn",
+            Status::Failed => "FAIL",
+        };
+        write!(f, "[{status:>4}] {}: {}", self.name, self.detail)?;
+        if let Some(hint) = self.hint {
+            write!(f, "\n       hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// runs all checks one after another so the output is in a stable order
+pub async fn run_checks(options: &DoctorOptions) -> Vec<CheckReport> {
+    vec![
+        CheckReport::new(
+            "database",
+            check_db(&options.paths).await,
+            "Another instance might be running and holding the lock on the database directory, or the directory isn't writable.",
+        ),
+        CheckReport::new(
+            "listen port",
+            check_listen_port(options.listen_port).await,
+            "Another program (or another instance) is using the port. Incoming peers won't be able to connect.",
+        ),
+        CheckReport::new(
+            "UPnP gateway",
+            check_upnp_gateway().await,
+            "Without UPnP you have to forward the listen port on your router manually to be reachable.",
+        )
+        .downgrade_to_warning(),
+        CheckReport::new(
+            "tracker (TCP)",
+            check_tcp_tracker(&options.tcp_tracker).await,
+            "Outbound TCP seems to be blocked. Check your firewall or proxy settings.",
+        ),
+        CheckReport::new(
+            "tracker (UDP)",
+            check_udp_tracker(&options.udp_tracker).await,
+            "Outbound UDP seems to be blocked. UDP trackers won't work on this network.",
+        )
+        .downgrade_to_warning(),
+        CheckReport::new(
+            "download directory",
+            check_disk(&options.target_dir, options.required_space),
+            "Choose another output directory or free up some space.",
+        ),
+    ]
+}
+
+async fn check_db(paths: &Paths) -> Result<String, String> {
+    DBConnection::new(paths, InfoHash([0; 20]))
+        .timeout(CHECK_TIMEOUT)
+        .await
+        .map_err(|_| "timed out opening the database".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(format!(
+        "the database in `{}` can be opened",
+        paths.db_dir().display()
+    ))
+}
+
+async fn check_listen_port(port: u16) -> Result<String, String> {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+    TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("failed to bind {addr}: {e}"))?;
+    Ok(format!("{addr} can be bound"))
+}
+
+/// sends a SSDP M-SEARCH for an internet gateway device and waits for any answer
+async fn check_upnp_gateway() -> Result<String, String> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n"
+    );
+    socket
+        .send_to(search.as_bytes(), SSDP_ADDR)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut buf = [0_u8; 1024];
+    let (_len, gateway) = socket
+        .recv_from(&mut buf)
+        .timeout(CHECK_TIMEOUT)
+        .await
+        .map_err(|_| "no gateway answered the SSDP search".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(format!("gateway found at {gateway}"))
+}
+
+async fn check_tcp_tracker(tracker: &str) -> Result<String, String> {
+    TcpStream::connect(tracker)
+        .timeout(CHECK_TIMEOUT)
+        .await
+        .map_err(|_| format!("timed out connecting to {tracker}"))?
+        .map_err(|e| format!("failed to connect to {tracker}: {e}"))?;
+    Ok(format!("connected to {tracker}"))
+}
+
+/// sends the connect request of the UDP tracker protocol and waits for the response
+async fn check_udp_tracker(tracker: &str) -> Result<String, String> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    socket
+        .connect(tracker)
+        .await
+        .map_err(|e| format!("failed to resolve {tracker}: {e}"))?;
+
+    let transaction_id: u32 = rand::random();
+    let mut request = [0_u8; 16];
+    request[0..8].copy_from_slice(&UDP_TRACKER_PROTOCOL_ID.to_be_bytes());
+    // action 0: connect
+    request[12..16].copy_from_slice(&transaction_id.to_be_bytes());
+    socket.send(&request).await.map_err(|e| e.to_string())?;
+
+    let mut response = [0_u8; 16];
+    let len = socket
+        .recv(&mut response)
+        .timeout(CHECK_TIMEOUT)
+        .await
+        .map_err(|_| format!("{tracker} didn't answer"))?
+        .map_err(|e| e.to_string())?;
+    if len < 16 || response[4..8] != transaction_id.to_be_bytes() {
+        return Err(format!("{tracker} sent an invalid response"));
+    }
+    Ok(format!("{tracker} answered"))
+}
+
+fn check_disk(target_dir: &Path, required_space: Option<u64>) -> Result<String, String> {
+    tempfile::tempfile_in(target_dir)
+        .map_err(|e| format!("can't write to `{}`: {e}", target_dir.display()))?;
+
+    let Some(available) = free_space(target_dir)? else {
+        return Ok(format!(
+            "`{}` is writable, its free space is unknown on this platform",
+            target_dir.display()
+        ));
+    };
+    let required = required_space.unwrap_or(MIN_FREE_SPACE);
+    if available < required {
+        return Err(format!(
+            "only {} MiB free in `{}` but {} MiB are needed",
+            available >> 20,
+            target_dir.display(),
+            required.div_ceil(1 << 20)
+        ));
+    }
+    Ok(format!(
+        "`{}` is writable and has {} MiB free",
+        target_dir.display(),
+        available >> 20
+    ))
+}
+
+#[cfg(unix)]
+fn free_space(dir: &Path) -> Result<Option<u64>, String> {
+    let stat = nix::sys::statvfs::statvfs(dir).map_err(|e| e.to_string())?;
+    Ok(Some(
+        stat.blocks_available() as u64 * stat.fragment_size() as u64,
+    ))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> Result<Option<u64>, String> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_checks_give_a_hint() {
+        let report = CheckReport::new("disk", Err("full".to_string()), "free up some space");
+        assert_eq!(report.status, Status::Failed);
+        assert_eq!(
+            report.to_string(),
+            "[FAIL] disk: full\n       hint: free up some space"
+        );
+        assert_eq!(report.downgrade_to_warning().status, Status::Warning);
+
+        let report = CheckReport::new("disk", Ok("fine".to_string()), "free up some space");
+        assert!(report.hint.is_none());
+        let report = report.downgrade_to_warning();
+        assert_eq!(report.status, Status::Ok);
+        assert_eq!(report.to_string(), "[  ok] disk: fine");
+    }
+
+    #[test]
+    fn disk_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_disk(dir.path(), Some(0)).is_ok());
+        let err = check_disk(dir.path(), Some(u64::MAX)).unwrap_err();
+        assert!(err.contains("MiB are needed"), "{err}");
+        let err = check_disk(&dir.path().join("missing"), None).unwrap_err();
+        assert!(err.starts_with("can't write to"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn taken_listen_port() {
+        let taken = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            .await
+            .unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(check_listen_port(port).await.is_err());
+        drop(taken);
+        assert!(check_listen_port(port).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn trackers() {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = http.local_addr().unwrap().to_string();
+        assert!(check_tcp_tracker(&addr).await.is_ok());
+        drop(http);
+        assert!(check_tcp_tracker(&addr).await.is_err());
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap().to_string();
+        let tracker = tokio::spawn(async move {
+            for echo_transaction in [true, false] {
+                let mut request = [0_u8; 16];
+                let (_, from) = udp.recv_from(&mut request).await.unwrap();
+                assert_eq!(request[0..8], UDP_TRACKER_PROTOCOL_ID.to_be_bytes());
+                let mut response = [0_u8; 16];
+                if echo_transaction {
+                    response[4..8].copy_from_slice(&request[12..16]);
+                }
+                udp.send_to(&response, from).await.unwrap();
+            }
+        });
+        assert!(check_udp_tracker(&addr).await.is_ok());
+        let err = check_udp_tracker(&addr).await.unwrap_err();
+        assert!(err.ends_with("sent an invalid response"), "{err}");
+        tracker.await.unwrap();
+    }
+}
@@ -1,6 +1,9 @@
 use std::str::FromStr;
 
-use crate::{extensions::ExtensionType, magnet_links::metadata_msg::MetadataRequester};
+use crate::{
+    extensions::{ExtensionType, pex::PexHandler},
+    magnet_links::metadata_msg::MetadataRequester,
+};
 
 use super::ExtensionHandler;
 
@@ -10,6 +13,7 @@ impl ExtensionFactory {
     pub fn build(name: &str) -> Option<Box<dyn ExtensionHandler>> {
         match ExtensionType::from_str(name).ok()? {
             ExtensionType::Metadata => Some(Box::new(MetadataRequester::new())),
+            ExtensionType::Pex => Some(Box::new(PexHandler)),
             _ => None,
         }
     }
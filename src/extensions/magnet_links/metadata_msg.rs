@@ -42,6 +42,16 @@ impl ExtensionHandler for MetadataRequester {
         let Ok(msg): Result<MetadataMsg, _> = serde_bencode::from_bytes(data) else {
             return ExtensionAction::Nothing;
         };
+        match msg.msg_type {
+            MetadataMsgType::Data => {}
+            MetadataMsgType::Reject => {
+                return ExtensionAction::SendPeerManager(ReqMessage::Extension(
+                    ExtensionMessage::RejectedMetadataPiece,
+                ));
+            }
+            // we don't serve metadata (yet)
+            MetadataMsgType::Request | MetadataMsgType::Other => return ExtensionAction::Nothing,
+        }
         // might not be the most efficient to serialize it again but who gives a fuck at this point really
         let offset = serde_bencode::to_bytes(&msg)
             .expect("we just deserialized it so serializing it again shoudln't be a problem.")
@@ -1,23 +1,63 @@
 //! This is all for the PeerManager
 
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
 use bytes::{Bytes, BytesMut};
 use rand::seq::IteratorRandom;
 use sha1::{Digest, Sha1};
 
 use crate::{
+    database::{DBConnection, DBError, PartialMetadata},
     magnet_links::metadata_msg::{MetadataMsg, MetadataMsgType},
     peer_manager::BlockState,
-    torrent::{InfoHash, Metainfo},
+    torrent::{InfoHash, Metainfo, TorrentError},
 };
 
 /// The metadata is handled in blocks of 16KiB (16384 Bytes).
 const METADATA_BLOCK_SIZE: usize = 1 << 14;
+/// A peer that made this many errors only gets blocks if there's no better peer.
+const MAX_METADATA_ERRORS: u32 = 3;
+/// After this time, any peer may request a block that is still in process by another peer.
+const METADATA_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// what we know about how well a peer serves metadata
+#[derive(Debug, Default, Clone, Copy)]
+struct MetadataPeer {
+    /// when we sent the last request
+    requested_at: Option<Instant>,
+    /// the round-trip time of the last block
+    rtt: Option<Duration>,
+    /// rejected requests and blocks that were part of metadata with a wrong hash
+    errors: u32,
+}
+
+impl MetadataPeer {
+    /// lower is better, peers we have never measured come last
+    fn rank(&self) -> (u32, Duration) {
+        (self.errors, self.rtt.unwrap_or(Duration::MAX))
+    }
+
+    fn timed_out(&self) -> bool {
+        self.requested_at
+            .is_some_and(|at| at.elapsed() > METADATA_REQUEST_TIMEOUT)
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct MetadataPieceManager {
     queue: Vec<BlockState>,
     bytes: BytesMut,
     pub info_hash: InfoHash,
+    /// the block each peer was asked for last
+    assignments: HashMap<[u8; 20], usize>,
+    peers: HashMap<[u8; 20], MetadataPeer>,
+    /// which peer sent which block, so we know whom to blame if the hash doesn't match
+    sources: HashMap<usize, [u8; 20]>,
+    /// stores the finished blocks so they survive a restart, None in tests
+    db_conn: Option<DBConnection>,
 }
 
 impl MetadataPieceManager {
@@ -26,29 +66,119 @@ impl MetadataPieceManager {
             queue: Vec::new(),
             bytes: BytesMut::new(),
             info_hash,
+            assignments: HashMap::new(),
+            peers: HashMap::new(),
+            sources: HashMap::new(),
+            db_conn: None,
+        }
+    }
+
+    /// restores the blocks we downloaded before the last restart
+    pub(crate) async fn from_db(
+        info_hash: InfoHash,
+        db_conn: DBConnection,
+    ) -> Result<Self, DBError> {
+        let mut manager = Self::new(info_hash);
+        if let Some(partial) = db_conn.get_partial_metadata().await? {
+            manager.restore(partial);
+        }
+        manager.db_conn = Some(db_conn);
+        Ok(manager)
+    }
+
+    fn restore(&mut self, partial: PartialMetadata) {
+        self.queue = partial
+            .finished
+            .into_iter()
+            .map(|finished| {
+                if finished {
+                    BlockState::Finished
+                } else {
+                    BlockState::None
+                }
+            })
+            .collect();
+        self.bytes = BytesMut::from(&partial.bytes[..]);
+    }
+
+    fn to_partial(&self) -> PartialMetadata {
+        PartialMetadata {
+            bytes: self.bytes.to_vec(),
+            finished: self
+                .queue
+                .iter()
+                .map(|b| *b == BlockState::Finished)
+                .collect(),
+        }
+    }
+
+    /// writes the finished blocks to the DB
+    pub(crate) async fn persist(&self) -> Result<(), DBError> {
+        if let Some(db_conn) = &self.db_conn {
+            db_conn.set_partial_metadata(self.to_partial()).await?;
+        }
+        Ok(())
+    }
+
+    /// the metadata is complete, so we don't need the blocks anymore
+    pub(crate) async fn clear_persisted(&self) -> Result<(), DBError> {
+        if let Some(db_conn) = &self.db_conn {
+            db_conn.delete_partial_metadata().await?;
         }
+        Ok(())
     }
 
-    pub(crate) fn add_block(&mut self, index: u32, data: Bytes) {
+    pub(crate) fn add_block(&mut self, peer_id: [u8; 20], index: u32, data: Bytes) {
         let len = data.len();
         let begin = index as usize * METADATA_BLOCK_SIZE;
         self.bytes[begin..begin + len].copy_from_slice(&data);
         self.queue[index as usize] = BlockState::Finished;
+        self.sources.insert(index as usize, peer_id);
+
+        let peer = self.peers.entry(peer_id).or_default();
+        if let Some(requested_at) = peer.requested_at.take() {
+            peer.rtt = Some(requested_at.elapsed());
+        }
+        if self.assignments.get(&peer_id) == Some(&(index as usize)) {
+            self.assignments.remove(&peer_id);
+        }
+    }
+
+    /// the peer doesn't want to give us the block, so it's counted as an error
+    pub(crate) fn reject_block(&mut self, peer_id: [u8; 20]) {
+        let peer = self.peers.entry(peer_id).or_default();
+        peer.errors += 1;
+        peer.requested_at = None;
+        self.release_assignment(peer_id);
     }
 
     /// returns Ok(None) if we're finished downloading the Metadata
     /// returns Err(..) if it couldn't serialize the MetadataMsg to bytes
     /// returns Ok(Bytes) of the data bytes in the BasicExtensionPayload
-    pub(crate) fn get_block_req_data(&mut self) -> Result<Option<Bytes>, serde_bencode::Error> {
+    pub(crate) fn get_block_req_data(
+        &mut self,
+        peer_id: [u8; 20],
+    ) -> Result<Option<Bytes>, serde_bencode::Error> {
+        let peer = self.peers.get(&peer_id).copied().unwrap_or_default();
+        if peer.errors >= MAX_METADATA_ERRORS
+            && self.peers.values().any(|p| p.errors < MAX_METADATA_ERRORS)
+        {
+            return Ok(None);
+        }
+
         let Some(piece_index) = self
             .queue
             .iter()
             .position(|i_have| *i_have == BlockState::None)
             .or_else(|| {
+                // all blocks are requested: help out with the ones of slower peers
                 self.queue
                     .iter()
                     .enumerate()
-                    .filter_map(|(index, i_have)| (*i_have == BlockState::InProcess).then(|| index))
+                    .filter(|(index, i_have)| {
+                        **i_have == BlockState::InProcess && self.is_slower_than(*index, &peer)
+                    })
+                    .map(|(index, _)| index)
                     .choose(&mut rand::rng())
             })
         // Note that one peer will not get the same request twice since it won't ask for another if it's waiting for the response of one.
@@ -56,8 +186,9 @@ impl MetadataPieceManager {
         else {
             return Ok(None);
         };
-        dbg!(piece_index);
         self.queue[piece_index] = BlockState::InProcess;
+        self.assignments.insert(peer_id, piece_index);
+        self.peers.entry(peer_id).or_default().requested_at = Some(Instant::now());
         let msg = MetadataMsg {
             msg_type: MetadataMsgType::Request,
             piece_index: piece_index as u32,
@@ -66,6 +197,32 @@ impl MetadataPieceManager {
         Ok(Some(serde_bencode::to_bytes(&msg)?.into()))
     }
 
+    /// whether all peers that are working on the block are worse than the given one
+    fn is_slower_than(&self, piece_index: usize, peer: &MetadataPeer) -> bool {
+        self.assignments
+            .iter()
+            .filter(|(_, i)| **i == piece_index)
+            .filter_map(|(id, _)| self.peers.get(id))
+            .all(|other| other.timed_out() || other.rank() > peer.rank())
+    }
+
+    /// frees the block assigned to the peer and forgets about the peer
+    pub(crate) fn release_peer(&mut self, peer_id: [u8; 20]) {
+        self.peers.remove(&peer_id);
+        self.release_assignment(peer_id);
+    }
+
+    /// frees the block assigned to the peer so another one can request it
+    fn release_assignment(&mut self, peer_id: [u8; 20]) {
+        let Some(piece_index) = self.assignments.remove(&peer_id) else {
+            return;
+        };
+        let assigned_elsewhere = self.assignments.values().any(|i| *i == piece_index);
+        if self.queue[piece_index] == BlockState::InProcess && !assigned_elsewhere {
+            self.queue[piece_index] = BlockState::None;
+        }
+    }
+
     /// initializes the fields of the MetadataPieceManager (like which blocks are finished)
     /// with the given length
     pub(crate) fn set_len(&mut self, length: usize) {
@@ -87,14 +244,17 @@ impl MetadataPieceManager {
             if sha1 == self.info_hash.0 {
                 return true;
             } else {
+                for (_, peer_id) in self.sources.drain() {
+                    self.peers.entry(peer_id).or_default().errors += 1;
+                }
                 self.queue = vec![BlockState::None; self.queue.len()];
             }
         }
         false
     }
 
-    pub(crate) fn get_metadata(&self) -> Result<Metainfo, serde_bencode::Error> {
-        serde_bencode::from_bytes(&self.bytes)
+    pub(crate) fn get_metadata(&self) -> Result<Metainfo, TorrentError> {
+        Metainfo::from_bytes(&self.bytes)
     }
 }
 
@@ -103,6 +263,8 @@ mod tests {
     use super::*;
     use crate::torrent::InfoHash;
 
+    const PEER_ID: [u8; 20] = [1; 20];
+
     #[test]
     fn test_new_metadata_piece_manager() {
         let info_hash = InfoHash([0x00; 20]);
@@ -140,18 +302,18 @@ mod tests {
         let block_data_0 = Bytes::from_owner([0x01; METADATA_BLOCK_SIZE]);
         let block_data_1 = Bytes::from_owner([0x02; METADATA_BLOCK_SIZE]);
 
-        manager.add_block(0, block_data_0.clone());
+        manager.add_block(PEER_ID, 0, block_data_0.clone());
         assert_eq!(manager.queue[0], BlockState::Finished);
         assert_eq!(&manager.bytes[0..METADATA_BLOCK_SIZE], block_data_0);
 
-        manager.add_block(1, block_data_1.clone());
+        manager.add_block(PEER_ID, 1, block_data_1.clone());
         assert_eq!(manager.queue[1], BlockState::Finished);
         assert_eq!(
             &manager.bytes[METADATA_BLOCK_SIZE..METADATA_BLOCK_SIZE * 2],
             block_data_1
         );
 
-        manager.add_block(2, Bytes::from_owner([1, 2, 3]));
+        manager.add_block(PEER_ID, 2, Bytes::from_owner([1, 2, 3]));
         assert_eq!(manager.queue[2], BlockState::Finished);
         assert_eq!(
             &manager.bytes[METADATA_BLOCK_SIZE * 2..METADATA_BLOCK_SIZE * 2 + 3],
@@ -166,7 +328,7 @@ mod tests {
         manager.set_len(METADATA_BLOCK_SIZE * 3); // 3 blocks
 
         // Request first block
-        let req_data_0 = manager.get_block_req_data().unwrap().unwrap();
+        let req_data_0 = manager.get_block_req_data(PEER_ID).unwrap().unwrap();
         assert_eq!(req_data_0, b"d8:msg_typei0e5:piecei0ee".to_vec());
         let msg_0: MetadataMsg = serde_bencode::from_bytes(&req_data_0).unwrap();
         assert_eq!(msg_0.msg_type, MetadataMsgType::Request);
@@ -175,7 +337,7 @@ mod tests {
         assert_eq!(manager.queue[0], BlockState::InProcess);
 
         // Request second block
-        let req_data_1 = manager.get_block_req_data().unwrap().unwrap();
+        let req_data_1 = manager.get_block_req_data(PEER_ID).unwrap().unwrap();
         assert_eq!(req_data_1, b"d8:msg_typei0e5:piecei1ee".to_vec());
         let msg_1: MetadataMsg = serde_bencode::from_bytes(&req_data_1).unwrap();
         assert_eq!(msg_1.msg_type, MetadataMsgType::Request);
@@ -186,7 +348,84 @@ mod tests {
         manager.queue[2] = BlockState::Finished;
 
         // Should return None when all blocks are received
-        assert!(manager.get_block_req_data().unwrap().is_none());
+        assert!(manager.get_block_req_data(PEER_ID).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_release_peer() {
+        let info_hash = InfoHash([0x00; 20]);
+        let mut manager = MetadataPieceManager::new(info_hash);
+        manager.set_len(METADATA_BLOCK_SIZE * 2);
+
+        manager.get_block_req_data(PEER_ID).unwrap().unwrap();
+        assert_eq!(manager.queue[0], BlockState::InProcess);
+        manager.release_peer(PEER_ID);
+        assert_eq!(manager.queue[0], BlockState::None);
+
+        // the next peer gets the freed block instead of the next one
+        let req_data = manager.get_block_req_data([2; 20]).unwrap().unwrap();
+        assert_eq!(req_data, b"d8:msg_typei0e5:piecei0ee".to_vec());
+    }
+
+    #[test]
+    fn test_only_faster_peers_duplicate_requests() {
+        let info_hash = InfoHash([0x00; 20]);
+        let mut manager = MetadataPieceManager::new(info_hash);
+        manager.set_len(METADATA_BLOCK_SIZE * 2);
+        let fast_peer = [2; 20];
+
+        // the fast peer proves itself with the first block
+        manager.get_block_req_data(fast_peer).unwrap().unwrap();
+        manager.add_block(fast_peer, 0, Bytes::from_owner([0; METADATA_BLOCK_SIZE]));
+        // the unmeasured peer gets the last block
+        manager.get_block_req_data(PEER_ID).unwrap().unwrap();
+        assert_eq!(manager.queue[1], BlockState::InProcess);
+
+        // another unmeasured peer isn't better, so it doesn't get the block
+        assert!(manager.get_block_req_data([3; 20]).unwrap().is_none());
+        // but the fast one may request it as well
+        let req_data = manager.get_block_req_data(fast_peer).unwrap().unwrap();
+        assert_eq!(req_data, b"d8:msg_typei0e5:piecei1ee".to_vec());
+    }
+
+    #[test]
+    fn test_failing_peer_is_avoided() {
+        let info_hash = InfoHash([0x00; 20]);
+        let mut manager = MetadataPieceManager::new(info_hash);
+        manager.set_len(METADATA_BLOCK_SIZE * 3);
+        let good_peer = [2; 20];
+        manager.get_block_req_data(good_peer).unwrap().unwrap();
+
+        for _ in 0..MAX_METADATA_ERRORS {
+            manager.get_block_req_data(PEER_ID).unwrap().unwrap();
+            manager.reject_block(PEER_ID);
+        }
+        assert!(manager.get_block_req_data(PEER_ID).unwrap().is_none());
+        // the rejected blocks are free again
+        assert!(manager.get_block_req_data(good_peer).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_restore_partial() {
+        let info_hash = InfoHash([0x00; 20]);
+        let mut manager = MetadataPieceManager::new(info_hash);
+        manager.set_len(METADATA_BLOCK_SIZE * 2 + 3);
+        manager.add_block(PEER_ID, 1, Bytes::from_owner([0x02; METADATA_BLOCK_SIZE]));
+        manager.get_block_req_data(PEER_ID).unwrap().unwrap();
+        let partial = manager.to_partial();
+        assert_eq!(partial.finished, vec![false, true, false]);
+
+        let mut restored = MetadataPieceManager::new(info_hash);
+        restored.restore(partial);
+        // the block that was in process is requested again
+        assert_eq!(
+            restored.queue,
+            vec![BlockState::None, BlockState::Finished, BlockState::None]
+        );
+        assert_eq!(restored.bytes, manager.bytes);
+        // set_len doesn't overwrite the restored blocks
+        restored.set_len(METADATA_BLOCK_SIZE * 2 + 3);
+        assert_eq!(restored.queue[1], BlockState::Finished);
     }
 
     #[test]
@@ -1,9 +1,9 @@
-use std::{net::SocketAddrV4, str::FromStr};
+use std::str::FromStr;
 
 use thiserror::Error;
 use url::form_urlencoded::Parse;
 
-use crate::torrent::InfoHash;
+use crate::{torrent::InfoHash, tracker::PeerAddr};
 
 // mod before_download_manager;
 // mod peer_manager_init;
@@ -37,7 +37,12 @@ pub struct MagnetLink {
     pub info_hash: InfoHash,
     file_name: Option<String>,
     trackers: Vec<url::Url>,
-    peer_addrs: Vec<SocketAddrV4>,
+    /// the `xl` parameter, the length of the torrent in bytes
+    exact_length: Option<u64>,
+    /// the `x.pe` peers, they may be given as `hostname:port`
+    peer_addrs: Vec<PeerAddr>,
+    /// the `ws` web seeds
+    web_seeds: Vec<url::Url>,
 }
 
 impl MagnetLink {
@@ -54,10 +59,24 @@ impl MagnetLink {
         Ok(self.trackers.clone())
     }
 
+    pub fn get_peer_addrs(&self) -> Vec<PeerAddr> {
+        self.peer_addrs.clone()
+    }
+
+    pub fn get_exact_length(&self) -> Option<u64> {
+        self.exact_length
+    }
+
+    pub fn get_web_seeds(&self) -> Vec<url::Url> {
+        self.web_seeds.clone()
+    }
+
     fn from_query_pairs(pairs: Parse) -> Result<Self, MagnetLinkError> {
         let mut trackers = Vec::new();
         let mut peer_addrs = Vec::new();
+        let mut web_seeds = Vec::new();
         let mut file_name = None;
+        let mut exact_length = None;
         let mut info_hash = None;
 
         for (key, value) in pairs.into_iter() {
@@ -66,22 +85,21 @@ impl MagnetLink {
                     info_hash = Some(InfoHash::from_str(&value)?);
                 }
                 "tr" => {
-                    if let Ok(mut url) = url::Url::parse(&value) {
-                        // current workaround for some trackers that only announce their udp addr but also have support for http
-                        if url.scheme() == "udp" {
-                            url.set_path("/announce");
-                            let url_str = &url.as_str()[3..];
-                            url = url::Url::parse(&format!("http{url_str}")).expect("is valid");
-                            // the reason I do this is because I cannot set the scheme via .set_scheme() from udp to http
-                        }
+                    if let Ok(url) = url::Url::parse(&value) {
                         trackers.push(url);
                     }
                 }
                 "x.pe" => {
-                    if let Ok(addr) = SocketAddrV4::from_str(&value) {
+                    if let Ok(addr) = PeerAddr::from_str(&value) {
                         peer_addrs.push(addr);
                     }
                 }
+                "ws" => {
+                    if let Ok(url) = url::Url::parse(&value) {
+                        web_seeds.push(url);
+                    }
+                }
+                "xl" => exact_length = value.parse().ok(),
                 "dn" => {
                     if !value.is_empty() {
                         file_name = Some(value.into_owned())
@@ -91,9 +109,6 @@ impl MagnetLink {
             }
         }
 
-        if trackers.is_empty() {
-            return Err(MagnetLinkError::NoTrackerUrl);
-        }
         let info_hash = info_hash.ok_or(MagnetLinkError::InvalidInfoHash(anyhow::anyhow!(
             "No info hash provided."
         )))?;
@@ -101,6 +116,8 @@ impl MagnetLink {
             info_hash,
             trackers,
             peer_addrs,
+            web_seeds,
+            exact_length,
             file_name,
         })
     }
@@ -118,8 +135,6 @@ pub enum MagnetLinkError {
     InvalidInfoHash(#[from] anyhow::Error),
     #[error("Failed to deserialize the query in the link with the error: `{0}`")]
     FailedToDesQuery(#[from] serde_urlencoded::de::Error),
-    #[error("downloading from a magnetlink without a provided tracker url isn't supported")]
-    NoTrackerUrl,
 }
 
 #[cfg(test)]
@@ -141,10 +156,30 @@ mod test_magnetlink {
             magnet_link.trackers,
             vec![
                 url::Url::parse("http://bittorrent-test-tracker.codecrafters.io/announce")
-                    .expect("is valid");
-                2
+                    .expect("is valid"),
+                url::Url::parse("udp://bittorrent-test-tracker.codecrafters.io").expect("is valid"),
             ]
         );
         assert_eq!(magnet_link.file_name, Some("magnet1.gif".to_owned()));
+        assert_eq!(magnet_link.get_exact_length(), None);
+    }
+
+    #[test]
+    fn exact_length() {
+        let magnet_link = MagnetLink::from_url(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&xl=629944&tr=http%3A%2F%2Ft.example%2Fannounce"
+        ).expect("is valid");
+        assert_eq!(magnet_link.get_exact_length(), Some(629944));
+    }
+
+    #[test]
+    fn web_seeds() {
+        let magnet_link = MagnetLink::from_url(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&ws=http%3A%2F%2Fseed.example%2Ffiles%2F&ws=not%20a%20url"
+        ).expect("is valid");
+        assert_eq!(
+            magnet_link.get_web_seeds(),
+            vec![url::Url::parse("http://seed.example/files/").expect("is valid")]
+        );
     }
 }
@@ -8,6 +8,7 @@ use crate::{
 };
 pub(crate) mod factory;
 pub mod magnet_links;
+pub(crate) mod pex;
 pub(crate) mod protocol_extension_handshake;
 
 /// This Payload is merely a holder for the extended message ID and data.
@@ -50,7 +51,12 @@ pub enum ExtensionAction {
 /// A message type specifically for communication from an extension to the PeerManager.
 #[derive(Clone, Debug, PartialEq)]
 pub enum ExtensionMessage {
-    ReceivedMetadataPiece { piece_index: u32, data: Bytes },
+    ReceivedMetadataPiece {
+        piece_index: u32,
+        data: Bytes,
+    },
+    /// the peer refused to send us the metadata piece
+    RejectedMetadataPiece,
     GotMetadataLength(usize),
 }
 
@@ -75,9 +81,11 @@ pub enum ExtensionType {
     Handshake,
     #[strum(to_string = "ut_metadata")]
     Metadata,
+    #[strum(to_string = "ut_pex")]
+    Pex,
 }
 
 // the handshake is always active
 /// List of the active and implemented extensions.
 /// Note, that the index of the ExtensionType in here corresponds to the index of the extended message ID **+ 1**
-pub const ACTIVE_EXTENSIONS: &[ExtensionType] = &[ExtensionType::Metadata];
+pub const ACTIVE_EXTENSIONS: &[ExtensionType] = &[ExtensionType::Metadata, ExtensionType::Pex];
@@ -0,0 +1,135 @@
+//! Peer exchange (BEP 11): connected peers tell each other whom else they're connected to.
+//! The PeerManager sends every peer the changes since its last message, see `send_pex`,
+//! the peers we're told about are dialed like the ones from the trackers.
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    extensions::{
+        ExtensionAction, ExtensionHandler, ExtensionType,
+        protocol_extension_handshake::AdditionalHandshakeInfo,
+    },
+    peer_manager::ReqMessage,
+};
+
+/// the most peers a message may add, we ignore the rest of a bigger one
+pub(crate) const MAX_ADDED: usize = 50;
+
+/// The peers are compact, 6 bytes per IPv4 peer and 18 per IPv6 peer.
+/// The flags hold one byte per added peer, we don't know anything about them and send 0.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PexMsg {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+impl PexMsg {
+    pub(crate) fn new(added: &[SocketAddr], dropped: &[SocketAddr]) -> Self {
+        let mut msg = Self::default();
+        for addr in added {
+            let (peers, flags) = match addr {
+                SocketAddr::V4(_) => (&mut msg.added, &mut msg.added_flags),
+                SocketAddr::V6(_) => (&mut msg.added6, &mut msg.added6_flags),
+            };
+            encode_peer(addr, peers);
+            flags.push(0);
+        }
+        for addr in dropped {
+            let peers = match addr {
+                SocketAddr::V4(_) => &mut msg.dropped,
+                SocketAddr::V6(_) => &mut msg.dropped6,
+            };
+            encode_peer(addr, peers);
+        }
+        msg
+    }
+
+    /// at most [`MAX_ADDED`] peers, a trailing partial entry is dropped
+    pub(crate) fn added(&self) -> Vec<SocketAddr> {
+        decode_peers(&self.added, 4)
+            .chain(decode_peers(&self.added6, 16))
+            .take(MAX_ADDED)
+            .collect()
+    }
+}
+
+fn encode_peer(addr: &SocketAddr, out: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => out.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => out.extend_from_slice(&ip.octets()),
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn decode_peers(bytes: &[u8], ip_len: usize) -> impl Iterator<Item = SocketAddr> {
+    bytes.chunks_exact(ip_len + 2).map(move |chunk| {
+        let (ip, port) = chunk.split_at(ip_len);
+        let ip = match ip_len {
+            4 => IpAddr::from(<[u8; 4]>::try_from(ip).expect("the chunk is 6 bytes")),
+            _ => IpAddr::from(<[u8; 16]>::try_from(ip).expect("the chunk is 18 bytes")),
+        };
+        SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+    })
+}
+
+/// hands the peers a peer tells us about to the PeerManager
+#[derive(Debug)]
+pub(crate) struct PexHandler;
+
+impl ExtensionHandler for PexHandler {
+    fn handle_message(&self, data: &[u8]) -> ExtensionAction {
+        let Ok(msg): Result<PexMsg, _> = serde_bencode::from_bytes(data) else {
+            return ExtensionAction::Nothing;
+        };
+        let added = msg.added();
+        if added.is_empty() {
+            return ExtensionAction::Nothing;
+        }
+        ExtensionAction::SendPeerManager(ReqMessage::PexPeers(added))
+    }
+
+    fn on_handshake(&self, _additional_info: &AdditionalHandshakeInfo) -> ExtensionAction {
+        ExtensionAction::Nothing
+    }
+
+    fn get_ext_type(&self) -> ExtensionType {
+        ExtensionType::Pex
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let added: Vec<SocketAddr> = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+        ];
+        let dropped: Vec<SocketAddr> = vec!["5.6.7.8:80".parse().unwrap()];
+        let bytes = serde_bencode::to_bytes(&PexMsg::new(&added, &dropped)).unwrap();
+        assert!(bytes.starts_with(b"d5:added6:\x01\x02\x03\x04\x1a\xe17:added.f1:\x00"));
+
+        let action = PexHandler.handle_message(&bytes);
+        assert_eq!(
+            action,
+            ExtensionAction::SendPeerManager(ReqMessage::PexPeers(added))
+        );
+        // only dropped peers are nothing to dial
+        let bytes = serde_bencode::to_bytes(&PexMsg::new(&[], &dropped)).unwrap();
+        assert_eq!(PexHandler.handle_message(&bytes), ExtensionAction::Nothing);
+    }
+}
@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
 
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteArray;
@@ -12,6 +15,24 @@ pub enum YourIp {
     V6(ByteArray<16>),
 }
 
+impl From<IpAddr> for YourIp {
+    fn from(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => YourIp::V4(ByteArray::new(ip.octets())),
+            IpAddr::V6(ip) => YourIp::V6(ByteArray::new(ip.octets())),
+        }
+    }
+}
+
+impl From<YourIp> for IpAddr {
+    fn from(ip: YourIp) -> Self {
+        match ip {
+            YourIp::V4(bytes) => IpAddr::V4(Ipv4Addr::from(bytes.into_array())),
+            YourIp::V6(bytes) => IpAddr::V6(Ipv6Addr::from(bytes.into_array())),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct HandshakeExtension {
     pub(crate) m: HashMap<String, u8>,
@@ -19,6 +40,11 @@ pub(crate) struct HandshakeExtension {
     pub(crate) other: AdditionalHandshakeInfo,
 }
 
+/// the `v` we send, like the user agent we announce with
+const CLIENT_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// We queue every request a peer sends and never drop one, but peers want a number.
+const REQQ: usize = 250;
+
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub(crate) struct AdditionalHandshakeInfo {
     pub(crate) metadata_size: Option<usize>,
@@ -32,15 +58,53 @@ pub(crate) struct AdditionalHandshakeInfo {
     pub(crate) reqq: Option<usize>,
 }
 
+impl AdditionalHandshakeInfo {
+    /// what every peer hears about us, the `yourip` differs per peer
+    pub(crate) fn ours(listen_port: Option<u16>, metadata_size: Option<usize>) -> Self {
+        Self {
+            metadata_size,
+            p: listen_port,
+            v: Some(CLIENT_VERSION.to_owned()),
+            yourip: None,
+            reqq: Some(REQQ),
+        }
+    }
+}
+
 impl HandshakeExtension {
-    pub fn new() -> Self {
+    pub fn new(other: AdditionalHandshakeInfo) -> Self {
         let mut m = HashMap::new();
         for &ext in crate::extensions::ACTIVE_EXTENSIONS {
             m.insert(ext.to_string(), ext as u8);
         }
-        Self {
-            m,
-            other: AdditionalHandshakeInfo::default(),
-        }
+        Self { m, other }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn our_handshake_fields() {
+        let mut info = AdditionalHandshakeInfo::ours(Some(6881), Some(312));
+        info.yourip = Some("::ffff:10.0.0.7".parse::<IpAddr>().unwrap().into());
+        let bytes = serde_bencode::to_bytes(&HandshakeExtension::new(info)).unwrap();
+        let back: HandshakeExtension = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(back.other.p, Some(6881));
+        assert_eq!(back.other.metadata_size, Some(312));
+        assert_eq!(back.other.reqq, Some(REQQ));
+        assert_eq!(back.other.v.as_deref(), Some(CLIENT_VERSION));
+        // the mapped address goes out as the IPv4 one
+        let yourip: IpAddr = back.other.yourip.unwrap().into();
+        assert_eq!(yourip, "10.0.0.7".parse::<IpAddr>().unwrap());
+
+        // nothing we don't know is sent
+        let bytes = serde_bencode::to_bytes(&AdditionalHandshakeInfo::ours(None, None)).unwrap();
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(
+            !text.contains("metadata_size") && !text.contains("1:p"),
+            "{text}"
+        );
     }
 }
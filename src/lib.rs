@@ -1,26 +1,62 @@
+//! A BitTorrent client library, [`prelude`] has everything needed to download a torrent.
+//! Everything re-exported here is the public API and only breaks with a major version,
+//! the hidden items are there for the CLI and may change at any time.
+pub mod client;
+pub mod clock;
+pub mod config;
 pub mod core;
 mod database;
+mod dht;
+pub mod doctor;
 mod extensions;
+mod lsd;
 mod messages;
+pub mod paths;
 mod peer;
 mod peer_manager;
+pub mod policy;
+mod sync;
+#[cfg(test)]
+mod test_fixtures;
 mod tracker;
 
 pub use crate::core::torrent::Torrent;
+pub use config::Config;
+pub use core::chunks::{ChunkError, MetadataAssembler, MetadataChunk};
 pub use core::torrent;
+pub use dht::{Dht, PeerLookup, SwarmEstimate};
 pub use extensions::magnet_links;
+pub use peer::{
+    rate_limit::{Bandwidth, RateLimit},
+    trace::WireTrace,
+};
+pub use peer_manager::{
+    CheckProgress, ClientCounts, ClientStats, ClientStatsSnapshot, Contribution, Contributor,
+    DiskBudget, DiskStats, Eta, EventKind, EventLog, EventPage, EventRecord, IntegrityReport,
+    PeerSlots, PexPolicy, PieceMap, PieceMapPage, PieceRun, PieceStatus, PieceVerification,
+    ProgressSnapshot, ReadError, StallReport, TorrentControl, error::PeerManagerError,
+};
+pub use tracker::{
+    AnnounceScheduler, ScrapeStats, TrackerRequestError, TrackerStatus, TrackerTiers,
+    UdpTrackerError, WebSocketTrackerError, scrape,
+};
+
+#[doc(hidden)]
 pub use peer::Peer;
+// `TorrentControl` doesn't give the progress and the tracker status yet, only these do
+#[doc(hidden)]
 pub use peer_manager::PeerManager;
-use std::collections::HashMap;
-pub use tracker::TrackerRequest;
-
-pub(crate) const BLOCK_MAX: u32 = 1 << 14;
+#[doc(hidden)]
+pub use tracker::{Announcer, TrackerRequest};
 
-// pub(crate) type MsgFrameType = Framed<tokio::net::TcpStream, MessageFramer>;
-
-pub struct BittorrentClient {
-    pub torrents: Vec<Torrent>,
-    pub peer_manager: HashMap<String, PeerManager>,
-    //
-    // pub peers: HashMap<MsgFrameType, (Peer, Framed<TcpStream, MessageFramer>)>,
+/// `use codecrafters_bittorrent::prelude::*;` for the types most users need
+pub mod prelude {
+    pub use crate::{
+        Config, EventKind, EventPage, EventRecord, Torrent, TorrentControl,
+        client::{Client, ClientError as Error},
+        magnet_links::MagnetLink,
+        torrent::InfoHash,
+    };
 }
+
+pub(crate) const BLOCK_MAX: u32 = 1 << 14;
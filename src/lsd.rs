@@ -0,0 +1,223 @@
+//! Local Service Discovery (BEP 14): finds peers of the same torrent in the local network.
+//! Every client announces its torrents to a multicast group now and then and listens to the
+//! announces of the others, so two clients on one LAN meet without any tracker or DHT.
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::mpsc};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::{policy::PeerSource, torrent::InfoHash};
+
+use crate::sync::Mutex;
+
+const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_PORT: u16 = 6771;
+/// BEP 14 asks for at most one announce per torrent every few minutes
+pub(crate) const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+type Subscribers = Arc<Mutex<HashMap<InfoHash, mpsc::Sender<(SocketAddr, PeerSource)>>>>;
+
+/// one `BT-SEARCH` datagram
+#[derive(Debug, PartialEq)]
+struct Announce {
+    port: u16,
+    info_hashes: Vec<InfoHash>,
+    /// lets us recognize our own announces, the multicast loops them back to us
+    cookie: Option<String>,
+}
+
+impl Announce {
+    fn encode(&self) -> String {
+        let mut msg = format!("BT-SEARCH * HTTP/1.1\r\nHost: {LSD_GROUP}:{LSD_PORT}\r\n");
+        msg.push_str(&format!("Port: {}\r\n", self.port));
+        for info_hash in &self.info_hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash.0)));
+        }
+        if let Some(cookie) = &self.cookie {
+            msg.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        msg.push_str("\r\n\r\n");
+        msg
+    }
+
+    /// None if it isn't an announce, info hashes that aren't 40 hex digits are skipped
+    fn parse(datagram: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(datagram).ok()?;
+        let mut lines = text.lines();
+        if lines.next()?.trim() != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    if let Some(info_hash) = hex::decode(value)
+                        .ok()
+                        .and_then(|bytes| bytes.try_into().ok())
+                    {
+                        info_hashes.push(InfoHash(info_hash));
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// The socket in the multicast group, shared by all torrents.
+/// This is cheap to clone, the listener stops when the last clone is dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct Lsd(Arc<LsdInner>);
+
+#[derive(Debug)]
+struct LsdInner {
+    socket: Arc<UdpSocket>,
+    /// the port the peers listen on
+    port: u16,
+    cookie: String,
+    /// where the peers of each torrent go, see [`Lsd::subscribe`]
+    subscribers: Subscribers,
+    _listener: DropGuard,
+}
+
+impl Lsd {
+    /// Joins the multicast group, other clients on this host may have joined it already.
+    pub(crate) fn bind(port: u16) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+        socket.join_multicast_v4(&LSD_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        // two clients on the same host should find each other too
+        socket.set_multicast_loop_v4(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+
+        let cookie = hex::encode(rand::random::<[u8; 8]>());
+        let subscribers = Arc::new(Mutex::new(HashMap::new()));
+        let cancel = CancellationToken::new();
+        tokio::spawn(listen(
+            socket.clone(),
+            cookie.clone(),
+            subscribers.clone(),
+            cancel.clone(),
+        ));
+        Ok(Self(Arc::new(LsdInner {
+            socket,
+            port,
+            cookie,
+            subscribers,
+            _listener: cancel.drop_guard(),
+        })))
+    }
+
+    /// Sends the peers that announce the torrent to `peers` until the receiver is dropped.
+    pub(crate) fn subscribe(
+        &self,
+        info_hash: InfoHash,
+        peers: mpsc::Sender<(SocketAddr, PeerSource)>,
+    ) {
+        self.0.subscribers.lock().unwrap().insert(info_hash, peers);
+    }
+
+    pub(crate) async fn announce(&self, info_hash: InfoHash) {
+        let announce = Announce {
+            port: self.0.port,
+            info_hashes: vec![info_hash],
+            cookie: Some(self.0.cookie.clone()),
+        };
+        if let Err(e) = self
+            .0
+            .socket
+            .send_to(announce.encode().as_bytes(), (LSD_GROUP, LSD_PORT))
+            .await
+        {
+            eprintln!("Failed to announce to the local network: {e}");
+        }
+    }
+}
+
+async fn listen(
+    socket: Arc<UdpSocket>,
+    cookie: String,
+    subscribers: Subscribers,
+    cancel: CancellationToken,
+) {
+    let mut buf = [0; 1500];
+    loop {
+        let (len, from) = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(_) => continue,
+            },
+            _ = cancel.cancelled() => return,
+        };
+        let Some(announce) = Announce::parse(&buf[..len]) else {
+            continue;
+        };
+        if announce.cookie.as_deref() == Some(cookie.as_str()) {
+            continue;
+        }
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        let mut subscribers = subscribers.lock().unwrap();
+        for info_hash in announce.info_hashes {
+            if let Some(peers) = subscribers.get(&info_hash)
+                && peers.try_send((peer, PeerSource::Lsd)).is_err()
+                && peers.is_closed()
+            {
+                subscribers.remove(&info_hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_roundtrip() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![InfoHash([0xab; 20]), InfoHash([1; 20])],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let encoded = announce.encode();
+        assert!(encoded.starts_with(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abab"
+        ));
+        assert!(encoded.ends_with("\r\n\r\n\r\n"));
+        assert_eq!(Announce::parse(encoded.as_bytes()), Some(announce));
+
+        // other clients differ in case and leave out the cookie
+        let foreign = b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nport: 51413\r\ninfohash: 0101010101010101010101010101010101010101\r\ninfohash: xyz\r\n\r\n\r\n";
+        assert_eq!(
+            Announce::parse(foreign),
+            Some(Announce {
+                port: 51413,
+                info_hashes: vec![InfoHash([1; 20])],
+                cookie: None,
+            })
+        );
+        assert_eq!(Announce::parse(b"M-SEARCH * HTTP/1.1\r\n\r\n"), None);
+    }
+}
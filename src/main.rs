@@ -1,21 +1,47 @@
 use anyhow::Context;
 use clap::{Parser, Subcommand};
+use codecrafters_bittorrent::client::{Client, ClientError};
+use codecrafters_bittorrent::core::bencode::Value;
+use codecrafters_bittorrent::doctor::{self, DoctorOptions, Status};
 use codecrafters_bittorrent::magnet_links::MagnetLink;
 // use codecrafters_bittorrent::magnet_links::MagnetLink;
-use codecrafters_bittorrent::{Peer, PeerManager, Torrent, TrackerRequest};
+use codecrafters_bittorrent::{
+    AnnounceScheduler, Config, Peer, Torrent, TrackerRequest, TrackerTiers, scrape,
+};
 use std::error::Error;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
 const PEER_ID: &[u8; 20] = b"-AZ2060-222222222222";
 const PEER_PORT: u16 = 6881;
+/// used by `doctor` if no torrent is given
+const DEFAULT_TCP_TRACKER: &str = "bittorrent-test-tracker.codecrafters.io:80";
+const DEFAULT_UDP_TRACKER: &str = "tracker.opentrackr.org:1337";
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: DecodeMetadataType,
+    /// path to a JSON config file
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// writes every message exchanged with the peers to the state directory,
+    /// SIGUSR1 toggles it while the torrent runs
+    #[arg(long, global = true)]
+    wire_trace: bool,
+    /// hashes the data on disk again before the torrent starts,
+    /// SIGUSR2 forces a re-announce while the torrent runs
+    #[arg(long, global = true)]
+    recheck: bool,
+    /// bytes per second all torrents may upload together, overrides the config
+    #[arg(long, global = true)]
+    max_upload_rate: Option<u64>,
+    /// bytes per second all torrents may download together, overrides the config
+    #[arg(long, global = true)]
+    max_download_rate: Option<u64>,
 }
 #[derive(Debug, Subcommand)]
 #[clap(rename_all = "snake_case")]
@@ -23,15 +49,24 @@ enum DecodeMetadataType {
     Decode {
         value: String,
     },
+    /// prints the metainfo of a torrent file or a magnet link whose metadata we downloaded before
     Info {
-        torrent: PathBuf,
+        torrent: String,
+        /// downloads the metadata of a magnet link from the swarm if we don't have it
+        #[arg(long)]
+        fetch: bool,
     },
     Peers {
         torrent: PathBuf,
     },
+    /// prints the number of seeders and leechers of the swarm
+    Scrape {
+        /// a torrent file or a magnet link
+        torrent: String,
+    },
     Handshake {
         torrent: PathBuf,
-        addr: SocketAddrV4,
+        addr: SocketAddr,
     },
     DownloadPiece {
         #[arg(short)]
@@ -43,11 +78,32 @@ enum DecodeMetadataType {
         #[arg(short)]
         output: Option<PathBuf>,
         torrent: PathBuf,
+        /// the ratio group of the config which decides when to stop seeding
+        #[arg(long)]
+        ratio_group: Option<String>,
+        /// only reports how many peers are reachable and what they have, downloads nothing
+        #[arg(long)]
+        dry_run: bool,
     },
     DownloadMagnet {
         #[arg(short)]
         output: Option<PathBuf>,
         magnet_link: String,
+        /// the ratio group of the config which decides when to stop seeding
+        #[arg(long)]
+        ratio_group: Option<String>,
+        /// only downloads the metadata and reports how many peers are reachable and what they
+        /// have
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// checks whether the environment is ready for downloading
+    Doctor {
+        /// the directory the download would be written to, the download directory by default
+        #[arg(long)]
+        dir: Option<PathBuf>,
+        /// uses the tracker and size of this torrent for the checks
+        torrent: Option<PathBuf>,
     },
 }
 
@@ -55,43 +111,115 @@ enum DecodeMetadataType {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+    let mut config = match &cli.config {
+        Some(path) => Config::read_from_file(path)?,
+        None => Config::default(),
+    };
+    if cli.max_upload_rate.is_some() {
+        config.max_upload_rate = cli.max_upload_rate;
+    }
+    if cli.max_download_rate.is_some() {
+        config.max_download_rate = cli.max_download_rate;
+    }
+    let config = Arc::new(config);
+    let scheduler = AnnounceScheduler::new(
+        config.min_announce_gap(),
+        &config.tracker_tls,
+        &config.tracker_compat,
+    )?;
 
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
     match &cli.command {
         DecodeMetadataType::Decode { value } => {
-            let decoded_value: serde_bencode::value::Value =
-                serde_bencode::from_str(value).context("decode bencode")?;
-            println!("{decoded_value:?}");
+            let decoded_value = Value::decode(value.as_bytes()).context("decode bencode")?;
+            println!("{}", decoded_value.to_json());
         }
-        DecodeMetadataType::Info { torrent } => {
-            let torrent = Torrent::read_from_file(torrent)?;
-            // println!("Tracker URL: {}", torrent.announce);
-            // println!("Length: {}", torrent.get_length());
-            let info_hash = torrent.info.info_hash();
-            println!("Info Hash: {}", hex::encode(info_hash.0));
-            println!("Piece Length: {}", torrent.info.piece_length);
-            // print everything except the piece hashes
-            println!("{:#?}", torrent.info.files);
-            println!("{:#?}", torrent.info.name);
-            println!("{:#?}", torrent.info.other);
+        DecodeMetadataType::Info { torrent, fetch } => {
+            let torrent = if torrent.starts_with("magnet:") {
+                let magnet_link = MagnetLink::from_url(torrent)?;
+                let client = client(&config, &cli)?;
+                match client.cached_metadata(&magnet_link).await? {
+                    Some(torrent) => torrent,
+                    None if *fetch => client.download_metadata(&magnet_link).await?,
+                    None => {
+                        return Err("The metadata of the magnet link wasn't downloaded yet, \
+                                    --fetch downloads it from the swarm"
+                            .into());
+                    }
+                }
+            } else {
+                Torrent::read_from_file(&PathBuf::from(torrent))?
+            };
+            print!("{}", torrent.summary());
         }
         DecodeMetadataType::Peers { torrent } => {
             let torrent = Torrent::read_from_file(torrent)?;
             let info_hash = torrent.info.info_hash();
-            let tracker_req =
-                TrackerRequest::new(&info_hash, PEER_ID, PEER_PORT, torrent.info.get_length());
-            let response = tracker_req.get_response(vec![torrent.announce]).await?;
-            for peer in response.peers.0 {
-                println!("{peer:?}");
+            let tracker_req = TrackerRequest::new(
+                &info_hash,
+                PEER_ID,
+                PEER_PORT,
+                torrent.info.get_length() as u64,
+            );
+            let response = tracker_req
+                .with_settings(&config.announce)
+                .get_response(torrent.announce, &scheduler)
+                .await?;
+            for peer in response.into_peers() {
+                // only trackers that don't answer in the compact form tell the peer ids
+                match peer.peer_id.as_ref().and_then(Peer::client_of) {
+                    Some(client) => println!("{} {client}", peer.addr),
+                    None => println!("{}", peer.addr),
+                }
             }
         }
+        DecodeMetadataType::Scrape { torrent } => {
+            let (info_hash, tiers) = if torrent.starts_with("magnet:") {
+                let magnet_link = MagnetLink::from_url(torrent)?;
+                (
+                    magnet_link.info_hash,
+                    TrackerTiers::single_tier(magnet_link.get_announce_urls()?),
+                )
+            } else {
+                let torrent = Torrent::read_from_file(&PathBuf::from(torrent))?;
+                (
+                    torrent.info.info_hash(),
+                    TrackerTiers::from_torrent(&torrent),
+                )
+            };
+            if tiers.is_empty() {
+                // a trackerless torrent, the DHT can only estimate the swarm
+                let dht = client(&config, &cli)?
+                    .dht()
+                    .await
+                    .ok_or("The torrent has no trackers and the DHT is disabled")?;
+                let swarm = dht.scrape(info_hash).await;
+                println!("Seeders: ~{}", swarm.seeds);
+                println!("Leechers: ~{}", swarm.downloaders);
+                return Ok(());
+            }
+            let stats = scrape(&info_hash, &tiers, &scheduler).await?;
+            println!("Seeders: {}", stats.complete);
+            println!("Leechers: {}", stats.incomplete);
+            println!("Completed: {}", stats.downloaded);
+        }
         DecodeMetadataType::Handshake { torrent, addr } => {
             let torrent = Torrent::read_from_file(torrent)?;
             let (tx, _rx) = mpsc::channel(1);
-            let peer =
-                Peer::connect_from_addr(*addr, torrent.info.info_hash(), *PEER_ID, tx).await?;
+            let peer = Peer::connect_from_addr(
+                *addr,
+                torrent.info.info_hash(),
+                *PEER_ID,
+                tx,
+                &config.socket,
+                None,
+            )
+            .await?;
             println!("Peer with id {:?} connected", peer.get_id());
+            if let Some(client) = peer.client() {
+                println!("Client: {client}");
+            }
         }
         DecodeMetadataType::DownloadPiece {
             output: _,
@@ -129,97 +257,88 @@ async fn main() -> Result<(), Box<dyn Error>> {
             // std::thread::sleep(std::time::Duration::MAX);
 
             // let torrent = read_torrent(torrent)?;
-            // assert!(*piece_i < torrent.info.pieces.0.len() as u32); // piece starts at 0
+            // assert!(*piece_i < torrent.info.pieces.len() as u32); // piece starts at 0
             // let all_blocks = download_piece(&torrent, *piece_i).await?;
 
             // let mut file = std::fs::File::create(output).context("create downloaded file")?;
             // file.write_all(&all_blocks)
             //     .context("write downloaded file")?;
         }
+        DecodeMetadataType::Download {
+            dry_run: true,
+            torrent,
+            ..
+        } => {
+            let torrent = Torrent::read_from_file(torrent)?;
+            println!("{}", client(&config, &cli)?.dry_run(&torrent).await?);
+        }
         DecodeMetadataType::Download {
             output,
-            torrent: torrent_path,
+            torrent,
+            ratio_group,
+            dry_run: false,
         } => {
-            let (peer_manager_tx, peer_manager_rx) = mpsc::channel(64);
-
-            let torrent = Torrent::read_from_file(torrent_path)?;
-            let peer_manager =
-                PeerManager::init_from_torrent(peer_manager_rx, output.clone(), torrent.clone())
-                    .await?;
-
-            let info_hash = torrent.info.info_hash();
-            let tracker =
-                TrackerRequest::new(&info_hash, PEER_ID, PEER_PORT, torrent.info.get_length());
-            let response = tracker.get_response(vec![torrent.announce]).await?;
-
-            tokio::spawn(async move {
-                let _ = peer_manager.run().await;
-            });
-
-            for &addr in response.peers.0.iter() {
-                let peer_manager_tx = peer_manager_tx.clone();
-                tokio::spawn(async move {
-                    let peer = Peer::connect_from_addr(addr, info_hash, *PEER_ID, peer_manager_tx)
-                        .await
-                        .context("initializing peer")
-                        .unwrap();
-                    peer.run().await.unwrap();
-                });
-            }
-
-            // peer listener
-            let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PEER_PORT);
-            let listener = tokio::net::TcpListener::bind(addr).await?;
-            loop {
-                let connection = listener.accept().await;
-                let Ok((stream, _addr)) = connection else {
-                    continue;
-                };
-                let peer =
-                    Peer::connect_from_stream(stream, info_hash, *PEER_ID, peer_manager_tx.clone())
-                        .await
-                        .context("initializing incoming peer connection")
-                        .unwrap();
-                peer.run().await.unwrap();
-            }
+            client(&config, &cli)?
+                .download_torrent(torrent, output.clone(), ratio_group.clone(), 0)
+                .await?;
+        }
+        DecodeMetadataType::DownloadMagnet {
+            dry_run: true,
+            magnet_link,
+            ..
+        } => {
+            let client = client(&config, &cli)?;
+            let torrent = client
+                .download_metadata(&MagnetLink::from_url(magnet_link)?)
+                .await?;
+            println!("{}", client.dry_run(&torrent).await?);
         }
         DecodeMetadataType::DownloadMagnet {
             output,
             magnet_link,
+            ratio_group,
+            dry_run: false,
         } => {
-            let (peer_manager_tx, rx) = mpsc::channel(64);
-            let magnet_link = MagnetLink::from_url(magnet_link)?;
-            let peer_manager =
-                PeerManager::init_from_magnet(rx, output.clone(), magnet_link.clone()).await?;
-
-            // using 999 as a placeholder since we don't know the length yet
-            let tracker = TrackerRequest::new(&magnet_link.info_hash, PEER_ID, PEER_PORT, 999);
-            let response = tracker
-                .get_response(magnet_link.get_announce_urls()?)
+            client(&config, &cli)?
+                .download_magnet(magnet_link, output.clone(), ratio_group.clone(), 0)
                 .await?;
+        }
+        DecodeMetadataType::Doctor { dir, torrent } => {
+            let torrent = torrent.as_ref().map(Torrent::read_from_file).transpose()?;
+            let tcp_tracker = torrent
+                .as_ref()
+                .and_then(|t| {
+                    let announce = t.announce.as_ref()?;
+                    let host = announce.host_str()?;
+                    Some(format!("{host}:{}", announce.port_or_known_default()?))
+                })
+                .unwrap_or(DEFAULT_TCP_TRACKER.to_string());
+            let options = DoctorOptions {
+                listen_port: PEER_PORT,
+                paths: config.paths(),
+                target_dir: dir
+                    .clone()
+                    .unwrap_or(config.paths().download_dir().to_path_buf()),
+                required_space: torrent.map(|t| t.info.get_length() as u64),
+                tcp_tracker,
+                udp_tracker: DEFAULT_UDP_TRACKER.to_string(),
+            };
 
-            tokio::spawn(async move {
-                peer_manager.run().await.unwrap();
-            });
-
-            for &addr in response.peers.0.iter() {
-                let peer_manager_tx = peer_manager_tx.clone();
-                tokio::spawn(async move {
-                    let peer = Peer::connect_from_addr(
-                        addr,
-                        magnet_link.info_hash,
-                        *PEER_ID,
-                        peer_manager_tx,
-                    )
-                    .await
-                    .context("initializing peer")
-                    .unwrap();
-                    peer.run().await.unwrap();
-                });
+            let reports = doctor::run_checks(&options).await;
+            for report in reports.iter() {
+                println!("{report}");
+            }
+            if reports.iter().any(|r| r.status == Status::Failed) {
+                std::process::exit(1);
             }
-            std::thread::sleep(std::time::Duration::MAX);
         }
     }
 
     Ok(())
 }
+
+fn client(config: &Arc<Config>, cli: &Cli) -> Result<Client, ClientError> {
+    Ok(Client::new(config.clone(), *PEER_ID, PEER_PORT)?
+        .with_wire_trace(cli.wire_trace)
+        .with_recheck(cli.recheck))
+}
@@ -1,139 +1,261 @@
-use serde_repr::Deserialize_repr;
+//! Which client a peer runs, from the first bytes of its peer id, see
+//! <https://www.bittorrent.org/beps/bep_0020.html>. Nothing checks it, a peer can claim to be
+//! anyone; it's for the logs and the UI.
+use std::fmt;
 
-#[derive(Deserialize_repr)]
-#[repr(u16)]
-pub(super) enum ClientIdentifier {
-    /// Ares
+use strum::{EnumMessage, EnumString};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerClient {
+    pub name: &'static str,
+    /// None if the peer id has no version we can read
+    pub version: Option<String>,
+}
+
+impl fmt::Display for PeerClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {version}", self.name),
+            None => f.write_str(self.name),
+        }
+    }
+}
+
+impl PeerClient {
+    /// None for the clients we don't know and for random peer ids
+    pub(crate) fn from_peer_id(peer_id: &[u8; 20]) -> Option<Self> {
+        azureus_style(peer_id).or_else(|| shadow_style(peer_id))
+    }
+}
+
+/// `-qB4650-`, the client code and four version digits
+fn azureus_style(peer_id: &[u8; 20]) -> Option<PeerClient> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let name = code.parse::<ClientIdentifier>().ok()?.get_message()?;
+    let digits = peer_id[3..7]
+        .iter()
+        .map(|b| (*b as char).to_digit(36))
+        .collect::<Option<Vec<_>>>();
+    let version = digits.map(|digits| {
+        // `4650` is 4.6.5, but `1000` is 1.0
+        let end = digits
+            .iter()
+            .rposition(|d| *d != 0)
+            .map_or(2, |i| i.max(1) + 1);
+        join(&digits[..end])
+    });
+    Some(PeerClient { name, version })
+}
+
+/// `S58B-----`, the client letter and up to five version digits until a `-`
+fn shadow_style(peer_id: &[u8; 20]) -> Option<PeerClient> {
+    let name = match peer_id[0] {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    };
+    let len = peer_id[1..7].iter().position(|b| *b == b'-')?;
+    let digits = peer_id[1..1 + len]
+        .iter()
+        .map(|b| shadow_digit(*b))
+        .collect::<Option<Vec<_>>>()?;
+    // a random peer id rarely starts with a letter, a few digits and `---`
+    if digits.is_empty() || peer_id[1 + len..4 + len].iter().any(|b| *b != b'-') {
+        return None;
+    }
+    Some(PeerClient {
+        name,
+        version: Some(join(&digits)),
+    })
+}
+
+fn shadow_digit(b: u8) -> Option<u32> {
+    match b {
+        b'0'..=b'9' => Some((b - b'0') as u32),
+        b'A'..=b'Z' => Some((b - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((b - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+fn join(digits: &[u32]) -> String {
+    digits
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// the two letters of an Azureus-style peer id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumMessage)]
+enum ClientIdentifier {
+    #[strum(message = "Ares")]
     AG,
-    /// Ares
-    #[serde(rename = "A~")]
+    #[strum(serialize = "A~", message = "Ares")]
     ATilde,
-    /// Arctic
+    #[strum(message = "Arctic")]
     AR,
-    /// Avicora
+    #[strum(message = "Avicora")]
     AV,
-    /// BitPump
+    #[strum(message = "BitPump")]
     AX,
-    /// Azureus
+    #[strum(message = "Azureus")]
     AZ,
-    /// BitBuddy
+    #[strum(message = "BitBuddy")]
     BB,
-    /// BitComet
+    #[strum(message = "BitComet")]
     BC,
-    /// Bitflu
+    #[strum(message = "Bitflu")]
     BF,
-    /// BTG (uses Rasterbar libtorrent)
+    #[strum(message = "BTG")]
     BG,
-    /// BitRocket
+    #[strum(message = "BitRocket")]
     BR,
-    /// BTSlave
+    #[strum(message = "BTSlave")]
     BS,
-    /// ~Bittorrent X
+    #[strum(message = "Bittorrent X")]
     BX,
-    /// Enhanced CTorrent
+    #[strum(message = "Enhanced CTorrent")]
     CD,
-    /// CTorrent
+    #[strum(message = "CTorrent")]
     CT,
-    /// DelugeTorrent
+    #[strum(message = "DelugeTorrent")]
     DE,
-    /// Propagate Data Client
+    #[strum(message = "Propagate Data Client")]
     DP,
-    /// EBit
+    #[strum(message = "EBit")]
     EB,
-    /// electric sheep
+    #[strum(message = "electric sheep")]
     ES,
-    /// FoxTorrent
+    #[strum(message = "FoxTorrent")]
     FT,
-    /// FrostWire
+    #[strum(message = "FrostWire")]
     FW,
-    /// Freebox BitTorrent
+    #[strum(message = "Freebox BitTorrent")]
     FX,
-    /// GSTorrent
+    #[strum(message = "GSTorrent")]
     GS,
-    /// Halite
+    #[strum(message = "Halite")]
     HL,
-    /// Hydranode
+    #[strum(message = "Hydranode")]
     HN,
-    /// KGet
+    #[strum(message = "KGet")]
     KG,
-    /// KTorrent
+    #[strum(message = "KTorrent")]
     KT,
-    /// LABC
+    #[strum(message = "LABC")]
     LH,
-    /// Lphant
+    #[strum(message = "Lphant")]
     LP,
-    /// libtorrent
+    #[strum(message = "libtorrent")]
     LT,
-    /// libTorrent
-    #[serde(rename = "lt")]
+    #[strum(serialize = "lt", message = "libTorrent")]
     Lt,
-    /// LimeWire
+    #[strum(message = "LimeWire")]
     LW,
-    /// MonoTorrent
+    #[strum(message = "MonoTorrent")]
     MO,
-    /// MooPolice
+    #[strum(message = "MooPolice")]
     MP,
-    /// Miro
+    #[strum(message = "Miro")]
     MR,
-    /// MoonlightTorrent
+    #[strum(message = "MoonlightTorrent")]
     MT,
-    /// Net Transport
+    #[strum(message = "Net Transport")]
     NX,
-    /// Pando
+    #[strum(message = "Pando")]
     PD,
-    /// qBittorrent
-    #[serde(rename = "qB")]
+    #[strum(serialize = "qB", message = "qBittorrent")]
     QB,
-    /// QQDownload
+    #[strum(message = "QQDownload")]
     QD,
-    /// Qt 4 Torrent example
+    #[strum(message = "Qt 4 Torrent example")]
     QT,
-    /// Retriever
+    #[strum(message = "Retriever")]
     RT,
-    /// Shareaza alpha/beta
-    #[serde(rename = "S~")]
+    #[strum(serialize = "S~", message = "Shareaza alpha/beta")]
     STilde,
-    /// ~Swiftbit
+    #[strum(message = "Swiftbit")]
     SB,
-    /// SwarmScope
+    #[strum(message = "SwarmScope")]
     SS,
-    /// SymTorrent
+    #[strum(message = "SymTorrent")]
     ST,
-    /// sharktorrent
-    #[serde(rename = "st")]
+    #[strum(serialize = "st", message = "sharktorrent")]
     St,
-    /// Shareaza
+    #[strum(message = "Shareaza")]
     SZ,
-    /// TorrentDotNET
+    #[strum(message = "TorrentDotNET")]
     TN,
-    /// Transmission
+    #[strum(message = "Transmission")]
     TR,
-    /// Torrentstorm
+    #[strum(message = "Torrentstorm")]
     TS,
-    /// TuoTu
+    #[strum(message = "TuoTu")]
     TT,
-    /// uLeecher!
+    #[strum(message = "uLeecher!")]
     UL,
-    /// µTorrent
+    #[strum(message = "µTorrent")]
     UT,
-    /// µTorrent Web
+    #[strum(message = "µTorrent Web")]
     UW,
-    /// Vagaa
+    #[strum(message = "Vagaa")]
     VG,
-    /// WebTorrent Desktop
+    #[strum(message = "WebTorrent Desktop")]
     WD,
-    /// BitLet
+    #[strum(message = "BitLet")]
     WT,
-    /// WebTorrent
+    #[strum(message = "WebTorrent")]
     WW,
-    /// FireTorrent
+    #[strum(message = "FireTorrent")]
     WY,
-    /// Xunlei
+    #[strum(message = "Xunlei")]
     XL,
-    /// XanTorrent
+    #[strum(message = "XanTorrent")]
     XT,
-    /// Xtorrent
+    #[strum(message = "Xtorrent")]
     XX,
-    /// ZipTorrent
+    #[strum(message = "ZipTorrent")]
     ZT,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id(prefix: &[u8]) -> [u8; 20] {
+        let mut peer_id = [0xff; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    fn client(prefix: &[u8]) -> Option<String> {
+        PeerClient::from_peer_id(&peer_id(prefix)).map(|client| client.to_string())
+    }
+
+    #[test]
+    fn azureus_and_shadow_style() {
+        assert_eq!(client(b"-qB4650-").as_deref(), Some("qBittorrent 4.6.5"));
+        assert_eq!(client(b"-LT2090-").as_deref(), Some("libtorrent 2.0.9"));
+        assert_eq!(client(b"-TR4050-").as_deref(), Some("Transmission 4.0.5"));
+        assert_eq!(client(b"-lt0D80-").as_deref(), Some("libTorrent 0.13.8"));
+        assert_eq!(client(b"-AZ1000-").as_deref(), Some("Azureus 1.0"));
+        assert_eq!(client(b"-UT35%W-").as_deref(), Some("µTorrent"));
+        assert_eq!(client(b"S58B-----").as_deref(), Some("Shadow 5.8.11"));
+        assert_eq!(client(b"T03I-----").as_deref(), Some("BitTornado 0.3.18"));
+
+        // unknown clients and random ids
+        assert_eq!(client(b"-ZZ1000-"), None);
+        assert_eq!(client(b"S5-A"), None);
+        assert_eq!(client(b""), None);
+    }
+}
@@ -9,6 +9,8 @@ use crate::extensions::BasicExtensionPayload;
 
 pub(crate) mod client_identifier;
 pub(crate) mod payloads;
+#[cfg(test)]
+mod wire_roundtrip;
 
 #[derive(Debug, Clone, PartialEq, AsRefStr)]
 pub enum PeerMessage {
@@ -21,6 +23,7 @@ pub enum PeerMessage {
     Request(RequestPiecePayload),
     Piece(ResponsePiecePayload),
     Cancel(RequestPiecePayload),
+    Port(PortPayload),
     KeepAlive(NoPayload),
     Extended(BasicExtensionPayload),
 }
@@ -38,6 +41,7 @@ impl PeerMessage {
             PeerMessage::Request(payload) => payload.to_be_bytes(),
             PeerMessage::Piece(payload) => payload.to_be_bytes(),
             PeerMessage::Cancel(payload) => payload.to_be_bytes(),
+            PeerMessage::Port(payload) => payload.to_be_bytes(),
             PeerMessage::KeepAlive(payload) => payload.to_be_bytes(),
             PeerMessage::Extended(payload) => payload.to_be_bytes(),
         }
@@ -53,6 +57,7 @@ impl PeerMessage {
             PeerMessage::Request(_) => Some(MessageType::Request),
             PeerMessage::Piece(_) => Some(MessageType::Piece),
             PeerMessage::Cancel(_) => Some(MessageType::Cancel),
+            PeerMessage::Port(_) => Some(MessageType::Port),
             PeerMessage::KeepAlive(_) => None,
             PeerMessage::Extended(_) => Some(MessageType::Extended),
         }
@@ -71,6 +76,8 @@ pub enum MessageType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    /// the DHT port of the sender, BEP 5
+    Port = 9,
     Extended = 20,
 }
 
@@ -152,6 +159,13 @@ impl Decoder for MessageFramer {
             Some(MessageType::Cancel) => Ok(PeerMessage::Cancel(
                 RequestPiecePayload::from_be_bytes(data),
             )),
+            Some(MessageType::Port) if data.len() == 2 => {
+                Ok(PeerMessage::Port(PortPayload::from_be_bytes(data)))
+            }
+            Some(MessageType::Port) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("The port message has {} bytes instead of 2", data.len()),
+            )),
             Some(MessageType::Extended) => Ok(PeerMessage::Extended(
                 BasicExtensionPayload::from_be_bytes(data),
             )),
@@ -184,7 +198,8 @@ impl Encoder<PeerMessage> for MessageFramer {
         }
 
         let Some(msg_type) = item.get_msg_type() else {
-            // it's a keep alive message
+            // a keep-alive is only the length prefix, 0
+            dst.put_u32(0);
             return Ok(());
         };
 
@@ -1,4 +1,5 @@
 use bytes::{Bytes, BytesMut};
+use thiserror::Error;
 
 pub trait Payload {
     fn from_be_bytes(payload: &[u8]) -> Self;
@@ -8,36 +9,82 @@ pub trait Payload {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct BitfieldPayload {
-    /// a bitfield with each index that downloader has sent set to one and the rest set to zero
-    pub(crate) pieces_available: Vec<bool>,
+    /// packed like on the wire, the first piece is the highest bit of the first byte
+    bytes: Bytes,
 }
 impl BitfieldPayload {
+    /// the bits are padded with zeros to the next full byte
+    pub(crate) fn from_bools(bools: &[bool]) -> Self {
+        let bytes = bools.chunks(8).map(|byte| {
+            byte.iter()
+                .enumerate()
+                .fold(0_u8, |acc, (i, &b)| acc | (if b { 128_u8 >> i } else { 0 }))
+        });
+        Self {
+            bytes: Bytes::from_iter(bytes),
+        }
+    }
+
+    pub(crate) fn from_packed(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes: bytes.into(),
+        }
+    }
+
+    /// a bool for every bit, the padding included
+    pub(crate) fn to_bools(&self) -> Vec<bool> {
+        (0..self.bytes.len() * 8)
+            .map(|i| self.bytes[i / 8] & (0x80 >> (i % 8)) != 0)
+            .collect()
+    }
+
+    /// the size on the wire
+    pub(crate) fn byte_len(&self) -> usize {
+        self.bytes.len()
+    }
+
     pub(crate) fn is_empty(&self) -> bool {
-        self.pieces_available.iter().all(|b| !*b)
+        self.bytes.iter().all(|b| *b == 0)
     }
-    pub(crate) fn is_finished(&self) -> bool {
-        self.pieces_available.iter().all(|b| *b)
+
+    /// The pieces of a torrent with `n_pieces` pieces.
+    /// The bitfield has to have a bit per piece, padded with zeros to the next full byte.
+    pub(crate) fn checked(self, n_pieces: usize) -> Result<Vec<bool>, BitfieldError> {
+        let bytes = self.bytes.len();
+        let expected = n_pieces.div_ceil(8);
+        if bytes != expected {
+            return Err(BitfieldError::WrongLength { bytes, expected });
+        }
+        fit_pieces(self.to_bools(), n_pieces)
     }
 }
+
+#[derive(Debug, Error, PartialEq)]
+pub enum BitfieldError {
+    #[error("The bitfield has {bytes} bytes, the torrent needs {expected}")]
+    WrongLength { bytes: usize, expected: usize },
+    #[error("The peer claims to have the piece {0}, the torrent doesn't have it")]
+    NoSuchPiece(usize),
+}
+
+/// Cuts off the bits past the last piece, none of them may be set.
+/// What we learned before the metadata came is shorter if the peer only sent Haves, it stays so.
+fn fit_pieces(mut has: Vec<bool>, n_pieces: usize) -> Result<Vec<bool>, BitfieldError> {
+    if let Some(i) = has.iter().skip(n_pieces).position(|b| *b) {
+        return Err(BitfieldError::NoSuchPiece(n_pieces + i));
+    }
+    has.truncate(n_pieces);
+    Ok(has)
+}
 impl Payload for BitfieldPayload {
     fn from_be_bytes(payload: &[u8]) -> Self {
-        let mut pieces_available = vec![false; payload.len() * 8];
-        for (byte_i, byte) in payload.iter().enumerate() {
-            for i in 0..8 {
-                if 1u8.rotate_right(i + 1) & byte != 0 {
-                    pieces_available[byte_i * 8 + i as usize] = true;
-                }
-            }
+        Self {
+            bytes: Bytes::copy_from_slice(payload),
         }
-        Self { pieces_available }
     }
 
     fn to_be_bytes(&self) -> Bytes {
-        Bytes::from_iter(self.pieces_available.chunks(8).map(|byte| {
-            byte.iter()
-                .enumerate()
-                .fold(0_u8, |acc, (i, &b)| acc | (if b { 128_u8 >> i } else { 0 }))
-        }))
+        self.bytes.clone()
     }
 }
 
@@ -123,6 +170,23 @@ impl Payload for HavePayload {
     }
 }
 
+/// the port the sender's DHT node listens on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortPayload {
+    pub port: u16,
+}
+
+impl Payload for PortPayload {
+    fn from_be_bytes(payload: &[u8]) -> Self {
+        PortPayload {
+            port: u16::from_be_bytes([payload[0], payload[1]]),
+        }
+    }
+    fn to_be_bytes(&self) -> Bytes {
+        Bytes::from_owner(self.port.to_be_bytes())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct NoPayload;
 impl Payload for NoPayload {
@@ -140,16 +204,67 @@ mod tests {
 
     #[test]
     fn test_bitfield_payload() {
-        let payload = BitfieldPayload {
-            pieces_available: vec![true, false, true, false, true, false, true, false, true],
-        };
+        let pieces = [true, false, true, false, true, false, true, false, true];
+        let payload = BitfieldPayload::from_bools(&pieces);
         let bytes = payload.to_be_bytes();
+        assert_eq!(bytes[..], [0b1010_1010, 0b1000_0000]);
         let payload2 = BitfieldPayload::from_be_bytes(&bytes);
-        // The from_be_bytes function pads with 'false' values to the next full byte,
-        // so we need to do the same for the original payload to compare them.
-        let mut expected_pieces = payload.pieces_available.clone();
+        // The bits are padded with 'false' values to the next full byte,
+        // so we need to do the same for the original pieces to compare them.
+        let mut expected_pieces = pieces.to_vec();
         expected_pieces.resize(16, false);
-        assert_eq!(payload2.pieces_available, expected_pieces);
+        assert_eq!(payload2.to_bools(), expected_pieces);
+    }
+
+    fn bitfield(hex: &str) -> BitfieldPayload {
+        BitfieldPayload::from_be_bytes(&hex::decode(hex).unwrap())
+    }
+
+    #[test]
+    fn bitfields_of_other_clients() {
+        // a seeder of a torrent with 13 pieces, the last 3 bits pad the second byte
+        let seeder = bitfield("fff8").checked(13).unwrap();
+        assert_eq!(seeder, vec![true; 13]);
+        // a leecher missing the first and the last piece
+        let leecher = bitfield("7ff0").checked(13).unwrap();
+        assert!(!leecher[0] && leecher[1..12].iter().all(|b| *b) && !leecher[12]);
+        // a full byte of ones from a client that pads with ones
+        assert_eq!(
+            bitfield("ffff").checked(13),
+            Err(BitfieldError::NoSuchPiece(13))
+        );
+        // a client that sends the bitfield of another torrent
+        assert_eq!(
+            bitfield("ffffff").checked(13),
+            Err(BitfieldError::WrongLength {
+                bytes: 3,
+                expected: 2
+            })
+        );
+        assert_eq!(
+            bitfield("ff").checked(13),
+            Err(BitfieldError::WrongLength {
+                bytes: 1,
+                expected: 2
+            })
+        );
+        // a torrent whose piece count is a multiple of 8 has no padding
+        assert_eq!(bitfield("ff01").checked(16).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn fit_what_we_knew_before_the_metadata() {
+        // only Haves, the last one for the piece 4
+        let haves = vec![false, true, false, false, true];
+        assert_eq!(fit_pieces(haves.clone(), 13), Ok(haves));
+        assert_eq!(
+            fit_pieces(vec![false, false, true], 2),
+            Err(BitfieldError::NoSuchPiece(2))
+        );
+        assert_eq!(
+            fit_pieces(vec![true, false, false], 2),
+            Ok(vec![true, false])
+        );
     }
 
     #[test]
@@ -0,0 +1,64 @@
+//! Every place the client writes to is derived from here.
+//! ```text
+//! download_dir/<name>            the downloaded data
+//! state_dir/files/               the database
+//! state_dir/<info-hash>/         the wire trace of a single torrent, if it is recorded
+//! ```
+use std::path::{Path, PathBuf};
+
+use crate::torrent::InfoHash;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paths {
+    download_dir: PathBuf,
+    state_dir: PathBuf,
+}
+
+impl Paths {
+    pub fn new(download_dir: PathBuf, state_dir: PathBuf) -> Self {
+        Self {
+            download_dir,
+            state_dir,
+        }
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    /// the directory of the RocksDB database
+    pub fn db_dir(&self) -> PathBuf {
+        self.state_dir.join("files")
+    }
+
+    /// where the data of a torrent is written if the user didn't choose a path
+    pub fn data_file(&self, name: &str) -> PathBuf {
+        self.download_dir.join(name)
+    }
+
+    pub fn wire_trace_file(&self, info_hash: &InfoHash) -> PathBuf {
+        self.state_dir
+            .join(hex::encode(info_hash.0))
+            .join("wire-trace.jsonl")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let paths = Paths::new("downloads".into(), "state".into());
+        let info_hash = InfoHash([0xab; 20]);
+        assert_eq!(paths.db_dir(), PathBuf::from("state/files"));
+        assert_eq!(
+            paths.data_file("sample.txt"),
+            PathBuf::from("downloads/sample.txt")
+        );
+        assert_eq!(
+            paths.wire_trace_file(&info_hash),
+            PathBuf::from(format!("state/{}/wire-trace.jsonl", "ab".repeat(20)))
+        );
+    }
+}
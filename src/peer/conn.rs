@@ -1,9 +1,9 @@
 use std::collections::HashMap;
 use std::mem;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::OnceLock;
 use std::sync::atomic::AtomicBool;
 use std::time::Duration;
 
@@ -20,46 +20,160 @@ use tokio::sync::mpsc::Sender;
 use tokio_util::codec::Framed;
 use tokio_util::time::FutureExt;
 
+use crate::config::{Encryption, SocketOptions};
+use crate::dht::Dht;
 use crate::extensions::ExtensionHandler;
+use crate::extensions::protocol_extension_handshake::AdditionalHandshakeInfo;
+use crate::messages::client_identifier::PeerClient;
 use crate::messages::{MessageFramer, PeerMessage};
 use crate::peer::Msg;
 use crate::peer::Peer;
-use crate::peer::error::PeerError;
+use crate::peer::error::{PeerError, Stage};
 use crate::peer::initial_handshake::Handshake;
+use crate::peer::mse::{self, PeerStream};
+use crate::peer::rate_limit::PeerRateLimiter;
+use crate::peer::trace::WireTrace;
+use crate::peer::{EXTENSION_HANDSHAKE_TIMEOUT, KEEP_ALIVE_CHECK};
 use crate::peer_manager::PeerConn;
+use crate::peer_manager::PieceSet;
 use crate::peer_manager::ReqMessage;
 use crate::peer_manager::ReqMsgFromPeer;
 use crate::peer_manager::ResMessage;
 use crate::torrent::InfoHash;
 
+use crate::sync::Mutex;
+
 impl Peer {
     pub async fn connect_from_addr(
-        addr: SocketAddrV4,
+        addr: SocketAddr,
         info_hash: InfoHash,
         peer_id: [u8; 20],
         peer_manager_tx: Sender<ReqMsgFromPeer>,
+        options: &SocketOptions,
+        dht: Option<&Dht>,
     ) -> Result<Self, PeerError> {
         // set up tcp connection & shake hands
-        let tcp = tokio::net::TcpStream::connect(addr)
+        let connect = || async {
+            options
+                .connect(addr)
+                .timeout(options.connect_timeout())
+                .await
+                .map_err(|_| PeerError::Timeout(Stage::Connect))?
+                .map_err(|error| PeerError::FailedToConnect { error, addr })
+        };
+        let stream = match mse::initiate(connect().await?, info_hash, options.encryption).await {
+            Ok(stream) => stream,
+            // a peer that doesn't know the encryption hangs up or waits for a plaintext handshake
+            Err(_) if options.encryption == Encryption::PreferEncrypted => {
+                PeerStream::plain(connect().await?)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Peer::from_stream(
+            stream,
+            info_hash,
+            peer_id,
+            peer_manager_tx,
+            true,
+            options,
+            dht,
+        )
+        .await
+    }
+
+    /// Leaves out the extensions a private torrent must not use, i.e. PEX.
+    pub(crate) fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// The port we listen on and the size of the metadata if we have it, for the extension
+    /// handshake.
+    pub(crate) fn with_handshake_info(
+        mut self,
+        listen_port: u16,
+        metadata_size: Option<usize>,
+    ) -> Self {
+        self.handshake_info = AdditionalHandshakeInfo::ours(Some(listen_port), metadata_size);
+        self
+    }
+
+    /// For connections the peer opened, `info_hashes` are the torrents it may ask for.
+    /// Returns its handshake without answering it, so we can pick the torrent it asked for, see
+    /// [`Peer::from_accepted`].
+    pub(crate) async fn accept_handshake(
+        tcp: TcpStream,
+        info_hashes: &[InfoHash],
+        options: &SocketOptions,
+    ) -> Result<(PeerStream, Handshake), PeerError> {
+        // the connection works without them, only slower
+        if let Err(e) = options.apply(&tcp) {
+            eprintln!("Failed to set the socket options of an incoming connection: {e}");
+        }
+        let mut stream = mse::respond(tcp, info_hashes, options.encryption).await?;
+        let handshake_recv = Handshake::receive(&mut stream)
+            .timeout(options.handshake_timeout())
             .await
-            .map_err(|error| PeerError::FailedToConnect { error, addr })?;
+            .map_err(|_| PeerError::Timeout(Stage::Handshake))??;
+        Ok((stream, handshake_recv))
+    }
 
-        Peer::connect_from_stream(tcp, info_hash, peer_id, peer_manager_tx).await
+    /// answers the handshake of a peer that connected to us for one of our torrents
+    pub(crate) async fn from_accepted(
+        mut tcp: PeerStream,
+        handshake_recv: Handshake,
+        peer_id: [u8; 20],
+        peer_manager_tx: Sender<ReqMsgFromPeer>,
+        options: &SocketOptions,
+        dht: Option<&Dht>,
+    ) -> Result<Self, PeerError> {
+        Handshake::new(InfoHash(handshake_recv.info_hash), peer_id)
+            .with_dht(dht.is_some())
+            .send(&mut tcp)
+            .await?;
+        Peer::from_handshake(tcp, handshake_recv, peer_manager_tx, false, options, dht).await
     }
 
-    pub async fn connect_from_stream(
-        mut tcp: TcpStream,
+    async fn from_stream(
+        mut tcp: PeerStream,
         info_hash: InfoHash,
         peer_id: [u8; 20],
         peer_manager_tx: Sender<ReqMsgFromPeer>,
+        outgoing: bool,
+        options: &SocketOptions,
+        dht: Option<&Dht>,
     ) -> Result<Self, PeerError> {
-        // let _ = Handshake::new(info_hash, peer_id).has_extensions_enabled();
         let handshake_recv = Handshake::new(info_hash, peer_id)
+            .with_dht(dht.is_some())
             .shake_hands(&mut tcp)
-            .await?;
-        println!("peer {} connected", tcp.peer_addr().unwrap());
+            .timeout(options.handshake_timeout())
+            .await
+            .map_err(|_| PeerError::Timeout(Stage::Handshake))??;
+        Peer::from_handshake(tcp, handshake_recv, peer_manager_tx, outgoing, options, dht).await
+    }
 
-        let peer_state = PeerState::new(handshake_recv);
+    async fn from_handshake(
+        tcp: PeerStream,
+        handshake_recv: Handshake,
+        peer_manager_tx: Sender<ReqMsgFromPeer>,
+        outgoing: bool,
+        options: &SocketOptions,
+        dht: Option<&Dht>,
+    ) -> Result<Self, PeerError> {
+        let addr = tcp.peer_addr().unwrap();
+        let encrypted = if tcp.is_encrypted() {
+            ", encrypted"
+        } else {
+            ""
+        };
+        let peer_state = PeerState::new(handshake_recv, addr, outgoing);
+        let client = peer_state
+            .0
+            .client
+            .as_ref()
+            .map_or_else(String::new, |client| format!(" ({client})"));
+        println!("peer {addr}{client} connected{encrypted}");
 
         // after the handshake as succeeded we can create the message framer that de- & encodes the messages
         // from the tcp stream
@@ -68,7 +182,8 @@ impl Peer {
         // set up peer_manager connection
         let peer_manager_rx = peer_state.connect_to_peer_manager(&peer_manager_tx).await?;
         let (peer_writer, peer_reader) = framed.split();
-        let receiver_stream = Some(get_stream(peer_reader, peer_manager_rx).await);
+        let receiver_stream =
+            Some(get_stream(peer_reader, peer_manager_rx, options.bitfield_timeout()).await);
 
         Ok(Self {
             state: peer_state,
@@ -76,6 +191,12 @@ impl Peer {
             peer_manager_tx,
             peer_writer,
             receiver_stream,
+            got_extension_handshake: false,
+            seeding: false,
+            last_write: std::time::Instant::now(),
+            private: false,
+            handshake_info: AdditionalHandshakeInfo::ours(None, None),
+            dht: dht.cloned(),
         })
     }
 }
@@ -83,11 +204,19 @@ impl Peer {
 async fn get_stream(
     framed_rx: PeerReader,
     peer_manager_rx: Receiver<ResMessage>,
+    bitfield_timeout: Duration,
 ) -> BoxedMsgStream {
-    let peer_msg_stream = unfold(framed_rx, |mut framed| async move {
-        match framed.next().timeout(Duration::from_secs(120)).await {
-            Ok(Some(Ok(message))) => Some((Msg::Data(message), framed)),
-            Err(_) => Some((Msg::Timeout, framed)),
+    // the first message gets its own timeout, a peer that never sends one isn't worth waiting for
+    let peer_msg_stream = unfold((framed_rx, true), move |(mut framed, first)| async move {
+        let timeout = if first {
+            bitfield_timeout
+        } else {
+            Duration::from_secs(120)
+        };
+        match framed.next().timeout(timeout).await {
+            Ok(Some(Ok(message))) => Some((Msg::Data(message), (framed, false))),
+            Err(_) if first => Some((Msg::BitfieldTimeout, (framed, false))),
+            Err(_) => Some((Msg::Timeout, (framed, false))),
             Ok(None) => {
                 // nothing really happens here
                 None
@@ -104,7 +233,27 @@ async fn get_stream(
         Some((Msg::Manager(msg), rx))
     });
 
-    let stream = futures_util::stream::select(peer_msg_stream, manager_stream);
+    // fires once to check whether the peer has sent its extension handshake in time
+    let extension_timeout_stream = futures_util::stream::once(async {
+        tokio::time::sleep(EXTENSION_HANDSHAKE_TIMEOUT).await;
+        Msg::ExtensionHandshakeTimeout
+    });
+
+    let keep_alive_stream = unfold(
+        tokio::time::interval_at(
+            tokio::time::Instant::now() + KEEP_ALIVE_CHECK,
+            KEEP_ALIVE_CHECK,
+        ),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((Msg::KeepAliveCheck, interval))
+        },
+    );
+
+    let stream = futures_util::stream::select(
+        futures_util::stream::select(peer_msg_stream, manager_stream),
+        futures_util::stream::select(extension_timeout_stream, keep_alive_stream),
+    );
     Box::pin(stream)
 }
 
@@ -132,20 +281,36 @@ pub(crate) struct PeerState(pub(crate) Arc<PeerStateInner>);
 pub(crate) struct PeerStateInner {
     /// the peer_id of the remote peer
     pub(crate) peer_id: [u8; 20],
+    /// the other end of the connection
+    pub(crate) addr: SocketAddr,
+    /// whether we dialed the peer, then `addr` is where it accepts connections
+    outgoing: bool,
+    /// the `p` of the extension handshake
+    pub(crate) listen_port: OnceLock<u16>,
+    /// the `reqq` of the extension handshake, how many requests the peer takes at once
+    pub(crate) reqq: OnceLock<usize>,
+    /// the DHT bit of its handshake, it takes our Port message
+    pub(crate) dht: bool,
+    /// what its peer id says it runs, None if we don't know the client
+    pub(crate) client: Option<PeerClient>,
     // dk if I need this at all
     // pub state: Arc<Mutex<super::PeerState>>,
     pub(crate) am_choking: AtomicBool,
     pub(crate) am_interested: AtomicBool,
     pub(crate) peer_choking: AtomicBool,
     pub(crate) peer_interested: AtomicBool,
-    /// the bitfield of the other peer
-    pub(crate) has: Mutex<Vec<bool>>,
+    /// the pieces of the other peer, packed since there is one per connection
+    pub(crate) has: Mutex<PieceSet>,
     /// maps extended message ID to names of extensions
     pub(crate) extensions: Mutex<Option<HashMap<u8, Box<dyn ExtensionHandler>>>>,
+    /// set by the PeerManager when it accepts the connection
+    pub(crate) wire_trace: OnceLock<WireTrace>,
+    /// unlimited until the PeerManager sets a limit
+    pub(crate) rate_limiter: PeerRateLimiter,
 }
 
 impl PeerState {
-    pub(crate) fn new(handshake: Handshake) -> Self {
+    pub(crate) fn new(handshake: Handshake, addr: SocketAddr, outgoing: bool) -> Self {
         let extensions = if handshake.has_extensions_enabled() {
             Some(HashMap::new())
         } else {
@@ -153,16 +318,38 @@ impl PeerState {
         };
         let peer_identifier_inner = PeerStateInner {
             peer_id: handshake.peer_id,
+            addr,
+            outgoing,
+            listen_port: OnceLock::new(),
+            reqq: OnceLock::new(),
+            dht: handshake.has_dht(),
+            client: PeerClient::from_peer_id(&handshake.peer_id),
             am_choking: AtomicBool::new(true),
             am_interested: AtomicBool::new(false),
             peer_choking: AtomicBool::new(true),
             peer_interested: AtomicBool::new(false),
-            has: Mutex::new(Vec::new()),
+            has: Mutex::new(PieceSet::default()),
             extensions: Mutex::new(extensions),
+            wire_trace: OnceLock::new(),
+            rate_limiter: PeerRateLimiter::default(),
         };
         Self(Arc::new(peer_identifier_inner))
     }
 
+    /// where other peers can reach the peer, None if it connected to us and didn't tell its port
+    pub(crate) fn listen_addr(&self) -> Option<SocketAddr> {
+        if self.0.outgoing {
+            return Some(self.0.addr);
+        }
+        let port = self.0.listen_port.get()?;
+        Some(SocketAddr::new(self.0.addr.ip(), *port))
+    }
+
+    /// whether we dialed the peer
+    pub(crate) fn is_outgoing(&self) -> bool {
+        self.0.outgoing
+    }
+
     async fn connect_to_peer_manager(
         &self,
         peer_manager_tx: &Sender<ReqMsgFromPeer>,
@@ -184,8 +371,8 @@ impl PeerState {
 }
 
 pub(super) type BoxedMsgStream = Pin<Box<dyn Stream<Item = Msg> + Send + Sync>>;
-pub(super) type PeerWriter = SplitSink<Framed<TcpStream, MessageFramer>, PeerMessage>;
-type PeerReader = SplitStream<Framed<TcpStream, MessageFramer>>;
+pub(super) type PeerWriter = SplitSink<Framed<PeerStream, MessageFramer>, PeerMessage>;
+type PeerReader = SplitStream<Framed<PeerStream, MessageFramer>>;
 
 impl Drop for Peer {
     fn drop(&mut self) {
@@ -201,3 +388,23 @@ impl Drop for Peer {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let options = SocketOptions {
+            handshake_timeout_secs: 1,
+            ..Default::default()
+        };
+        // the OS accepts the connection, nobody answers the handshake
+        let listener = options.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let peer =
+            Peer::connect_from_addr(addr, InfoHash([0; 20]), [1; 20], tx, &options, None).await;
+        assert!(matches!(peer, Err(PeerError::Timeout(Stage::Handshake))));
+    }
+}
@@ -1,9 +1,12 @@
-use std::{io, mem::Discriminant, net::SocketAddrV4};
+use std::{fmt, io, mem::Discriminant, net::SocketAddr};
 
 use thiserror::Error;
 use tokio::sync::mpsc;
 
-use crate::peer_manager::{ReqMessage, ReqMsgFromPeer};
+use crate::{
+    peer::mse::MseError,
+    peer_manager::{ReqMessage, ReqMsgFromPeer},
+};
 
 #[derive(Error, Debug)]
 pub enum PeerError {
@@ -26,10 +29,11 @@ pub enum PeerError {
     #[error("The peer unexpectedly disconnected.")]
     PeerDisconnected,
     #[error("Failed to establish a tcp connection to the address `{addr}` with error: `{error:?}`")]
-    FailedToConnect {
-        error: io::Error,
-        addr: SocketAddrV4,
-    },
+    FailedToConnect { error: io::Error, addr: SocketAddr },
+    #[error("The peer took too long to {0}.")]
+    Timeout(Stage),
+    #[error(transparent)]
+    Encryption(#[from] MseError),
     #[error(
         "Failed to read the bytes from the remote peer needed for the handshake with the error: `{0}`."
     )]
@@ -41,3 +45,21 @@ pub enum PeerError {
     #[error("Failed to de- or encode the message from/to the peer with the error: `{0}`")]
     BenCoding(#[from] serde_bencode::Error),
 }
+
+/// how far the connection got before the peer went quiet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Connect,
+    Handshake,
+    Bitfield,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Connect => "accept the connection",
+            Stage::Handshake => "shake hands",
+            Stage::Bitfield => "send its bitfield",
+        })
+    }
+}
@@ -2,25 +2,27 @@ use futures_util::StreamExt;
 use std::{mem, sync::atomic::Ordering};
 
 use crate::{
-    extensions::BasicExtensionPayload,
+    extensions::{BasicExtensionPayload, ExtensionType},
     messages::{
         PeerMessage,
         payloads::{HavePayload, NoPayload},
     },
-    peer::{Msg, Peer, error::PeerError},
+    peer::{
+        Msg, Peer,
+        error::{PeerError, Stage},
+        trace::Direction,
+    },
     peer_manager::{ReqMessage, ResMessage},
 };
 
 impl Peer {
     pub async fn run(mut self) -> Result<(), PeerError> {
-        // TODO: do choking
-        self.state.0.am_choking.store(false, Ordering::Relaxed);
-
         let mut receiver_stream = mem::take(&mut self.receiver_stream)
             .expect("The receiver stream is initialized after creation of the peer.");
 
         // for the inital handshake, look in conn.rs
         self.send_extended_handshake().await?;
+        self.send_dht_port().await?;
 
         // this message is essentially which kick-starts the loop
         self.send_peer_manager(ReqMessage::WhatDoWeHave).await?;
@@ -30,20 +32,24 @@ impl Peer {
                 } else if let Msg::Data(PeerMessage::Extended(ref p)) = message
                     && p.extension_id == 2
                 {
-                } else {
+                } else if message != Msg::KeepAliveCheck {
                     println!("INCOMING: {message:?}");
                 } /*else if let Msg::Manager(ResMessage::NewBlockQueue(_)) = message {
                 dbg!(&message);
                 }*/
+                if let Msg::Data(ref message) = message {
+                    self.trace(Direction::In, message);
+                }
                 match message {
                     Msg::Manager(peer_msg) => match peer_msg {
                         ResMessage::FinishedFile => {
                             if !self.state.0.peer_interested.load(Ordering::Relaxed) {
                                 break Ok(());
                             }
+                            self.start_seeding();
                         }
                         ResMessage::FinishedPiece(piece_index) => {
-                            // later TODO: implement have suppression
+                            // only peers that lack the piece get this, see `broadcast_have`
                             let have_payload = HavePayload { piece_index };
                             self.send_peer(PeerMessage::Have(have_payload)).await?;
                         }
@@ -55,31 +61,36 @@ impl Peer {
                             self.queue
                                 .to_send
                                 .extend_from_slice(&req_piece_payload_msgs);
-
-                            // TODO: keep interested state up-to-date
-                            // if self.queue.to_send.is_empty() {
-                            //     self.set_interested(false).await?;
-                            // } else {
-                            //     self.set_interested(true).await?;
-                            // };
                         }
                         ResMessage::Block(response_piece_payload) => {
                             if let Some(payload) = response_piece_payload {
+                                let len = payload.block.len() as u64;
+                                self.state.0.rate_limiter.upload(len).await;
                                 self.send_peer(PeerMessage::Piece(payload)).await?;
                             }
                             // if we don't have the piece, Ig we just ignore
                         }
                         ResMessage::WeHave(bitfield) => {
-                            // later TODO: implement lazy bitfield?
-                            if bitfield.is_finished() {
-                                self.set_interested(false).await?;
-                            } else {
-                                self.set_interested(true).await?;
-                                if !bitfield.is_empty() {
-                                    self.send_peer(PeerMessage::Bitfield(bitfield)).await?;
-                                }
+                            if !bitfield.is_empty() {
+                                self.send_peer(PeerMessage::Bitfield(bitfield)).await?;
                             }
                         }
+                        ResMessage::WeSeed(bitfield) => {
+                            self.start_seeding();
+                            self.send_peer(PeerMessage::Bitfield(bitfield)).await?;
+                        }
+                        ResMessage::Interested(interested) => {
+                            self.set_interested(interested).await?;
+                        }
+                        ResMessage::Disconnect => break Ok(()),
+                        ResMessage::Choke => {
+                            self.state.0.am_choking.store(true, Ordering::Relaxed);
+                            self.send_peer(PeerMessage::Choke(NoPayload)).await?;
+                        }
+                        ResMessage::Unchoke => {
+                            self.state.0.am_choking.store(false, Ordering::Relaxed);
+                            self.send_peer(PeerMessage::Unchoke(NoPayload)).await?;
+                        }
                         ResMessage::ExtensionData((ext_type, data)) => {
                             let msg = {
                                 let extensions = self.state.0.extensions.lock().unwrap();
@@ -96,11 +107,18 @@ impl Peer {
                                     None
                                 }
                             };
-                            if let Some(msg) = msg {
-                                self.queue.to_send.push(msg);
+                            match msg {
+                                // metadata requests count towards the requests in flight
+                                Some(msg) if ext_type == ExtensionType::Metadata => {
+                                    self.queue.to_send.push(msg)
+                                }
+                                Some(msg) => self.send_peer(msg).await?,
+                                None => {}
                             }
                         }
                         ResMessage::StartDownload => {
+                            // e.g. a recheck found pieces missing
+                            self.seeding = false;
                             self.send_peer_manager(ReqMessage::NeedBlockQueue).await?;
                         }
                     },
@@ -119,11 +137,12 @@ impl Peer {
                             self.state.0.peer_interested.store(false, Ordering::Relaxed);
                         }
                         PeerMessage::Have(have_payload) => {
-                            self.state.0.has.lock().unwrap()[have_payload.piece_index as usize] =
-                                true;
+                            self.send_peer_manager(ReqMessage::PeerHas(have_payload.piece_index))
+                                .await?;
                         }
                         PeerMessage::Bitfield(bitfield_payload) => {
-                            *self.state.0.has.lock().unwrap() = bitfield_payload.pieces_available;
+                            self.send_peer_manager(ReqMessage::PeerBitfield(bitfield_payload))
+                                .await?;
                         }
                         PeerMessage::Request(request_piece_payload) => {
                             self.send_peer_manager(ReqMessage::NeedBlock(request_piece_payload))
@@ -135,10 +154,16 @@ impl Peer {
                                 response_piece_payload.begin, response_piece_payload.index
                             );
                             self.queue.have_sent -= 1;
+                            let len = response_piece_payload.block.len() as u64;
+                            self.state.0.rate_limiter.download(len).await;
                             self.send_peer_manager(ReqMessage::GotBlock(response_piece_payload))
                                 .await?;
                         }
-                        PeerMessage::Cancel(_request_piece_payload) => todo!(),
+                        PeerMessage::Cancel(request_piece_payload) => {
+                            self.send_peer_manager(ReqMessage::CancelBlock(request_piece_payload))
+                                .await?;
+                        }
+                        PeerMessage::Port(port_payload) => self.ping_dht_node(port_payload.port),
                         PeerMessage::KeepAlive(_no_payload) => {
                             eprintln!("he sent a keep alive")
                         }
@@ -149,10 +174,16 @@ impl Peer {
                     Msg::Timeout => {
                         self.send_peer(PeerMessage::KeepAlive(NoPayload)).await?;
                     }
+                    Msg::ExtensionHandshakeTimeout => {
+                        self.on_extension_handshake_timeout().await?;
+                    }
+                    Msg::BitfieldTimeout => break Err(PeerError::Timeout(Stage::Bitfield)),
+                    Msg::KeepAliveCheck => self.keep_alive().await?,
                 }
 
                 // request next blocks
-                if self.queue.have_sent == 0
+                if !self.seeding
+                    && self.queue.have_sent == 0
                     && self.state.0.am_interested.load(Ordering::Relaxed)
                     && !self.state.0.peer_choking.load(Ordering::Relaxed)
                 {
@@ -9,14 +9,21 @@ use crate::{
         protocol_extension_handshake::HandshakeExtension,
     },
     messages::PeerMessage,
-    peer::error::PeerError,
+    peer::{conn::PeerState, error::PeerError},
     peer_manager::ReqMessage,
 };
 
 impl Peer {
     pub(super) async fn send_extended_handshake(&mut self) -> Result<(), PeerError> {
         if self.state.0.extensions.lock().unwrap().is_some() {
-            let handshake_extension = HandshakeExtension::new();
+            let mut info = self.handshake_info.clone();
+            info.yourip = Some(self.state.0.addr.ip().into());
+            let mut handshake_extension = HandshakeExtension::new(info);
+            if self.private {
+                handshake_extension
+                    .m
+                    .remove(&ExtensionType::Pex.to_string());
+            }
             dbg!(&handshake_extension);
             self.send_peer(PeerMessage::Extended(BasicExtensionPayload {
                 extension_id: 0,
@@ -36,7 +43,8 @@ impl Peer {
 
             if let Some(extensions) = maybe_extensions {
                 if payload.extension_id == ExtensionType::Handshake as u8 {
-                    update_extensions(extensions, payload)?
+                    self.got_extension_handshake = true;
+                    update_extensions(extensions, payload, &self.state)?
                 } else if let Some(ext_type) =
                     ACTIVE_EXTENSIONS.get(payload.extension_id as usize - 1)
                     && let Some(extension) = extensions
@@ -59,16 +67,33 @@ impl Peer {
         Ok(())
     }
 
+    /// If the peer announced the extension protocol but never sent the extension handshake,
+    /// we stop waiting for it and use the plain protocol.
+    pub(super) async fn on_extension_handshake_timeout(&mut self) -> Result<(), PeerError> {
+        if self.got_extension_handshake {
+            return Ok(());
+        }
+        let downgraded = self.state.0.extensions.lock().unwrap().take().is_some();
+        if downgraded {
+            eprintln!(
+                "peer didn't send the extension handshake, downgrading to the plain protocol"
+            );
+            self.send_peer_manager(ReqMessage::ExtensionsDowngraded)
+                .await?;
+        }
+        Ok(())
+    }
+
     async fn handle_action(&mut self, action: ExtensionAction) -> Result<(), PeerError> {
         match action {
             ExtensionAction::SendPeer(peer_message) => self.send_peer(peer_message).await,
             ExtensionAction::SendPeerManager(msg) => {
                 // TODO: I update the self.queue.have_sent depending whether I received a real piece or a metadata piece on two different locations.
                 // maybe put the queue inside a Mutex aswell and let the PeerManager update it
-                if let ReqMessage::Extension(ExtensionMessage::ReceivedMetadataPiece {
-                    piece_index: _,
-                    data: _,
-                }) = msg
+                if let ReqMessage::Extension(
+                    ExtensionMessage::ReceivedMetadataPiece { .. }
+                    | ExtensionMessage::RejectedMetadataPiece,
+                ) = msg
                 {
                     self.queue.have_sent -= 1;
                 }
@@ -89,10 +114,31 @@ impl Peer {
 fn update_extensions(
     extensions: &mut HashMap<u8, Box<dyn ExtensionHandler>>,
     payload: BasicExtensionPayload,
+    state: &PeerState,
 ) -> Result<Vec<ExtensionAction>, PeerError> {
     let handshake = serde_bencode::from_bytes::<HandshakeExtension>(&payload.data)?;
     dbg!(&handshake);
     let mut actions = Vec::new();
+    if let Some(port) = handshake.other.p
+        && state.0.listen_port.set(port).is_ok()
+    {
+        actions.push(ExtensionAction::SendPeerManager(ReqMessage::ListenPort(
+            port,
+        )));
+    }
+    if let Some(reqq) = handshake.other.reqq {
+        let _ = state.0.reqq.set(reqq);
+    }
+    if let Some(ip) = handshake.other.yourip {
+        actions.push(ExtensionAction::SendPeerManager(ReqMessage::ExternalIp(
+            ip.into(),
+        )));
+    }
+    if let Some(version) = &handshake.other.v {
+        actions.push(ExtensionAction::SendPeerManager(ReqMessage::ClientVersion(
+            version.clone(),
+        )));
+    }
     for (msg_type, msg_id) in handshake.m {
         if msg_id == 0 {
             extensions.remove(&msg_id);
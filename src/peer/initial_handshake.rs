@@ -1,4 +1,4 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{peer::error::PeerError, torrent::InfoHash};
 
@@ -12,6 +12,12 @@ pub struct Handshake {
 }
 const HANDSHAKE_LEN: usize = std::mem::size_of::<Handshake>();
 
+fn config() -> impl bincode::config::Config {
+    bincode::config::standard()
+        .with_big_endian()
+        .with_limit::<HANDSHAKE_LEN>()
+}
+
 impl Handshake {
     pub fn new(info_hash: InfoHash, peer_id: [u8; 20]) -> Self {
         let mut reserved = [0_u8; 8];
@@ -29,39 +35,51 @@ impl Handshake {
         }
     }
 
+    /// sets the bit that tells the peer we run a DHT node, BEP 5
+    pub fn with_dht(mut self, dht: bool) -> Self {
+        if dht {
+            self.reserved[7] |= 0x01;
+        }
+        self
+    }
+
     /// Initializes the handshake by writing the handshake to the tcp stream
     /// and returning the handshake received from the tcp stream
     pub async fn shake_hands(
         self,
-        tcp: &mut tokio::net::TcpStream,
+        tcp: &mut (impl AsyncRead + AsyncWrite + Unpin),
     ) -> Result<Handshake, PeerError> {
-        let config = bincode::config::standard()
-            .with_big_endian()
-            .with_limit::<HANDSHAKE_LEN>();
-        // write handshake
+        self.send(tcp).await?;
+        let handshake_recv = Handshake::receive(tcp).await?;
+        assert_eq!(handshake_recv.info_hash, self.info_hash);
+        Ok(handshake_recv)
+    }
+
+    pub async fn send(self, tcp: &mut (impl AsyncWrite + Unpin)) -> Result<(), PeerError> {
         let mut handshake_bytes = [0_u8; HANDSHAKE_LEN];
-        bincode::encode_into_slice(self, &mut handshake_bytes, config)?;
+        bincode::encode_into_slice(self, &mut handshake_bytes, config())?;
         tcp.write_all(&handshake_bytes)
             .await
             .map_err(|error| PeerError::SendToPeer {
                 error,
                 peer_id: self.peer_id,
                 msg_type_str: "Handshake".to_string(),
-            })?;
+            })
+    }
 
-        // read handshake
-        handshake_bytes = [0_u8; HANDSHAKE_LEN];
+    /// the handshake of the peer, for any torrent
+    pub async fn receive(tcp: &mut (impl AsyncRead + Unpin)) -> Result<Handshake, PeerError> {
+        let mut handshake_bytes = [0_u8; HANDSHAKE_LEN];
         tcp.read_exact(&mut handshake_bytes)
             .await
             .map_err(PeerError::RecvHandshake)?;
 
         let (handshake_recv, len) =
-            bincode::decode_from_slice::<Handshake, _>(&handshake_bytes, config)?;
+            bincode::decode_from_slice::<Handshake, _>(&handshake_bytes, config())?;
 
         assert_eq!(len, HANDSHAKE_LEN);
         assert_eq!(handshake_recv.length, 19);
         assert_eq!(handshake_recv.protocol, *b"BitTorrent protocol");
-        assert_eq!(handshake_recv.info_hash, self.info_hash);
         Ok(handshake_recv)
     }
 
@@ -69,4 +87,9 @@ impl Handshake {
         let extension_bit = self.reserved[5] & 0x10;
         extension_bit == 0x10
     }
+
+    /// whether the peer runs a DHT node and takes a Port message
+    pub fn has_dht(&self) -> bool {
+        self.reserved[7] & 0x01 == 0x01
+    }
 }
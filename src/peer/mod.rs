@@ -1,21 +1,40 @@
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
 use futures_util::{self, SinkExt};
 use tokio::sync::mpsc;
 
+use crate::dht::Dht;
+use crate::extensions::protocol_extension_handshake::AdditionalHandshakeInfo;
 use crate::messages::PeerMessage;
-use crate::messages::payloads::NoPayload;
+use crate::messages::client_identifier::PeerClient;
+use crate::messages::payloads::{NoPayload, PortPayload};
 use crate::peer::conn::PeerWriter;
 use crate::peer::conn::send_peer_manager;
 use crate::peer::conn::{BoxedMsgStream, PeerState};
 use crate::peer::error::PeerError;
+use crate::peer::trace::Direction;
 use crate::peer_manager::{ReqMessage, ReqMsgFromPeer, ResMessage};
 
 pub mod conn;
-mod error;
+pub(crate) mod error;
 mod event_loop;
 mod extensions;
 pub mod initial_handshake;
+pub(crate) mod mse;
+pub(crate) mod rate_limit;
+pub(crate) mod socket;
+pub(crate) mod trace;
+
+/// If a peer announces the extension protocol but doesn't send the extension handshake
+/// within this time, we treat it like a peer without extensions.
+const EXTENSION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Peers drop connections that were silent for two minutes,
+/// so we send a keep-alive when we didn't write anything for this long.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// how often we look whether a keep-alive is due
+const KEEP_ALIVE_CHECK: Duration = Duration::from_secs(15);
 
 /// this enum is used to select between different stream-types a peer can receive
 #[derive(Debug, PartialEq)]
@@ -24,6 +43,12 @@ pub enum Msg {
     Manager(ResMessage),
     Data(PeerMessage),
     Timeout,
+    /// the time for the peer to send the extension handshake is up
+    ExtensionHandshakeTimeout,
+    /// the peer sent nothing after the handshake, not even its bitfield
+    BitfieldTimeout,
+    /// time to look whether we have to send a keep-alive
+    KeepAliveCheck,
 }
 pub struct Peer {
     pub(crate) state: PeerState,
@@ -32,6 +57,18 @@ pub struct Peer {
     peer_writer: PeerWriter,
     // this is an Option because the event-loop takes the Stream and leaves a None in its place while running
     receiver_stream: Option<BoxedMsgStream>,
+    /// whether the peer has sent us its extension handshake
+    got_extension_handshake: bool,
+    /// see [`Peer::with_private`]
+    private: bool,
+    /// what our extension handshake says about us, see [`Peer::with_handshake_info`]
+    handshake_info: AdditionalHandshakeInfo,
+    /// our DHT node, None for private torrents or without a DHT
+    dht: Option<Dht>,
+    /// we have every piece, so we only serve the peer and never request from it
+    seeding: bool,
+    /// when we last wrote to the peer, see [`KEEP_ALIVE_INTERVAL`]
+    last_write: Instant,
 }
 struct ReqQueue {
     to_send: Vec<PeerMessage>,
@@ -42,6 +79,14 @@ impl Peer {
     pub fn get_id(&self) -> [u8; 20] {
         self.state.0.peer_id
     }
+    /// the client and version its peer id names, e.g. `qBittorrent 4.6.5`
+    pub fn client(&self) -> Option<String> {
+        self.state.0.client.as_ref().map(ToString::to_string)
+    }
+    /// [`Peer::client`] of a peer we aren't connected to, e.g. from a tracker
+    pub fn client_of(peer_id: &[u8; 20]) -> Option<String> {
+        PeerClient::from_peer_id(peer_id).map(|client| client.to_string())
+    }
     async fn send_peer_manager(&self, msg: ReqMessage) -> Result<(), PeerError> {
         let peer_id = self.get_id();
         let msg = ReqMsgFromPeer { peer_id, msg };
@@ -53,6 +98,7 @@ impl Peer {
             .map(|msg_type| format!("{msg_type:?}"))
             .unwrap_or("KeepAlive".to_string());
         println!("OUTGOING: {msg:?}");
+        self.trace(Direction::Out, &msg);
         self.peer_writer
             .send(msg)
             .await
@@ -60,7 +106,51 @@ impl Peer {
                 error,
                 peer_id: self.get_id(),
                 msg_type_str,
-            })
+            })?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// a keep-alive if we were silent for too long, the messages we send anyway keep the
+    /// connection alive as well
+    async fn keep_alive(&mut self) -> Result<(), PeerError> {
+        if keep_alive_due(self.last_write, Instant::now()) {
+            self.send_peer(PeerMessage::KeepAlive(NoPayload)).await?;
+        }
+        Ok(())
+    }
+
+    /// tells a peer that runs a DHT node where ours listens, BEP 5
+    async fn send_dht_port(&mut self) -> Result<(), PeerError> {
+        if !self.state.0.dht {
+            return Ok(());
+        }
+        match self.dht.as_ref().and_then(Dht::port) {
+            Some(port) => {
+                self.send_peer(PeerMessage::Port(PortPayload { port }))
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// the node of the peer joins our routing table if it answers
+    fn ping_dht_node(&self, port: u16) {
+        let Some(dht) = self.dht.clone() else {
+            return;
+        };
+        let addr = SocketAddr::new(self.state.0.addr.ip().to_canonical(), port);
+        tokio::spawn(async move {
+            if let Err(e) = dht.ping(addr).await {
+                eprintln!("The DHT node {addr} of a peer didn't answer: {e}");
+            }
+        });
+    }
+
+    /// from now on we only serve the peer, the requests we meant to send are of no use
+    fn start_seeding(&mut self) {
+        self.seeding = true;
+        self.queue.to_send.clear();
     }
 
     /// this sets our interested flag and sends the message to the peer
@@ -87,6 +177,10 @@ impl Peer {
     }
 }
 
+fn keep_alive_due(last_write: Instant, now: Instant) -> bool {
+    now.saturating_duration_since(last_write) >= KEEP_ALIVE_INTERVAL
+}
+
 impl ReqQueue {
     fn new() -> Self {
         ReqQueue {
@@ -95,3 +189,17 @@ impl ReqQueue {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_after_silence() {
+        let last_write = Instant::now();
+        assert!(!keep_alive_due(last_write, last_write + KEEP_ALIVE_CHECK));
+        assert!(keep_alive_due(last_write, last_write + KEEP_ALIVE_INTERVAL));
+        // the check may race with a write
+        assert!(!keep_alive_due(last_write + KEEP_ALIVE_CHECK, last_write));
+    }
+}
@@ -0,0 +1,608 @@
+//! Message Stream Encryption, the obfuscated handshake before the BitTorrent handshake.
+//! Both sides exchange Diffie-Hellman keys, the one who dialed proves it knows the info hash and
+//! offers plaintext and/or RC4, the other one picks. Random padding hides the length of the first
+//! messages, so each side searches the stream for the first bytes it can recognize.
+//! See <https://wiki.vuze.com/w/Message_Stream_Encryption>.
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::LazyLock,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{config::Encryption, peer::socket::canonical, torrent::InfoHash};
+
+/// the 768 bit prime of the key exchange
+static PRIME: LazyLock<BigUint> = LazyLock::new(|| {
+    BigUint::parse_bytes(
+        b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E\
+          3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000\
+          000000090563",
+        16,
+    )
+    .unwrap()
+});
+const GENERATOR: u32 = 2;
+/// the public keys and the shared secret, big endian
+const KEY_LEN: usize = 96;
+/// the most padding either side may send after its public key
+const PAD_MAX: usize = 512;
+/// the verification constant
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// how a plaintext handshake starts, a public key doesn't
+const PLAINTEXT_HANDSHAKE: &[u8; 20] = b"\x13BitTorrent protocol";
+/// a peer that doesn't know the encryption may wait for the rest of its plaintext handshake forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum MseError {
+    #[error("The encrypted handshake failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("The peer's handshake didn't show up after the padding")]
+    NoSync,
+    #[error("The peer asked for another torrent")]
+    WrongTorrent,
+    #[error("The peer sent an invalid verification constant")]
+    BadVc,
+    #[error("The peer and we have no encryption method in common, it sent {0:#x}")]
+    NoCommonMethod(u32),
+    #[error("The peer didn't encrypt the connection")]
+    PlaintextRefused,
+    #[error("The encrypted handshake took too long")]
+    Timeout,
+}
+
+/// Shakes hands as the one who dialed. With [`Encryption::PreferPlaintext`] the connection stays
+/// as it is.
+pub(crate) async fn initiate(
+    tcp: TcpStream,
+    info_hash: InfoHash,
+    encryption: Encryption,
+) -> Result<PeerStream, MseError> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, dialed(tcp, info_hash, encryption))
+        .await
+        .map_err(|_| MseError::Timeout)?
+}
+
+/// Shakes hands with a peer that dialed us, plaintext or encrypted for one of `info_hashes`.
+/// Which torrent it wants is in the BitTorrent handshake that follows.
+pub(crate) async fn respond(
+    tcp: TcpStream,
+    info_hashes: &[InfoHash],
+    encryption: Encryption,
+) -> Result<PeerStream, MseError> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, accepted(tcp, info_hashes, encryption))
+        .await
+        .map_err(|_| MseError::Timeout)?
+}
+
+async fn dialed(
+    tcp: TcpStream,
+    info_hash: InfoHash,
+    encryption: Encryption,
+) -> Result<PeerStream, MseError> {
+    let provide = match encryption {
+        Encryption::PreferPlaintext => return Ok(PeerStream::plain(tcp)),
+        Encryption::PreferEncrypted => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        Encryption::RequireEncrypted => CRYPTO_RC4,
+    };
+    let keys = Keys::new();
+    let mut reader = Reader::new(tcp);
+    reader
+        .write(&[&keys.public[..], &padding()[..]].concat())
+        .await?;
+    let secret = keys.secret(&reader.take(KEY_LEN).await?);
+    let skey = info_hash.0;
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &skey]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &skey]));
+
+    // no padding and no initial payload, the BitTorrent handshake follows encrypted
+    let mut offer = [&VC[..], &provide.to_be_bytes(), &[0; 2], &[0; 2]].concat();
+    encrypt.apply(&mut offer);
+    let message = [
+        &hash(&[b"req1", &secret])[..],
+        &xor(hash(&[b"req2", &skey]), hash(&[b"req3", &secret])),
+        &offer,
+    ]
+    .concat();
+    reader.write(&message).await?;
+
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    reader.sync(&vc).await?;
+    let mut answer = reader.take(6).await?;
+    decrypt.apply(&mut answer);
+    let select = u32::from_be_bytes(answer[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(answer[4..].try_into().unwrap());
+    // the padding is only there to advance the keystream
+    decrypt.apply(&mut reader.take(pad_len as usize).await?);
+
+    match select {
+        CRYPTO_RC4 if provide & CRYPTO_RC4 != 0 => Ok(reader.encrypted(decrypt, encrypt)),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => {
+            Ok(PeerStream::with_buffered(reader.tcp, reader.buf, None))
+        }
+        select => Err(MseError::NoCommonMethod(select)),
+    }
+}
+
+async fn accepted(
+    tcp: TcpStream,
+    info_hashes: &[InfoHash],
+    encryption: Encryption,
+) -> Result<PeerStream, MseError> {
+    let mut reader = Reader::new(tcp);
+    reader.fill(PLAINTEXT_HANDSHAKE.len()).await?;
+    if reader.buf.starts_with(PLAINTEXT_HANDSHAKE) {
+        if encryption == Encryption::RequireEncrypted {
+            return Err(MseError::PlaintextRefused);
+        }
+        return Ok(PeerStream::with_buffered(reader.tcp, reader.buf, None));
+    }
+
+    let theirs = reader.take(KEY_LEN).await?;
+    let keys = Keys::new();
+    reader
+        .write(&[&keys.public[..], &padding()[..]].concat())
+        .await?;
+    let secret = keys.secret(&theirs);
+    reader.sync(&hash(&[b"req1", &secret])).await?;
+    // the hash of the info hash the peer wants, hidden behind the secret
+    let req2 = xor(
+        reader.take(20).await?[..].try_into().unwrap(),
+        hash(&[b"req3", &secret]),
+    );
+    let skey = info_hashes
+        .iter()
+        .map(|info_hash| info_hash.0)
+        .find(|skey| hash(&[b"req2", skey]) == req2)
+        .ok_or(MseError::WrongTorrent)?;
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &skey]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &skey]));
+
+    let mut offer = reader.take(14).await?;
+    decrypt.apply(&mut offer);
+    if offer[..8] != VC {
+        return Err(MseError::BadVc);
+    }
+    let provide = u32::from_be_bytes(offer[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(offer[12..].try_into().unwrap());
+    decrypt.apply(&mut reader.take(pad_len as usize).await?);
+    let mut initial_len = reader.take(2).await?;
+    decrypt.apply(&mut initial_len);
+    let initial_len = u16::from_be_bytes(initial_len[..].try_into().unwrap());
+    // usually the start of the BitTorrent handshake
+    let mut initial = reader.take(initial_len as usize).await?;
+    decrypt.apply(&mut initial);
+
+    let select = select(provide, encryption)?;
+    let mut answer = [&VC[..], &select.to_be_bytes(), &[0; 2]].concat();
+    encrypt.apply(&mut answer);
+    reader.write(&answer).await?;
+
+    let mut stream = if select == CRYPTO_RC4 {
+        reader.encrypted(decrypt, encrypt)
+    } else {
+        PeerStream::with_buffered(reader.tcp, reader.buf, None)
+    };
+    initial.unsplit(stream.buffered);
+    stream.buffered = initial;
+    Ok(stream)
+}
+
+/// what we pick of the methods the peer that dialed offers
+fn select(provide: u32, encryption: Encryption) -> Result<u32, MseError> {
+    let rc4 = provide & CRYPTO_RC4 != 0;
+    let plaintext = provide & CRYPTO_PLAINTEXT != 0;
+    match (encryption, rc4, plaintext) {
+        (Encryption::PreferPlaintext, _, true) => Ok(CRYPTO_PLAINTEXT),
+        (_, true, _) => Ok(CRYPTO_RC4),
+        (Encryption::RequireEncrypted, false, _) | (_, false, false) => {
+            Err(MseError::NoCommonMethod(provide))
+        }
+        (_, false, true) => Ok(CRYPTO_PLAINTEXT),
+    }
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::rng();
+    let mut pad = vec![0; rng.random_range(0..=PAD_MAX)];
+    rng.fill(&mut pad[..]);
+    pad
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(mut a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    for (a, b) in a.iter_mut().zip(b) {
+        *a ^= b;
+    }
+    a
+}
+
+struct Keys {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl Keys {
+    fn new() -> Self {
+        // 160 bits are enough, the spec recommends at least 128
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = to_key(&BigUint::from(GENERATOR).modpow(&private, &PRIME));
+        Self { private, public }
+    }
+
+    fn secret(&self, theirs: &[u8]) -> [u8; KEY_LEN] {
+        to_key(&BigUint::from_bytes_be(theirs).modpow(&self.private, &PRIME))
+    }
+}
+
+/// padded with zeros at the front
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+/// Reads the handshake ahead, what's left after it belongs to the stream.
+struct Reader {
+    tcp: TcpStream,
+    buf: BytesMut,
+}
+
+impl Reader {
+    fn new(tcp: TcpStream) -> Self {
+        Self {
+            tcp,
+            buf: BytesMut::new(),
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.tcp.write_all(bytes).await
+    }
+
+    async fn fill(&mut self, len: usize) -> io::Result<()> {
+        while self.buf.len() < len {
+            if self.tcp.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
+    async fn take(&mut self, len: usize) -> io::Result<BytesMut> {
+        self.fill(len).await?;
+        Ok(self.buf.split_to(len))
+    }
+
+    /// skips the padding up to and including `pattern`
+    async fn sync(&mut self, pattern: &[u8]) -> Result<(), MseError> {
+        for skip in 0..=PAD_MAX {
+            self.fill(skip + pattern.len()).await?;
+            if self.buf[skip..].starts_with(pattern) {
+                self.buf.advance(skip + pattern.len());
+                return Ok(());
+            }
+        }
+        Err(MseError::NoSync)
+    }
+
+    fn encrypted(mut self, mut decrypt: Rc4, encrypt: Rc4) -> PeerStream {
+        decrypt.apply(&mut self.buf);
+        PeerStream::with_buffered(
+            self.tcp,
+            self.buf,
+            Some(Cipher {
+                decrypt,
+                encrypt,
+                unsent: BytesMut::new(),
+            }),
+        )
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the state is as good as the key
+        f.write_str("Rc4")
+    }
+}
+
+impl Rc4 {
+    /// drops the first KiB of the keystream like the spec says, it gives the key away
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::with_key(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn with_key(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j = 0_u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// encrypts and decrypts alike
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cipher {
+    decrypt: Rc4,
+    encrypt: Rc4,
+    /// encrypted bytes the socket didn't take yet, the keystream moved on already
+    unsent: BytesMut,
+}
+
+/// The connection to a peer after the encrypted handshake, or instead of it.
+#[derive(Debug)]
+pub(crate) struct PeerStream {
+    tcp: TcpStream,
+    /// what the handshake read ahead, decrypted already
+    buffered: BytesMut,
+    /// None if the connection isn't encrypted
+    cipher: Option<Cipher>,
+}
+
+impl PeerStream {
+    pub(crate) fn plain(tcp: TcpStream) -> Self {
+        Self::with_buffered(tcp, BytesMut::new(), None)
+    }
+
+    fn with_buffered(tcp: TcpStream, buffered: BytesMut, cipher: Option<Cipher>) -> Self {
+        Self {
+            tcp,
+            buffered,
+            cipher,
+        }
+    }
+
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.peer_addr().map(canonical)
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn poll_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(cipher) = &mut self.cipher else {
+            return Poll::Ready(Ok(()));
+        };
+        while !cipher.unsent.is_empty() {
+            let n = ready!(Pin::new(&mut self.tcp).poll_write(cx, &cipher.unsent))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            cipher.unsent.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let n = this.buffered.len().min(buf.remaining());
+            buf.put_slice(&this.buffered.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.tcp).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.cipher {
+            cipher.decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.cipher.is_none() {
+            return Pin::new(&mut this.tcp).poll_write(cx, buf);
+        }
+        ready!(this.poll_unsent(cx))?;
+        let cipher = this.cipher.as_mut().unwrap();
+        let start = cipher.unsent.len();
+        cipher.unsent.extend_from_slice(buf);
+        cipher.encrypt.apply(&mut cipher.unsent[start..]);
+        // the bytes are ours now, what the socket doesn't take goes out with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_unsent(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_unsent(cx))?;
+        Pin::new(&mut this.tcp).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_unsent(cx))?;
+        Pin::new(&mut this.tcp).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn rc4_test_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::with_key(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+        assert_eq!(PRIME.bits(), 768);
+    }
+
+    #[test]
+    fn select_a_method() {
+        let both = CRYPTO_RC4 | CRYPTO_PLAINTEXT;
+        assert_eq!(
+            select(both, Encryption::PreferPlaintext).unwrap(),
+            CRYPTO_PLAINTEXT
+        );
+        assert_eq!(
+            select(CRYPTO_RC4, Encryption::PreferPlaintext).unwrap(),
+            CRYPTO_RC4
+        );
+        assert_eq!(
+            select(both, Encryption::PreferEncrypted).unwrap(),
+            CRYPTO_RC4
+        );
+        assert_eq!(
+            select(CRYPTO_PLAINTEXT, Encryption::PreferEncrypted).unwrap(),
+            CRYPTO_PLAINTEXT
+        );
+        assert!(select(CRYPTO_PLAINTEXT, Encryption::RequireEncrypted).is_err());
+        assert!(select(0x08, Encryption::PreferEncrypted).is_err());
+    }
+
+    /// the acceptor knows the torrents of `known`
+    async fn connected(
+        dial: Encryption,
+        accept: Encryption,
+        known: &[InfoHash],
+    ) -> (Result<PeerStream, MseError>, Result<PeerStream, MseError>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = InfoHash([7; 20]);
+        let dialed = async {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let mut stream = initiate(tcp, info_hash, dial).await?;
+            // a plaintext dialer starts with the BitTorrent handshake
+            stream.write_all(PLAINTEXT_HANDSHAKE).await?;
+            stream.write_all(b"from the dialer").await?;
+            Ok(stream)
+        };
+        let accepted = async {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut stream = respond(tcp, known, accept).await?;
+            stream.write_all(b"from the acceptor").await?;
+            Ok(stream)
+        };
+        tokio::join!(dialed, accepted)
+    }
+
+    #[tokio::test]
+    async fn both_ends_agree() {
+        for (dial, accept, encrypted) in [
+            (
+                Encryption::PreferEncrypted,
+                Encryption::PreferEncrypted,
+                true,
+            ),
+            (
+                Encryption::RequireEncrypted,
+                Encryption::PreferPlaintext,
+                true,
+            ),
+            (
+                Encryption::PreferEncrypted,
+                Encryption::PreferPlaintext,
+                false,
+            ),
+            (
+                Encryption::PreferPlaintext,
+                Encryption::PreferEncrypted,
+                false,
+            ),
+        ] {
+            let known = [InfoHash([1; 20]), InfoHash([7; 20])];
+            let (dialed, accepted) = connected(dial, accept, &known).await;
+            let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+            assert_eq!(dialed.is_encrypted(), encrypted);
+            assert_eq!(accepted.is_encrypted(), encrypted);
+
+            let mut buf = [0; 35];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..20], PLAINTEXT_HANDSHAKE);
+            assert_eq!(&buf[20..], b"from the dialer");
+            let mut buf = [0; 17];
+            dialed.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"from the acceptor");
+        }
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_refused() {
+        let (_, accepted) = connected(
+            Encryption::PreferPlaintext,
+            Encryption::RequireEncrypted,
+            &[InfoHash([7; 20])],
+        )
+        .await;
+        assert!(matches!(accepted, Err(MseError::PlaintextRefused)));
+    }
+
+    #[tokio::test]
+    async fn unknown_torrent_is_refused() {
+        let (_, accepted) = connected(
+            Encryption::RequireEncrypted,
+            Encryption::PreferEncrypted,
+            &[InfoHash([1; 20])],
+        )
+        .await;
+        assert!(matches!(accepted, Err(MseError::WrongTorrent)));
+    }
+}
@@ -0,0 +1,212 @@
+//! Caps how fast we upload and download, for a single peer, e.g. one that hogs the upload, and for
+//! all peers of all torrents together, see [`Bandwidth`]. A block that's over a limit waits before
+//! we send it, a block we received waits before we read on, so the socket fills up and the peer
+//! slows down. A block first waits for the quota of its peer, then for the session's.
+use std::{
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sync::Mutex;
+
+/// Bytes per second, None is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// what we send the peers
+    pub upload: Option<u64>,
+    /// what the peers send us
+    pub download: Option<u64>,
+}
+
+/// The rates all peers of a client share, cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth(Arc<Buckets>);
+
+impl Bandwidth {
+    pub fn new(limit: RateLimit) -> Self {
+        let bandwidth = Self::default();
+        bandwidth.set_limit(limit);
+        bandwidth
+    }
+
+    /// takes effect for the next block of every peer
+    pub fn set_limit(&self, limit: RateLimit) {
+        self.0.set(limit);
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        RateLimit {
+            upload: self.0.upload.rate(),
+            download: self.0.download.rate(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Buckets {
+    fn set(&self, limit: RateLimit) {
+        self.upload.set_rate(limit.upload);
+        self.download.set_rate(limit.download);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PeerRateLimiter {
+    own: Buckets,
+    /// set by the PeerManager when it accepts the connection
+    shared: OnceLock<Bandwidth>,
+}
+
+impl PeerRateLimiter {
+    pub(crate) fn set(&self, limit: RateLimit) {
+        self.own.set(limit);
+    }
+
+    pub(crate) fn share(&self, bandwidth: &Bandwidth) {
+        let _ = self.shared.set(bandwidth.clone());
+    }
+
+    /// waits until we may send the bytes to the peer
+    pub(crate) async fn upload(&self, bytes: u64) {
+        wait(self.own.upload.take(bytes, Instant::now())).await;
+        if let Some(shared) = self.shared.get() {
+            wait(shared.0.upload.take(bytes, Instant::now())).await;
+        }
+    }
+
+    /// waits until we may read on after the peer sent us the bytes
+    pub(crate) async fn download(&self, bytes: u64) {
+        wait(self.own.download.take(bytes, Instant::now())).await;
+        if let Some(shared) = self.shared.get() {
+            wait(shared.0.download.take(bytes, Instant::now())).await;
+        }
+    }
+}
+
+async fn wait(duration: Duration) {
+    if !duration.is_zero() {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket(Mutex<Bucket>);
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self(Mutex::new(Bucket {
+            rate: None,
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    /// negative while we owe bytes
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn rate(&self) -> Option<u64> {
+        self.0.lock().unwrap().rate
+    }
+
+    fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        if let Some(rate) = rate {
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        }
+    }
+
+    /// takes the bytes right away, returns how long to wait until they're paid for
+    fn take(&self, bytes: u64, now: Instant) -> Duration {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill(now);
+        let Some(rate) = bucket.rate.filter(|rate| *rate > 0) else {
+            return Duration::ZERO;
+        };
+        bucket.tokens -= bytes as f64;
+        Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate as f64)
+    }
+}
+
+impl Bucket {
+    /// at most a second worth of bytes piles up while the peer is idle
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.refilled_at = now;
+        if let Some(rate) = self.rate {
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_over_the_rate_wait() {
+        let bucket = TokenBucket::default();
+        let start = Instant::now();
+        assert_eq!(bucket.take(1 << 20, start), Duration::ZERO);
+
+        bucket.set_rate(Some(16384));
+        let start = bucket.0.lock().unwrap().refilled_at;
+        // the bucket starts empty
+        assert_eq!(bucket.take(16384, start), Duration::from_secs(1));
+        assert_eq!(bucket.take(8192, start), Duration::from_millis(1500));
+        // paid off after 1.5s, another second fills the bucket
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(16384, later), Duration::ZERO);
+        assert_eq!(bucket.take(16384, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn peers_share_the_bandwidth() {
+        let limit = RateLimit {
+            upload: Some(16384),
+            download: None,
+        };
+        let bandwidth = Bandwidth::new(limit);
+        let (a, b) = (PeerRateLimiter::default(), PeerRateLimiter::default());
+        a.share(&bandwidth);
+        b.share(&bandwidth);
+        let now = bandwidth.0.upload.0.lock().unwrap().refilled_at;
+        assert_eq!(
+            a.shared.get().unwrap().0.upload.take(16384, now),
+            Duration::from_secs(1)
+        );
+        // the second peer pays for what the first one sent
+        assert_eq!(
+            b.shared.get().unwrap().0.upload.take(16384, now),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            b.shared.get().unwrap().0.download.take(1 << 20, now),
+            Duration::ZERO
+        );
+
+        bandwidth.set_limit(RateLimit::default());
+        assert_eq!(bandwidth.limit(), RateLimit::default());
+        assert_eq!(
+            a.shared.get().unwrap().0.upload.take(16384, now),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Applies the [`SocketOptions`] of the config to the peer connections.
+//! The buffer sizes must be set before the connection is established, the window scale of TCP is
+//! agreed on in the SYN, so we dial and listen through a [`TcpSocket`] instead of connecting right
+//! away. An accepted connection inherits the buffers of the listener.
+//! We listen on IPv6 and IPv4 with one dual-stack socket if the system has IPv6.
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::fd::AsFd,
+    time::Duration,
+};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::config::SocketOptions;
+
+/// how many connections may wait to be accepted
+const LISTEN_BACKLOG: u32 = 1024;
+
+impl SocketOptions {
+    fn socket_for(&self, addr: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        self.apply_buffers(&socket)?;
+        Ok(socket)
+    }
+
+    fn apply_buffers(&self, socket: &impl AsFd) -> io::Result<()> {
+        let socket = SockRef::from(socket);
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// for the options that can change on an established connection
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        socket.set_tcp_nodelay(self.nodelay)?;
+        match self.keepalive_secs {
+            Some(secs) => socket
+                .set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(secs)))?,
+            None => socket.set_keepalive(false)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let stream = self.socket_for(addr)?.connect(addr).await?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+
+    pub(crate) fn listen(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = self.socket_for(addr)?;
+        // like TcpListener::bind, so a restart doesn't wait for the old connections to time out
+        socket.set_reuseaddr(true)?;
+        if addr.is_ipv6() {
+            // IPv4 peers connect to it too, as IPv4-mapped addresses
+            SockRef::from(&socket).set_only_v6(false)?;
+        }
+        socket.bind(addr)?;
+        socket.listen(LISTEN_BACKLOG)
+    }
+
+    /// on all addresses, IPv4 only if the system doesn't have IPv6
+    pub(crate) fn listen_on_port(&self, port: u16) -> io::Result<TcpListener> {
+        self.listen((Ipv6Addr::UNSPECIFIED, port).into())
+            .or_else(|_| self.listen((Ipv4Addr::UNSPECIFIED, port).into()))
+    }
+}
+
+/// An IPv4 peer on a dual-stack socket shows up as `::ffff:a.b.c.d`.
+pub(crate) fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// The global IPv6 address the system would send from, None if it has none.
+/// Connecting a UDP socket only picks the route, nothing is sent.
+pub(crate) fn global_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    // any global address does, this one is Google's DNS
+    socket.connect(("2001:4860:4860::8888", 53)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global(ip) => Some(ip),
+        _ => None,
+    }
+}
+
+fn is_global(ip: Ipv6Addr) -> bool {
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_unicast_link_local()
+        || ip.is_unique_local()
+        || ip.to_ipv4_mapped().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn options_reach_both_ends() {
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive_secs: Some(30),
+            send_buffer: Some(1 << 16),
+            recv_buffer: Some(1 << 16),
+            ..Default::default()
+        };
+        let listener = options.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dialed, accepted) = tokio::join!(options.connect(addr), listener.accept());
+        let dialed = dialed.unwrap();
+        let (accepted, _) = accepted.unwrap();
+        options.apply(&accepted).unwrap();
+
+        for stream in [&dialed, &accepted] {
+            let socket = SockRef::from(stream);
+            assert!(socket.tcp_nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            // the kernel may round it up, e.g. Linux doubles it for its bookkeeping
+            assert!(socket.recv_buffer_size().unwrap() >= 1 << 16);
+        }
+    }
+
+    #[test]
+    fn mapped_and_private_addresses() {
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:6881".parse().unwrap();
+        assert_eq!(canonical(mapped), "10.0.0.1:6881".parse().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(canonical(v6), v6);
+
+        assert!(is_global("2a00:1450::1".parse().unwrap()));
+        assert!(!is_global("fe80::1".parse().unwrap()));
+        assert!(!is_global("fd00::1".parse().unwrap()));
+        assert!(!is_global(Ipv6Addr::LOCALHOST));
+    }
+}
@@ -0,0 +1,274 @@
+//! An opt-in trace of the messages we exchange with the peers of a torrent, one JSON object per line.
+//! Block data is never written, only its size. This is meant for debugging clients that don't
+//! interoperate with us when wireshark isn't at hand.
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{messages::PeerMessage, peer::Peer};
+
+use crate::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Serialize)]
+struct TraceRecord<'a> {
+    /// milliseconds since the unix epoch
+    ts_ms: u128,
+    peer: String,
+    dir: Direction,
+    msg: &'a str,
+    /// the size of the payload in bytes
+    size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    begin: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extension_id: Option<u8>,
+}
+
+impl<'a> TraceRecord<'a> {
+    fn new(peer_id: &[u8; 20], dir: Direction, msg: &'a PeerMessage) -> Self {
+        let mut record = Self {
+            ts_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            peer: hex::encode(peer_id),
+            dir,
+            msg: msg.as_ref(),
+            size: 0,
+            index: None,
+            begin: None,
+            length: None,
+            extension_id: None,
+        };
+        match msg {
+            PeerMessage::Have(payload) => {
+                record.size = 4;
+                record.index = Some(payload.piece_index);
+            }
+            PeerMessage::Bitfield(payload) => {
+                record.size = payload.byte_len();
+            }
+            PeerMessage::Request(payload) | PeerMessage::Cancel(payload) => {
+                record.size = 12;
+                record.index = Some(payload.index);
+                record.begin = Some(payload.begin);
+                record.length = Some(payload.length);
+            }
+            PeerMessage::Piece(payload) => {
+                record.size = 8 + payload.block.len();
+                record.index = Some(payload.index);
+                record.begin = Some(payload.begin);
+                record.length = Some(payload.block.len() as u32);
+            }
+            PeerMessage::Port(_) => record.size = 2,
+            PeerMessage::Extended(payload) => {
+                record.size = 1 + payload.data.len();
+                record.extension_id = Some(payload.extension_id);
+            }
+            PeerMessage::Choke(_)
+            | PeerMessage::Unchoke(_)
+            | PeerMessage::Interested(_)
+            | PeerMessage::NotInterested(_)
+            | PeerMessage::KeepAlive(_) => {}
+        }
+        record
+    }
+}
+
+/// The trace of a torrent, shared by all of its peers.
+/// It can be switched on and off at any time, the file is only created once it's first enabled.
+#[derive(Debug, Clone)]
+pub struct WireTrace(Arc<WireTraceInner>);
+
+#[derive(Debug)]
+struct WireTraceInner {
+    path: PathBuf,
+    enabled: AtomicBool,
+    file: Mutex<Option<File>>,
+}
+
+impl WireTrace {
+    pub fn new(path: PathBuf) -> Self {
+        Self(Arc::new(WireTraceInner {
+            path,
+            enabled: AtomicBool::new(false),
+            file: Mutex::new(None),
+        }))
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// returns whether the trace is enabled now
+    pub fn toggle(&self) -> bool {
+        !self.0.enabled.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, peer_id: &[u8; 20], dir: Direction, msg: &PeerMessage) {
+        if !self.is_enabled() {
+            return;
+        }
+        let line = serde_json::to_string(&TraceRecord::new(peer_id, dir, msg))
+            .expect("The record only consists of strings and numbers.");
+        if let Err(e) = self.write_line(&line) {
+            eprintln!(
+                "Failed to write the wire trace to `{}`, disabling it: {e}",
+                self.0.path.display()
+            );
+            self.set_enabled(false);
+        }
+    }
+
+    fn write_line(&self, line: &str) -> std::io::Result<()> {
+        let mut file = self.0.file.lock().unwrap();
+        if file.is_none() {
+            if let Some(parent) = self.0.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.0.path)?,
+            );
+        }
+        let file = file.as_mut().expect("The file was opened above.");
+        writeln!(file, "{line}")
+    }
+}
+
+impl Peer {
+    /// records the message if the PeerManager gave us a trace and it's enabled
+    pub(super) fn trace(&self, dir: Direction, msg: &PeerMessage) {
+        if let Some(trace) = self.state.0.wire_trace.get() {
+            trace.record(&self.get_id(), dir, msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::messages::payloads::{RequestPiecePayload, ResponsePiecePayload};
+
+    #[test]
+    fn records_leave_out_block_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace").join("wire-trace.jsonl");
+        let trace = WireTrace::new(path.clone());
+        let request = PeerMessage::Request(RequestPiecePayload::new(3, 16384, 16384));
+        // disabled traces don't touch the disk
+        trace.record(&[b'a'; 20], Direction::Out, &request);
+        assert!(!path.exists());
+
+        assert!(trace.toggle());
+        trace.record(&[b'a'; 20], Direction::Out, &request);
+        trace.record(
+            &[b'a'; 20],
+            Direction::In,
+            &PeerMessage::Piece(ResponsePiecePayload {
+                index: 3,
+                begin: 16384,
+                block: Bytes::from(vec![0xff; 16384]),
+            }),
+        );
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["dir"], "out");
+        assert_eq!(lines[0]["msg"], "Request");
+        assert_eq!(lines[0]["length"], 16384);
+        assert_eq!(lines[1]["msg"], "Piece");
+        assert_eq!(lines[1]["size"], 8 + 16384);
+        assert!(contents.len() < 1024);
+    }
+
+    #[test]
+    fn golden_session() {
+        use crate::{
+            extensions::BasicExtensionPayload,
+            messages::payloads::{BitfieldPayload, HavePayload, NoPayload},
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wire-trace.jsonl");
+        let trace = WireTrace::new(path.clone());
+        trace.set_enabled(true);
+        let request = RequestPiecePayload::new(1, 0, 16384);
+        let session = [
+            (
+                Direction::Out,
+                PeerMessage::Extended(BasicExtensionPayload {
+                    extension_id: 0,
+                    data: Bytes::from_static(b"d1:md11:ut_metadatai1eee"),
+                }),
+            ),
+            (
+                Direction::In,
+                PeerMessage::Bitfield(BitfieldPayload::from_bools(&[true; 10])),
+            ),
+            (Direction::Out, PeerMessage::Interested(NoPayload)),
+            (Direction::In, PeerMessage::Unchoke(NoPayload)),
+            (Direction::Out, PeerMessage::Request(request)),
+            (
+                Direction::In,
+                PeerMessage::Piece(ResponsePiecePayload {
+                    index: 1,
+                    begin: 0,
+                    block: Bytes::from(vec![7; 16384]),
+                }),
+            ),
+            (Direction::Out, PeerMessage::Cancel(request)),
+            (
+                Direction::Out,
+                PeerMessage::Have(HavePayload { piece_index: 1 }),
+            ),
+            (Direction::In, PeerMessage::KeepAlive(NoPayload)),
+        ];
+        for (dir, msg) in session.iter() {
+            trace.record(&[b'a'; 20], *dir, msg);
+        }
+
+        // everything but the time is deterministic
+        let lines: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let mut record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record.as_object_mut().unwrap().remove("ts_ms");
+                record.to_string()
+            })
+            .collect();
+        crate::test_fixtures::assert_golden("wire-trace.jsonl", &(lines.join("\n") + "\n"));
+    }
+}
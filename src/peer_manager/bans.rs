@@ -0,0 +1,115 @@
+//! Peers that keep sending blocks of pieces that fail the hash are banned for a while, see
+//! [`BanSettings`]. We can't tell which sender of a corrupt piece lied, so each of them gets a
+//! strike; an honest peer only collects a few by bad luck while a liar collects one per piece.
+//! Bans go by IP, a peer gets a new peer id when it restarts, and keep it from connecting to us
+//! as well as us from dialing it.
+//!
+//! [`BanSettings`]: crate::config::BanSettings
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::peer_manager::{EventKind, PeerManager};
+use crate::sync::Mutex;
+
+/// The IPs that are banned and until when, shared with the tasks that dial peers.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BanList(Arc<Mutex<HashMap<IpAddr, Instant>>>);
+
+impl BanList {
+    pub(super) fn ban(&self, ip: IpAddr, until: Instant) {
+        self.0.lock().unwrap().insert(ip.to_canonical(), until);
+    }
+
+    /// forgets the bans that ran out
+    pub(crate) fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut bans = self.0.lock().unwrap();
+        let ip = ip.to_canonical();
+        match bans.get(&ip) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// how many corrupt pieces each IP sent blocks of
+#[derive(Debug, Default)]
+pub(super) struct Strikes(HashMap<IpAddr, u32>);
+
+impl Strikes {
+    /// returns the strikes of the IP so far
+    fn strike(&mut self, ip: IpAddr) -> u32 {
+        let strikes = self.0.entry(ip.to_canonical()).or_default();
+        *strikes += 1;
+        *strikes
+    }
+
+    /// a ban wipes the slate, after it the peer starts over
+    fn clear(&mut self, ip: IpAddr) {
+        self.0.remove(&ip.to_canonical());
+    }
+}
+
+impl PeerManager {
+    /// gives the senders of a corrupt piece a strike, disconnects and bans the ones with too many
+    pub(super) async fn strike_senders(&mut self, senders: &[[u8; 20]]) {
+        let Some(max) = self.config.bans.max_hash_failures else {
+            return;
+        };
+        let until = self.clock.now() + Duration::from_secs(self.config.bans.ban_secs);
+        let mut offenders = Vec::new();
+        for peer_id in senders {
+            let Some(conn) = self.peers.get(peer_id) else {
+                continue;
+            };
+            let ip = conn.identifier.0.addr.ip();
+            if self.strikes.strike(ip) >= max {
+                self.strikes.clear(ip);
+                self.candidates.bans().ban(ip, until);
+                self.events.record(EventKind::PeerBanned { ip });
+                offenders.push(*peer_id);
+            }
+        }
+        for peer_id in offenders {
+            self.drop_peer(peer_id, "it sent too many pieces that failed the hash")
+                .await;
+        }
+    }
+
+    /// for a connection that comes in while its IP is banned
+    pub(super) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.candidates.bans().is_banned(ip, self.clock.now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_run_out() {
+        let bans = BanList::default();
+        let now = Instant::now();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        bans.ban(ip, now + Duration::from_secs(60));
+        assert!(bans.is_banned(ip, now));
+        // the same peer over a dual-stack socket
+        assert!(bans.is_banned("::ffff:10.0.0.1".parse().unwrap(), now));
+        assert!(!bans.is_banned("10.0.0.2".parse().unwrap(), now));
+        assert!(!bans.is_banned(ip, now + Duration::from_secs(60)));
+        assert!(bans.0.lock().unwrap().is_empty());
+
+        let mut strikes = Strikes::default();
+        assert_eq!(strikes.strike(ip), 1);
+        assert_eq!(strikes.strike(ip), 2);
+        strikes.clear(ip);
+        assert_eq!(strikes.strike(ip), 1);
+    }
+}
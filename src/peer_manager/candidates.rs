@@ -0,0 +1,362 @@
+//! Every address we heard of a peer of the torrent at, and who told us about it.
+//! The trackers, the DHT, PEX and LSD often know the same peers, and a peer may connect to us while
+//! we dial it. An address is only dialed while nobody is dialing it or connected to it.
+//! An address is reachable once a peer accepted our connection there or told us it listens there
+//! in the extension handshake. Only those are cached for the next run, and when we lose a peer
+//! with a good score at one, it's dialed again, see [`MAX_REDIALS`].
+//! Nothing is dialed while the peers we have and the ones we dial fill the torrent's or the
+//! client's connection limit, see [`peer_slots`](super::peer_slots). The addresses are kept anyway,
+//! a peer that leaves makes room for them.
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    peer_manager::{PeerManager, PeerSlots, bans::BanList},
+    policy::PeerSource,
+};
+
+use crate::sync::Mutex;
+
+/// how often we dial a lost peer again, a peer that keeps leaving us isn't worth more
+pub(super) const MAX_REDIALS: u32 = 3;
+/// the peer may have left because it restarts or is at its connection limit
+pub(super) const REDIAL_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct PeerCandidate {
+    /// in the order they told us about the address, each one once
+    sources: Vec<PeerSource>,
+    dialing: bool,
+    /// the peer that's connected from this address
+    connected: Option<[u8; 20]>,
+    /// the last quality score of the peer at this address, see [`PeerManager::rate_candidates`]
+    score: Option<f64>,
+    /// a peer accepts connections at this address
+    reachable: bool,
+    redials: u32,
+}
+
+impl PeerCandidate {
+    fn add_source(&mut self, source: PeerSource) {
+        if !self.sources.contains(&source) {
+            self.sources.push(source);
+        }
+    }
+}
+
+/// how many peers the torrent has and may have
+#[derive(Debug)]
+struct Room {
+    peers: usize,
+    /// see [`Config::max_peers_per_torrent`](crate::Config::max_peers_per_torrent)
+    max_peers: Option<usize>,
+    slots: PeerSlots,
+    /// the clock of the PeerManager, the bans run out by it
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Self {
+            peers: 0,
+            max_peers: None,
+            slots: PeerSlots::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Room {
+    /// whether another dial fits next to the peers we have and the `dialing` ones
+    fn fits(&self, dialing: usize) -> bool {
+        self.max_peers.is_none_or(|max| self.peers + dialing < max) && self.slots.has_room(dialing)
+    }
+}
+
+/// Cheap to clone, the PeerManager and the tasks dialing its peers share one pool.
+/// The banned IPs aren't dialed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerCandidates(
+    Arc<Mutex<HashMap<SocketAddr, PeerCandidate>>>,
+    BanList,
+    Arc<Mutex<Room>>,
+);
+
+impl PeerCandidates {
+    pub(super) fn new(max_peers: Option<usize>) -> Self {
+        let candidates = Self::default();
+        candidates.2.lock().unwrap().max_peers = max_peers;
+        candidates
+    }
+
+    /// Records the source, returns None if the address is being dialed or connected already,
+    /// or if there's no room for another peer. The address may be dialed again once the guard is
+    /// dropped.
+    pub(crate) fn try_dial(&self, addr: SocketAddr, source: PeerSource) -> Option<DialGuard> {
+        let addr = canonical(addr);
+        let now = self.2.lock().unwrap().clock.now();
+        if self.1.is_banned(addr.ip(), now) {
+            return None;
+        }
+        let mut candidates = self.0.lock().unwrap();
+        let candidate = candidates.entry(addr).or_default();
+        candidate.add_source(source);
+        if candidate.dialing || candidate.connected.is_some() {
+            return None;
+        }
+        // a dialed peer that connected is one of the peers already
+        let dialing = candidates
+            .values()
+            .filter(|candidate| candidate.dialing && candidate.connected.is_none())
+            .count();
+        if !self.2.lock().unwrap().fits(dialing) {
+            return None;
+        }
+        let candidate = candidates.get_mut(&addr)?;
+        candidate.dialing = true;
+        Some(DialGuard {
+            candidates: self.clone(),
+            addr,
+        })
+    }
+
+    /// `addr` is where the peer accepts connections, the source is None if we dialed it
+    fn connected(&self, addr: SocketAddr, peer_id: [u8; 20], source: Option<PeerSource>) {
+        let mut candidates = self.0.lock().unwrap();
+        let candidate = candidates.entry(canonical(addr)).or_default();
+        if let Some(source) = source {
+            candidate.add_source(source);
+        }
+        candidate.connected = Some(peer_id);
+        candidate.reachable = true;
+    }
+
+    /// keeps the score of the peer with the address it's connected from
+    pub(super) fn rate(&self, peer_id: &[u8; 20], score: f64) {
+        for candidate in self.0.lock().unwrap().values_mut() {
+            if candidate.connected.as_ref() == Some(peer_id) {
+                candidate.score = Some(score);
+            }
+        }
+    }
+
+    /// returns the address to dial the peer at again if it had a good score
+    pub(super) fn disconnected(&self, peer_id: &[u8; 20]) -> Option<SocketAddr> {
+        let mut redial = None;
+        for (addr, candidate) in self.0.lock().unwrap().iter_mut() {
+            if candidate.connected.as_ref() != Some(peer_id) {
+                continue;
+            }
+            candidate.connected = None;
+            if candidate.reachable
+                && candidate.score.is_some_and(|score| score > 0.0)
+                && candidate.redials < MAX_REDIALS
+            {
+                candidate.redials += 1;
+                redial = Some(*addr);
+            }
+        }
+        redial
+    }
+
+    pub(super) fn bans(&self) -> &BanList {
+        &self.1
+    }
+
+    /// the connected peers of the torrent, for the room left to dial
+    pub(super) fn set_peers(&self, peers: usize) {
+        self.2.lock().unwrap().peers = peers;
+    }
+
+    /// the connections of all torrents count against the room as well
+    pub(super) fn share_peer_slots(&self, slots: &PeerSlots) {
+        self.2.lock().unwrap().slots = slots.clone();
+    }
+
+    /// see [`PeerManager::set_clock`]
+    pub(super) fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.2.lock().unwrap().clock = clock;
+    }
+
+    /// the `n` reachable addresses with the best scores, the best first
+    pub(crate) fn best(&self, n: usize) -> Vec<(SocketAddr, f64)> {
+        let mut rated: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, candidate)| candidate.reachable)
+            .filter_map(|(addr, candidate)| Some((*addr, candidate.score?)))
+            .collect();
+        rated.sort_by(|a, b| b.1.total_cmp(&a.1));
+        rated.truncate(n);
+        rated
+    }
+}
+
+impl PeerManager {
+    /// the addresses our peers came from, for the tasks that dial new ones
+    pub(crate) fn candidates(&self) -> PeerCandidates {
+        self.candidates.clone()
+    }
+
+    /// keeps the scores of the connected peers, so they're there when we save the peer cache
+    pub(super) fn rate_candidates(&self) {
+        let now = self.clock.now();
+        for peer_id in self.peers.keys() {
+            if let Some(score) = self.quality.score(peer_id, now) {
+                self.candidates.rate(peer_id, score);
+            }
+        }
+    }
+
+    /// Marks where the peer accepts connections as connected, so we don't dial it as well.
+    /// A peer that connected to us tells us its port in the extension handshake, if at all.
+    pub(super) fn track_candidate(&self, peer_id: [u8; 20]) {
+        let Some(state) = self.peers.get(&peer_id).map(|conn| &conn.identifier) else {
+            return;
+        };
+        if let Some(addr) = state.listen_addr() {
+            let source = (!state.is_outgoing()).then_some(PeerSource::Incoming);
+            self.candidates.connected(addr, peer_id, source);
+        }
+    }
+
+    /// for a peer that went away by itself, we dial it again if it was worth it
+    pub(super) fn lose_peer(&mut self, peer_id: [u8; 20]) {
+        let Some(addr) = self.remove_peer(peer_id) else {
+            return;
+        };
+        if let Some(announcer) = &self.announcer {
+            eprintln!("Lost the peer {addr}, dialing it again in {REDIAL_DELAY:?}.");
+            announcer.redial(addr, REDIAL_DELAY);
+        }
+    }
+}
+
+/// Held for as long as the connection we dialed lasts.
+#[derive(Debug)]
+pub(crate) struct DialGuard {
+    candidates: PeerCandidates,
+    addr: SocketAddr,
+}
+
+impl Drop for DialGuard {
+    fn drop(&mut self) {
+        if let Some(candidate) = self.candidates.0.lock().unwrap().get_mut(&self.addr) {
+            candidate.dialing = false;
+        }
+    }
+}
+
+/// an IPv4 address mapped into IPv6 is the same peer as the plain one
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TokioClock;
+
+    #[test]
+    fn one_dial_per_address() {
+        let candidates = PeerCandidates::default();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:6881".parse().unwrap();
+
+        let guard = candidates.try_dial(addr, PeerSource::Tracker).unwrap();
+        assert!(candidates.try_dial(mapped, PeerSource::Dht).is_none());
+        assert!(candidates.try_dial(addr, PeerSource::Tracker).is_none());
+        let other_port = candidates.try_dial("10.0.0.1:6882".parse().unwrap(), PeerSource::Pex);
+        assert!(other_port.is_some());
+        assert_eq!(
+            candidates.0.lock().unwrap()[&addr].sources,
+            [PeerSource::Tracker, PeerSource::Dht]
+        );
+
+        // the dial failed or the connection ended
+        drop(guard);
+        assert!(candidates.try_dial(addr, PeerSource::Lsd).is_some());
+    }
+
+    #[test]
+    fn no_dial_while_connected() {
+        let candidates = PeerCandidates::default();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        candidates.connected(addr, [1; 20], Some(PeerSource::Incoming));
+        assert!(candidates.try_dial(addr, PeerSource::Pex).is_none());
+
+        candidates.disconnected(&[2; 20]);
+        assert!(candidates.try_dial(addr, PeerSource::Pex).is_none());
+        candidates.rate(&[1; 20], 2.5);
+        candidates.disconnected(&[1; 20]);
+        assert!(candidates.try_dial(addr, PeerSource::Pex).is_some());
+        assert_eq!(candidates.best(10), [(addr, 2.5)]);
+        assert_eq!(
+            candidates.0.lock().unwrap()[&addr].sources,
+            [PeerSource::Incoming, PeerSource::Pex]
+        );
+    }
+
+    #[test]
+    fn good_peers_are_redialed() {
+        let candidates = PeerCandidates::default();
+        let good: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let bad: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        // heard of, but never connected
+        let unknown: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+        drop(candidates.try_dial(unknown, PeerSource::Dht));
+        candidates.connected(bad, [2; 20], None);
+        candidates.rate(&[2; 20], 0.0);
+        assert_eq!(candidates.disconnected(&[2; 20]), None);
+
+        for _ in 0..MAX_REDIALS {
+            candidates.connected(good, [1; 20], None);
+            candidates.rate(&[1; 20], 1.5);
+            assert_eq!(candidates.disconnected(&[1; 20]), Some(good));
+        }
+        candidates.connected(good, [1; 20], None);
+        assert_eq!(candidates.disconnected(&[1; 20]), None);
+        assert_eq!(candidates.best(10), [(good, 1.5), (bad, 0.0)]);
+    }
+
+    #[test]
+    fn no_dial_when_full() {
+        let candidates = PeerCandidates::new(Some(2));
+        let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        candidates.set_peers(1);
+        let dialing = candidates.try_dial(first, PeerSource::Tracker).unwrap();
+        // one peer and one dial fill the torrent, the address is kept for later
+        assert!(candidates.try_dial(second, PeerSource::Dht).is_none());
+        assert_eq!(
+            candidates.0.lock().unwrap()[&second].sources,
+            [PeerSource::Dht]
+        );
+
+        // the dial failed
+        drop(dialing);
+        assert!(candidates.try_dial(second, PeerSource::Dht).is_some());
+
+        // the other torrents took the connections of the client
+        let slots = PeerSlots::new(Some(1));
+        let _taken = slots.try_take().unwrap();
+        let candidates = PeerCandidates::new(None);
+        candidates.share_peer_slots(&slots);
+        assert!(candidates.try_dial(first, PeerSource::Tracker).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_run_out_by_the_shared_clock() {
+        let candidates = PeerCandidates::default();
+        candidates.set_clock(Arc::new(TokioClock));
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let ban = Duration::from_secs(600);
+        candidates.bans().ban(addr.ip(), TokioClock.now() + ban);
+        assert!(candidates.try_dial(addr, PeerSource::Tracker).is_none());
+        // the OS clock barely moved, the ban is over by the one the PeerManager reads
+        tokio::time::advance(ban).await;
+        assert!(candidates.try_dial(addr, PeerSource::Tracker).is_some());
+    }
+}
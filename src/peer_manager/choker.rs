@@ -0,0 +1,357 @@
+//! Decides which peers may request blocks from us.
+//! While we leech, the slots go to the peers that sent us the most over the last few intervals,
+//! so uploading to us is what gets a peer served (tit-for-tat).
+//! Nobody sends us anything while we seed, then the slots go to the peers we upload to fastest.
+//! The last of the slots (see [`crate::Config::upload_slots`]) rotates between the other interested peers
+//! so newcomers get a chance to prove themselves.
+//! Among peers with the same rate, the ones that have pieces we lack come first, see [`Choker::prefer`].
+//! Snubbed peers only get the optimistic slot, see [`Choker::snub`].
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+
+use rand::seq::IteratorRandom;
+
+use crate::peer_manager::{PeerManager, ResMessage, TorrentState, error::PeerManagerError};
+
+/// how often the slots are handed out again
+pub(super) const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// the rates are summed over this many intervals, one lucky interval isn't enough for a slot
+const RATE_WINDOW: usize = 3;
+/// the optimistic unchoke moves on after this many intervals
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// the bytes exchanged with a peer, the newest interval first
+#[derive(Debug, Default)]
+struct PeerRates {
+    /// what the peer sent us
+    downloaded: VecDeque<u64>,
+    /// what we sent the peer
+    uploaded: VecDeque<u64>,
+}
+
+impl PeerRates {
+    fn start_interval(&mut self) {
+        for window in [&mut self.downloaded, &mut self.uploaded] {
+            window.push_front(0);
+            window.truncate(RATE_WINDOW);
+        }
+    }
+}
+
+/// the peers whose state changes with this round
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct ChokeChanges {
+    pub(super) unchoke: Vec<[u8; 20]>,
+    pub(super) choke: Vec<[u8; 20]>,
+}
+
+#[derive(Debug)]
+pub(super) struct Choker {
+    /// how many peers may be unchoked at once, the optimistic unchoke included
+    slots: usize,
+    rates: HashMap<[u8; 20], PeerRates>,
+    unchoked: HashSet<[u8; 20]>,
+    optimistic: Option<[u8; 20]>,
+    rounds: u32,
+    preferred: HashSet<[u8; 20]>,
+    snubbed: HashSet<[u8; 20]>,
+}
+
+impl Choker {
+    pub(super) fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            rates: HashMap::new(),
+            unchoked: HashSet::new(),
+            optimistic: None,
+            rounds: 0,
+            preferred: HashSet::new(),
+            snubbed: HashSet::new(),
+        }
+    }
+
+    /// takes effect with the next rechoke
+    pub(super) fn set_slots(&mut self, slots: usize) {
+        self.slots = slots;
+    }
+
+    pub(super) fn downloaded(&mut self, peer_id: [u8; 20], bytes: u64) {
+        let rates = self.rates.entry(peer_id).or_default();
+        match rates.downloaded.front_mut() {
+            Some(current) => *current += bytes,
+            None => rates.downloaded.push_front(bytes),
+        }
+    }
+
+    pub(super) fn uploaded(&mut self, peer_id: [u8; 20], bytes: u64) {
+        let rates = self.rates.entry(peer_id).or_default();
+        match rates.uploaded.front_mut() {
+            Some(current) => *current += bytes,
+            None => rates.uploaded.push_front(bytes),
+        }
+    }
+
+    /// breaks ties in favor of these peers and picks the optimistic unchoke among them first
+    pub(super) fn prefer(&mut self, peers: HashSet<[u8; 20]>) {
+        self.preferred = peers;
+    }
+
+    /// these peers don't get a slot for their rate, they have to win the optimistic one
+    pub(super) fn snub(&mut self, peers: HashSet<[u8; 20]>) {
+        self.snubbed = peers;
+    }
+
+    pub(super) fn is_optimistic(&self, peer_id: &[u8; 20]) -> bool {
+        self.optimistic.as_ref() == Some(peer_id)
+    }
+
+    pub(super) fn is_unchoked(&self, peer_id: &[u8; 20]) -> bool {
+        self.unchoked.contains(peer_id)
+    }
+
+    pub(super) fn remove_peer(&mut self, peer_id: &[u8; 20]) {
+        self.rates.remove(peer_id);
+        self.unchoked.remove(peer_id);
+        if self.optimistic.as_ref() == Some(peer_id) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Hands out the slots among the interested peers and starts the next interval.
+    /// `seeding` ranks the peers by what we uploaded to them instead of what they sent us.
+    pub(super) fn rechoke(&mut self, interested: &[[u8; 20]], seeding: bool) -> ChokeChanges {
+        let rate = |peer_id: &[u8; 20]| {
+            self.rates.get(peer_id).map_or(0, |rates| {
+                let window = if seeding {
+                    &rates.uploaded
+                } else {
+                    &rates.downloaded
+                };
+                window.iter().sum::<u64>()
+            })
+        };
+        let mut ranked = interested.to_vec();
+        ranked.sort_by_key(|peer_id| {
+            std::cmp::Reverse((rate(peer_id), self.preferred.contains(peer_id)))
+        });
+        let by_rate = self.slots.saturating_sub(1);
+        let mut unchoked: HashSet<_> = ranked
+            .iter()
+            .filter(|peer_id| !self.snubbed.contains(*peer_id))
+            .take(by_rate)
+            .copied()
+            .collect();
+
+        let optimistic_lost = self
+            .optimistic
+            .is_none_or(|peer_id| unchoked.contains(&peer_id) || !interested.contains(&peer_id));
+        if self.slots == 0 {
+            self.optimistic = None;
+        } else if optimistic_lost || self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS) {
+            let candidates = || ranked.iter().filter(|peer_id| !unchoked.contains(*peer_id));
+            self.optimistic = candidates()
+                .filter(|peer_id| self.preferred.contains(*peer_id))
+                .choose(&mut rand::rng())
+                .or_else(|| candidates().choose(&mut rand::rng()))
+                .copied();
+        }
+        unchoked.extend(self.optimistic);
+
+        let changes = ChokeChanges {
+            unchoke: unchoked.difference(&self.unchoked).copied().collect(),
+            choke: self.unchoked.difference(&unchoked).copied().collect(),
+        };
+        self.unchoked = unchoked;
+        self.rounds += 1;
+        self.rates.values_mut().for_each(PeerRates::start_interval);
+        changes
+    }
+}
+
+impl PeerManager {
+    /// tells the peers whose slot changed, peers we choke lose their pending requests
+    pub(super) async fn rechoke(&mut self) -> Result<(), PeerManagerError> {
+        let seeding = match self.torrent_state {
+            TorrentState::Downloading { .. } => false,
+            TorrentState::Seeding { .. } => true,
+            TorrentState::WaitingForMetadata { .. } | TorrentState::Stopped => return Ok(()),
+        };
+        let interested: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, conn)| {
+                conn.identifier
+                    .0
+                    .peer_interested
+                    .load(std::sync::atomic::Ordering::Relaxed)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        let preferred = self.peers_with_missing_pieces();
+        self.choker.prefer(preferred.clone());
+        let now = self.clock.now();
+        let snubbed = interested
+            .iter()
+            .filter(|peer_id| self.is_snubbed(peer_id, now))
+            .copied()
+            .collect();
+        self.choker.snub(snubbed);
+        self.upload_queue.prefer(preferred);
+        let changes = self.choker.rechoke(&interested, seeding);
+        for peer_id in changes.choke {
+            self.upload_queue.remove_peer(&peer_id);
+            self.send_peer(peer_id, ResMessage::Choke).await?;
+        }
+        for peer_id in changes.unchoke {
+            self.send_peer(peer_id, ResMessage::Unchoke).await?;
+        }
+        Ok(())
+    }
+
+    /// the peers that have a piece we lack, nobody while we don't download
+    fn peers_with_missing_pieces(&self) -> HashSet<[u8; 20]> {
+        let TorrentState::Downloading { piece_manager, .. } = &self.torrent_state else {
+            return HashSet::new();
+        };
+        self.peers
+            .iter()
+            .filter(|(_, conn)| {
+                let has = conn.identifier.0.has.lock().unwrap();
+                has.iter_ones().any(|i| !piece_manager.have.contains(i))
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the peers that are unchoked because of their rate with the default config
+    const UPLOAD_SLOTS: usize = 4;
+
+    impl Default for Choker {
+        fn default() -> Self {
+            Self::new(UPLOAD_SLOTS + 1)
+        }
+    }
+
+    fn peers(n: u8) -> Vec<[u8; 20]> {
+        (1..=n).map(|i| [i; 20]).collect()
+    }
+
+    #[test]
+    fn leeching_reciprocates() {
+        let mut choker = Choker::default();
+        let peers = peers(6);
+        // peer 1 sends us nothing but we upload a lot to it
+        choker.uploaded(peers[0], 1 << 20);
+        for (i, peer_id) in peers.iter().enumerate().skip(1) {
+            choker.downloaded(*peer_id, i as u64 * 1000);
+        }
+        let changes = choker.rechoke(&peers, false);
+        for peer_id in &peers[2..] {
+            assert!(changes.unchoke.contains(peer_id));
+        }
+        // the fifth slot is the optimistic one
+        assert_eq!(changes.unchoke.len(), UPLOAD_SLOTS + 1);
+
+        // while seeding only our upload counts, so peer 1 gets a regular slot
+        choker.rechoke(&peers, true);
+        assert!(choker.is_unchoked(&peers[0]));
+        assert_ne!(choker.optimistic, Some(peers[0]));
+    }
+
+    #[test]
+    fn equal_rates_prefer_peers_with_missing_pieces() {
+        let mut choker = Choker::default();
+        let peers = peers(UPLOAD_SLOTS as u8 + 3);
+        let preferred: HashSet<_> = peers[2..].iter().copied().collect();
+        choker.prefer(preferred.clone());
+        choker.rechoke(&peers, false);
+        // nobody sent anything yet, so the peers with pieces for us get all slots and the optimistic one
+        assert!(!choker.is_unchoked(&peers[0]));
+        assert!(!choker.is_unchoked(&peers[1]));
+        for peer_id in &preferred {
+            assert!(choker.is_unchoked(peer_id));
+        }
+    }
+
+    #[test]
+    fn the_rest_is_choked() {
+        let mut choker = Choker::default();
+        let peers = peers(UPLOAD_SLOTS as u8 + 3);
+        for (i, peer_id) in peers.iter().enumerate() {
+            choker.downloaded(*peer_id, 1000 * (i as u64 + 1));
+        }
+        let first = choker.rechoke(&peers, false);
+        assert!(first.choke.is_empty());
+        assert_eq!(first.unchoke.len(), UPLOAD_SLOTS + 1);
+
+        // the best peer lost interest, the next one moves up
+        let best = *peers.last().unwrap();
+        let still_interested = &peers[..peers.len() - 1];
+        let second = choker.rechoke(still_interested, false);
+        assert!(second.choke.contains(&best));
+        assert!(!choker.is_unchoked(&best));
+        assert_eq!(choker.unchoked.len(), UPLOAD_SLOTS + 1);
+        for peer_id in &peers[peers.len() - 1 - UPLOAD_SLOTS..peers.len() - 1] {
+            assert!(choker.is_unchoked(peer_id));
+        }
+    }
+
+    #[test]
+    fn snubbed_peers_only_get_the_optimistic_slot() {
+        let mut choker = Choker::new(2);
+        let peers = peers(2);
+        // peer 2 sent more, but not lately
+        choker.downloaded(peers[0], 1000);
+        choker.downloaded(peers[1], 2000);
+        choker.snub(HashSet::from([peers[1]]));
+        choker.rechoke(&peers, false);
+        assert!(choker.is_unchoked(&peers[0]));
+        assert!(!choker.is_optimistic(&peers[0]));
+        assert!(choker.is_optimistic(&peers[1]));
+    }
+
+    #[test]
+    fn limited_slots() {
+        let peers = peers(8);
+        let mut choker = Choker::new(2);
+        for (i, peer_id) in peers.iter().enumerate() {
+            choker.downloaded(*peer_id, 1000 * (i as u64 + 1));
+        }
+        choker.rechoke(&peers, false);
+        assert_eq!(choker.unchoked.len(), 2);
+        assert!(choker.is_unchoked(&peers[7]));
+
+        choker.set_slots(0);
+        let changes = choker.rechoke(&peers, false);
+        assert_eq!(changes.choke.len(), 2);
+        assert!(choker.unchoked.is_empty());
+    }
+
+    #[test]
+    fn rates_fade_out_of_the_window() {
+        let mut choker = Choker::default();
+        let peers = peers(UPLOAD_SLOTS as u8 + 2);
+        choker.downloaded(peers[0], 1 << 20);
+        choker.rechoke(&peers, false);
+        assert!(choker.is_unchoked(&peers[0]));
+
+        // the others keep sending, peer 1 stopped
+        for _ in 0..RATE_WINDOW {
+            for peer_id in &peers[1..=UPLOAD_SLOTS] {
+                choker.downloaded(*peer_id, 1000);
+            }
+            choker.rechoke(&peers, false);
+        }
+        for peer_id in &peers[1..=UPLOAD_SLOTS] {
+            assert!(choker.is_unchoked(peer_id));
+        }
+        assert_eq!(choker.unchoked.len(), UPLOAD_SLOTS + 1);
+    }
+}
@@ -0,0 +1,170 @@
+//! Counts which client software our peers run, per torrent and over all torrents.
+//! Only the client name and version are kept, never a peer id or an address.
+//! This tells us which clients we have trouble with and which workarounds are worth it.
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use serde::Serialize;
+
+use crate::sync::Mutex;
+
+/// at most this many different clients are counted, the rest goes into [`OTHER`]
+const MAX_CLIENTS: usize = 256;
+const OTHER: &str = "other";
+/// longer `v` strings are cut off
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ClientCounts {
+    pub connections: u64,
+    /// connections we dropped because of an error
+    pub failures: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ClientStatsSnapshot {
+    /// keyed by the client code in the peer id, e.g. `qB4650` for qBittorrent 4.6.5
+    pub by_peer_id: BTreeMap<String, ClientCounts>,
+    /// keyed by the `v` of the extension handshake, e.g. `qBittorrent/4.6.5`
+    pub by_version: BTreeMap<String, u64>,
+}
+
+impl fmt::Display for ClientStatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (client, counts) in self.by_peer_id.iter() {
+            writeln!(
+                f,
+                "{client}: {} connections, {} failures",
+                counts.connections, counts.failures
+            )?;
+        }
+        for (version, count) in self.by_version.iter() {
+            writeln!(f, "{version}: {count} handshakes")?;
+        }
+        Ok(())
+    }
+}
+
+/// Cheap to clone, all clones count into the same snapshot.
+/// A child also counts everything into its parent, so a torrent can count into the global stats.
+#[derive(Debug, Clone, Default)]
+pub struct ClientStats {
+    snapshot: Arc<Mutex<ClientStatsSnapshot>>,
+    parent: Option<Box<ClientStats>>,
+}
+
+impl ClientStats {
+    pub fn child(&self) -> Self {
+        Self {
+            snapshot: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn snapshot(&self) -> ClientStatsSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    pub(super) fn connected(&self, peer_id: &[u8; 20]) {
+        self.update(|snapshot| {
+            let key = bounded_key(&snapshot.by_peer_id, client_code(peer_id));
+            snapshot.by_peer_id.entry(key).or_default().connections += 1;
+        });
+    }
+
+    pub(super) fn failed(&self, peer_id: &[u8; 20]) {
+        self.update(|snapshot| {
+            let key = bounded_key(&snapshot.by_peer_id, client_code(peer_id));
+            snapshot.by_peer_id.entry(key).or_default().failures += 1;
+        });
+    }
+
+    pub(super) fn handshake(&self, version: &str) {
+        let version: String = version
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_NAME_LEN)
+            .collect();
+        self.update(|snapshot| {
+            let key = bounded_key(&snapshot.by_version, version.clone());
+            *snapshot.by_version.entry(key).or_default() += 1;
+        });
+    }
+
+    fn update(&self, f: impl Fn(&mut ClientStatsSnapshot)) {
+        f(&mut self.snapshot.lock().unwrap());
+        if let Some(parent) = &self.parent {
+            parent.update(f);
+        }
+    }
+}
+
+/// peers could make up endless names, so only the first clients get their own entry
+fn bounded_key<V>(map: &BTreeMap<String, V>, key: String) -> String {
+    if map.contains_key(&key) || map.len() < MAX_CLIENTS {
+        key
+    } else {
+        OTHER.to_string()
+    }
+}
+
+/// the client code of the peer id, see <https://www.bittorrent.org/beps/bep_0020.html>
+fn client_code(peer_id: &[u8; 20]) -> String {
+    let printable = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'.')
+    };
+    if peer_id[0] == b'-' && peer_id[7] == b'-' && printable(&peer_id[1..7]) {
+        // Azureus style: `-qB4650-`
+        String::from_utf8_lossy(&peer_id[1..7]).into_owned()
+    } else if printable(&peer_id[..1]) {
+        // Shadow style: `S58B----`, the version encoding differs between clients
+        String::from_utf8_lossy(&peer_id[..1]).into_owned()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id(prefix: &[u8]) -> [u8; 20] {
+        let mut peer_id = [0xff; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    #[test]
+    fn counts_into_parent() {
+        let global = ClientStats::default();
+        let torrent = global.child();
+        torrent.connected(&peer_id(b"-qB4650-"));
+        torrent.connected(&peer_id(b"-qB4650-"));
+        torrent.failed(&peer_id(b"-TR3000-"));
+        torrent.handshake("Transmission 3.00");
+        global.child().connected(&peer_id(b"S58B----"));
+
+        let snapshot = torrent.snapshot();
+        assert_eq!(snapshot.by_peer_id["qB4650"].connections, 2);
+        assert_eq!(snapshot.by_peer_id["TR3000"].failures, 1);
+        assert_eq!(snapshot.by_version["Transmission 3.00"], 1);
+        assert!(!snapshot.by_peer_id.contains_key("S"));
+
+        let snapshot = global.snapshot();
+        assert_eq!(snapshot.by_peer_id["qB4650"].connections, 2);
+        assert_eq!(snapshot.by_peer_id["S"].connections, 1);
+        assert_eq!(client_code(&[0xff; 20]), "unknown");
+    }
+
+    #[test]
+    fn made_up_names_are_bounded() {
+        let stats = ClientStats::default();
+        for i in 0..MAX_CLIENTS + 10 {
+            stats.handshake(&format!("client {i}"));
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.by_version.len(), MAX_CLIENTS + 1);
+        assert_eq!(snapshot.by_version[OTHER], 10);
+    }
+}
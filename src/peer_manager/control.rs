@@ -0,0 +1,215 @@
+//! The operations the user triggers on a running torrent, e.g. through a signal of the CLI.
+//! They're handled in the loop of the PeerManager like the messages of the peers.
+use std::{mem, time::Instant};
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    peer::rate_limit::RateLimit,
+    peer_manager::{
+        EventLog, EventPage, PeerManager, ResMessage, TorrentState,
+        error::PeerManagerError,
+        progress::{CheckProgress, PROGRESS_INTERVAL},
+        reads::{ReadError, ReadRange, ReadRequest},
+    },
+};
+
+#[derive(Debug)]
+pub(super) enum Command {
+    Reannounce,
+    Recheck,
+    Read(ReadRequest),
+    LimitPeers(RateLimit),
+    LimitPeer([u8; 20], Option<RateLimit>),
+}
+
+#[derive(Debug)]
+pub(super) struct Commands {
+    tx: mpsc::Sender<Command>,
+    pub(super) rx: mpsc::Receiver<Command>,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::channel(8);
+        Self { tx, rx }
+    }
+}
+
+/// Cheap to clone, see [`PeerManager::control`].
+#[derive(Debug, Clone)]
+pub struct TorrentControl {
+    commands: mpsc::Sender<Command>,
+    events: EventLog,
+}
+
+impl TorrentControl {
+    /// Announces to the trackers right away, unless we announced in the last minute.
+    pub fn reannounce(&self) {
+        self.send(Command::Reannounce);
+    }
+
+    /// Hashes every piece on disk again and forgets the ones that don't match,
+    /// the progress shows how far it got.
+    pub fn recheck(&self) {
+        self.send(Command::Recheck);
+    }
+
+    /// see [`PeerManager::limit_peers`]
+    pub fn limit_peers(&self, limit: RateLimit) {
+        self.send(Command::LimitPeers(limit));
+    }
+
+    /// see [`PeerManager::limit_peer`]
+    pub fn limit_peer(&self, peer_id: [u8; 20], limit: Option<RateLimit>) {
+        self.send(Command::LimitPeer(peer_id, limit));
+    }
+
+    /// The events of the torrent from `cursor` on, the cursor of the page fetches the next ones.
+    /// It works after the torrent stopped as well.
+    pub fn events_since(&self, cursor: u64) -> EventPage {
+        self.events.since(cursor)
+    }
+
+    /// The verified bytes `offset..offset + len` of the torrent, waits until they're downloaded.
+    /// The pieces readers wait for are downloaded first, those of the earliest deadline before the
+    /// others. The read fails if they aren't there by the deadline.
+    pub async fn read(
+        &self,
+        offset: u64,
+        len: u64,
+        deadline: Option<Instant>,
+    ) -> Result<Bytes, ReadError> {
+        self.request_read(ReadRange::Bytes { offset, len }, deadline)
+            .await
+    }
+
+    /// the whole piece once it's verified, see [`TorrentControl::read`]
+    pub async fn read_piece(
+        &self,
+        piece_i: u32,
+        deadline: Option<Instant>,
+    ) -> Result<Bytes, ReadError> {
+        self.request_read(ReadRange::Piece(piece_i), deadline).await
+    }
+
+    async fn request_read(
+        &self,
+        range: ReadRange,
+        deadline: Option<Instant>,
+    ) -> Result<Bytes, ReadError> {
+        let (reply, data) = oneshot::channel();
+        let read = ReadRequest {
+            range,
+            deadline,
+            reply,
+        };
+        // unlike the other commands a read waits for a free slot
+        self.commands
+            .send(Command::Read(read))
+            .await
+            .map_err(|_| ReadError::Stopped)?;
+        let data = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), data)
+                .await
+                .map_err(|_| ReadError::DeadlineMissed)?,
+            None => data.await,
+        };
+        data.map_err(|_| ReadError::Stopped)?
+    }
+
+    fn send(&self, command: Command) {
+        if let Err(e) = self.commands.try_send(command) {
+            eprintln!(
+                "The torrent is busy or stopped, skipping the {:?}.",
+                e.into_inner()
+            );
+        }
+    }
+}
+
+impl PeerManager {
+    /// lets the user reannounce or recheck the torrent while it runs
+    pub fn control(&self) -> TorrentControl {
+        TorrentControl {
+            commands: self.commands.tx.clone(),
+            events: self.events.clone(),
+        }
+    }
+
+    pub(super) async fn on_command(&mut self, command: Command) -> Result<(), PeerManagerError> {
+        match command {
+            Command::Reannounce => {
+                if let Some(announcer) = &self.announcer
+                    && let Some(progress) = self.announce_progress()
+                {
+                    announcer.force_announce(progress);
+                }
+                Ok(())
+            }
+            Command::Recheck => self.recheck().await,
+            Command::LimitPeers(limit) => {
+                self.limit_peers(limit);
+                Ok(())
+            }
+            Command::LimitPeer(peer_id, limit) => {
+                self.limit_peer(peer_id, limit);
+                Ok(())
+            }
+            Command::Read(read) => {
+                self.reads.push(read);
+                self.serve_reads().await;
+                Ok(())
+            }
+        }
+    }
+
+    async fn recheck(&mut self) -> Result<(), PeerManagerError> {
+        let (TorrentState::Downloading {
+            metainfo,
+            piece_manager,
+        }
+        | TorrentState::Seeding {
+            metainfo,
+            piece_manager,
+        }) = &mut self.torrent_state
+        else {
+            eprintln!("There's no data to recheck without the metadata.");
+            return Ok(());
+        };
+        let total = metainfo.pieces.len() as u32;
+        let progress = &self.progress;
+        let mut published: Option<Instant> = None;
+        piece_manager
+            .recheck(metainfo, |checked| {
+                // a message per piece would flood the output
+                if checked == total || published.is_none_or(|at| at.elapsed() >= PROGRESS_INTERVAL)
+                {
+                    progress.send_modify(|p| p.checking = Some(CheckProgress { checked, total }));
+                    published = Some(Instant::now());
+                }
+            })
+            .await?;
+        eprintln!(
+            "The recheck found {} of {total} pieces.",
+            piece_manager.have.count_ones()
+        );
+
+        let finished = piece_manager.is_finished();
+        match &self.torrent_state {
+            TorrentState::Downloading { .. } if finished => self.finish_download().await?,
+            TorrentState::Seeding { .. } if !finished => {
+                self.torrent_state =
+                    mem::replace(&mut self.torrent_state, TorrentState::Stopped).into_downloading();
+                self.broadcast_peers(ResMessage::StartDownload).await?;
+            }
+            _ => {}
+        }
+        self.recount_all_interest().await;
+        self.serve_reads().await;
+        self.publish_piece_map();
+        self.publish_progress();
+        Ok(())
+    }
+}
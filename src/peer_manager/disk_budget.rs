@@ -0,0 +1,330 @@
+//! The disk bandwidth all torrents of a client share.
+//! With a few torrents leeching and seeding at once the disk is often slower than the network,
+//! so every read and write waits for its share. The writes of downloaded pieces and the reads of
+//! blocks peers asked for go before the reads of a recheck, among equals the torrents take turns.
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::sync::Mutex;
+
+/// in the order they get the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum DiskOp {
+    /// a downloaded piece
+    Write,
+    /// a block a peer requested
+    Read,
+    /// a piece that's hashed again
+    Verify,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DiskStats {
+    /// the operations waiting for the budget right now
+    pub queue_depth: usize,
+    pub ops: u64,
+    pub bytes: u64,
+    /// how long the operations waited for the budget, in total
+    pub wait: Duration,
+    /// how long the operations took once they got the budget, in total
+    pub service: Duration,
+}
+
+impl DiskStats {
+    pub fn mean_wait(&self) -> Duration {
+        self.wait.checked_div(self.ops as u32).unwrap_or_default()
+    }
+
+    pub fn mean_service(&self) -> Duration {
+        self.service
+            .checked_div(self.ops as u32)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for DiskStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} disk operations with {} KiB, {} waiting, {:?} mean wait, {:?} mean service time",
+            self.ops,
+            self.bytes / 1024,
+            self.queue_depth,
+            self.mean_wait(),
+            self.mean_service()
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    op: DiskOp,
+    torrent: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    /// the bytes that may be moved right now, negative after an operation larger than the burst
+    tokens: f64,
+    refilled_at: Instant,
+    waiting: Vec<Waiter>,
+    next_id: u64,
+    /// when each torrent last got the disk, counted in grants
+    last_turn: HashMap<u64, u64>,
+    turns: u64,
+    stats: DiskStats,
+}
+
+impl State {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: 0.0,
+            refilled_at: now,
+            waiting: Vec::new(),
+            next_id: 0,
+            last_turn: HashMap::new(),
+            turns: 0,
+            stats: DiskStats::default(),
+        }
+    }
+
+    /// at most a second worth of bytes piles up while the disk is idle
+    fn refill(&mut self, now: Instant, rate: u64) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.refilled_at = now;
+    }
+
+    /// the most urgent operation, of the torrent whose turn was longest ago
+    fn next(&self) -> Option<&Waiter> {
+        self.waiting.iter().min_by_key(|w| {
+            let last_turn = self.last_turn.get(&w.torrent).copied().unwrap_or(0);
+            (w.op, last_turn, w.id)
+        })
+    }
+
+    fn grant(&mut self, id: u64) {
+        let i = self.waiting.iter().position(|w| w.id == id).unwrap();
+        let waiter = self.waiting.swap_remove(i);
+        self.tokens -= waiter.bytes as f64;
+        self.turns += 1;
+        self.last_turn.insert(waiter.torrent, self.turns);
+        self.stats.ops += 1;
+        self.stats.bytes += waiter.bytes;
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// bytes per second, None if the disk isn't throttled
+    rate: Option<u64>,
+    state: Mutex<State>,
+    /// woken whenever an operation got the disk, the next one may go now
+    granted: Notify,
+}
+
+/// Cheap to clone, all clones share the budget.
+#[derive(Debug, Clone)]
+pub struct DiskBudget(Arc<Inner>);
+
+impl Default for DiskBudget {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl DiskBudget {
+    /// `rate` is in bytes per second, None means unlimited
+    pub fn new(rate: Option<u64>) -> Self {
+        Self(Arc::new(Inner {
+            rate,
+            state: Mutex::new(State::new(Instant::now())),
+            granted: Notify::new(),
+        }))
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        let state = self.0.state.lock().unwrap();
+        DiskStats {
+            queue_depth: state.waiting.len(),
+            ..state.stats.clone()
+        }
+    }
+
+    /// the handle of one torrent, it takes turns with the others
+    pub(crate) fn share(&self) -> DiskShare {
+        let mut state = self.0.state.lock().unwrap();
+        state.next_id += 1;
+        DiskShare {
+            budget: self.clone(),
+            torrent: state.next_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DiskShare {
+    budget: DiskBudget,
+    torrent: u64,
+}
+
+impl DiskShare {
+    /// waits until the operation may use the disk, the permit is held while it does
+    pub(crate) async fn acquire(&self, op: DiskOp, bytes: u64) -> DiskPermit {
+        let inner = &self.budget.0;
+        let queued_at = Instant::now();
+        let id = {
+            let mut state = inner.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.waiting.push(Waiter {
+                id,
+                op,
+                torrent: self.torrent,
+                bytes,
+            });
+            id
+        };
+        // a cancelled operation mustn't block the ones behind it
+        let mut queued = Queued {
+            budget: &self.budget,
+            id: Some(id),
+        };
+        loop {
+            let mut granted = pin!(inner.granted.notified());
+            granted.as_mut().enable();
+            let wait = {
+                let mut state = inner.state.lock().unwrap();
+                let now = Instant::now();
+                if state.next().is_some_and(|w| w.id == id) {
+                    let wait = match inner.rate {
+                        None => Duration::ZERO,
+                        Some(rate) => {
+                            state.refill(now, rate);
+                            // an operation larger than the burst goes once the bucket is full
+                            let missing = (bytes.min(rate) as f64 - state.tokens).max(0.0);
+                            Duration::from_secs_f64(missing / rate as f64)
+                        }
+                    };
+                    if wait.is_zero() {
+                        state.grant(id);
+                        state.stats.wait += now - queued_at;
+                        queued.id = None;
+                    }
+                    Some(wait)
+                } else {
+                    None
+                }
+            };
+            match wait {
+                Some(wait) if wait.is_zero() => {
+                    inner.granted.notify_waiters();
+                    return DiskPermit {
+                        budget: self.budget.clone(),
+                        granted_at: Instant::now(),
+                    };
+                }
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => granted.await,
+            }
+        }
+    }
+}
+
+struct Queued<'a> {
+    budget: &'a DiskBudget,
+    /// None once the operation got the disk
+    id: Option<u64>,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.budget.0.state.lock().unwrap();
+            state.waiting.retain(|w| w.id != id);
+            drop(state);
+            self.budget.0.granted.notify_waiters();
+        }
+    }
+}
+
+/// Records how long the operation took when it's dropped.
+#[derive(Debug)]
+pub(crate) struct DiskPermit {
+    budget: DiskBudget,
+    granted_at: Instant,
+}
+
+impl Drop for DiskPermit {
+    fn drop(&mut self) {
+        self.budget.0.state.lock().unwrap().stats.service += self.granted_at.elapsed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiter(id: u64, op: DiskOp, torrent: u64) -> Waiter {
+        Waiter {
+            id,
+            op,
+            torrent,
+            bytes: 16 * 1024,
+        }
+    }
+
+    #[test]
+    fn writes_first_then_turns() {
+        let mut state = State::new(Instant::now());
+        state.waiting.extend([
+            waiter(1, DiskOp::Verify, 1),
+            waiter(2, DiskOp::Read, 1),
+            waiter(3, DiskOp::Read, 1),
+            waiter(4, DiskOp::Write, 2),
+            waiter(5, DiskOp::Read, 2),
+        ]);
+        let mut order = Vec::new();
+        while let Some(next) = state.next().map(|w| w.id) {
+            state.grant(next);
+            order.push(next);
+        }
+        // torrent 2 had its turn with the write, so torrent 1 reads first
+        assert_eq!(order, [4, 2, 5, 3, 1]);
+        assert_eq!(state.stats.ops, 5);
+    }
+
+    #[test]
+    fn bounded_burst() {
+        let start = Instant::now();
+        let mut state = State::new(start);
+        state.refill(start + Duration::from_millis(500), 1000);
+        assert_eq!(state.tokens, 500.0);
+        state.refill(start + Duration::from_secs(10), 1000);
+        assert_eq!(state.tokens, 1000.0);
+    }
+
+    #[tokio::test]
+    async fn stats_of_the_operations() {
+        let budget = DiskBudget::new(Some(1 << 30));
+        let (first, second) = (budget.share(), budget.share());
+        drop(first.acquire(DiskOp::Write, 1000).await);
+        let permit = second.acquire(DiskOp::Read, 24).await;
+        assert_eq!(budget.stats().bytes, 1024);
+        assert_eq!(budget.stats().queue_depth, 0);
+        drop(permit);
+        assert_eq!(budget.stats().ops, 2);
+    }
+}
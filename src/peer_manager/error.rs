@@ -4,7 +4,10 @@ use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
 
 use crate::{
-    database::DBError, extensions::magnet_links::MagnetLinkError, peer_manager::ResMessage,
+    config::{ConfigError, LimitError},
+    database::DBError,
+    extensions::magnet_links::MagnetLinkError,
+    peer_manager::ResMessage,
     torrent::TorrentError,
 };
 #[derive(Debug, Error)]
@@ -15,8 +18,18 @@ pub enum PeerManagerError {
     Torrent(#[from] TorrentError),
     #[error("The request-manager failed with the following magnet-link error: {0}")]
     MagnetLink(#[from] MagnetLinkError),
+    #[error("The request-manager failed with the following config error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("The torrent was rejected: {0}")]
+    Limit(#[from] LimitError),
     #[error("Failed to open the file at the path `{path}` with the error: `{error}`")]
     OpenError { path: PathBuf, error: io::Error },
+    #[error("Failed to move the finished download from `{from}` to `{to}`: `{error}`")]
+    RenameError {
+        from: PathBuf,
+        to: PathBuf,
+        error: io::Error,
+    },
     #[error(
         "Failed to send a message: `{msg}` to peer with ID {peer_id:?} with the error: `{error}`"
     )]
@@ -34,8 +47,30 @@ pub enum PeerManagerError {
     PeerNotFound,
     #[error("An error occured when writing to the file: `{0}`")]
     WritingToFile(#[from] io::Error),
+    #[error(
+        "The piece buffer budget of {budget} bytes is smaller than the piece length of {piece_length} bytes"
+    )]
+    PieceBufferBudget { budget: u64, piece_length: u32 },
     #[error("No file name provided")]
     NoFileName,
     #[error("Some other error occured: `{0}`")]
     Other(Box<dyn Error + Send + Sync>),
 }
+
+impl PeerManagerError {
+    /// whether the error only concerns a single peer, the torrent can go on without it
+    pub(super) fn is_peer_scoped(&self) -> bool {
+        matches!(
+            self,
+            PeerManagerError::SendError { .. } | PeerManagerError::PeerNotFound
+        )
+    }
+
+    /// the peer that caused the error, if we know it
+    pub(super) fn peer_id(&self) -> Option<[u8; 20]> {
+        match self {
+            PeerManagerError::SendError { peer_id, .. } => Some(*peer_id),
+            _ => None,
+        }
+    }
+}
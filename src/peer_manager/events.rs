@@ -0,0 +1,188 @@
+//! The recent events of a torrent and of all torrents, e.g. peers that came and went or pieces
+//! that were verified. Every event has a sequence number, a UI that attaches later or reconnects
+//! asks for the events since the last one it saw and learns how many fell out of the ring meanwhile.
+//! How many are kept is [`Config::event_history`].
+//!
+//! [`Config::event_history`]: crate::Config::event_history
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    PeerConnected {
+        peer: SocketAddr,
+        /// e.g. `qBittorrent 4.6.5`, from its peer id
+        client: Option<String>,
+    },
+    PeerDropped {
+        peer: SocketAddr,
+        reason: String,
+    },
+    PeerBanned {
+        ip: IpAddr,
+    },
+    PieceVerified {
+        piece: u32,
+    },
+    PieceCorrupt {
+        piece: u32,
+    },
+    MetadataComplete,
+    DownloadFinished,
+    Stalled {
+        secs: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EventRecord {
+    /// one more than the event before it in the same log
+    pub seq: u64,
+    /// milliseconds since the UNIX epoch
+    pub at_ms: u64,
+    /// hex, the same in the log of the torrent and in the global one
+    pub info_hash: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// what [`EventLog::since`] found
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EventPage {
+    pub events: Vec<EventRecord>,
+    /// pass it to the next call to get only the newer events
+    pub cursor: u64,
+    /// the events after the old cursor that were dropped before they were read
+    pub missed: u64,
+}
+
+#[derive(Debug)]
+struct Ring {
+    events: VecDeque<EventRecord>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+/// Cheap to clone, all clones share the ring.
+/// A torrent's log also records everything into its parent, see [`EventLog::attach_to`].
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    ring: Arc<Mutex<Ring>>,
+    parent: Option<Box<EventLog>>,
+    info_hash: String,
+}
+
+impl EventLog {
+    /// the global log, the events come from the torrents attached to it
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ring: Arc::new(Mutex::new(Ring {
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+                next_seq: 0,
+            })),
+            parent: None,
+            info_hash: String::new(),
+        }
+    }
+
+    pub(super) fn for_torrent(info_hash: [u8; 20], capacity: usize) -> Self {
+        Self {
+            info_hash: hex::encode(info_hash),
+            ..Self::new(capacity)
+        }
+    }
+
+    /// records everything into `parent` as well, with the parent's sequence numbers
+    pub(super) fn attach_to(&mut self, parent: &EventLog) {
+        self.parent = Some(Box::new(parent.clone()));
+    }
+
+    pub(super) fn record(&self, kind: EventKind) {
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.push(at_ms, &self.info_hash, kind);
+    }
+
+    fn push(&self, at_ms: u64, info_hash: &str, kind: EventKind) {
+        if let Some(parent) = &self.parent {
+            parent.push(at_ms, info_hash, kind.clone());
+        }
+        let mut ring = self.ring.lock().unwrap();
+        let event = EventRecord {
+            seq: ring.next_seq,
+            at_ms,
+            info_hash: info_hash.to_owned(),
+            kind,
+        };
+        ring.next_seq += 1;
+        if ring.capacity == 0 {
+            return;
+        }
+        if ring.events.len() == ring.capacity {
+            ring.events.pop_front();
+        }
+        ring.events.push_back(event);
+    }
+
+    /// the events with a sequence number of at least `cursor`, 0 for all that are kept
+    pub fn since(&self, cursor: u64) -> EventPage {
+        let ring = self.ring.lock().unwrap();
+        let oldest = ring.events.front().map_or(ring.next_seq, |event| event.seq);
+        EventPage {
+            events: ring
+                .events
+                .iter()
+                .filter(|event| event.seq >= cursor)
+                .cloned()
+                .collect(),
+            cursor: ring.next_seq,
+            missed: oldest.saturating_sub(cursor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_catches_up() {
+        let global = EventLog::new(3);
+        let mut torrent = EventLog::for_torrent([0xab; 20], 8);
+        torrent.attach_to(&global);
+        for piece in 0..5 {
+            torrent.record(EventKind::PieceVerified { piece });
+        }
+
+        let page = torrent.since(0);
+        assert_eq!(page.events.len(), 5);
+        assert_eq!((page.cursor, page.missed), (5, 0));
+        assert_eq!(page.events[0].info_hash, "ab".repeat(20));
+
+        // the global ring only kept the last 3
+        let page = global.since(1);
+        let seqs: Vec<_> = page.events.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, [2, 3, 4]);
+        assert_eq!((page.cursor, page.missed), (5, 1));
+        assert_eq!(page.events[2].kind, EventKind::PieceVerified { piece: 4 });
+
+        // nothing new
+        assert_eq!(global.since(5).events, []);
+        assert_eq!(global.since(5).missed, 0);
+
+        let json = serde_json::to_value(&page.events[0]).unwrap();
+        assert_eq!(json["event"], "piece_verified");
+        assert_eq!(json["piece"], 2);
+    }
+}
@@ -0,0 +1,124 @@
+//! Finds out the IP the other peers see us under from the `yourip` of their extension handshakes.
+//! A single peer could lie or sit in our LAN, so an IP only counts once enough peers agree on it.
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::peer_manager::PeerManager;
+
+/// the number of peers that have to report an IP before we believe it
+const MIN_VOTES: usize = 2;
+
+#[derive(Debug, Default)]
+pub(crate) struct ExternalIpVotes {
+    /// the IP every peer reported
+    votes: HashMap<[u8; 20], IpAddr>,
+    detected: Option<IpAddr>,
+}
+
+impl ExternalIpVotes {
+    /// returns the new IP if the vote changed what we believe
+    pub(crate) fn vote(&mut self, peer_id: [u8; 20], ip: IpAddr) -> Option<IpAddr> {
+        let ip = ip.to_canonical();
+        if !is_global(&ip) {
+            return None;
+        }
+        self.votes.insert(peer_id, ip);
+
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in self.votes.values() {
+            *counts.entry(*ip).or_default() += 1;
+        }
+        let (leader, votes) = counts.into_iter().max_by_key(|(_, votes)| *votes)?;
+        // on a tie we stick with what we have
+        let current_votes = self
+            .detected
+            .map(|detected| self.votes.values().filter(|ip| **ip == detected).count())
+            .unwrap_or(0);
+        if votes < MIN_VOTES || Some(leader) == self.detected || votes <= current_votes {
+            return None;
+        }
+        self.detected = Some(leader);
+        self.detected
+    }
+}
+
+/// whether the IP could be how the internet sees us
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local())
+        }
+    }
+}
+
+impl PeerManager {
+    /// counts the `yourip` of a peer and re-announces if our IP changed
+    pub(super) fn on_external_ip(&mut self, peer_id: [u8; 20], ip: IpAddr) {
+        let Some(ip) = self.external_ip_votes.vote(peer_id, ip) else {
+            return;
+        };
+        eprintln!("Our external IP is {ip}.");
+        self.external_ip.send_replace(Some(ip));
+        self.announce(None);
+    }
+
+    /// The IP the peers agree they see us under, it's sent to the trackers as `ip`.
+    pub fn subscribe_external_ip(&self) -> tokio::sync::watch::Receiver<Option<IpAddr>> {
+        self.external_ip.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn needs_agreeing_peers() {
+        let mut votes = ExternalIpVotes::default();
+        assert_eq!(votes.vote([1; 20], ip("198.51.1.1")), None);
+        // the same peer doesn't count twice
+        assert_eq!(votes.vote([1; 20], ip("198.51.1.1")), None);
+        assert_eq!(
+            votes.vote([2; 20], ip("198.51.1.1")),
+            Some(ip("198.51.1.1"))
+        );
+        assert_eq!(votes.vote([3; 20], ip("198.51.1.1")), None);
+    }
+
+    #[test]
+    fn ignores_local_addresses() {
+        let mut votes = ExternalIpVotes::default();
+        for i in 0..3 {
+            assert_eq!(votes.vote([i; 20], ip("192.168.0.10")), None);
+            assert_eq!(votes.vote([i + 10; 20], ip("::ffff:10.0.0.1")), None);
+        }
+    }
+
+    #[test]
+    fn follows_a_changed_ip() {
+        let mut votes = ExternalIpVotes::default();
+        votes.vote([1; 20], ip("198.51.1.1"));
+        votes.vote([2; 20], ip("198.51.1.1"));
+        // a tie keeps the old IP
+        assert_eq!(votes.vote([3; 20], ip("198.51.2.2")), None);
+        assert_eq!(votes.vote([4; 20], ip("198.51.2.2")), None);
+        assert_eq!(
+            votes.vote([1; 20], ip("198.51.2.2")),
+            Some(ip("198.51.2.2"))
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! A record of how a download went, for archives that want proof the data arrived intact.
+//! It's published when the last piece is verified, see [`PeerManager::subscribe_integrity`],
+//! and written next to the data if [`Config::integrity_report`] is on.
+//!
+//! [`Config::integrity_report`]: crate::Config::integrity_report
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::{
+    peer_manager::{PeerManager, TorrentState},
+    torrent::Metainfo,
+};
+
+/// Who sent us the data.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Contributor {
+    /// the hex encoded peer id
+    Peer(String),
+    WebSeed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Contribution {
+    pub contributor: Contributor,
+    /// including the blocks of pieces that failed the hash
+    pub bytes: u64,
+    /// of all bytes we received in this run
+    pub percent: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PieceVerification {
+    pub piece: u32,
+    /// how often the piece failed the hash before it passed
+    pub hash_failures: u32,
+    /// false if it was on disk already when the torrent started
+    pub downloaded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IntegrityReport {
+    pub info_hash: String,
+    pub name: String,
+    pub size: u64,
+    pub pieces: Vec<PieceVerification>,
+    /// the bytes of the pieces that failed the hash and were downloaded again
+    pub corrupt_bytes: u64,
+    /// the biggest contributors first
+    pub contributions: Vec<Contribution>,
+    /// since the torrent started, a resumed download only counts this run
+    pub elapsed_secs: f64,
+    /// the verified bytes of this run per second
+    pub average_download_rate: f64,
+    /// the uploaded bytes of this run per second
+    pub average_upload_rate: f64,
+}
+
+#[derive(Debug)]
+pub(super) struct IntegrityLog {
+    started_at: Instant,
+    hash_failures: HashMap<u32, u32>,
+    verified: HashSet<u32>,
+    received: HashMap<Contributor, u64>,
+    corrupt_bytes: u64,
+}
+
+impl IntegrityLog {
+    pub(super) fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            hash_failures: HashMap::new(),
+            verified: HashSet::new(),
+            received: HashMap::new(),
+            corrupt_bytes: 0,
+        }
+    }
+
+    pub(super) fn received(&mut self, contributor: Contributor, bytes: u64) {
+        *self.received.entry(contributor).or_default() += bytes;
+    }
+
+    pub(super) fn corrupt(&mut self, piece_i: u32, metainfo: &Metainfo) {
+        *self.hash_failures.entry(piece_i).or_default() += 1;
+        self.corrupt_bytes += piece_len(piece_i, metainfo);
+    }
+
+    pub(super) fn verified(&mut self, piece_i: u32) {
+        self.verified.insert(piece_i);
+    }
+
+    fn report(
+        &self,
+        metainfo: &Metainfo,
+        downloaded: u64,
+        uploaded: u64,
+        now: Instant,
+    ) -> IntegrityReport {
+        let pieces = (0..metainfo.pieces.len() as u32)
+            .map(|piece| PieceVerification {
+                piece,
+                hash_failures: self.hash_failures.get(&piece).copied().unwrap_or(0),
+                downloaded: self.verified.contains(&piece),
+            })
+            .collect();
+        let total = self.received.values().sum::<u64>().max(1) as f64;
+        let mut contributions: Vec<_> = self
+            .received
+            .iter()
+            .map(|(contributor, bytes)| Contribution {
+                contributor: contributor.clone(),
+                bytes: *bytes,
+                percent: *bytes as f64 * 100.0 / total,
+            })
+            .collect();
+        contributions.sort_by_key(|c| std::cmp::Reverse(c.bytes));
+        let elapsed_secs = now.duration_since(self.started_at).as_secs_f64();
+        // a torrent that was complete on disk finishes right away
+        let secs = elapsed_secs.max(f64::EPSILON);
+        IntegrityReport {
+            info_hash: hex::encode(metainfo.info_hash().0),
+            name: metainfo.name.clone(),
+            size: metainfo.total_size(),
+            pieces,
+            corrupt_bytes: self.corrupt_bytes,
+            contributions,
+            elapsed_secs,
+            average_download_rate: downloaded as f64 / secs,
+            average_upload_rate: uploaded as f64 / secs,
+        }
+    }
+}
+
+/// the last piece may be shorter
+fn piece_len(piece_i: u32, metainfo: &Metainfo) -> u64 {
+    let piece_length = metainfo.piece_length as u64;
+    let start = piece_i as u64 * piece_length;
+    metainfo
+        .total_size()
+        .saturating_sub(start)
+        .min(piece_length)
+}
+
+/// `movie.mkv` gets `movie.mkv.integrity.json`
+fn report_path(data: &Path) -> PathBuf {
+    let mut path = data.as_os_str().to_owned();
+    path.push(".integrity.json");
+    PathBuf::from(path)
+}
+
+impl PeerManager {
+    /// The report of the finished download, None until it finishes.
+    pub fn subscribe_integrity(&self) -> tokio::sync::watch::Receiver<Option<IntegrityReport>> {
+        self.integrity.subscribe()
+    }
+
+    /// publishes the report once the download is finished and the file has its final name
+    pub(super) fn finish_integrity_report(&self) {
+        let TorrentState::Seeding {
+            metainfo,
+            piece_manager,
+        } = &self.torrent_state
+        else {
+            return;
+        };
+        let report = self.integrity_log.report(
+            metainfo,
+            piece_manager.downloaded,
+            piece_manager.session_uploaded(),
+            self.clock.now(),
+        );
+        if self.config.integrity_report {
+            let path = report_path(piece_manager.path());
+            let written = serde_json::to_vec_pretty(&report)
+                .map_err(std::io::Error::from)
+                .and_then(|json| std::fs::write(&path, json));
+            if let Err(e) = written {
+                eprintln!(
+                    "Failed to write the integrity report to {}: {e}",
+                    path.display()
+                );
+            }
+        }
+        self.integrity.send_replace(Some(report));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+
+    #[test]
+    fn report_of_a_download() {
+        // 3 pieces, the last one is 1 byte long
+        let metainfo = test_fixtures::metainfo(32769, 16384);
+        let start = Instant::now();
+        let mut log = IntegrityLog::new(start);
+        let (good, liar) = (
+            Contributor::Peer("01".into()),
+            Contributor::Peer("02".into()),
+        );
+        log.received(liar.clone(), 16384);
+        log.corrupt(0, &metainfo);
+        log.received(good.clone(), 16384);
+        log.received(Contributor::WebSeed("http://seed".into()), 16384);
+        log.received(good.clone(), 16384);
+        log.verified(0);
+        log.corrupt(2, &metainfo);
+        log.verified(2);
+
+        let report = log.report(
+            &metainfo,
+            32769,
+            0,
+            start + std::time::Duration::from_secs(2),
+        );
+        assert_eq!(report.size, 32769);
+        assert_eq!(report.corrupt_bytes, 16385);
+        assert_eq!(
+            report.pieces[..2],
+            [
+                PieceVerification {
+                    piece: 0,
+                    hash_failures: 1,
+                    downloaded: true,
+                },
+                // it was on disk already
+                PieceVerification {
+                    piece: 1,
+                    hash_failures: 0,
+                    downloaded: false,
+                },
+            ]
+        );
+        assert_eq!(report.contributions[0].contributor, good);
+        assert_eq!(report.contributions[0].percent, 50.0);
+        assert_eq!(report.contributions.len(), 3);
+        assert_eq!(report.elapsed_secs, 2.0);
+        assert_eq!(report.average_download_rate, 32769.0 / 2.0);
+        assert_eq!(
+            report_path(Path::new("/data/test")),
+            Path::new("/data/test.integrity.json")
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Whether we're interested in a peer, i.e. it has a piece we don't.
+//! We count per peer the pieces it has that we need: a Have of such a piece raises the count, a
+//! piece we finish lowers it for every peer that has it. The peer hears from us when the count
+//! crosses zero, a seed stays interesting until we have everything.
+//! Without the metadata we're interested in everyone, we can't tell what we need and the metadata
+//! requests only go out while we're interested.
+use std::{collections::HashMap, sync::atomic::Ordering};
+
+use crate::peer_manager::{PeerManager, ResMessage, TorrentState, piece_manager::PieceSet};
+
+#[derive(Debug, Default)]
+pub(super) struct PeerNeeds(HashMap<[u8; 20], usize>);
+
+impl PeerNeeds {
+    /// starts over from the pieces of the peer, returns whether we need any of them
+    pub(super) fn recount(&mut self, peer_id: [u8; 20], has: &PieceSet, have: &PieceSet) -> bool {
+        let needed = has.iter_ones().filter(|i| !have.contains(*i)).count();
+        self.0.insert(peer_id, needed);
+        needed > 0
+    }
+
+    /// the peer got a piece we need
+    pub(super) fn gained(&mut self, peer_id: [u8; 20]) {
+        *self.0.entry(peer_id).or_default() += 1;
+    }
+
+    /// we finished a piece the peer has, returns whether we still need something from it
+    pub(super) fn finished(&mut self, peer_id: [u8; 20]) -> bool {
+        let needed = self.0.entry(peer_id).or_default();
+        *needed = needed.saturating_sub(1);
+        *needed > 0
+    }
+
+    pub(super) fn remove_peer(&mut self, peer_id: &[u8; 20]) {
+        self.0.remove(peer_id);
+    }
+}
+
+impl PeerManager {
+    pub(super) fn our_pieces(&self) -> Option<&PieceSet> {
+        match &self.torrent_state {
+            TorrentState::Downloading { piece_manager, .. }
+            | TorrentState::Seeding { piece_manager, .. } => Some(&piece_manager.have),
+            TorrentState::WaitingForMetadata { .. } | TorrentState::Stopped => None,
+        }
+    }
+
+    /// counts anew what we need from the peer, e.g. after its bitfield
+    pub(super) async fn recount_interest(&mut self, peer_id: [u8; 20]) {
+        let Some(has) = self.get_peer_has(&peer_id) else {
+            return;
+        };
+        let interested = match &self.torrent_state {
+            TorrentState::Downloading { piece_manager, .. } => {
+                self.needs.recount(peer_id, &has, &piece_manager.have)
+            }
+            // nothing to count, we need nothing
+            TorrentState::Seeding { .. } => false,
+            TorrentState::WaitingForMetadata { .. } | TorrentState::Stopped => true,
+        };
+        self.tell_interest(peer_id, interested).await;
+    }
+
+    /// after our pieces changed other than one by one, e.g. the metadata arrived or a recheck
+    pub(super) async fn recount_all_interest(&mut self) {
+        let peer_ids: Vec<_> = self.peers.keys().copied().collect();
+        for peer_id in peer_ids {
+            self.recount_interest(peer_id).await;
+        }
+    }
+
+    /// the peer announced a piece with a Have
+    pub(super) async fn peer_gained_piece(&mut self, peer_id: [u8; 20], piece_i: usize) {
+        if self
+            .our_pieces()
+            .is_some_and(|have| !have.contains(piece_i))
+        {
+            self.needs.gained(peer_id);
+            self.tell_interest(peer_id, true).await;
+        }
+    }
+
+    /// we finished the piece, the peers that only had this one for us are no longer interesting
+    pub(super) async fn we_gained_piece(&mut self, piece_i: usize) {
+        let peer_ids: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, conn)| conn.identifier.0.has.lock().unwrap().contains(piece_i))
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in peer_ids {
+            let interested = self.needs.finished(peer_id);
+            self.tell_interest(peer_id, interested).await;
+        }
+    }
+
+    async fn tell_interest(&mut self, peer_id: [u8; 20], interested: bool) {
+        let Some(conn) = self.peers.get(&peer_id) else {
+            return;
+        };
+        if conn.identifier.0.am_interested.load(Ordering::Relaxed) != interested {
+            // its connection may be gone already
+            let _ = self
+                .send_peer(peer_id, ResMessage::Interested(interested))
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interest_follows_the_needed_pieces() {
+        let mut needs = PeerNeeds::default();
+        let peer = [1; 20];
+        let have = PieceSet::from_bools(&[true, false, false]);
+        assert!(needs.recount(peer, &PieceSet::from_bools(&[true, true, false]), &have));
+        // a Have of piece 2
+        needs.gained(peer);
+        // we finish piece 1, piece 2 is left
+        assert!(needs.finished(peer));
+        assert!(!needs.finished(peer));
+        assert!(!needs.finished(peer));
+
+        let seed = [2; 20];
+        let have_all = PieceSet::from_bools(&[true; 3]);
+        assert!(!needs.recount(seed, &have_all, &have_all));
+        needs.remove_peer(&seed);
+        assert!(!needs.0.contains_key(&seed));
+    }
+}
@@ -0,0 +1,121 @@
+//! Some ISPs spot seeds by their full bitfield and throttle them. With a lazy bitfield, see
+//! [`Config::lazy_bitfield`], the bitfield a new peer gets leaves out a few random pieces we have,
+//! they follow as Have messages, a few every [`LAZY_HAVE_INTERVAL`].
+//!
+//! [`Config::lazy_bitfield`]: crate::Config::lazy_bitfield
+use std::{collections::HashMap, time::Duration};
+
+use rand::seq::IteratorRandom;
+
+use crate::{
+    messages::payloads::BitfieldPayload,
+    peer_manager::{PeerManager, ResMessage, piece_manager::PieceSet},
+};
+
+pub(super) const LAZY_HAVE_INTERVAL: Duration = Duration::from_secs(1);
+/// the most pieces a bitfield leaves out
+const WITHHELD_MAX: usize = 32;
+/// the Haves a peer gets per interval
+const HAVES_PER_TICK: usize = 4;
+
+/// the pieces each peer wasn't told about yet
+#[derive(Debug, Default)]
+pub(super) struct LazyHaves(HashMap<[u8; 20], Vec<u32>>);
+
+impl LazyHaves {
+    /// takes some of the pieces out of the bitfield, they're sent as Haves later
+    pub(super) fn withhold(&mut self, peer_id: [u8; 20], bitfield: &mut PieceSet) {
+        let withheld: Vec<u32> = bitfield
+            .iter()
+            .enumerate()
+            .filter(|(_, have)| *have)
+            .map(|(i, _)| i as u32)
+            .choose_multiple(&mut rand::rng(), WITHHELD_MAX);
+        for piece_i in &withheld {
+            bitfield.remove(*piece_i as usize);
+        }
+        if !withheld.is_empty() {
+            self.0.insert(peer_id, withheld);
+        }
+    }
+
+    /// the next few pieces of every peer, a peer is forgotten once it was told everything
+    fn next(&mut self) -> Vec<([u8; 20], Vec<u32>)> {
+        let batches = self
+            .0
+            .iter_mut()
+            .map(|(peer_id, pending)| {
+                let n = pending.len().min(HAVES_PER_TICK);
+                (*peer_id, pending.drain(..n).collect())
+            })
+            .collect();
+        self.0.retain(|_, pending| !pending.is_empty());
+        batches
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(super) fn remove_peer(&mut self, peer_id: &[u8; 20]) {
+        self.0.remove(peer_id);
+    }
+}
+
+impl PeerManager {
+    /// overrides [`Config::lazy_bitfield`] for this torrent, for the peers that connect from now on
+    ///
+    /// [`Config::lazy_bitfield`]: crate::Config::lazy_bitfield
+    pub fn set_lazy_bitfield(&mut self, lazy: bool) {
+        self.lazy_bitfield = lazy;
+    }
+
+    /// the bitfield we send the peer, see the module docs
+    pub(super) fn bitfield_for(
+        &mut self,
+        peer_id: [u8; 20],
+        mut have: PieceSet,
+    ) -> BitfieldPayload {
+        if self.lazy_bitfield {
+            self.lazy_haves.withhold(peer_id, &mut have);
+        }
+        BitfieldPayload::from_packed(have.to_packed())
+    }
+
+    pub(super) async fn send_lazy_haves(&mut self) {
+        for (peer_id, pieces) in self.lazy_haves.next() {
+            for piece_i in pieces {
+                // the peer only turns it into a Have, its connection may be gone already
+                let _ = self
+                    .send_peer(peer_id, ResMessage::FinishedPiece(piece_i))
+                    .await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn withheld_pieces_follow_as_haves() {
+        let mut lazy = LazyHaves::default();
+        let mut bitfield = PieceSet::from_bools(&[true, false, true, true, true, false, true]);
+        lazy.withhold([1; 20], &mut bitfield);
+        // there are fewer than WITHHELD_MAX pieces, all of them are left out
+        assert_eq!(bitfield.count_ones(), 0);
+
+        let mut told: Vec<u32> = lazy.next().into_iter().flat_map(|(_, p)| p).collect();
+        assert_eq!(told.len(), HAVES_PER_TICK);
+        told.extend(lazy.next().into_iter().flat_map(|(_, p)| p));
+        told.sort();
+        assert_eq!(told, [0, 2, 3, 4, 6]);
+        assert!(lazy.is_empty());
+
+        // only pieces we have are withheld
+        let mut nothing = PieceSet::from_bools(&[false; 3]);
+        lazy.withhold([2; 20], &mut nothing);
+        assert!(lazy.is_empty());
+    }
+}
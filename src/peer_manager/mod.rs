@@ -1,29 +1,102 @@
 //! a peer announces to us that he exists via the mpsc
 //! We create peer with our current have bitfield which he can send to new connections and we send
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt, mem,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bytes::{Bytes, BytesMut};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     Torrent,
+    clock::{Clock, SystemClock},
+    config::Config,
     database::DBConnection,
     extensions::{
         ExtensionMessage, ExtensionType,
         magnet_links::{MagnetLink, metadata_piece_manager::MetadataPieceManager},
     },
-    messages::payloads::{BitfieldPayload, RequestPiecePayload, ResponsePiecePayload},
-    peer::conn::PeerState,
-    peer_manager::{error::PeerManagerError, piece_manager::PieceManager},
+    messages::payloads::{
+        BitfieldError, BitfieldPayload, RequestPiecePayload, ResponsePiecePayload,
+    },
+    peer::{
+        conn::PeerState,
+        rate_limit::{Bandwidth, RateLimit},
+        trace::WireTrace,
+    },
+    peer_manager::{
+        bans::Strikes,
+        choker::{CHOKE_INTERVAL, Choker},
+        control::Commands,
+        disk_budget::DiskShare,
+        error::PeerManagerError,
+        integrity::IntegrityLog,
+        interest::PeerNeeds,
+        lazy_bitfield::{LAZY_HAVE_INTERVAL, LazyHaves},
+        peer_slots::PeerSlot,
+        pex::{PEX_INTERVAL, PeerExchange},
+        piece_manager::{CompletedPiece, PieceManager, piece_selector::PieceSelector},
+        progress::{PROGRESS_INTERVAL, ThroughputEstimator},
+        quality::PeerQualities,
+        reads::ReadRequest,
+        upload_queue::UploadQueue,
+        watchdog::{WATCHDOG_INTERVAL, Watchdog},
+        webseed::{WEB_SEED_INTERVAL, WebSeeds},
+    },
     torrent::{InfoHash, Metainfo},
+    tracker::{AnnounceHandle, AnnounceProgress, Event, TrackerTiers},
 };
 
+mod bans;
+mod candidates;
+mod choker;
+mod client_stats;
+mod control;
+mod disk_budget;
 pub mod error;
+mod events;
+mod external_ip;
+mod integrity;
+mod interest;
+mod lazy_bitfield;
+mod peer_slots;
+mod pex;
 mod piece_manager;
+mod piece_map;
+mod progress;
+mod quality;
+mod reads;
+mod seeding;
+mod upload_queue;
+mod watchdog;
+mod webseed;
+
+pub(crate) use candidates::PeerCandidates;
+pub use client_stats::{ClientCounts, ClientStats, ClientStatsSnapshot};
+pub use control::TorrentControl;
+pub use disk_budget::{DiskBudget, DiskStats};
+pub use events::{EventKind, EventLog, EventPage, EventRecord};
+pub(crate) use external_ip::ExternalIpVotes;
+pub use integrity::{Contribution, Contributor, IntegrityReport, PieceVerification};
+pub use peer_slots::PeerSlots;
+pub use pex::PexPolicy;
+pub(crate) use piece_manager::PieceSet;
+pub use piece_map::{PieceMap, PieceMapPage, PieceRun, PieceStatus};
+pub use progress::{CheckProgress, Eta, ProgressSnapshot};
+pub use reads::ReadError;
+pub use watchdog::StallReport;
 
 pub const BLOCK_QUEUE_SIZE_MAX: usize = 20;
 /// how many pieces are in the queue at max
 pub(crate) const MAX_PIECES_IN_PARALLEL: usize = 5;
+/// how often we look for block requests that timed out, see [`Config::block_timeout_secs`]
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct PeerManager {
@@ -31,6 +104,81 @@ pub struct PeerManager {
     rx: mpsc::Receiver<ReqMsgFromPeer>,
     announce_urls: Vec<url::Url>,
     peers: HashMap<[u8; 20], PeerConn>,
+    config: Arc<Config>,
+    /// a ratio group that was assigned while we didn't have the metainfo yet
+    /// it's persisted as soon as the DB entry is created
+    pending_ratio_group: Option<String>,
+    /// the latest snapshot of the piece states, see [`PeerManager::subscribe_piece_map`]
+    piece_map: watch::Sender<PieceMap>,
+    /// the pieces changed since the map was published, see [`PeerManager::piece_map_changed`]
+    piece_map_stale: bool,
+    /// see [`PeerManager::subscribe_progress`]
+    progress: watch::Sender<ProgressSnapshot>,
+    throughput: ThroughputEstimator,
+    /// handed to every peer that connects
+    wire_trace: WireTrace,
+    /// the block requests of the peers we haven't served yet
+    upload_queue: UploadQueue,
+    /// which peers may request blocks from us
+    choker: Choker,
+    /// how good each peer is for us
+    quality: PeerQualities,
+    /// the peers every peer heard about from us
+    pex: PeerExchange,
+    /// every address we heard of a peer at, shared with the tasks that dial them
+    candidates: PeerCandidates,
+    /// the HTTP servers we download the pieces no peer has from
+    web_seeds: WebSeeds,
+    /// the metainfo once we have it, see [`PeerManager::subscribe_metadata`]
+    metadata: watch::Sender<Option<Metainfo>>,
+    /// `run` returns as soon as the metadata of a magnet link is complete
+    stop_after_metadata: bool,
+    /// the `yourip` the peers sent us
+    external_ip_votes: ExternalIpVotes,
+    /// see [`PeerManager::subscribe_external_ip`]
+    external_ip: watch::Sender<Option<IpAddr>>,
+    /// see [`PeerManager::client_stats`]
+    client_stats: ClientStats,
+    /// None if nobody wants us to announce, e.g. in tests
+    announcer: Option<AnnounceHandle>,
+    /// see [`PeerManager::control`]
+    commands: Commands,
+    /// see [`PeerManager::limit_peers`]
+    default_peer_limit: RateLimit,
+    /// see [`PeerManager::limit_peer`]
+    peer_limits: HashMap<[u8; 20], RateLimit>,
+    /// the reads of [`TorrentControl::read`] that wait for their pieces
+    reads: Vec<ReadRequest>,
+    /// see [`PeerManager::share_disk`]
+    disk: DiskShare,
+    /// see [`PeerManager::share_bandwidth`]
+    bandwidth: Bandwidth,
+    /// restarts the download when it hangs
+    watchdog: Watchdog,
+    /// see [`PeerManager::subscribe_stalls`]
+    stalls: watch::Sender<Option<StallReport>>,
+    /// what goes into the integrity report
+    integrity_log: IntegrityLog,
+    /// how many pieces we need from each peer, see [`interest`]
+    needs: PeerNeeds,
+    /// see [`PeerManager::set_lazy_bitfield`]
+    lazy_bitfield: bool,
+    /// the pieces the lazy bitfields left out
+    lazy_haves: LazyHaves,
+    /// the corrupt pieces of each IP, see [`bans`]
+    strikes: Strikes,
+    /// see [`PeerManager::subscribe_integrity`]
+    integrity: watch::Sender<Option<IntegrityReport>>,
+    /// see [`PeerManager::events`]
+    events: EventLog,
+    /// see [`PeerManager::set_clock`]
+    clock: Arc<dyn Clock>,
+    /// see [`PeerManager::share_peer_slots`]
+    peer_slots: PeerSlots,
+    /// the slot of every peer in `peers`
+    held_slots: HashMap<[u8; 20], PeerSlot>,
+    /// cancelled when the program is shutting down
+    shutdown: CancellationToken,
 }
 
 #[derive(Debug)]
@@ -39,34 +187,71 @@ enum TorrentState {
     WaitingForMetadata {
         file_path: Option<PathBuf>,
         metadata_piece_manager: MetadataPieceManager, // A helper to track downloaded metadata pieces
+        /// the length the magnet link told us, we report it as `left` to the trackers
+        exact_length: Option<u64>,
     },
     // We have the metadata and can download the actual files.
     Downloading {
         metainfo: Metainfo,
         piece_manager: PieceManager,
     },
-    // Optional: A seeding state
+    // We have the whole file and only upload to other peers.
     Seeding {
         metainfo: Metainfo,
-        // ... state relevant to seeding
+        piece_manager: PieceManager,
     },
+    // The seeding goal of the ratio group is reached, there's nothing left to do.
+    Stopped,
 }
 
 impl TorrentState {
+    /// the trackers of the torrent are merged into the ones the DB has
     async fn from_info(
         db_conn: DBConnection,
         file_path: Option<PathBuf>,
-        metainfo: Metainfo,
-        announce: url::Url,
+        torrent: Torrent,
+        config: &Config,
     ) -> Result<Self, PeerManagerError> {
-        let torrent = Torrent {
-            announce,
-            info: metainfo,
-        };
-        Ok(TorrentState::Downloading {
-            piece_manager: PieceManager::new(db_conn, file_path, &torrent).await?,
-            metainfo: torrent.info,
-        })
+        let piece_manager = PieceManager::new(db_conn, file_path, &torrent, config).await?;
+        if piece_manager.is_finished() {
+            Ok(TorrentState::Seeding {
+                metainfo: torrent.info,
+                piece_manager,
+            })
+        } else {
+            Ok(TorrentState::Downloading {
+                piece_manager,
+                metainfo: torrent.info,
+            })
+        }
+    }
+
+    /// moves a finished download into the seeding state
+    fn into_seeding(self) -> Self {
+        match self {
+            TorrentState::Downloading {
+                metainfo,
+                piece_manager,
+            } => TorrentState::Seeding {
+                metainfo,
+                piece_manager,
+            },
+            other => other,
+        }
+    }
+
+    /// moves a seeding torrent that lost pieces back to downloading
+    fn into_downloading(self) -> Self {
+        match self {
+            TorrentState::Seeding {
+                metainfo,
+                piece_manager,
+            } => TorrentState::Downloading {
+                metainfo,
+                piece_manager,
+            },
+            other => other,
+        }
     }
 }
 
@@ -77,9 +262,26 @@ pub enum ReqMessage {
     NeedBlockQueue,
     GotBlock(ResponsePiecePayload),
     NeedBlock(RequestPiecePayload),
+    /// the peer no longer wants a block it requested, e.g. because another peer sent it
+    CancelBlock(RequestPiecePayload),
     WhatDoWeHave,
     Extension(ExtensionMessage),
     PeerDisconnected(InfoHash),
+    /// the peer announced extension support but never sent the extension handshake
+    ExtensionsDowngraded,
+    /// the IP the peer sees us under according to its extension handshake
+    ExternalIp(IpAddr),
+    /// the `v` of the peer's extension handshake
+    ClientVersion(String),
+    /// the `p` of the peer's extension handshake, the port it accepts connections on
+    ListenPort(u16),
+    /// the peers a peer told us about through peer exchange
+    PexPeers(Vec<SocketAddr>),
+    /// the peer got a piece, the PeerManager is the only one that writes the `has` of a peer
+    /// it counts towards the rarity of the piece and our interest in the peer
+    PeerHas(u32),
+    /// the pieces the peer has, replacing what we knew and its share of the rarity counts
+    PeerBitfield(BitfieldPayload),
 }
 
 pub struct ReqMsgFromPeer {
@@ -87,10 +289,6 @@ pub struct ReqMsgFromPeer {
     pub(crate) msg: ReqMessage,
 }
 
-// TODO Next-up:
-//  - rarest-first-piece-selection
-//  - choking: 4 active downloaders
-
 #[derive(Debug, Clone, PartialEq)]
 pub enum ResMessage {
     /// indication to the peer to start the download loop
@@ -98,8 +296,18 @@ pub enum ResMessage {
     NewBlockQueue(Vec<RequestPiecePayload>),
     Block(Option<ResponsePiecePayload>),
     WeHave(BitfieldPayload),
+    /// we have every piece, the peer gets the full bitfield and is only served from now on
+    WeSeed(BitfieldPayload),
     FinishedPiece(u32),
     FinishedFile,
+    /// whether the peer has a piece we need
+    Interested(bool),
+    /// the torrent is stopped, the peer should sever the connection
+    Disconnect,
+    /// the peer may no longer request blocks from us
+    Choke,
+    /// the peer may request blocks from us
+    Unchoke,
     /// Data that is passed to BasicExtensionPayload.
     /// The peer has to 'add' the extended_msg_id itself since it is peer-dependent
     ExtensionData((ExtensionType, Bytes)),
@@ -135,6 +343,8 @@ pub(crate) struct PieceState {
     blocks: Vec<BlockState>,
     piece_i: u32,
     buf: BytesMut,
+    /// the peers that sent the blocks, each one once
+    senders: Vec<[u8; 20]>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -158,201 +368,725 @@ impl PeerManager {
         rx: mpsc::Receiver<ReqMsgFromPeer>,
         file_path: Option<PathBuf>,
         magnet_link: MagnetLink,
+        config: Arc<Config>,
     ) -> Result<Self, PeerManagerError> {
-        let db_conn = DBConnection::new(magnet_link.info_hash).await?;
-        if let Some(file_entry) = db_conn.get_entry().await? {
-            Ok(Self {
-                torrent_state: TorrentState::from_info(
+        let db_conn = DBConnection::new(&config.paths(), magnet_link.info_hash).await?;
+        let announce_urls = magnet_link.get_announce_urls()?;
+        let torrent_state = if let Some(file_entry) = db_conn.get_entry().await? {
+            let torrent = Torrent {
+                announce: None,
+                announce_list: Some(vec![
+                    announce_urls.iter().map(url::Url::to_string).collect(),
+                ]),
+                url_list: Vec::new(),
+                info: file_entry.torrent_info,
+            };
+            TorrentState::from_info(
+                db_conn,
+                Some(file_entry.file.to_path_buf()),
+                torrent,
+                &config,
+            )
+            .await?
+        } else {
+            TorrentState::WaitingForMetadata {
+                file_path,
+                metadata_piece_manager: MetadataPieceManager::from_db(
+                    magnet_link.info_hash,
                     db_conn,
-                    Some(file_entry.file.to_path_buf()),
-                    file_entry.torrent_info,
-                    file_entry.announce.clone(),
                 )
                 .await?,
-                rx,
-                announce_urls: vec![file_entry.announce],
-                peers: HashMap::new(),
-            })
-        } else {
-            let torrent_state = TorrentState::WaitingForMetadata {
-                file_path,
-                metadata_piece_manager: MetadataPieceManager::new(magnet_link.info_hash),
-            };
-            Ok(Self {
-                torrent_state,
-                rx,
-                announce_urls: magnet_link.get_announce_urls()?,
-                peers: HashMap::new(),
-            })
-        }
+                exact_length: magnet_link.get_exact_length(),
+            }
+        };
+        Ok(Self::new(
+            rx,
+            torrent_state,
+            announce_urls,
+            WebSeeds::new(magnet_link.get_web_seeds()),
+            magnet_link.info_hash,
+            config,
+        ))
     }
 
     pub async fn init_from_torrent(
         rx: mpsc::Receiver<ReqMsgFromPeer>,
         file_path: Option<PathBuf>,
         torrent: Torrent,
+        config: Arc<Config>,
     ) -> Result<Self, PeerManagerError> {
+        config.limits.check(&torrent.info)?;
         let info_hash = torrent.info.info_hash();
-        let db_conn = DBConnection::new(info_hash).await?;
-        let torrent_state =
-            TorrentState::from_info(db_conn, file_path, torrent.info, torrent.announce.clone())
-                .await?;
+        let db_conn = DBConnection::new(&config.paths(), info_hash).await?;
+        let announce_urls = torrent.announce.iter().cloned().collect();
+        let web_seeds = WebSeeds::new(
+            torrent
+                .url_list
+                .iter()
+                .filter_map(|url| url::Url::parse(url).ok()),
+        );
+        let torrent_state = TorrentState::from_info(db_conn, file_path, torrent, &config).await?;
 
-        Ok(Self {
+        Ok(Self::new(
+            rx,
+            torrent_state,
+            announce_urls,
+            web_seeds,
+            info_hash,
+            config,
+        ))
+    }
+
+    /// what every PeerManager starts with, the constructors only differ in the arguments
+    fn new(
+        rx: mpsc::Receiver<ReqMsgFromPeer>,
+        torrent_state: TorrentState,
+        announce_urls: Vec<url::Url>,
+        web_seeds: WebSeeds,
+        info_hash: InfoHash,
+        config: Arc<Config>,
+    ) -> Self {
+        Self {
             torrent_state,
             rx,
-            announce_urls: vec![torrent.announce],
+            announce_urls,
+            web_seeds,
+            events: EventLog::for_torrent(info_hash.0, config.event_history),
+            wire_trace: WireTrace::new(config.paths().wire_trace_file(&info_hash)),
             peers: HashMap::new(),
-        })
+            choker: Choker::new(config.upload_slots),
+            lazy_bitfield: config.lazy_bitfield,
+            clock: Arc::new(SystemClock),
+            peer_slots: PeerSlots::default(),
+            held_slots: HashMap::new(),
+            candidates: PeerCandidates::new(config.max_peers_per_torrent),
+            watchdog: Watchdog::new(config.stall_timeout(), Instant::now()),
+            config,
+            pending_ratio_group: None,
+            piece_map: watch::Sender::new(PieceMap::default()),
+            piece_map_stale: false,
+            progress: watch::Sender::new(ProgressSnapshot::default()),
+            throughput: ThroughputEstimator::default(),
+            upload_queue: UploadQueue::default(),
+            quality: PeerQualities::default(),
+            pex: PeerExchange::default(),
+            metadata: watch::Sender::new(None),
+            stop_after_metadata: false,
+            external_ip_votes: ExternalIpVotes::default(),
+            external_ip: watch::Sender::new(None),
+            client_stats: ClientStats::default(),
+            announcer: None,
+            commands: Commands::default(),
+            default_peer_limit: RateLimit::default(),
+            peer_limits: HashMap::new(),
+            reads: Vec::new(),
+            stalls: watch::Sender::new(None),
+            integrity_log: IntegrityLog::new(Instant::now()),
+            needs: PeerNeeds::default(),
+            lazy_haves: LazyHaves::default(),
+            strikes: Strikes::default(),
+            integrity: watch::Sender::new(None),
+            disk: DiskShare::default(),
+            bandwidth: Bandwidth::default(),
+            shutdown: CancellationToken::new(),
+        }
+        .with_piece_map()
+    }
+
+    /// publishes the initial piece map and the metainfo if we have it already
+    fn with_piece_map(self) -> Self {
+        self.publish_piece_map();
+        if let TorrentState::Downloading { metainfo, .. } | TorrentState::Seeding { metainfo, .. } =
+            &self.torrent_state
+        {
+            self.metadata.send_replace(Some(metainfo.clone()));
+        }
+        self
+    }
+
+    /// The metainfo of the torrent, None until the metadata of a magnet link is downloaded.
+    pub fn subscribe_metadata(&self) -> watch::Receiver<Option<Metainfo>> {
+        self.metadata.subscribe()
+    }
+
+    /// Makes `run` return once the metadata is complete instead of downloading the files.
+    /// Nothing is written to the DB except the metadata pieces, which are cleared at the end.
+    pub fn stop_after_metadata(&mut self) {
+        self.stop_after_metadata = true;
+    }
+
+    /// lets the announcer know about our progress from now on
+    pub fn attach_announcer(&mut self, announcer: AnnounceHandle) {
+        self.announcer = Some(announcer);
+    }
+
+    /// whether the torrent may only get its peers from its trackers, false until we have the metainfo
+    fn is_private(&self) -> bool {
+        match &self.torrent_state {
+            TorrentState::Downloading { metainfo, .. } | TorrentState::Seeding { metainfo, .. } => {
+                metainfo.is_private()
+            }
+            _ => false,
+        }
+    }
+
+    /// None while we wait for the metadata
+    fn n_pieces(&self) -> Option<usize> {
+        match &self.torrent_state {
+            TorrentState::Downloading { metainfo, .. } | TorrentState::Seeding { metainfo, .. } => {
+                Some(metainfo.pieces.len())
+            }
+            _ => None,
+        }
+    }
+
+    /// The trackers the DB kept for the torrent, with the ones of the torrent file or magnet link
+    /// this run was started from. Only the ones of the magnet link while we wait for the metadata.
+    pub fn tracker_tiers(&self) -> TrackerTiers {
+        match &self.torrent_state {
+            TorrentState::Downloading { piece_manager, .. }
+            | TorrentState::Seeding { piece_manager, .. } => {
+                TrackerTiers::from_tiers(piece_manager.trackers.clone())
+            }
+            _ => TrackerTiers::single_tier(self.announce_urls.clone()),
+        }
+    }
+
+    /// whether we have the whole torrent
+    pub fn is_seeding(&self) -> bool {
+        matches!(self.torrent_state, TorrentState::Seeding { .. })
+    }
+
+    /// counts the clients of this torrent's peers into `global` as well
+    pub fn count_clients_into(&mut self, global: &ClientStats) {
+        self.client_stats = global.child();
+    }
+
+    /// records the events of this torrent into `global` as well
+    pub fn log_events_into(&mut self, global: &EventLog) {
+        self.events.attach_to(global);
+    }
+
+    /// the recent events of this torrent, see [`TorrentControl::events_since`]
+    pub fn events(&self) -> EventLog {
+        self.events.clone()
+    }
+
+    /// overrides [`Config::upload_slots`] for this torrent
+    pub fn set_upload_slots(&mut self, slots: usize) {
+        self.choker.set_slots(slots);
+    }
+
+    /// the limit of every peer that has none of its own, see [`PeerManager::limit_peer`]
+    pub fn limit_peers(&mut self, limit: RateLimit) {
+        self.default_peer_limit = limit;
+        for (peer_id, conn) in &self.peers {
+            if !self.peer_limits.contains_key(peer_id) {
+                conn.identifier.0.rate_limiter.set(limit);
+            }
+        }
+    }
+
+    /// Caps the rates of one peer, it keeps the limit when it reconnects.
+    /// None goes back to the limit of every peer.
+    pub fn limit_peer(&mut self, peer_id: [u8; 20], limit: Option<RateLimit>) {
+        match limit {
+            Some(limit) => self.peer_limits.insert(peer_id, limit),
+            None => self.peer_limits.remove(&peer_id),
+        };
+        if let Some(conn) = self.peers.get(&peer_id) {
+            conn.identifier
+                .0
+                .rate_limiter
+                .set(self.rate_limit_of(&peer_id));
+        }
+    }
+
+    fn rate_limit_of(&self, peer_id: &[u8; 20]) -> RateLimit {
+        self.peer_limits
+            .get(peer_id)
+            .copied()
+            .unwrap_or(self.default_peer_limit)
+    }
+
+    /// reads and writes the data within `budget`, taking turns with its other torrents
+    pub fn share_disk(&mut self, budget: &DiskBudget) {
+        self.disk = budget.share();
+        if let TorrentState::Downloading { piece_manager, .. }
+        | TorrentState::Seeding { piece_manager, .. } = &mut self.torrent_state
+        {
+            piece_manager.disk = self.disk.clone();
+        }
+    }
+
+    /// uploads and downloads within the limits of `bandwidth` on top of the limits of the peers,
+    /// the peers that are connected already keep the bandwidth they had
+    pub fn share_bandwidth(&mut self, bandwidth: &Bandwidth) {
+        self.bandwidth = bandwidth.clone();
+    }
+
+    /// reads the time from `clock` instead of the OS, for the request timeouts, the stall
+    /// watchdog, the choke rounds and the rates; set it before `run`
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let now = clock.now();
+        self.watchdog = Watchdog::new(self.config.stall_timeout(), now);
+        self.integrity_log = IntegrityLog::new(now);
+        self.candidates.set_clock(clock.clone());
+        self.clock = clock;
+    }
+
+    /// the clients of this torrent's peers
+    pub fn client_stats(&self) -> ClientStats {
+        self.client_stats.clone()
+    }
+
+    /// the trace of the messages exchanged with the peers, disabled at first
+    pub fn wire_trace(&self) -> WireTrace {
+        self.wire_trace.clone()
+    }
+
+    /// cancelling the token makes `run` announce `stopped` and return
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
     }
 
     pub async fn run(mut self) -> Result<(), PeerManagerError> {
-        while let Some(peer_msg) = self.rx.recv().await {
-            match peer_msg.msg {
-                ReqMessage::NewConnection(peer_conn) => {
-                    self.peers.insert(peer_msg.peer_id, peer_conn);
-
-                    if let TorrentState::Downloading {
-                        metainfo: _,
-                        piece_manager: _,
-                    } = self.torrent_state
-                    {
-                        self.send_peer(peer_msg.peer_id, ResMessage::StartDownload)
-                            .await?;
+        self.announce(None);
+        let mut progress_tick = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut choke_tick = tokio::time::interval(CHOKE_INTERVAL);
+        let mut pex_tick = tokio::time::interval(PEX_INTERVAL);
+        let mut web_seed_tick = tokio::time::interval(WEB_SEED_INTERVAL);
+        let mut watchdog_tick = tokio::time::interval(WATCHDOG_INTERVAL);
+        let mut expire_tick = tokio::time::interval(EXPIRE_INTERVAL);
+        let mut lazy_have_tick = tokio::time::interval(LAZY_HAVE_INTERVAL);
+        loop {
+            let peer_msg = tokio::select! {
+                peer_msg = self.rx.recv() => peer_msg,
+                _ = progress_tick.tick() => {
+                    self.publish_progress();
+                    if mem::take(&mut self.piece_map_stale) {
+                        self.publish_piece_map();
                     }
+                    continue;
                 }
-                ReqMessage::GotBlock(block) => {
-                    if let TorrentState::Downloading {
-                        metainfo,
-                        piece_manager,
-                    } = &mut self.torrent_state
-                        && let Some(piece_index) =
-                            piece_manager.write_block(block, metainfo).await?
-                    {
-                        let msg = ResMessage::FinishedPiece(piece_index);
-                        eprintln!("Finished piece number {piece_index}.");
-                        if piece_manager.is_finished() {
-                            self.torrent_state = TorrentState::Seeding {
-                                metainfo: metainfo.clone(),
-                            };
-                            self.broadcast_peers(ResMessage::FinishedFile).await?;
-                        }
-                        self.broadcast_peers(msg).await?;
+                _ = choke_tick.tick() => {
+                    if let Err(e) = self.rechoke().await {
+                        self.recover(e)?;
                     }
+                    self.rate_candidates();
+                    continue;
                 }
-                ReqMessage::NeedBlock(block) => {
-                    if let TorrentState::Downloading {
-                        metainfo,
-                        piece_manager,
-                    } = &self.torrent_state
-                    {
-                        if piece_manager.have[block.index as usize] {
-                            let block = piece_manager.get_block(block, metainfo);
-                            let msg = ResMessage::Block(block);
-                            self.send_peer(peer_msg.peer_id, msg).await?;
-                        } else {
-                            todo!(
-                                "send a message to the peer that we don't have the block? (not sure if there's a message type for this)"
-                            );
-                        }
+                _ = pex_tick.tick() => {
+                    if let Err(e) = self.send_pex().await {
+                        self.recover(e)?;
+                    }
+                    continue;
+                }
+                _ = web_seed_tick.tick() => {
+                    self.fetch_from_web_seeds();
+                    continue;
+                }
+                _ = expire_tick.tick() => {
+                    if let Err(e) = self.expire_requests().await {
+                        self.recover(e)?;
+                    }
+                    // the metadata may be there now, and the readers that gave up are dropped
+                    self.serve_reads().await;
+                    continue;
+                }
+                _ = lazy_have_tick.tick(), if !self.lazy_haves.is_empty() => {
+                    self.send_lazy_haves().await;
+                    continue;
+                }
+                _ = watchdog_tick.tick() => {
+                    if let Err(e) = self.check_stall().await {
+                        self.recover(e)?;
                     }
+                    continue;
+                }
+                Some(command) = self.commands.rx.recv() => {
+                    if let Err(e) = self.on_command(command).await {
+                        self.recover(e)?;
+                    }
+                    continue;
+                }
+                Some(fetched) = self.web_seeds.fetched.recv() => {
+                    if let Err(e) = self.on_web_seed_piece(fetched).await {
+                        self.recover(e)?;
+                    }
+                    continue;
+                }
+                _ = std::future::ready(()), if !self.upload_queue.is_empty() => {
+                    match self.serve_next_block().await {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(e) => self.recover(e)?,
+                    }
+                    continue;
+                }
+                _ = self.shutdown.cancelled() => None,
+            };
+            let Some(peer_msg) = peer_msg else {
+                break;
+            };
+            match self.handle_message(peer_msg).await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => self.recover(e)?,
+            }
+        }
+
+        self.rate_candidates();
+        // if the seeding goal was reached, we announced `stopped` already
+        self.announce(Some(Event::Stopped));
+        if let TorrentState::Downloading { piece_manager, .. }
+        | TorrentState::Seeding { piece_manager, .. } = &self.torrent_state
+        {
+            // the next run picks up the ratio where this one left off
+            piece_manager.persist_uploaded().await?;
+            piece_manager.flush_db().await?;
+        }
+        Ok(())
+    }
+
+    /// handles a message of a peer
+    /// returns true if the torrent is done, e.g. because we only wanted the metadata
+    async fn handle_message(&mut self, peer_msg: ReqMsgFromPeer) -> Result<bool, PeerManagerError> {
+        match peer_msg.msg {
+            ReqMessage::NewConnection(peer_conn) => {
+                if self.is_banned(peer_conn.identifier.0.addr.ip()) {
+                    // it never made it into `peers`, there's nothing else to clean up
+                    let _ = peer_conn.sender.send(ResMessage::Disconnect).await;
+                    return Ok(false);
                 }
-                ReqMessage::NeedBlockQueue => {
-                    let Some(peer_has) = self.get_peer_has(&peer_msg.peer_id) else {
-                        continue;
-                    };
-                    if let TorrentState::Downloading {
+                let Some(slot) = self.admit_peer(self.clock.now()).await else {
+                    eprintln!(
+                        "Turning away the peer {}, we're at the connection limit.",
+                        peer_conn.identifier.0.addr
+                    );
+                    let _ = peer_conn.sender.send(ResMessage::Disconnect).await;
+                    return Ok(false);
+                };
+                self.held_slots.insert(peer_msg.peer_id, slot);
+                let _ = peer_conn
+                    .identifier
+                    .0
+                    .wire_trace
+                    .set(self.wire_trace.clone());
+                peer_conn
+                    .identifier
+                    .0
+                    .rate_limiter
+                    .set(self.rate_limit_of(&peer_msg.peer_id));
+                peer_conn.identifier.0.rate_limiter.share(&self.bandwidth);
+                self.client_stats.connected(&peer_msg.peer_id);
+                self.events.record(EventKind::PeerConnected {
+                    peer: peer_conn.identifier.0.addr,
+                    client: peer_conn
+                        .identifier
+                        .0
+                        .client
+                        .as_ref()
+                        .map(ToString::to_string),
+                });
+                self.quality.connected(peer_msg.peer_id, self.clock.now());
+                if let Some(rarity) = self.rarity() {
+                    rarity.add_peer(&peer_conn.identifier.0.has.lock().unwrap());
+                }
+                self.peers.insert(peer_msg.peer_id, peer_conn);
+                self.candidates.set_peers(self.peers.len());
+                self.track_candidate(peer_msg.peer_id);
+                self.check_rarity();
+
+                if let TorrentState::Downloading {
+                    metainfo: _,
+                    piece_manager: _,
+                } = self.torrent_state
+                {
+                    self.send_peer(peer_msg.peer_id, ResMessage::StartDownload)
+                        .await?;
+                }
+            }
+            ReqMessage::GotBlock(block) => {
+                let bytes = block.block.len() as u64;
+                self.choker.downloaded(peer_msg.peer_id, bytes);
+                self.quality
+                    .received(peer_msg.peer_id, bytes, self.clock.now());
+                self.integrity_log
+                    .received(Contributor::Peer(hex::encode(peer_msg.peer_id)), bytes);
+                let completed = match &mut self.torrent_state {
+                    TorrentState::Downloading {
                         metainfo,
                         piece_manager,
-                    } = &mut self.torrent_state
-                    {
-                        let blocks = piece_manager.prepare_next_blocks(
-                            BLOCK_QUEUE_SIZE_MAX,
-                            &peer_has,
-                            metainfo,
-                        );
-                        let msg = ResMessage::NewBlockQueue(blocks);
-                        self.send_peer(peer_msg.peer_id, msg).await?;
-                    } else if let TorrentState::WaitingForMetadata {
-                        file_path: _,
-                        metadata_piece_manager,
-                    } = &mut self.torrent_state
-                    {
-                        let msg = get_metadata_queue(metadata_piece_manager)?;
-                        if let Some(msg) = msg {
-                            self.send_peer(peer_msg.peer_id, msg).await?;
+                    } => {
+                        piece_manager
+                            .write_block(block, metainfo, peer_msg.peer_id)
+                            .await?
+                    }
+                    _ => None,
+                };
+                match completed {
+                    Some(CompletedPiece::Verified(piece_index)) => {
+                        self.finish_piece(piece_index).await?
+                    }
+                    Some(CompletedPiece::Corrupt { piece_i, senders }) => {
+                        eprintln!("Piece number {piece_i} doesn't match its hash.");
+                        self.quality.corrupt_piece(&senders);
+                        self.events
+                            .record(EventKind::PieceCorrupt { piece: piece_i });
+                        self.strike_senders(&senders).await;
+                        if let TorrentState::Downloading { metainfo, .. } = &self.torrent_state {
+                            self.integrity_log.corrupt(piece_i, metainfo);
                         }
                     }
+                    None => {}
                 }
-                ReqMessage::WhatDoWeHave => {
-                    if let TorrentState::Downloading {
-                        metainfo: _,
-                        piece_manager,
-                    } = &self.torrent_state
-                    {
-                        let msg = ResMessage::WeHave(BitfieldPayload {
-                            pieces_available: piece_manager.have.clone(),
-                        });
-                        self.send_peer(peer_msg.peer_id, msg).await?;
-                    } else {
-                        // If we don't have the metainfo, we have nothing.
-                        // We don't know the length either so we just return one element.
-                        // We don't send the bitfield if it's empty anyway
-                        let msg = ResMessage::WeHave(BitfieldPayload {
-                            pieces_available: vec![false],
+                self.piece_map_changed();
+            }
+            ReqMessage::NeedBlock(block) => {
+                let have = self
+                    .our_pieces()
+                    .is_some_and(|have| have.contains(block.index as usize));
+                if !have {
+                    // we never announced it, there's nothing to answer with
+                    eprintln!(
+                        "Ignoring a request for the piece {}, we don't have it.",
+                        block.index
+                    );
+                } else if self.choker.is_unchoked(&peer_msg.peer_id) {
+                    // a choked peer may have sent the request before it got our choke, this one is
+                    // served in `serve_next_block` so every peer gets its share
+                    self.upload_queue.push(peer_msg.peer_id, block);
+                }
+            }
+            ReqMessage::CancelBlock(block) => {
+                // it's gone already if we served it
+                self.upload_queue.cancel(&peer_msg.peer_id, &block);
+            }
+            ReqMessage::NeedBlockQueue => {
+                let Some(peer_has) = self.get_peer_has(&peer_msg.peer_id) else {
+                    return Ok(false);
+                };
+                let now = self.clock.now();
+                // the peer asks again with its next message, maybe once it's the optimistic unchoke
+                if self.is_snubbed(&peer_msg.peer_id, now)
+                    && !self.choker.is_optimistic(&peer_msg.peer_id)
+                {
+                    return Ok(false);
+                }
+                if let TorrentState::Downloading {
+                    metainfo,
+                    piece_manager,
+                } = &mut self.torrent_state
+                {
+                    let max = self
+                        .peers
+                        .get(&peer_msg.peer_id)
+                        .map_or(BLOCK_QUEUE_SIZE_MAX, |conn| {
+                            request_limit(conn.identifier.0.reqq.get().copied())
                         });
+                    let blocks = piece_manager.prepare_next_blocks(
+                        self.quality.queue_len(&peer_msg.peer_id, max, now),
+                        &peer_has,
+                        metainfo,
+                        peer_msg.peer_id,
+                        now,
+                    );
+                    let choking_us = self.peers.get(&peer_msg.peer_id).is_some_and(|conn| {
+                        conn.identifier
+                            .0
+                            .peer_choking
+                            .load(std::sync::atomic::Ordering::Relaxed)
+                    });
+                    if !blocks.is_empty() && !choking_us {
+                        self.quality.requested(peer_msg.peer_id, now);
+                    }
+                    let msg = ResMessage::NewBlockQueue(blocks);
+                    self.send_peer(peer_msg.peer_id, msg).await?;
+                    self.piece_map_changed();
+                } else if let TorrentState::WaitingForMetadata {
+                    metadata_piece_manager,
+                    ..
+                } = &mut self.torrent_state
+                    // the request would never reach a peer without ut_metadata and the block would be stuck
+                    && supports_extension(&self.peers, &peer_msg.peer_id, ExtensionType::Metadata)
+                {
+                    let msg = get_metadata_queue(metadata_piece_manager, peer_msg.peer_id)?;
+                    if let Some(msg) = msg {
                         self.send_peer(peer_msg.peer_id, msg).await?;
                     }
                 }
-                ReqMessage::Extension(extension_message) => {
-                    if let TorrentState::WaitingForMetadata {
-                        file_path,
-                        metadata_piece_manager,
-                    } = &mut self.torrent_state
-                    {
-                        match extension_message {
-                            ExtensionMessage::ReceivedMetadataPiece { piece_index, data } => {
-                                println!("Received metadata Block with index {piece_index}");
-                                metadata_piece_manager.add_block(piece_index, data);
-                                if metadata_piece_manager.check_finished() {
-                                    let metainfo = metadata_piece_manager.get_metadata().expect("This shouldn't fail since we checked that the hashes match.");
-                                    let torrent = Torrent {
-                                        announce: self
-                                            .announce_urls
-                                            .first()
-                                            .expect("If there's none, the parsing would have failed long ago.")
-                                            .clone(),
-                                        info: metainfo,
-                                    };
-                                    let piece_manager = PieceManager::new(
-                                        DBConnection::new(metadata_piece_manager.info_hash).await?,
-                                        file_path.clone(),
-                                        &torrent,
-                                    )
-                                    .await?;
-                                    self.torrent_state = TorrentState::Downloading {
-                                        metainfo: torrent.info,
-                                        piece_manager,
-                                    };
-                                    self.broadcast_peers(ResMessage::StartDownload).await?;
+            }
+            ReqMessage::WhatDoWeHave => {
+                if let TorrentState::Seeding { piece_manager, .. } = &self.torrent_state {
+                    let have = piece_manager.have.clone();
+                    let msg = ResMessage::WeSeed(self.bitfield_for(peer_msg.peer_id, have));
+                    self.send_peer(peer_msg.peer_id, msg).await?;
+                } else if let TorrentState::Downloading { piece_manager, .. } = &self.torrent_state
+                {
+                    let have = piece_manager.have.clone();
+                    let msg = ResMessage::WeHave(self.bitfield_for(peer_msg.peer_id, have));
+                    self.send_peer(peer_msg.peer_id, msg).await?;
+                    self.recount_interest(peer_msg.peer_id).await;
+                } else {
+                    // If we don't have the metainfo, we have nothing.
+                    // We don't know the length either so we just return one element.
+                    // We don't send the bitfield if it's empty anyway
+                    let msg = ResMessage::WeHave(BitfieldPayload::from_bools(&[false]));
+                    self.send_peer(peer_msg.peer_id, msg).await?;
+                    self.recount_interest(peer_msg.peer_id).await;
+                }
+            }
+            ReqMessage::Extension(extension_message) => {
+                if let TorrentState::WaitingForMetadata {
+                    file_path,
+                    metadata_piece_manager,
+                    ..
+                } = &mut self.torrent_state
+                {
+                    match extension_message {
+                        ExtensionMessage::ReceivedMetadataPiece { piece_index, data } => {
+                            println!("Received metadata Block with index {piece_index}");
+                            metadata_piece_manager.add_block(peer_msg.peer_id, piece_index, data);
+                            if !metadata_piece_manager.check_finished() {
+                                metadata_piece_manager.persist().await?;
+                            } else {
+                                metadata_piece_manager.clear_persisted().await?;
+                                let metainfo = metadata_piece_manager.get_metadata().expect(
+                                    "This shouldn't fail since we checked that the hashes match.",
+                                );
+                                self.config.limits.check(&metainfo)?;
+                                self.metadata.send_replace(Some(metainfo.clone()));
+                                self.events.record(EventKind::MetadataComplete);
+                                if self.stop_after_metadata {
                                     eprintln!("Finished downloading the metainfo.");
+                                    return Ok(true);
                                 }
+                                // all trackers of the magnet link, the next run may not have it
+                                let torrent = Torrent {
+                                    announce: None,
+                                    announce_list: Some(vec![
+                                        self.announce_urls
+                                            .iter()
+                                            .map(url::Url::to_string)
+                                            .collect(),
+                                    ]),
+                                    url_list: Vec::new(),
+                                    info: metainfo,
+                                };
+                                let mut piece_manager = PieceManager::new(
+                                    DBConnection::new(
+                                        &self.config.paths(),
+                                        metadata_piece_manager.info_hash,
+                                    )
+                                    .await?,
+                                    file_path.clone(),
+                                    &torrent,
+                                    &self.config,
+                                )
+                                .await?;
+                                piece_manager.disk = self.disk.clone();
+                                if let Some(ratio_group) = self.pending_ratio_group.take() {
+                                    piece_manager.set_ratio_group(ratio_group).await?;
+                                }
+                                let n_pieces = torrent.info.pieces.len();
+                                let mut violators = Vec::new();
+                                for (peer_id, conn) in &self.peers {
+                                    let mut has = conn.identifier.0.has.lock().unwrap();
+                                    // what we learned before is shorter if the peer only sent Haves
+                                    let past_the_end = has.iter_ones().find(|i| *i >= n_pieces);
+                                    match past_the_end {
+                                        None => {
+                                            has.resize(n_pieces);
+                                            piece_manager.rarity().add_peer(&has);
+                                        }
+                                        Some(i) => violators
+                                            .push((*peer_id, BitfieldError::NoSuchPiece(i))),
+                                    }
+                                }
+                                self.torrent_state = TorrentState::Downloading {
+                                    metainfo: torrent.info,
+                                    piece_manager,
+                                };
+                                for (peer_id, e) in violators {
+                                    self.drop_peer(peer_id, e).await;
+                                }
+                                self.check_rarity();
+                                self.recount_all_interest().await;
+                                self.publish_piece_map();
+                                // the first announce had to guess `left`
+                                self.announce(None);
+                                self.broadcast_peers(ResMessage::StartDownload).await?;
+                                eprintln!("Finished downloading the metainfo.");
                             }
-                            ExtensionMessage::GotMetadataLength(length) => {
-                                metadata_piece_manager.set_len(length);
-                            }
+                        }
+                        ExtensionMessage::RejectedMetadataPiece => {
+                            metadata_piece_manager.reject_block(peer_msg.peer_id);
+                        }
+                        ExtensionMessage::GotMetadataLength(length) => {
+                            metadata_piece_manager.set_len(length);
                         }
                     }
                 }
-                ReqMessage::PeerDisconnected(info_hash) => {
-                    self.peers.remove(&info_hash.0);
+            }
+            ReqMessage::PeerDisconnected(info_hash) => self.lose_peer(info_hash.0),
+            ReqMessage::ExternalIp(ip) => self.on_external_ip(peer_msg.peer_id, ip),
+            ReqMessage::ClientVersion(version) => self.client_stats.handshake(&version),
+            ReqMessage::ListenPort(_) => self.track_candidate(peer_msg.peer_id),
+            ReqMessage::PexPeers(peers) => self.on_pex_peers(peers),
+            ReqMessage::PeerHas(piece_i) => self.peer_has(peer_msg.peer_id, piece_i as usize).await,
+            ReqMessage::PeerBitfield(bitfield) => {
+                self.peer_bitfield(peer_msg.peer_id, bitfield).await
+            }
+            ReqMessage::ExtensionsDowngraded => {
+                if let TorrentState::WaitingForMetadata {
+                    metadata_piece_manager,
+                    ..
+                } = &mut self.torrent_state
+                {
+                    metadata_piece_manager.release_peer(peer_msg.peer_id);
                 }
+                self.find_metadata_peers();
             }
         }
+        Ok(false)
+    }
 
+    /// tells the peers about the piece and starts seeding if it was the last one
+    async fn finish_piece(&mut self, piece_index: u32) -> Result<(), PeerManagerError> {
+        eprintln!("Finished piece number {piece_index}.");
+        self.events
+            .record(EventKind::PieceVerified { piece: piece_index });
+        self.integrity_log.verified(piece_index);
+        if let TorrentState::Downloading { piece_manager, .. } = &self.torrent_state
+            && piece_manager.is_finished()
+        {
+            self.finish_download().await?;
+        }
+        for peer_id in broadcast_have(&self.peers, piece_index).await {
+            self.remove_peer(peer_id);
+        }
+        self.we_gained_piece(piece_index as usize).await;
+        self.serve_reads().await;
         Ok(())
     }
 
+    /// moves the file to its final name and starts seeding
+    async fn finish_download(&mut self) -> Result<(), PeerManagerError> {
+        if let TorrentState::Downloading { piece_manager, .. } = &mut self.torrent_state {
+            piece_manager.move_to_final_path().await?;
+            if self.config.mmap_reads {
+                piece_manager.map_for_seeding();
+            }
+        }
+        self.torrent_state =
+            mem::replace(&mut self.torrent_state, TorrentState::Stopped).into_seeding();
+        self.finish_integrity_report();
+        self.events.record(EventKind::DownloadFinished);
+        self.announce(Some(Event::Completed));
+        self.broadcast_peers(ResMessage::FinishedFile).await
+    }
+
     async fn send_peer(
         &mut self,
         peer_id: [u8; 20],
@@ -365,7 +1099,7 @@ impl PeerManager {
         peer.send(msg, peer_id).await
     }
 
-    fn get_peer_has(&self, peer_id: &[u8; 20]) -> Option<Vec<bool>> {
+    fn get_peer_has(&self, peer_id: &[u8; 20]) -> Option<PieceSet> {
         Some(
             self.peers
                 .get(peer_id)?
@@ -379,22 +1113,270 @@ impl PeerManager {
         .and_then(|q| if q.is_empty() { None } else { Some(q) })
     }
 
+    /// if none of our peers can send us the metadata, we ask the trackers for more peers
+    fn find_metadata_peers(&self) {
+        if matches!(self.torrent_state, TorrentState::WaitingForMetadata { .. })
+            && !self
+                .peers
+                .keys()
+                .any(|peer_id| supports_extension(&self.peers, peer_id, ExtensionType::Metadata))
+        {
+            eprintln!("None of the peers supports ut_metadata, looking for more.");
+            self.announce(None);
+        }
+    }
+
+    /// asks the announcer to announce our current progress
+    pub(super) fn announce(&self, event: Option<Event>) {
+        if let Some(announcer) = &self.announcer
+            && let Some(progress) = self.announce_progress()
+        {
+            announcer.announce(progress, event);
+        }
+    }
+
+    /// None once we stopped
+    fn announce_progress(&self) -> Option<AnnounceProgress> {
+        let progress = match &self.torrent_state {
+            TorrentState::Downloading {
+                metainfo,
+                piece_manager,
+            }
+            | TorrentState::Seeding {
+                metainfo,
+                piece_manager,
+            } => AnnounceProgress {
+                uploaded: piece_manager.session_uploaded(),
+                downloaded: piece_manager.downloaded,
+                left: Some(piece_manager.bytes_left(metainfo)),
+            },
+            TorrentState::WaitingForMetadata { exact_length, .. } => AnnounceProgress {
+                uploaded: 0,
+                downloaded: 0,
+                left: *exact_length,
+            },
+            TorrentState::Stopped => return None,
+        };
+        Some(progress)
+    }
+
+    /// sends the message to every peer, the peers that are gone are dropped
     async fn broadcast_peers(&mut self, msg: ResMessage) -> Result<(), PeerManagerError> {
-        for (&peer_id, conn) in self.peers.iter() {
-            conn.send(msg.clone(), peer_id).await?;
+        for peer_id in broadcast(&self.peers, msg).await {
+            self.remove_peer(peer_id);
+        }
+        Ok(())
+    }
+
+    /// forgets the peer and hands its blocks to the others
+    /// the rarity counts, as long as there is a PieceManager to keep them
+    fn rarity(&mut self) -> Option<&mut PieceSelector> {
+        match &mut self.torrent_state {
+            TorrentState::Downloading { piece_manager, .. }
+            | TorrentState::Seeding { piece_manager, .. } => Some(piece_manager.rarity()),
+            _ => None,
+        }
+    }
+
+    /// hands the blocks the peers didn't send in time to the others
+    async fn expire_requests(&mut self) -> Result<(), PeerManagerError> {
+        let TorrentState::Downloading { piece_manager, .. } = &mut self.torrent_state else {
+            return Ok(());
+        };
+        let stalled = piece_manager.expire_requests(self.clock.now(), self.config.block_timeout());
+        if stalled.is_empty() {
+            return Ok(());
+        }
+        eprintln!(
+            "{} peers didn't send the blocks we requested in time, requesting them elsewhere.",
+            stalled.len()
+        );
+        // the idle peers wouldn't ask for the blocks until their next message
+        self.broadcast_peers(ResMessage::StartDownload).await
+    }
+
+    fn check_rarity(&mut self) {
+        let bitfields: Vec<_> = self
+            .peers
+            .values()
+            .map(|conn| conn.identifier.0.has.lock().unwrap().clone())
+            .collect();
+        if let Some(rarity) = self.rarity() {
+            rarity.debug_check(bitfields.iter());
+        }
+    }
+
+    async fn peer_has(&mut self, peer_id: [u8; 20], piece_i: usize) {
+        if self.n_pieces().is_some_and(|n_pieces| piece_i >= n_pieces) {
+            self.drop_peer(peer_id, BitfieldError::NoSuchPiece(piece_i))
+                .await;
+            return;
+        }
+        let Some(conn) = self.peers.get(&peer_id) else {
+            return;
+        };
+        let n_pieces = self.n_pieces();
+        if !mark_have(
+            &mut conn.identifier.0.has.lock().unwrap(),
+            piece_i,
+            n_pieces,
+        ) {
+            return;
+        }
+        if let Some(rarity) = self.rarity() {
+            rarity.have(piece_i);
+        }
+        self.peer_gained_piece(peer_id, piece_i).await;
+    }
+
+    /// without the metadata we can't check the bitfield yet, that happens once it's complete
+    async fn peer_bitfield(&mut self, peer_id: [u8; 20], bitfield: BitfieldPayload) {
+        let has = match self.n_pieces() {
+            Some(n_pieces) => match bitfield.checked(n_pieces) {
+                Ok(has) => has,
+                Err(e) => return self.drop_peer(peer_id, e).await,
+            },
+            None => bitfield.to_bools(),
+        };
+        let has = PieceSet::from_bools(&has);
+        let Some(conn) = self.peers.get(&peer_id) else {
+            return;
+        };
+        let old = std::mem::replace(&mut *conn.identifier.0.has.lock().unwrap(), has.clone());
+        if let Some(rarity) = self.rarity() {
+            rarity.remove_peer(&old);
+            rarity.add_peer(&has);
+        }
+        self.check_rarity();
+        self.recount_interest(peer_id).await;
+    }
+
+    /// disconnects a peer that broke the protocol
+    async fn drop_peer(&mut self, peer_id: [u8; 20], reason: impl fmt::Display) {
+        eprintln!("Disconnecting the peer {peer_id:?}: {reason}");
+        self.record_dropped(peer_id, reason.to_string());
+        self.quality.violation(peer_id);
+        // its connection may be gone already
+        let _ = self.send_peer(peer_id, ResMessage::Disconnect).await;
+        self.remove_peer(peer_id);
+    }
+
+    fn record_dropped(&self, peer_id: [u8; 20], reason: String) {
+        if let Some(conn) = self.peers.get(&peer_id) {
+            let peer = conn.identifier.0.addr;
+            self.events.record(EventKind::PeerDropped { peer, reason });
         }
+    }
+
+    /// returns where to dial the peer again, see [`PeerCandidates::disconnected`]
+    fn remove_peer(&mut self, peer_id: [u8; 20]) -> Option<SocketAddr> {
+        if let Some(conn) = self.peers.get(&peer_id) {
+            let has = conn.identifier.0.has.lock().unwrap().clone();
+            if let Some(rarity) = self.rarity() {
+                rarity.remove_peer(&has);
+            }
+        }
+        self.peers.remove(&peer_id);
+        self.candidates.set_peers(self.peers.len());
+        self.held_slots.remove(&peer_id);
+        if let Some(score) = self.quality.remove_peer(&peer_id, self.clock.now()) {
+            self.candidates.rate(&peer_id, score);
+        }
+        let redial = self.candidates.disconnected(&peer_id);
+        self.upload_queue.remove_peer(&peer_id);
+        self.choker.remove_peer(&peer_id);
+        self.pex.remove_peer(&peer_id);
+        self.needs.remove_peer(&peer_id);
+        self.lazy_haves.remove_peer(&peer_id);
+        match &mut self.torrent_state {
+            TorrentState::Downloading { piece_manager, .. } => piece_manager.release_peer(peer_id),
+            TorrentState::WaitingForMetadata {
+                metadata_piece_manager,
+                ..
+            } => metadata_piece_manager.release_peer(peer_id),
+            _ => {}
+        }
+        self.check_rarity();
+        self.piece_map_changed();
+        self.find_metadata_peers();
+        redial
+    }
 
+    /// A peer that fails only costs us that peer, any other error stops the torrent.
+    fn recover(&mut self, error: PeerManagerError) -> Result<(), PeerManagerError> {
+        if !error.is_peer_scoped() {
+            return Err(error);
+        }
+        eprintln!("Continuing without the peer after the error: {error}");
+        if let Some(peer_id) = error.peer_id() {
+            self.record_dropped(peer_id, error.to_string());
+            self.client_stats.failed(&peer_id);
+            self.remove_peer(peer_id);
+        }
         Ok(())
     }
 }
 
+/// returns the peers we couldn't reach
+async fn broadcast(peers: &HashMap<[u8; 20], PeerConn>, msg: ResMessage) -> Vec<[u8; 20]> {
+    let mut dead = Vec::new();
+    for (&peer_id, conn) in peers.iter() {
+        if conn.send(msg.clone(), peer_id).await.is_err() {
+            dead.push(peer_id);
+        }
+    }
+    dead
+}
+
+/// Tells the peers that don't have the piece that we have it, the others don't need a Have.
+/// Returns the peers we couldn't reach.
+async fn broadcast_have(peers: &HashMap<[u8; 20], PeerConn>, piece_i: u32) -> Vec<[u8; 20]> {
+    let mut dead = Vec::new();
+    for (&peer_id, conn) in peers.iter() {
+        let has = conn
+            .identifier
+            .0
+            .has
+            .lock()
+            .unwrap()
+            .contains(piece_i as usize);
+        if !has
+            && conn
+                .send(ResMessage::FinishedPiece(piece_i), peer_id)
+                .await
+                .is_err()
+        {
+            dead.push(peer_id);
+        }
+    }
+    dead
+}
+
+/// whether the peer told us in the extension handshake that it supports the extension
+fn supports_extension(
+    peers: &HashMap<[u8; 20], PeerConn>,
+    peer_id: &[u8; 20],
+    ext_type: ExtensionType,
+) -> bool {
+    peers.get(peer_id).is_some_and(|conn| {
+        conn.identifier
+            .0
+            .extensions
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|extensions| extensions.values().any(|e| e.get_ext_type() == ext_type))
+    })
+}
+
 /// helper function that get's the new blocks to be added and creates a message of it
 /// it's not really a queue, rather just one message
 fn get_metadata_queue(
     metadata_piece_manager: &mut MetadataPieceManager,
+    peer_id: [u8; 20],
 ) -> Result<Option<ResMessage>, PeerManagerError> {
     let new_data = metadata_piece_manager
-        .get_block_req_data()
+        .get_block_req_data(peer_id)
         .map_err(|e| PeerManagerError::Other(Box::new(e)))?;
 
     if let Some(data) = new_data {
@@ -406,3 +1388,197 @@ fn get_metadata_queue(
         Ok(None)
     }
 }
+
+/// how many blocks we request from the peer at once, never more than it said it takes
+fn request_limit(reqq: Option<usize>) -> usize {
+    reqq.map_or(BLOCK_QUEUE_SIZE_MAX, |reqq| {
+        reqq.clamp(1, BLOCK_QUEUE_SIZE_MAX)
+    })
+}
+
+/// Marks the piece in the bitfield of the peer, a Have may come without a bitfield before it.
+/// Once the metadata is known the bitfield spans the whole torrent, before it only grows as far as
+/// the Haves go. Returns false if we knew the peer had it.
+fn mark_have(has: &mut PieceSet, piece_i: usize, n_pieces: Option<usize>) -> bool {
+    let len = n_pieces.unwrap_or(0).max(piece_i + 1);
+    if has.len() < len {
+        has.resize(len);
+    }
+    if has.contains(piece_i) {
+        return false;
+    }
+    has.insert(piece_i);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BLOCK_MAX, peer::initial_handshake::Handshake, test_fixtures};
+
+    fn peer_conn(id: u8) -> (PeerConn, mpsc::Receiver<ResMessage>) {
+        let (sender, rx) = mpsc::channel(16);
+        let identifier = PeerState::new(
+            Handshake::new(InfoHash([0; 20]), [id; 20]),
+            SocketAddr::from(([127, 0, 0, id], 6881)),
+            true,
+        );
+        (PeerConn { sender, identifier }, rx)
+    }
+
+    #[tokio::test]
+    async fn dead_peer_doesnt_stop_the_broadcast() {
+        let (alive, mut alive_rx) = peer_conn(1);
+        let (dead, dead_rx) = peer_conn(2);
+        // the peer died in the middle of the download
+        drop(dead_rx);
+        let peers = HashMap::from([([1; 20], alive), ([2; 20], dead.clone())]);
+
+        assert_eq!(
+            broadcast(&peers, ResMessage::FinishedPiece(3)).await,
+            vec![[2; 20]]
+        );
+        assert_eq!(alive_rx.recv().await, Some(ResMessage::FinishedPiece(3)));
+
+        let error = dead
+            .send(ResMessage::FinishedPiece(3), [2; 20])
+            .await
+            .unwrap_err();
+        assert!(error.is_peer_scoped());
+        assert_eq!(error.peer_id(), Some([2; 20]));
+        assert!(!PeerManagerError::NoFileName.is_peer_scoped());
+    }
+
+    #[test]
+    fn reqq_caps_the_requests() {
+        assert_eq!(request_limit(None), BLOCK_QUEUE_SIZE_MAX);
+        assert_eq!(request_limit(Some(250)), BLOCK_QUEUE_SIZE_MAX);
+        assert_eq!(request_limit(Some(4)), 4);
+        // a peer that takes none would never get a request
+        assert_eq!(request_limit(Some(0)), 1);
+    }
+
+    #[tokio::test]
+    async fn no_have_for_peers_that_have_the_piece() {
+        let (lacks, mut lacks_rx) = peer_conn(1);
+        let (seed, mut seed_rx) = peer_conn(2);
+        *seed.identifier.0.has.lock().unwrap() = PieceSet::from_bools(&[true; 4]);
+        let peers = HashMap::from([([1; 20], lacks), ([2; 20], seed)]);
+
+        assert!(broadcast_have(&peers, 3).await.is_empty());
+        assert_eq!(lacks_rx.recv().await, Some(ResMessage::FinishedPiece(3)));
+        assert!(seed_rx.try_recv().is_err());
+    }
+
+    /// a download of 3 pieces with 2 blocks each, everything it writes goes to `dir`
+    async fn downloading(dir: &std::path::Path) -> PeerManager {
+        let torrent = test_fixtures::torrent_of(BLOCK_MAX as u64 * 6, BLOCK_MAX * 2);
+        let config = Config {
+            download_dir: dir.to_path_buf(),
+            state_dir: dir.to_path_buf(),
+            ..Config::default()
+        };
+        let (_tx, rx) = mpsc::channel(1);
+        PeerManager::init_from_torrent(rx, None, torrent, Arc::new(config))
+            .await
+            .unwrap()
+    }
+
+    async fn send(manager: &mut PeerManager, id: u8, msg: ReqMessage) {
+        let peer_msg = ReqMsgFromPeer {
+            peer_id: [id; 20],
+            msg,
+        };
+        assert!(!manager.handle_message(peer_msg).await.unwrap());
+    }
+
+    /// the blocks the peer gets when it asks for its next ones
+    async fn next_blocks(
+        manager: &mut PeerManager,
+        id: u8,
+        rx: &mut mpsc::Receiver<ResMessage>,
+    ) -> Vec<(u32, u32)> {
+        send(manager, id, ReqMessage::NeedBlockQueue).await;
+        loop {
+            if let ResMessage::NewBlockQueue(blocks) = rx.try_recv().unwrap() {
+                return blocks.iter().map(|b| (b.index, b.begin)).collect();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn have_and_bitfield_steer_the_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = downloading(dir.path()).await;
+        let (a, mut a_rx) = peer_conn(1);
+        // A takes one piece at a time
+        a.identifier.0.reqq.set(2).unwrap();
+        let (b, mut b_rx) = peer_conn(2);
+        send(&mut manager, 1, ReqMessage::NewConnection(a)).await;
+        send(&mut manager, 2, ReqMessage::NewConnection(b)).await;
+        let bitfield = |pieces: [bool; 3]| BitfieldPayload::from_bools(&pieces);
+        send(
+            &mut manager,
+            1,
+            ReqMessage::PeerBitfield(bitfield([true, true, false])),
+        )
+        .await;
+        // B sent no bitfield, only a Have of piece 1
+        send(&mut manager, 2, ReqMessage::PeerHas(1)).await;
+
+        // piece 0 is the rarer one of A's
+        let blocks = next_blocks(&mut manager, 1, &mut a_rx).await;
+        assert_eq!(blocks, [(0, 0), (0, BLOCK_MAX)]);
+        // B only gets what it announced
+        let blocks = next_blocks(&mut manager, 2, &mut b_rx).await;
+        assert_eq!(blocks, [(1, 0), (1, BLOCK_MAX)]);
+
+        // a bitfield replaces B's Haves, now it has piece 2 as well
+        send(
+            &mut manager,
+            2,
+            ReqMessage::PeerBitfield(bitfield([false, true, true])),
+        )
+        .await;
+        let blocks = next_blocks(&mut manager, 2, &mut b_rx).await;
+        assert_eq!(blocks, [(2, 0), (2, BLOCK_MAX)]);
+    }
+
+    #[tokio::test]
+    async fn queued_blocks_wait_for_the_next_piece_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = downloading(dir.path()).await;
+        let mut piece_map = manager.subscribe_piece_map();
+        piece_map.borrow_and_update();
+        let (a, mut a_rx) = peer_conn(1);
+        send(&mut manager, 1, ReqMessage::NewConnection(a)).await;
+        let bitfield = BitfieldPayload::from_bools(&[true; 3]);
+        send(&mut manager, 1, ReqMessage::PeerBitfield(bitfield)).await;
+        next_blocks(&mut manager, 1, &mut a_rx).await;
+        assert!(!piece_map.has_changed().unwrap());
+        assert!(manager.piece_map_stale);
+
+        manager.publish_piece_map();
+        assert!(piece_map.has_changed().unwrap());
+        assert!(piece_map.borrow().count(PieceStatus::Downloading) > 0);
+    }
+
+    #[test]
+    fn have_grows_the_bitfield() {
+        let (conn, _rx) = peer_conn(1);
+        let mut has = conn.identifier.0.has.lock().unwrap();
+        assert!(mark_have(&mut has, 2, None));
+        assert_eq!(has.to_bools(), [false, false, true]);
+        // a second Have of the same piece isn't counted twice
+        assert!(!mark_have(&mut has, 2, None));
+        assert!(mark_have(&mut has, 0, None));
+        assert_eq!(has.to_bools(), [true, false, true]);
+    }
+
+    #[test]
+    fn have_spans_the_known_pieces() {
+        let mut has = PieceSet::default();
+        assert!(mark_have(&mut has, 1, Some(4)));
+        assert_eq!(has.to_bools(), [false, true, false, false]);
+    }
+}
@@ -0,0 +1,131 @@
+//! How many peers we keep connections to, per torrent, see [`Config::max_peers_per_torrent`], and
+//! for all torrents together, see [`Config::max_peers`]. When we're full, a new peer takes the
+//! place of the peer with the worst quality score, see [`quality`](super::quality), as long as
+//! that one had [`EVICTION_GRACE`] to show what it's worth. Otherwise the new peer is turned away,
+//! so a flood of new peers can't push out the ones we just connected to.
+//!
+//! [`Config::max_peers_per_torrent`]: crate::Config::max_peers_per_torrent
+//! [`Config::max_peers`]: crate::Config::max_peers
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::peer_manager::{EventKind, PeerManager, ResMessage};
+
+pub(super) const EVICTION_GRACE: Duration = Duration::from_secs(30);
+
+/// The connections of all torrents of a client, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct PeerSlots {
+    used: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl Default for PeerSlots {
+    /// unlimited
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl PeerSlots {
+    /// None is unlimited
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            used: Arc::default(),
+            max,
+        }
+    }
+
+    /// the connections of all torrents right now
+    pub fn in_use(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// whether a slot is left once the `pending` connections took theirs
+    pub(super) fn has_room(&self, pending: usize) -> bool {
+        self.max.is_none_or(|max| self.in_use() + pending < max)
+    }
+
+    /// None if all slots are taken
+    pub(super) fn try_take(&self) -> Option<PeerSlot> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used < max).then_some(used + 1)
+            })
+            .ok()?;
+        Some(PeerSlot(self.used.clone()))
+    }
+}
+
+/// Held for as long as the peer is connected, dropping it frees the slot.
+#[derive(Debug)]
+pub(super) struct PeerSlot(Arc<AtomicUsize>);
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl PeerManager {
+    /// counts the peers of this torrent into the connections of all torrents
+    pub fn share_peer_slots(&mut self, slots: &PeerSlots) {
+        self.candidates.share_peer_slots(slots);
+        self.peer_slots = slots.clone();
+    }
+
+    /// A slot for a new peer, the worst peer goes if we're full. None if there's no room.
+    pub(super) async fn admit_peer(&mut self, now: Instant) -> Option<PeerSlot> {
+        let torrent_full = self
+            .config
+            .max_peers_per_torrent
+            .is_some_and(|max| self.peers.len() >= max);
+        if !torrent_full && let Some(slot) = self.peer_slots.try_take() {
+            return Some(slot);
+        }
+        let worst = self.quality.worst(now, EVICTION_GRACE)?;
+        // the new peer gets its slot, even if another torrent is waiting for one
+        let slot = self.held_slots.remove(&worst);
+        if let Some(conn) = self.peers.get(&worst) {
+            let peer = conn.identifier.0.addr;
+            eprintln!("Disconnecting the peer {peer}, it's the worst and a new peer needs room.");
+            self.events.record(EventKind::PeerDropped {
+                peer,
+                reason: "we're at the connection limit and it was the worst peer".to_string(),
+            });
+        }
+        // its connection may be gone already
+        let _ = self.send_peer(worst, ResMessage::Disconnect).await;
+        self.remove_peer(worst);
+        slot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_shared_and_freed() {
+        let slots = PeerSlots::new(Some(2));
+        let torrent = slots.clone();
+        let first = slots.try_take().unwrap();
+        let _second = torrent.try_take().unwrap();
+        assert!(slots.try_take().is_none());
+        assert_eq!(torrent.in_use(), 2);
+
+        drop(first);
+        assert_eq!(slots.in_use(), 1);
+        assert!(torrent.try_take().is_some());
+
+        let unlimited = PeerSlots::default();
+        let held: Vec<_> = (0..1000).map(|_| unlimited.try_take().unwrap()).collect();
+        assert_eq!(unlimited.in_use(), held.len());
+    }
+}
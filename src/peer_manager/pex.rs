@@ -0,0 +1,174 @@
+//! Tells every peer that supports ut_pex which peers connected and disconnected since its last message.
+//! What we told whom is kept per torrent, the [`PexPolicy`] that limits how many of the peers we're
+//! told about get dialed is shared by all torrents of a client.
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    extensions::{
+        ExtensionType,
+        pex::{MAX_ADDED, PexMsg},
+    },
+    peer_manager::{PeerManager, ResMessage, error::PeerManagerError, supports_extension},
+    policy::PeerSource,
+    sync::Mutex,
+};
+
+/// BEP 11 allows one message per minute
+pub(super) const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// the most peers learned through PEX all torrents together dial per PEX_INTERVAL
+const MAX_ADMITTED: usize = 500;
+
+#[derive(Debug, Default)]
+pub(super) struct PeerExchange {
+    /// the peers we told each peer about and didn't drop since
+    told: HashMap<[u8; 20], HashSet<SocketAddr>>,
+    /// see [`PeerManager::share_pex`]
+    pub(super) policy: PexPolicy,
+}
+
+/// Cheap to clone, all clones share the budget.
+/// Every connected peer of every torrent may tell us about 50 peers a minute,
+/// without a shared limit a client with many torrents would dial thousands of them.
+#[derive(Debug, Clone, Default)]
+pub struct PexPolicy(Arc<Mutex<PexWindow>>);
+
+#[derive(Debug, Default)]
+struct PexWindow {
+    started_at: Option<Instant>,
+    admitted: usize,
+}
+
+impl PexPolicy {
+    /// the peers that may be dialed, the rest is dropped until the next interval
+    fn admit(&self, mut peers: Vec<SocketAddr>, now: Instant) -> Vec<SocketAddr> {
+        let mut window = self.0.lock().unwrap();
+        if window
+            .started_at
+            .is_none_or(|started_at| now.duration_since(started_at) >= PEX_INTERVAL)
+        {
+            window.started_at = Some(now);
+            window.admitted = 0;
+        }
+        peers.truncate(MAX_ADMITTED - window.admitted);
+        window.admitted += peers.len();
+        peers
+    }
+}
+
+impl PeerExchange {
+    /// The changes since the last message to the peer, None if there are none.
+    /// `own_addr` is left out, nobody needs to be told about itself.
+    fn message_for(
+        &mut self,
+        peer_id: [u8; 20],
+        own_addr: Option<SocketAddr>,
+        connected: &HashSet<SocketAddr>,
+    ) -> Option<PexMsg> {
+        let told = self.told.entry(peer_id).or_default();
+        let dropped: Vec<_> = told.difference(connected).copied().collect();
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|addr| !told.contains(*addr) && Some(**addr) != own_addr)
+            .take(MAX_ADDED)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for addr in &dropped {
+            told.remove(addr);
+        }
+        told.extend(&added);
+        Some(PexMsg::new(&added, &dropped))
+    }
+
+    pub(super) fn remove_peer(&mut self, peer_id: &[u8; 20]) {
+        self.told.remove(peer_id);
+    }
+}
+
+impl PeerManager {
+    /// dials the peers PEX finds within the budget all torrents of `policy` share
+    pub fn share_pex(&mut self, policy: &PexPolicy) {
+        self.pex.policy = policy.clone();
+    }
+
+    /// private torrents only get their peers from the trackers
+    pub(super) fn on_pex_peers(&self, peers: Vec<SocketAddr>) {
+        if let Some(announcer) = &self.announcer
+            && !self.is_private()
+        {
+            let admitted = self.pex.policy.admit(peers, self.clock.now());
+            announcer.add_peers(admitted, PeerSource::Pex);
+        }
+    }
+
+    pub(super) async fn send_pex(&mut self) -> Result<(), PeerManagerError> {
+        if self.is_private() {
+            return Ok(());
+        }
+        // peers that connected to us without telling their port can't be dialed by anyone
+        let connected: HashSet<_> = self
+            .peers
+            .values()
+            .filter_map(|conn| conn.identifier.listen_addr())
+            .collect();
+        let receivers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| supports_extension(&self.peers, peer_id, ExtensionType::Pex))
+            .map(|(peer_id, conn)| (*peer_id, conn.identifier.listen_addr()))
+            .collect();
+        for (peer_id, own_addr) in receivers {
+            let Some(msg) = self.pex.message_for(peer_id, own_addr, &connected) else {
+                continue;
+            };
+            let data = serde_bencode::to_bytes(&msg).expect("a PexMsg only holds byte strings");
+            let msg = ResMessage::ExtensionData((ExtensionType::Pex, data.into()));
+            self.send_peer(peer_id, msg).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_changes_are_sent() {
+        let mut pex = PeerExchange::default();
+        let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        let mut connected: HashSet<_> = [addr(1), addr(2)].into();
+        let first = pex.message_for([1; 20], Some(addr(1)), &connected).unwrap();
+        assert_eq!(first, PexMsg::new(&[addr(2)], &[]));
+        assert_eq!(pex.message_for([1; 20], Some(addr(1)), &connected), None);
+
+        connected.remove(&addr(2));
+        connected.insert(addr(3));
+        let second = pex.message_for([1; 20], Some(addr(1)), &connected).unwrap();
+        assert_eq!(second, PexMsg::new(&[addr(3)], &[addr(2)]));
+    }
+
+    #[test]
+    fn torrents_share_the_budget() {
+        let policy = PexPolicy::default();
+        let (first, second) = (policy.clone(), policy.clone());
+        let peers = |n| -> Vec<_> {
+            (0..n)
+                .map(|port| SocketAddr::from(([10, 0, 0, 1], port)))
+                .collect()
+        };
+        let start = Instant::now();
+        assert_eq!(first.admit(peers(400), start).len(), 400);
+        assert_eq!(second.admit(peers(400), start).len(), MAX_ADMITTED - 400);
+        assert!(first.admit(peers(1), start + PEX_INTERVAL / 2).is_empty());
+        assert_eq!(second.admit(peers(400), start + PEX_INTERVAL).len(), 400);
+    }
+}
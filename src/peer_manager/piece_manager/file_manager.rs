@@ -1,6 +1,6 @@
-use std::os::unix::fs::FileExt;
+use std::{ops::Range, os::unix::fs::FileExt};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use sha1::{Digest, Sha1};
 
 use super::PieceState;
@@ -8,26 +8,68 @@ use crate::{
     BLOCK_MAX,
     messages::payloads::{RequestPiecePayload, ResponsePiecePayload},
     peer_manager::{
-        BlockState, PieceManager, error::PeerManagerError, piece_manager::DownloadQueue,
+        BlockState, PieceManager,
+        disk_budget::DiskOp,
+        error::PeerManagerError,
+        piece_manager::{
+            DownloadQueue, in_flight::BlockId, piece_set::PieceSet, req_preparer::get_piece_size,
+        },
     },
     torrent::Metainfo,
 };
 
+/// a piece whose last block came in
+#[derive(Debug, PartialEq)]
+pub(in crate::peer_manager) enum CompletedPiece {
+    Verified(u32),
+    /// one of the peers that sent its blocks sent garbage, it's downloaded again
+    Corrupt {
+        piece_i: u32,
+        senders: Vec<[u8; 20]>,
+    },
+}
+
 impl PieceManager {
-    /// writes a block to the buffer
+    /// writes a block the peer sent to the buffer
     /// handles the piece if it's the last one
-    /// returns Some index of the piece if it's finished
     pub(in crate::peer_manager) async fn write_block(
         &mut self,
         block: ResponsePiecePayload,
         metainfo: &Metainfo,
-    ) -> Result<Option<u32>, PeerManagerError> {
-        if let Some(piece_state) = self.download_queue.update_piece_state(block) {
-            self.handle_piece(&piece_state, metainfo).await?;
-            Ok(Some(piece_state.piece_i))
+        peer_id: [u8; 20],
+    ) -> Result<Option<CompletedPiece>, PeerManagerError> {
+        let Some(piece_state) = self.download_queue.update_piece_state(block, peer_id) else {
+            return Ok(None);
+        };
+        self.handle_piece(&piece_state, metainfo).await?;
+        let piece_i = piece_state.piece_i;
+        Ok(Some(if self.have.contains(piece_i as usize) {
+            CompletedPiece::Verified(piece_i)
         } else {
-            Ok(None)
-        }
+            CompletedPiece::Corrupt {
+                piece_i,
+                senders: piece_state.senders,
+            }
+        }))
+    }
+
+    /// writes a piece a web seed downloaded as a whole
+    /// returns whether it matched the hash
+    pub(in crate::peer_manager) async fn write_piece(
+        &mut self,
+        piece_i: u32,
+        data: Bytes,
+        metainfo: &Metainfo,
+    ) -> Result<bool, PeerManagerError> {
+        self.release_web_seed_piece(piece_i);
+        let piece_state = PieceState {
+            blocks: Vec::new(),
+            piece_i,
+            buf: BytesMut::from(data),
+            senders: Vec::new(),
+        };
+        self.handle_piece(&piece_state, metainfo).await?;
+        Ok(self.have.contains(piece_i as usize))
     }
 
     // it checks the hash, updates the bitfield and writes the piece to the file
@@ -39,17 +81,22 @@ impl PieceManager {
     ) -> Result<(), PeerManagerError> {
         let hashs_match = piece_state.check_hash(metainfo);
         if !hashs_match {
+            self.failed.insert(piece_state.piece_i);
             return Ok(());
         }
+        self.failed.remove(&piece_state.piece_i);
         self.write_piece_to_file(piece_state, metainfo).await?;
 
         // we first calculate the new bitfield, then update it in the DB and lastly update the struct
         // this is so if the DB fails, the struct is still in the old state
         let mut new_bitfield = self.have.clone();
         let piece_i = piece_state.piece_i as usize;
-        new_bitfield[piece_i] = true;
-        self.db_conn.update_bitfields(new_bitfield).await?;
-        self.have[piece_i] = true;
+        new_bitfield.insert(piece_i);
+        self.db_conn
+            .update_bitfields(new_bitfield.to_packed())
+            .await?;
+        self.have.insert(piece_i);
+        self.downloaded += piece_state.buf.len() as u64;
 
         Ok(())
     }
@@ -62,13 +109,51 @@ impl PieceManager {
         let offset = piece_state.piece_i as u64 * metainfo.piece_length as u64;
 
         let buf = &piece_state.buf[..];
+        let _permit = self.disk.acquire(DiskOp::Write, buf.len() as u64).await;
         self.file.write_all_at(buf, offset)?;
 
         Ok(())
     }
 
+    /// Hashes every piece in the file again and keeps only the ones that match.
+    /// `on_checked` gets the number of pieces that are done after each piece.
+    pub(in crate::peer_manager) async fn recheck(
+        &mut self,
+        metainfo: &Metainfo,
+        mut on_checked: impl FnMut(u32),
+    ) -> Result<(), PeerManagerError> {
+        let n_pieces = metainfo.pieces.len();
+        let mut have = PieceSet::from_packed(&[], n_pieces);
+        for piece_i in 0..n_pieces as u32 {
+            let mut piece_state = PieceState {
+                blocks: Vec::new(),
+                piece_i,
+                buf: BytesMut::zeroed(get_piece_size(metainfo, piece_i) as usize),
+                senders: Vec::new(),
+            };
+            let offset = piece_i as u64 * metainfo.piece_length as u64;
+            let permit = self
+                .disk
+                .acquire(DiskOp::Verify, piece_state.buf.len() as u64)
+                .await;
+            // the pieces past the end of a short file are missing
+            let read = self.file.read_exact_at(&mut piece_state.buf, offset);
+            drop(permit);
+            if read.is_ok() && piece_state.check_hash(metainfo) {
+                have.insert(piece_i as usize);
+            }
+            on_checked(piece_i + 1);
+            // hashing a large file mustn't hold up the other tasks
+            tokio::task::yield_now().await;
+        }
+        self.db_conn.update_bitfields(have.to_packed()).await?;
+        self.have = have;
+        self.failed.clear();
+        Ok(())
+    }
+
     /// returns a block a peer requested
-    pub(in crate::peer_manager) fn get_block(
+    pub(in crate::peer_manager) async fn get_block(
         &self,
         req_payload: RequestPiecePayload,
         metainfo: &Metainfo,
@@ -76,7 +161,8 @@ impl PieceManager {
         let mut buf = BytesMut::zeroed(req_payload.length as usize);
         let offset =
             req_payload.index as u64 * metainfo.piece_length as u64 + req_payload.begin as u64;
-        if self.file.read_exact_at(&mut buf, offset).is_err() {
+        let _permit = self.disk.acquire(DiskOp::Read, buf.len() as u64).await;
+        if self.read_exact_at(&mut buf, offset).is_err() {
             return None;
         }
 
@@ -88,17 +174,57 @@ impl PieceManager {
         Some(res)
     }
 
+    /// the pieces of the byte range we don't have yet
+    pub(in crate::peer_manager) fn missing_pieces(
+        &self,
+        range: &Range<u64>,
+        metainfo: &Metainfo,
+    ) -> Vec<u32> {
+        let piece_length = metainfo.piece_length as u64;
+        if range.is_empty() {
+            return Vec::new();
+        }
+        let pieces = (range.start / piece_length) as u32..=((range.end - 1) / piece_length) as u32;
+        pieces
+            .filter(|piece_i| !self.have.contains(*piece_i as usize))
+            .collect()
+    }
+
+    /// reads the byte range, every piece of it has to be verified
+    pub(in crate::peer_manager) async fn read(&self, range: Range<u64>) -> std::io::Result<Bytes> {
+        let mut buf = BytesMut::zeroed((range.end - range.start) as usize);
+        let _permit = self.disk.acquire(DiskOp::Read, buf.len() as u64).await;
+        self.read_exact_at(&mut buf, range.start)?;
+        Ok(buf.freeze())
+    }
+
+    /// from the map once we seed, see [`PieceManager::map_for_seeding`]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        match &self.mapped {
+            Some(mapped) => mapped.read_exact_at(buf, offset),
+            None => self.file.read_exact_at(buf, offset),
+        }
+    }
+
     pub(in crate::peer_manager) fn is_finished(&self) -> bool {
-        self.have.iter().all(|b| *b)
+        self.have.is_full()
     }
 }
 
 impl DownloadQueue {
     /// function that updates the PieceState in the queue in response to a payload
     /// also if we're done with the piece, it gets removed and returned from the queue
-    fn update_piece_state(&mut self, block: ResponsePiecePayload) -> Option<PieceState> {
+    fn update_piece_state(
+        &mut self,
+        block: ResponsePiecePayload,
+        peer_id: [u8; 20],
+    ) -> Option<PieceState> {
+        self.in_flight.complete(BlockId {
+            piece_i: block.index,
+            begin: block.begin,
+        });
         let (queue_i, piece_state) = self
-            .0
+            .pieces
             .iter_mut()
             .enumerate()
             .find(|(_i, s)| s.piece_i == block.index)?;
@@ -108,9 +234,12 @@ impl DownloadQueue {
         // recursion not really ideal
 
         piece_state.update_state(block);
+        if !piece_state.senders.contains(&peer_id) {
+            piece_state.senders.push(peer_id);
+        }
         if piece_state.blocks.iter().all(|b| b.is_finished()) {
             // we're done with this piece
-            Some(self.0.swap_remove(queue_i))
+            Some(self.pieces.swap_remove(queue_i))
         } else {
             None
         }
@@ -133,7 +262,7 @@ impl PieceState {
         let mut sha1 = Sha1::new();
         sha1.update(&self.buf);
         let hash: [u8; 20] = sha1.finalize().into();
-        let torrent_hash = torrent_info.pieces.0[self.piece_i as usize];
-        hash == torrent_hash
+        let torrent_hash = torrent_info.pieces.hash_of(self.piece_i as usize);
+        &hash == torrent_hash
     }
 }
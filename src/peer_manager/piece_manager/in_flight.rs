@@ -0,0 +1,242 @@
+//! The authoritative registry of the blocks we have requested and from whom.
+//! `BlockState::InProcess` in the PieceState only says that *someone* is downloading a block,
+//! this registry guarantees that it is exactly one peer.
+//! A peer that doesn't send a block in time loses it, see [`InFlight::expire`].
+//! Which pieces have blocks in flight is a packed set, the owners are only kept for the blocks of
+//! those few pieces, so the registry doesn't grow with the torrent.
+use std::time::{Duration, Instant};
+
+use crate::{BLOCK_MAX, peer_manager::piece_manager::PieceSet};
+
+/// identifies a block by the piece it's in and its offset in that piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(in crate::peer_manager) struct BlockId {
+    pub(in crate::peer_manager) piece_i: u32,
+    pub(in crate::peer_manager) begin: u32,
+}
+
+impl BlockId {
+    fn block_i(&self) -> usize {
+        (self.begin / BLOCK_MAX) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Assignment {
+    peer_id: [u8; 20],
+    at: Instant,
+}
+
+/// the owners of the blocks of a piece, by the index of the block
+#[derive(Debug)]
+struct PieceFlight {
+    piece_i: u32,
+    owners: Vec<Option<Assignment>>,
+}
+
+/// maps each requested block to the peer we requested it from
+#[derive(Debug, Default)]
+pub(in crate::peer_manager) struct InFlight {
+    /// the pieces with at least one block in flight
+    pieces: PieceSet,
+    /// one entry per piece in `pieces`
+    flights: Vec<PieceFlight>,
+}
+
+impl InFlight {
+    /// assigns the block to the peer
+    /// returns false if the block is already assigned to any peer
+    pub(super) fn try_assign(&mut self, block: BlockId, peer_id: [u8; 20], now: Instant) -> bool {
+        if self.contains(&block) {
+            return false;
+        }
+        let piece_i = block.piece_i as usize;
+        if self.pieces.len() <= piece_i {
+            self.pieces.resize(piece_i + 1);
+        }
+        if !self.pieces.contains(piece_i) {
+            self.pieces.insert(piece_i);
+            self.flights.push(PieceFlight {
+                piece_i: block.piece_i,
+                owners: Vec::new(),
+            });
+        }
+        let owners = &mut self.flight_mut(block.piece_i).owners;
+        if owners.len() <= block.block_i() {
+            owners.resize(block.block_i() + 1, None);
+        }
+        owners[block.block_i()] = Some(Assignment { peer_id, at: now });
+        true
+    }
+
+    /// removes the block from the registry since we received it
+    /// returns the peer it was assigned to
+    pub(super) fn complete(&mut self, block: BlockId) -> Option<[u8; 20]> {
+        if !self.pieces.contains(block.piece_i as usize) {
+            return None;
+        }
+        let owner = self
+            .flight_mut(block.piece_i)
+            .owners
+            .get_mut(block.block_i())?
+            .take()?;
+        self.forget_idle_pieces();
+        Some(owner.peer_id)
+    }
+
+    /// unassigns all blocks of a peer, e.g. because it disconnected
+    /// returns the blocks so they can be handed to someone else
+    pub(super) fn release_peer(&mut self, peer_id: [u8; 20]) -> Vec<BlockId> {
+        let released = self
+            .take_where(|owner| owner.peer_id == peer_id)
+            .into_iter()
+            .map(|(block, _)| block)
+            .collect();
+        self.forget_idle_pieces();
+        released
+    }
+
+    /// unassigns the blocks that were requested more than `timeout` ago
+    /// returns them with the peers that didn't send them
+    pub(super) fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<(BlockId, [u8; 20])> {
+        let expired = self
+            .take_where(|owner| now.saturating_duration_since(owner.at) >= timeout)
+            .into_iter()
+            .map(|(block, owner)| (block, owner.peer_id))
+            .collect();
+        self.forget_idle_pieces();
+        expired
+    }
+
+    pub(super) fn contains(&self, block: &BlockId) -> bool {
+        self.pieces.contains(block.piece_i as usize)
+            && self
+                .flights
+                .iter()
+                .find(|flight| flight.piece_i == block.piece_i)
+                .and_then(|flight| flight.owners.get(block.block_i()))
+                .is_some_and(Option::is_some)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.flights
+            .iter()
+            .flat_map(|flight| flight.owners.iter())
+            .filter(|owner| owner.is_some())
+            .count()
+    }
+
+    /// forgets every assignment, see [`PieceManager::reset_in_flight`]
+    ///
+    /// [`PieceManager::reset_in_flight`]: crate::peer_manager::PieceManager::reset_in_flight
+    pub(super) fn clear(&mut self) {
+        self.pieces = PieceSet::default();
+        self.flights.clear();
+    }
+
+    /// the piece must be in `pieces`
+    fn flight_mut(&mut self, piece_i: u32) -> &mut PieceFlight {
+        self.flights
+            .iter_mut()
+            .find(|flight| flight.piece_i == piece_i)
+            .expect("Every piece in the set has a flight.")
+    }
+
+    /// unassigns the blocks whose owner matches, the pieces stay until [`Self::forget_idle_pieces`]
+    fn take_where(&mut self, matches: impl Fn(&Assignment) -> bool) -> Vec<(BlockId, Assignment)> {
+        let mut taken = Vec::new();
+        for flight in self.flights.iter_mut() {
+            for (block_i, owner) in flight.owners.iter_mut().enumerate() {
+                if let Some(assignment) = owner.take_if(|owner| matches(owner)) {
+                    let block = BlockId {
+                        piece_i: flight.piece_i,
+                        begin: block_i as u32 * BLOCK_MAX,
+                    };
+                    taken.push((block, assignment));
+                }
+            }
+        }
+        taken
+    }
+
+    /// drops the pieces without any block in flight
+    fn forget_idle_pieces(&mut self) {
+        let pieces = &mut self.pieces;
+        self.flights.retain(|flight| {
+            let busy = flight.owners.iter().any(Option::is_some);
+            if !busy {
+                pieces.remove(flight.piece_i as usize);
+            }
+            busy
+        });
+    }
+
+    /// the number of bytes on the heap
+    #[cfg(test)]
+    fn heap_size(&self) -> usize {
+        self.pieces.heap_size()
+            + self.flights.capacity() * size_of::<PieceFlight>()
+            + (self.flights.iter())
+                .map(|flight| flight.owners.capacity() * size_of::<Option<Assignment>>())
+                .sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_A: [u8; 20] = [1; 20];
+    const PEER_B: [u8; 20] = [2; 20];
+
+    fn block(piece_i: u32, block_i: u32) -> BlockId {
+        BlockId {
+            piece_i,
+            begin: block_i * BLOCK_MAX,
+        }
+    }
+
+    #[test]
+    fn one_owner_per_block() {
+        let now = Instant::now();
+        let mut in_flight = InFlight::default();
+        assert!(in_flight.try_assign(block(3, 1), PEER_A, now));
+        assert!(!in_flight.try_assign(block(3, 1), PEER_B, now));
+        assert!(in_flight.try_assign(block(3, 0), PEER_B, now));
+        assert!(in_flight.try_assign(block(0, 0), PEER_B, now));
+        assert_eq!(in_flight.len(), 3);
+
+        assert_eq!(in_flight.release_peer(PEER_B), [block(3, 0), block(0, 0)]);
+        assert!(!in_flight.contains(&block(0, 0)));
+        assert_eq!(in_flight.complete(block(3, 1)), Some(PEER_A));
+        assert_eq!(in_flight.complete(block(3, 1)), None);
+        assert_eq!(in_flight.len(), 0);
+        assert_eq!(in_flight.pieces.count_ones(), 0);
+        assert!(in_flight.flights.is_empty());
+    }
+
+    #[test]
+    fn expire_only_the_late_blocks() {
+        let now = Instant::now();
+        let mut in_flight = InFlight::default();
+        in_flight.try_assign(block(1, 0), PEER_A, now);
+        in_flight.try_assign(block(1, 1), PEER_B, now + Duration::from_secs(10));
+        let expired = in_flight.expire(now + Duration::from_secs(15), Duration::from_secs(10));
+        assert_eq!(expired, [(block(1, 0), PEER_A)]);
+        assert!(in_flight.contains(&block(1, 1)));
+    }
+
+    #[test]
+    fn large_torrent_memory() {
+        let n_pieces = 100_000;
+        let now = Instant::now();
+        let mut in_flight = InFlight::default();
+        // a full request pipeline in the last piece of the torrent
+        for block_i in 0..16 {
+            in_flight.try_assign(block(n_pieces - 1, block_i), PEER_A, now);
+        }
+        // one bit per piece and the owners of a single piece
+        let bitset = (n_pieces as usize).div_ceil(64) * size_of::<u64>();
+        assert!(in_flight.heap_size() < bitset + 4 * size_of::<PieceFlight>() + 1024);
+    }
+}
@@ -0,0 +1,95 @@
+//! The file of a torrent we seed, mapped into memory, see [`Config::mmap_reads`]. A block a peer
+//! asks for is copied straight out of the page cache instead of read with a syscall of its own.
+//! Touching a page past the end of a file that shrank kills the process with SIGBUS. Every read
+//! first checks that the file is still as long as the map and goes through `pread` if it isn't,
+//! which only narrows the window: a file truncated between the check and the copy still crashes
+//! us. Only use the map if nothing else writes to the download directory.
+//!
+//! [`Config::mmap_reads`]: crate::Config::mmap_reads
+use std::{fs::File, io, os::unix::fs::FileExt, path::Path};
+
+#[derive(Debug)]
+#[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+pub(super) struct MappedFile {
+    #[cfg(feature = "mmap")]
+    map: memmap2::Mmap,
+    /// opened read-only for the map and its length
+    file: File,
+}
+
+impl MappedFile {
+    /// None if the file can't be mapped, the reads go through `pread` then
+    #[cfg(feature = "mmap")]
+    pub(super) fn open(path: &Path) -> Option<Self> {
+        let file = File::open(path)
+            .inspect_err(|e| eprintln!("Failed to open `{}` for mapping: {e}", path.display()))
+            .ok()?;
+        // SAFETY: not guaranteed. The length check of every read catches a file that shrank before
+        // the read, not one that shrinks during the copy; that's why the map is opt-in. Our own
+        // writes to the file only replace pieces that failed the hash and never shrink it.
+        let map = unsafe { memmap2::Mmap::map(&file) }
+            .inspect_err(|e| eprintln!("Failed to map `{}`: {e}", path.display()))
+            .ok()?;
+        Some(Self { map, file })
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    pub(super) fn open(_path: &Path) -> Option<Self> {
+        eprintln!(
+            "`mmap_reads` is set but this build lacks the `mmap` feature, reading with pread."
+        );
+        None
+    }
+
+    /// None if the range is outside the file, e.g. because it was truncated after we mapped it.
+    /// A truncation after the check isn't caught, see the module docs.
+    #[cfg(feature = "mmap")]
+    fn slice(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        let end = offset.checked_add(len as u64)?;
+        let file_len = self.file.metadata().ok()?.len();
+        if end > file_len.min(self.map.len() as u64) {
+            return None;
+        }
+        Some(&self.map[offset as usize..end as usize])
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn slice(&self, _offset: u64, _len: usize) -> Option<&[u8]> {
+        None
+    }
+
+    /// from the map if the range is still in the file, otherwise with `pread`
+    pub(super) fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self.slice(offset, buf.len()) {
+            Some(mapped) => {
+                buf.copy_from_slice(mapped);
+                Ok(())
+            }
+            None => self.file.read_exact_at(buf, offset),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mmap"))]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn truncated_file_falls_back_to_pread() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"0123456789").unwrap();
+        let mapped = MappedFile::open(tmp.path()).unwrap();
+        let mut buf = [0; 4];
+        mapped.read_exact_at(&mut buf, 3).unwrap();
+        assert_eq!(&buf, b"3456");
+        assert!(mapped.read_exact_at(&mut buf, 8).is_err());
+
+        tmp.as_file().set_len(5).unwrap();
+        assert!(mapped.slice(3, 4).is_none());
+        assert!(mapped.read_exact_at(&mut buf, 3).is_err());
+        mapped.read_exact_at(&mut buf[..2], 3).unwrap();
+        assert_eq!(&buf[..2], b"34");
+    }
+}
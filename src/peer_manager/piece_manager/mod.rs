@@ -1,28 +1,59 @@
 use std::{
+    collections::HashSet,
+    ffi::OsString,
     fs::{File, OpenOptions},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use crate::{
     Torrent,
+    config::Config,
     database::DBConnection,
     peer_manager::{
-        PieceState, error::PeerManagerError, piece_manager::req_preparer::DownloadQueue,
+        PieceState,
+        disk_budget::DiskShare,
+        error::PeerManagerError,
+        piece_manager::{mapped_file::MappedFile, req_preparer::DownloadQueue},
     },
+    tracker::{merge_trackers, torrent_trackers},
 };
 mod file_manager;
+pub(super) use file_manager::CompletedPiece;
+mod in_flight;
+mod mapped_file;
+pub(super) mod piece_selector;
+mod piece_set;
+pub(crate) use piece_set::PieceSet;
 mod req_preparer;
 
 #[derive(Debug)]
 pub(super) struct PieceManager {
     /// I need this information too often to always query the DB
     /// so let's cache it
-    pub(super) have: Vec<bool>,
+    pub(super) have: PieceSet,
     /// if it's None, we are finished
     download_queue: DownloadQueue,
     db_conn: DBConnection,
     /// the output file
     file: File,
+    /// where `file` is, it ends with `.part` until the download is finished
+    path: PathBuf,
+    /// of every torrent file and magnet link of the info hash that was added, in tiers
+    pub(super) trackers: Vec<Vec<url::Url>>,
+    /// `file` mapped for the reads once it's complete, see [`Config::mmap_reads`]
+    mapped: Option<MappedFile>,
+    /// the amount of verified bytes we have downloaded since the start
+    pub(super) downloaded: u64,
+    /// the amount of bytes we have uploaded, restored from the DB
+    pub(super) uploaded: u64,
+    /// `uploaded` when we started, the trackers only want to know about this session
+    uploaded_at_start: u64,
+    /// the name of the ratio group this torrent is assigned to
+    pub(super) ratio_group: Option<String>,
+    /// pieces whose last download didn't match the hash
+    pub(super) failed: HashSet<u32>,
+    /// this torrent's share of the disk bandwidth of the client
+    pub(super) disk: DiskShare,
 }
 
 impl PieceManager {
@@ -30,37 +61,215 @@ impl PieceManager {
         db_conn: DBConnection,
         file_path: Option<PathBuf>,
         torrent: &Torrent,
+        config: &Config,
     ) -> Result<Self, PeerManagerError> {
-        let file_path = file_path.unwrap_or(torrent.info.name.clone().into());
+        let piece_length = torrent.info.piece_length;
+        let buffer_budget = config
+            .low_memory
+            .map(|low_memory| low_memory.piece_buffer_budget);
+        if let Some(budget) = buffer_budget
+            && budget < piece_length as u64
+        {
+            return Err(PeerManagerError::PieceBufferBudget {
+                budget,
+                piece_length,
+            });
+        }
+
+        let file_path = file_path.unwrap_or(config.paths().data_file(&torrent.info.name));
         let file_entry = db_conn.get_entry().await?;
         let file_existed = file_entry.is_some();
-        let file_entry = if let Some(file_entry) = file_entry {
+        let file_entry = if let Some(mut file_entry) = file_entry {
+            // the torrent file or magnet link may know trackers the one we added first didn't
+            if merge_trackers(&mut file_entry.trackers, torrent_trackers(torrent)) {
+                db_conn.update_trackers(&file_entry.trackers).await?;
+            }
             file_entry
         } else {
-            db_conn.set_entry(file_path, torrent.clone()).await?
+            // other tools watching the directory shouldn't see the file before it's complete
+            db_conn
+                .set_entry(part_path(&file_path), torrent.clone())
+                .await?
         };
 
-        let file = OpenOptions::new()
-            .create(!file_existed)
-            .append(true)
-            .truncate(false)
-            .open(&file_entry.file)
-            .map_err(|error| PeerManagerError::OpenError {
+        let file = open_data_file(&file_entry.file, !file_existed).map_err(|error| {
+            PeerManagerError::OpenError {
                 path: file_entry.file.to_path_buf(),
                 error,
-            })?;
-
-        let download_queue = if file_entry.is_finished() {
-            todo!("We are finished and now seeding which isn't implemented yet.")
-        } else {
-            DownloadQueue::new()
-        };
+            }
+        })?;
 
-        Ok(PieceManager {
-            have: file_entry.bitfield.to_vec(),
-            download_queue,
+        let mut piece_manager = PieceManager {
+            have: PieceSet::from_packed(&file_entry.bitfield, torrent.info.pieces.len()),
+            download_queue: DownloadQueue::new(buffer_budget),
             db_conn,
             file,
-        })
+            path: file_entry.file.to_path_buf(),
+            trackers: file_entry.trackers,
+            mapped: None,
+            downloaded: 0,
+            uploaded: file_entry.uploaded,
+            uploaded_at_start: file_entry.uploaded,
+            ratio_group: file_entry.ratio_group,
+            failed: HashSet::new(),
+            disk: DiskShare::default(),
+        };
+        // we may have stopped between the last piece and the rename
+        if piece_manager.is_finished() {
+            piece_manager.move_to_final_path().await?;
+            if config.mmap_reads {
+                piece_manager.map_for_seeding();
+            }
+        }
+        Ok(piece_manager)
+    }
+
+    /// Renames the finished download to its final name, the open file stays valid.
+    /// Does nothing if it's there already.
+    /// A multi-file torrent is still one file with all of its files back to back, it's renamed as a
+    /// whole; splitting it into its files is not done yet.
+    pub(super) async fn move_to_final_path(&mut self) -> Result<(), PeerManagerError> {
+        let Some(final_path) = final_path(&self.path) else {
+            return Ok(());
+        };
+        rename_without_replacing(&self.path, &final_path)?;
+        self.db_conn.update_file(&final_path).await?;
+        self.path = final_path;
+        Ok(())
+    }
+
+    /// serves the reads from the page cache from now on, it keeps reading with `pread` if the file
+    /// can't be mapped
+    pub(super) fn map_for_seeding(&mut self) {
+        if self.mapped.is_none() {
+            self.mapped = MappedFile::open(&self.path);
+        }
+    }
+
+    pub(super) async fn set_ratio_group(
+        &mut self,
+        ratio_group: String,
+    ) -> Result<(), PeerManagerError> {
+        self.db_conn.update_ratio_group(&ratio_group).await?;
+        self.ratio_group = Some(ratio_group);
+        Ok(())
+    }
+
+    /// the pieces in the download queue and at the web seeds
+    pub(super) fn queued_pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        let queue = &self.download_queue;
+        (queue.pieces.iter().map(|state| state.piece_i)).chain(queue.web_seeded.iter().copied())
+    }
+
+    /// where the data is
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn session_uploaded(&self) -> u64 {
+        self.uploaded - self.uploaded_at_start
+    }
+
+    /// writes the upload counter to the DB
+    pub(super) async fn persist_uploaded(&self) -> Result<(), PeerManagerError> {
+        self.db_conn.update_uploaded(self.uploaded).await?;
+        Ok(())
+    }
+
+    /// retries the DB writes that failed until they go through or we give up
+    pub(super) async fn flush_db(&self) -> Result<(), PeerManagerError> {
+        self.db_conn.flush().await?;
+        Ok(())
+    }
+}
+
+/// The pieces come in any order and are written at their offsets, so no `append`: with it, Linux
+/// ignores the offset of `pwrite` and appends.
+fn open_data_file(path: &Path, create: bool) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(create)
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// fails instead of replacing a file that already has the final name, it isn't ours
+fn rename_without_replacing(from: &Path, to: &Path) -> Result<(), PeerManagerError> {
+    let rename_error = |error| PeerManagerError::RenameError {
+        from: from.to_path_buf(),
+        to: to.to_path_buf(),
+        error,
+    };
+    if to.try_exists().map_err(rename_error)? {
+        return Err(rename_error(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "a file with the final name exists already",
+        )));
+    }
+    std::fs::rename(from, to).map_err(rename_error)
+}
+
+/// `<path>.part`, where the data is written while we download it
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = OsString::from(path.as_os_str());
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// the path without `.part`, None if it doesn't end with it
+fn final_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".part")?;
+    Some(path.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_path_roundtrip() {
+        let path = Path::new("downloads/sample.txt");
+        assert_eq!(part_path(path), Path::new("downloads/sample.txt.part"));
+        assert_eq!(final_path(&part_path(path)).as_deref(), Some(path));
+        assert_eq!(final_path(path), None);
+    }
+
+    #[test]
+    fn pieces_land_at_their_offsets() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.txt.part");
+        let file = open_data_file(&path, true).unwrap();
+        // the last piece first, like rarest-first does it all the time
+        file.write_all_at(b"world", 6).unwrap();
+        file.write_all_at(b"hello ", 0).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        // reopening keeps what's there
+        let file = open_data_file(&path, false).unwrap();
+        file.write_all_at(b"W", 6).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello World");
+    }
+
+    #[test]
+    fn final_name_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("sample.txt.part");
+        let done = dir.path().join("sample.txt");
+        std::fs::write(&part, b"ours").unwrap();
+        std::fs::write(&done, b"theirs").unwrap();
+        let err = rename_without_replacing(&part, &done).unwrap_err();
+        assert!(matches!(
+            err,
+            PeerManagerError::RenameError { error, .. }
+                if error.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(std::fs::read(&done).unwrap(), b"theirs");
+
+        std::fs::remove_file(&done).unwrap();
+        rename_without_replacing(&part, &done).unwrap();
+        assert_eq!(std::fs::read(&done).unwrap(), b"ours");
+        assert!(!part.exists());
     }
 }
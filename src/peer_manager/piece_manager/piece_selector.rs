@@ -0,0 +1,202 @@
+//! Counts how many connected peers have each piece so we download the rarest pieces first.
+//! A piece only few peers have is gone once they leave, the common ones we can get anytime.
+//! The counts are stored in binary across bit planes: plane `j` holds bit `j` of the count of every
+//! piece. With 50 peers that are 6 bits per piece instead of the 32 of a `u32` per piece, and a
+//! peer's bitfield is added or removed 64 pieces at a time.
+use rand::seq::IteratorRandom;
+
+use crate::peer_manager::piece_manager::PieceSet;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(in crate::peer_manager) struct PieceSelector {
+    /// a plane is added once a count needs another bit
+    planes: Vec<PieceSet>,
+    /// the length of every plane, as long as the longest bitfield
+    len: usize,
+}
+
+impl PieceSelector {
+    pub(in crate::peer_manager) fn add_peer(&mut self, has: &PieceSet) {
+        self.fit(has.len());
+        for (word_i, word) in has.words().iter().enumerate() {
+            let mut carry = *word;
+            for plane in self.planes.iter_mut() {
+                if carry == 0 {
+                    break;
+                }
+                let bits = &mut plane.words_mut()[word_i];
+                let overflow = *bits & carry;
+                *bits ^= carry;
+                carry = overflow;
+            }
+            if carry != 0 {
+                self.push_plane().words_mut()[word_i] = carry;
+            }
+        }
+    }
+
+    /// `has` must be the bitfield the peer was counted with
+    pub(in crate::peer_manager) fn remove_peer(&mut self, has: &PieceSet) {
+        for (word_i, word) in has.words().iter().enumerate() {
+            let mut borrow = *word;
+            for plane in self.planes.iter_mut() {
+                if borrow == 0 {
+                    break;
+                }
+                let bits = &mut plane.words_mut()[word_i];
+                let underflow = !*bits & borrow;
+                *bits ^= borrow;
+                borrow = underflow;
+            }
+            assert_eq!(
+                borrow, 0,
+                "A peer is only removed with the pieces it was added with."
+            );
+        }
+    }
+
+    /// a peer that was counted without the piece got it
+    pub(in crate::peer_manager) fn have(&mut self, piece_i: usize) {
+        self.fit(piece_i + 1);
+        for plane in self.planes.iter_mut() {
+            if !plane.contains(piece_i) {
+                plane.insert(piece_i);
+                return;
+            }
+            plane.remove(piece_i);
+        }
+        self.push_plane().insert(piece_i);
+    }
+
+    pub(in crate::peer_manager) fn rarity(&self, piece_i: u32) -> u32 {
+        self.planes
+            .iter()
+            .enumerate()
+            .filter(|(_, plane)| plane.contains(piece_i as usize))
+            .map(|(bit, _)| 1 << bit)
+            .sum()
+    }
+
+    /// one of the candidates that the fewest peers have, chosen at random among equally rare ones
+    pub(in crate::peer_manager) fn rarest(
+        &self,
+        candidates: impl Iterator<Item = u32> + Clone,
+    ) -> Option<u32> {
+        let min = candidates.clone().map(|i| self.rarity(i)).min()?;
+        candidates
+            .filter(|i| self.rarity(*i) == min)
+            .choose(&mut rand::rng())
+    }
+
+    /// every plane spans at least `len` pieces
+    fn fit(&mut self, len: usize) {
+        if self.len < len {
+            self.len = len;
+            for plane in self.planes.iter_mut() {
+                plane.resize(len);
+            }
+        }
+    }
+
+    fn push_plane(&mut self) -> &mut PieceSet {
+        self.planes.push(PieceSet::new(self.len));
+        self.planes.last_mut().unwrap()
+    }
+
+    /// Panics in debug builds if the counts drifted from the bitfields of the connected peers.
+    pub(in crate::peer_manager) fn debug_check<'a>(
+        &self,
+        bitfields: impl Iterator<Item = &'a PieceSet>,
+    ) {
+        if cfg!(debug_assertions) {
+            let mut expected = PieceSelector::default();
+            for has in bitfields {
+                expected.add_peer(has);
+            }
+            let counts = |selector: &PieceSelector| {
+                let mut counts: Vec<u32> = (0..selector.len as u32)
+                    .map(|i| selector.rarity(i))
+                    .collect();
+                let trimmed = counts.iter().rposition(|c| *c != 0).map_or(0, |i| i + 1);
+                counts.truncate(trimmed);
+                counts
+            };
+            assert_eq!(
+                counts(self),
+                counts(&expected),
+                "the rarity counts drifted from the bitfields of the peers"
+            );
+        }
+    }
+
+    /// the number of bytes on the heap
+    #[cfg(test)]
+    fn heap_size(&self) -> usize {
+        self.planes.capacity() * size_of::<PieceSet>()
+            + self.planes.iter().map(PieceSet::heap_size).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn churn_keeps_the_counts() {
+        let mut selector = PieceSelector::default();
+        let mut peers: Vec<PieceSet> = Vec::new();
+        // peers come and go, send bitfields and haves in every order
+        for round in 0..50_usize {
+            let has: Vec<bool> = (0..70).map(|i| (round + i) % 3 == 0).collect();
+            let has = PieceSet::from_bools(&has);
+            selector.add_peer(&has);
+            peers.push(has);
+            if round % 4 == 0 {
+                let peer = &mut peers[round / 2];
+                if !peer.contains(round % 70) {
+                    peer.insert(round % 70);
+                    selector.have(round % 70);
+                }
+            }
+            if round % 3 == 0 {
+                let gone = peers.swap_remove(round % peers.len());
+                selector.remove_peer(&gone);
+            }
+            selector.debug_check(peers.iter());
+        }
+        for gone in peers.drain(..) {
+            selector.remove_peer(&gone);
+        }
+        selector.debug_check(std::iter::empty());
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut selector = PieceSelector::default();
+        selector.add_peer(&PieceSet::from_bools(&[true, true, false]));
+        selector.add_peer(&PieceSet::from_bools(&[true, false, true]));
+        selector.add_peer(&PieceSet::from_bools(&[true, false, false]));
+        assert_eq!(selector.rarity(0), 3);
+        assert_eq!(selector.rarest(0..2), Some(1));
+        assert_eq!(selector.rarest([0].into_iter()), Some(0));
+        assert_eq!(selector.rarest(std::iter::empty()), None);
+        let rare = selector.rarest(0..3).unwrap();
+        assert!(rare == 1 || rare == 2);
+    }
+
+    #[test]
+    fn large_torrent_memory() {
+        let n_pieces = 100_000;
+        let mut selector = PieceSelector::default();
+        let seed = PieceSet::from_bools(&vec![true; n_pieces]);
+        for _ in 0..50 {
+            selector.add_peer(&seed);
+        }
+        assert_eq!(selector.rarity(n_pieces as u32 - 1), 50);
+        // 50 fits into 6 bits, a u32 per piece would take 400KB
+        let plane = n_pieces.div_ceil(64) * size_of::<u64>();
+        assert_eq!(selector.planes.len(), 6);
+        assert!(selector.heap_size() <= 6 * plane + 8 * size_of::<PieceSet>());
+        assert!(selector.heap_size() < n_pieces * size_of::<u32>() / 5);
+    }
+}
@@ -0,0 +1,169 @@
+//! A packed bitset of pieces: one bit per piece instead of the byte a `Vec<bool>` needs.
+//! For torrents with 100k+ pieces this is the difference between 12KiB and 100KiB per copy.
+//! It holds what we have, what every peer has and which pieces are in flight.
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct PieceSet {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl PieceSet {
+    /// `len` pieces, none of them in the set
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    pub(crate) fn from_bools(bools: &[bool]) -> Self {
+        let mut set = Self::new(bools.len());
+        for (i, _) in bools.iter().enumerate().filter(|(_, b)| **b) {
+            set.insert(i);
+        }
+        set
+    }
+
+    /// reads the layout of the DB: the first piece is the highest bit of the first byte
+    pub(crate) fn from_packed(bytes: &[u8], len: usize) -> Self {
+        let mut set = Self::new(len);
+        for i in (0..len).filter(|i| bytes.get(i / 8).is_some_and(|b| b & (0x80 >> (i % 8)) != 0)) {
+            set.insert(i);
+        }
+        set
+    }
+
+    /// the layout of the DB and of the bitfield message
+    pub(crate) fn to_packed(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.len.div_ceil(8)];
+        for i in (0..self.len).filter(|i| self.contains(*i)) {
+            bytes[i / 8] |= 0x80 >> (i % 8);
+        }
+        bytes
+    }
+
+    /// returns false if the index is out of bounds
+    pub(crate) fn contains(&self, i: usize) -> bool {
+        i < self.len && self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub(crate) fn insert(&mut self, i: usize) {
+        assert!(i < self.len);
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+
+    pub(crate) fn remove(&mut self, i: usize) {
+        assert!(i < self.len);
+        self.words[i / 64] &= !(1 << (i % 64));
+    }
+
+    /// the number of pieces, in the set or not
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Grows or shrinks the set to `len` pieces, the new ones aren't in it.
+    pub(crate) fn resize(&mut self, len: usize) {
+        self.words.resize(len.div_ceil(64), 0);
+        if len % 64 != 0 {
+            // the pieces cut off must not come back when it grows again
+            self.words[len / 64] &= (1 << (len % 64)) - 1;
+        }
+        self.len = len;
+    }
+
+    pub(crate) fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|i| self.contains(i))
+    }
+
+    /// the pieces in the set, in order
+    pub(crate) fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(word_i, word)| {
+            let mut word = *word;
+            std::iter::from_fn(move || {
+                (word != 0).then(|| {
+                    let bit = word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    word_i * 64 + bit
+                })
+            })
+        })
+    }
+
+    /// 64 pieces per word, the first piece is the lowest bit of the first word
+    pub(in crate::peer_manager) fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    pub(in crate::peer_manager) fn words_mut(&mut self) -> &mut [u64] {
+        &mut self.words
+    }
+
+    #[cfg(test)]
+    pub(in crate::peer_manager) fn to_bools(&self) -> Vec<bool> {
+        self.iter().collect()
+    }
+
+    /// the number of bytes on the heap
+    #[cfg(test)]
+    pub(in crate::peer_manager) fn heap_size(&self) -> usize {
+        self.words.capacity() * size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let bools: Vec<bool> = (0..130).map(|i| i % 3 == 0).collect();
+        let set = PieceSet::from_bools(&bools);
+        assert_eq!(set.to_bools(), bools);
+        assert_eq!(set.count_ones(), 44);
+        assert!(!set.contains(130));
+        assert!(!set.is_full());
+        assert!(PieceSet::from_bools(&[true; 70]).is_full());
+    }
+
+    #[test]
+    fn resize_forgets_the_cut_off_pieces() {
+        let mut set = PieceSet::from_bools(&[true; 70]);
+        set.resize(3);
+        assert_eq!(set.iter_ones().collect::<Vec<_>>(), [0, 1, 2]);
+        set.resize(130);
+        assert_eq!(set.count_ones(), 3);
+        set.insert(129);
+        assert_eq!(set.iter_ones().collect::<Vec<_>>(), [0, 1, 2, 129]);
+    }
+
+    #[test]
+    fn packed_roundtrip() {
+        let bools: Vec<bool> = (0..13).map(|i| i % 4 == 0).collect();
+        let set = PieceSet::from_bools(&bools);
+        assert_eq!(set.to_packed(), vec![0b1000_1000, 0b1000_1000]);
+        assert_eq!(PieceSet::from_packed(&set.to_packed(), 13), set);
+    }
+
+    #[test]
+    fn large_torrent_memory() {
+        let n_pieces = 100_000;
+        let set = PieceSet::from_bools(&vec![false; n_pieces]);
+        // one bit per piece, rounded up to whole words
+        assert_eq!(set.heap_size(), n_pieces.div_ceil(64) * 8);
+        assert!(set.heap_size() * 8 < n_pieces + 64);
+    }
+}
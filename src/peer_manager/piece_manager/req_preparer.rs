@@ -1,115 +1,347 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    time::{Duration, Instant},
+};
+
 use bytes::BytesMut;
-use rand::seq::IteratorRandom;
 
 use crate::{
     BLOCK_MAX,
     messages::payloads::RequestPiecePayload,
-    peer_manager::{BlockState, MAX_PIECES_IN_PARALLEL, PieceManager, PieceState},
+    peer_manager::{
+        BlockState, MAX_PIECES_IN_PARALLEL, PieceManager, PieceState,
+        piece_manager::{
+            in_flight::{BlockId, InFlight},
+            piece_selector::PieceSelector,
+            piece_set::PieceSet,
+        },
+    },
     torrent::Metainfo,
 };
 
 #[derive(Debug)]
-pub(super) struct DownloadQueue(pub(in crate::peer_manager::piece_manager) Vec<PieceState>);
+pub(super) struct DownloadQueue {
+    pub(in crate::peer_manager::piece_manager) pieces: Vec<PieceState>,
+    /// which peer is downloading which block
+    pub(in crate::peer_manager::piece_manager) in_flight: InFlight,
+    /// the maximum amount of bytes the piece buffers may take up in low-memory mode
+    buffer_budget: Option<u64>,
+    /// how many peers have each piece
+    pub(in crate::peer_manager::piece_manager) rarity: PieceSelector,
+    /// the pieces a web seed is downloading, the peers leave them alone
+    pub(in crate::peer_manager::piece_manager) web_seeded: HashSet<u32>,
+    /// the pieces a reader waits for and the earliest deadline of their readers
+    wanted: HashMap<u32, Option<Instant>>,
+}
 
 impl DownloadQueue {
-    pub(super) fn new() -> Self {
-        Self(Vec::with_capacity(MAX_PIECES_IN_PARALLEL))
+    pub(super) fn new(buffer_budget: Option<u64>) -> Self {
+        Self {
+            pieces: Vec::with_capacity(MAX_PIECES_IN_PARALLEL),
+            in_flight: InFlight::default(),
+            buffer_budget,
+            rarity: PieceSelector::default(),
+            web_seeded: HashSet::new(),
+            wanted: HashMap::new(),
+        }
     }
 
+    /// the amount of bytes the piece buffers currently take up
+    fn buffered_bytes(&self) -> u64 {
+        self.pieces.iter().map(|p| p.buf.capacity() as u64).sum()
+    }
+
+    /// returns the index in the queue of the piece the peer should download next
     fn get_queue_for_peer(
         &mut self,
-        i_have: &[bool],
-        peer_has: &[bool],
+        i_have: &PieceSet,
+        peer_has: &PieceSet,
         metainfo: &Metainfo,
-    ) -> Option<&mut PieceState> {
+    ) -> Option<usize> {
         // 1. Try if we have something in the download queue
-        let piece_i = self.0.iter().position(|state| {
-            let peer_has_it = peer_has[state.piece_i as usize];
-            let blocks_we_need = state.blocks.iter().filter(|b| b.is_none());
-            // TODO: now currently if there's only one block remaining in the queue, it will return only that one
-            // we might want to return that plus like 9 more of the next piece
-            peer_has_it && blocks_we_need.count() >= 1
+        let piece_i = self.pieces.iter().position(|state| {
+            // a peer that only sent a few Haves has a bitfield shorter than the torrent
+            peer_has.contains(state.piece_i as usize) && state.blocks.iter().any(|b| b.is_none())
         });
 
-        // 2. If not, add something to the queue: realistically rarest-first
+        // 2. If not, add the rarest piece the peer has to the queue
         if piece_i.is_none() && !self.add_piece_to_queue(i_have, peer_has, metainfo) {
             return None;
         }
 
-        let piece_i = piece_i.unwrap_or_else(|| self.0.len() - 1);
         // the download_queue will have a last piece, because it may have been added by self.add_piece_to_queue. If it hasn't, we have returned.
-        Some(self.0.get_mut(piece_i).expect("we checked that before"))
+        Some(piece_i.unwrap_or(self.pieces.len() - 1))
     }
 
     /// checks whether the queue is full, if not adds a new item
     /// returns whether a new piece is added (true) or not (false)
     pub(in crate::peer_manager) fn add_piece_to_queue(
         &mut self,
-        i_have: &[bool],
-        peer_has: &[bool],
+        i_have: &PieceSet,
+        peer_has: &PieceSet,
         metainfo: &Metainfo,
     ) -> bool {
         // if the queue is already to big but we're at the last piece, we still want to add it
-        if self.0.len() == MAX_PIECES_IN_PARALLEL && i_have.iter().filter(|b| **b).count() > 1 {
+        if self.pieces.len() == MAX_PIECES_IN_PARALLEL && i_have.count_ones() > 1 {
             return false;
         }
 
-        let Some(piece_i)= i_have
+        let candidates = peer_has.iter_ones().filter_map(|index| {
+            // a piece that is already in the queue may have all of its blocks in flight,
+            // adding it a second time would hand out the same blocks again
+            let in_queue = self.pieces.iter().any(|s| s.piece_i == index as u32)
+                || self.web_seeded.contains(&(index as u32));
+            (!i_have.contains(index) && !in_queue).then_some(index as u32)
+        });
+        let candidates: Vec<u32> = candidates.collect();
+        // the pieces a reader waits for go first, those without a deadline last among them
+        let wanted = candidates
             .iter()
-            .zip(peer_has)
-            .enumerate()
-            .filter_map(|(index, (i_have, p_has))| {
-                if !*i_have && *p_has /* Now theoretically we would check if the piece is already in the queue aswell but since we checked that before calling this function I don't do it here again */{
-                    Some(index as u32)
-                } else {
-                    None
-                }
-            }).choose(&mut rand::rng()) else { return false };
+            .filter_map(|i| Some((*self.wanted.get(i)?, *i)))
+            .min_by_key(|(deadline, i)| (deadline.is_none(), *deadline, *i))
+            .map(|(_, i)| i);
+        let Some(piece_i) = wanted.or_else(|| self.rarity.rarest(candidates.into_iter())) else {
+            return false;
+        };
+        // in low-memory mode the buffers must never exceed the budget
+        if let Some(budget) = self.buffer_budget
+            && self.buffered_bytes() + get_piece_size(metainfo, piece_i) as u64 > budget
+        {
+            return false;
+        }
 
         let piece_state = PieceState::new(metainfo, piece_i);
-        self.0.push(piece_state);
+        self.pieces.push(piece_state);
 
         true
     }
-}
 
-impl PieceManager {
-    /// returns a list of blocks that we want to request
-    pub(in crate::peer_manager) fn prepare_next_blocks(
+    /// Returns up to `n` blocks that the peer should request, from as many pieces as it takes,
+    /// so a piece with only a few blocks left doesn't leave the pipeline short.
+    /// every returned block is assigned to exactly this peer in the in-flight registry
+    fn prepare_next_blocks(
         &mut self,
         n: usize,
-        peer_has: &[bool],
+        i_have: &PieceSet,
+        peer_has: &PieceSet,
         metainfo: &Metainfo,
+        peer_id: [u8; 20],
+        now: Instant,
     ) -> Vec<RequestPiecePayload> {
-        let Some(piece) = self
-            .download_queue
-            .get_queue_for_peer(&self.have, peer_has, metainfo)
-        else {
-            return vec![];
-        };
-
         let mut requests = Vec::with_capacity(n);
+        // every round marks at least one block, a piece without unmarked blocks isn't picked again
+        while requests.len() < n {
+            let Some(queue_i) = self.get_queue_for_peer(i_have, peer_has, metainfo) else {
+                break;
+            };
+            self.take_blocks(queue_i, n, peer_id, now, &mut requests);
+        }
+        requests
+    }
+
+    /// adds the unmarked blocks of the piece to `requests` until there are `n`
+    fn take_blocks(
+        &mut self,
+        queue_i: usize,
+        n: usize,
+        peer_id: [u8; 20],
+        now: Instant,
+        requests: &mut Vec<RequestPiecePayload>,
+    ) {
+        let piece = &mut self.pieces[queue_i];
         let n_blocks = piece.blocks.capacity() as u32;
         let piece_size = piece.buf.capacity() as u32;
+        let index = piece.piece_i;
 
         for (block_i, block) in piece
             .blocks
             .iter_mut()
             .enumerate()
             .filter(|(_, b)| b.is_none())
-            .take(n)
         {
+            if requests.len() == n {
+                break;
+            }
             let block_i = block_i as u32;
-            let index = piece.piece_i;
             let begin = block_i * BLOCK_MAX;
+            // it's InProcess either way: if the registry refuses, someone else already has it
+            *block = BlockState::InProcess;
+            if !self.in_flight.try_assign(
+                BlockId {
+                    piece_i: index,
+                    begin,
+                },
+                peer_id,
+                now,
+            ) {
+                continue;
+            }
             let length = get_block_len(n_blocks, piece_size, block_i);
-            let req = RequestPiecePayload::new(index, begin, length);
-            requests.push(req);
+            requests.push(RequestPiecePayload::new(index, begin, length));
+        }
+    }
 
-            *block = BlockState::InProcess;
+    /// `(assigned, orphaned)`: the blocks a peer is downloading and the ones that are marked as
+    /// in process although the registry doesn't know of any peer downloading them
+    fn in_flight_counts(&self) -> (usize, usize) {
+        let orphaned = self
+            .pieces
+            .iter()
+            .flat_map(|piece| {
+                piece.blocks.iter().enumerate().filter(|(block_i, block)| {
+                    **block == BlockState::InProcess
+                        && !self.in_flight.contains(&BlockId {
+                            piece_i: piece.piece_i,
+                            begin: *block_i as u32 * BLOCK_MAX,
+                        })
+                })
+            })
+            .count();
+        (self.in_flight.len(), orphaned)
+    }
+
+    /// every block that isn't finished may be requested again
+    fn reset_in_flight(&mut self) {
+        self.in_flight.clear();
+        for block in self.pieces.iter_mut().flat_map(|p| p.blocks.iter_mut()) {
+            if *block == BlockState::InProcess {
+                *block = BlockState::None;
+            }
         }
+    }
 
-        requests
+    /// hands the blocks of a peer back so they can be requested from someone else
+    fn release_peer(&mut self, peer_id: [u8; 20]) {
+        for block in self.in_flight.release_peer(peer_id) {
+            self.unclaim(block);
+        }
+    }
+
+    /// hands the blocks that weren't sent within `timeout` to the next peer that asks
+    /// returns the peers that didn't send them
+    fn expire_requests(&mut self, now: Instant, timeout: Duration) -> Vec<[u8; 20]> {
+        let expired = self.in_flight.expire(now, timeout);
+        let mut peers = Vec::new();
+        for (block, peer_id) in expired {
+            self.unclaim(block);
+            if !peers.contains(&peer_id) {
+                peers.push(peer_id);
+            }
+        }
+        peers
+    }
+
+    /// a block that's still in process may be requested again
+    fn unclaim(&mut self, block: BlockId) {
+        if let Some(piece) = self.pieces.iter_mut().find(|p| p.piece_i == block.piece_i) {
+            let block_i = (block.begin / BLOCK_MAX) as usize;
+            if piece.blocks[block_i] == BlockState::InProcess {
+                piece.blocks[block_i] = BlockState::None;
+            }
+        }
+    }
+}
+
+impl PieceManager {
+    /// returns a list of blocks that we want to request from the peer
+    pub(in crate::peer_manager) fn prepare_next_blocks(
+        &mut self,
+        n: usize,
+        peer_has: &PieceSet,
+        metainfo: &Metainfo,
+        peer_id: [u8; 20],
+        now: Instant,
+    ) -> Vec<RequestPiecePayload> {
+        self.download_queue
+            .prepare_next_blocks(n, &self.have, peer_has, metainfo, peer_id, now)
+    }
+
+    /// the amount of bytes of the pieces we don't have yet
+    pub(in crate::peer_manager) fn bytes_left(&self, metainfo: &Metainfo) -> u64 {
+        self.have
+            .iter()
+            .enumerate()
+            .filter(|(_, have)| !have)
+            .map(|(piece_i, _)| get_piece_size(metainfo, piece_i as u32) as u64)
+            .sum()
+    }
+
+    /// frees the blocks that were assigned to a peer that disconnected
+    pub(in crate::peer_manager) fn release_peer(&mut self, peer_id: [u8; 20]) {
+        self.download_queue.release_peer(peer_id);
+    }
+
+    /// see [`DownloadQueue::expire_requests`]
+    pub(in crate::peer_manager) fn expire_requests(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+    ) -> Vec<[u8; 20]> {
+        self.download_queue.expire_requests(now, timeout)
+    }
+
+    /// see [`DownloadQueue::in_flight_counts`]
+    pub(in crate::peer_manager) fn in_flight_counts(&self) -> (usize, usize) {
+        self.download_queue.in_flight_counts()
+    }
+
+    /// the pieces in the download queue
+    pub(in crate::peer_manager) fn pieces_in_progress(&self) -> Vec<u32> {
+        self.download_queue
+            .pieces
+            .iter()
+            .map(|p| p.piece_i)
+            .collect()
+    }
+
+    /// Forgets which peer downloads which block, so the stuck ones are handed out again.
+    /// A block that arrives late is still written.
+    pub(in crate::peer_manager) fn reset_in_flight(&mut self) {
+        self.download_queue.reset_in_flight();
+    }
+
+    /// the counts the next pieces are picked by, the PeerManager keeps them up to date
+    pub(in crate::peer_manager) fn rarity(&mut self) -> &mut PieceSelector {
+        &mut self.download_queue.rarity
+    }
+
+    /// Picks the rarest piece neither we nor the peers are downloading for a web seed,
+    /// returns it with the bytes of the file it covers.
+    pub(in crate::peer_manager) fn claim_web_seed_piece(
+        &mut self,
+        metainfo: &Metainfo,
+    ) -> Option<(u32, Range<u64>)> {
+        let queue = &self.download_queue;
+        let candidates: Vec<u32> = self
+            .have
+            .iter()
+            .enumerate()
+            .map(|(index, have)| (index as u32, have))
+            .filter(|(index, have)| {
+                !have
+                    && !queue.web_seeded.contains(index)
+                    && !queue.pieces.iter().any(|s| s.piece_i == *index)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        let piece_i = queue.rarity.rarest(candidates.into_iter())?;
+        self.download_queue.web_seeded.insert(piece_i);
+        let start = piece_i as u64 * metainfo.piece_length as u64;
+        Some((
+            piece_i,
+            start..start + get_piece_size(metainfo, piece_i) as u64,
+        ))
+    }
+
+    /// the pieces the readers wait for, they're downloaded before the rarest ones
+    pub(in crate::peer_manager) fn prioritize(&mut self, wanted: HashMap<u32, Option<Instant>>) {
+        self.download_queue.wanted = wanted;
+    }
+
+    /// the web seed is done with the piece, the peers may download it if we still need it
+    pub(in crate::peer_manager) fn release_web_seed_piece(&mut self, piece_i: u32) {
+        self.download_queue.web_seeded.remove(&piece_i);
     }
 }
 
@@ -123,14 +355,15 @@ impl PieceState {
             blocks: vec![BlockState::None; n_blocks as usize],
             piece_i,
             buf: BytesMut::zeroed(piece_size as usize),
+            senders: Vec::new(),
         }
     }
 }
 
-fn get_piece_size(torrent_info: &Metainfo, piece_i: u32) -> u32 {
+pub(super) fn get_piece_size(torrent_info: &Metainfo, piece_i: u32) -> u32 {
     let length = torrent_info.get_length();
     let piece_length = torrent_info.piece_length;
-    if piece_i == torrent_info.pieces.0.len() as u32 - 1 && length % piece_length != 0 {
+    if piece_i == torrent_info.pieces.len() as u32 - 1 && length % piece_length != 0 {
         length % piece_length
     } else {
         piece_length
@@ -144,3 +377,289 @@ fn get_block_len(n_blocks: u32, piece_size: u32, block_i: u32) -> u32 {
         BLOCK_MAX
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::test_fixtures;
+
+    const PEER_A: [u8; 20] = [1; 20];
+    const PEER_B: [u8; 20] = [2; 20];
+
+    /// 3 pieces of 2 blocks each
+    fn metainfo() -> Metainfo {
+        test_fixtures::metainfo(BLOCK_MAX as u64 * 6, BLOCK_MAX * 2)
+    }
+
+    fn block_ids(requests: &[RequestPiecePayload]) -> Vec<(u32, u32)> {
+        requests.iter().map(|r| (r.index, r.begin)).collect()
+    }
+
+    #[test]
+    fn racing_peers_never_get_the_same_block() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let peer_has = PieceSet::from_bools(&[true; 3]);
+        let mut queue = DownloadQueue::new(None);
+
+        let mut assigned = HashSet::new();
+        // the peers ask alternately like they would if their NeedBlockQueue messages interleave
+        for peer_id in [PEER_A, PEER_B].into_iter().cycle().take(10) {
+            let requests =
+                queue.prepare_next_blocks(1, &i_have, &peer_has, &metainfo, peer_id, now);
+            for block in block_ids(&requests) {
+                assert!(assigned.insert(block), "{block:?} was assigned twice");
+            }
+        }
+        assert_eq!(assigned.len(), 6);
+    }
+
+    #[test]
+    fn piece_in_flight_is_not_queued_twice() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        // only the first piece is available
+        let peer_has = PieceSet::from_bools(&[true, false, false]);
+        let mut queue = DownloadQueue::new(None);
+
+        let requests_a = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_A, now);
+        assert_eq!(block_ids(&requests_a), vec![(0, 0), (0, BLOCK_MAX)]);
+        let requests_b = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_B, now);
+        assert!(requests_b.is_empty());
+        assert_eq!(queue.pieces.len(), 1);
+    }
+
+    #[test]
+    fn blocks_of_disconnected_peer_are_reassigned() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let peer_has = PieceSet::from_bools(&[true, false, false]);
+        let mut queue = DownloadQueue::new(None);
+
+        let requests_a = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_A, now);
+        queue.release_peer(PEER_A);
+        let requests_b = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_B, now);
+        assert_eq!(block_ids(&requests_a), block_ids(&requests_b));
+    }
+
+    #[test]
+    fn stalled_requests_expire() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let peer_has = PieceSet::from_bools(&[true, false, false]);
+        let mut queue = DownloadQueue::new(None);
+        let timeout = Duration::from_secs(60);
+
+        let requests_a = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_A, now);
+        assert!(queue.expire_requests(now, timeout).is_empty());
+        assert!(
+            queue
+                .prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_B, now)
+                .is_empty()
+        );
+
+        assert_eq!(queue.expire_requests(now + timeout, timeout), [PEER_A]);
+        let requests_b = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_B, now);
+        assert_eq!(block_ids(&requests_a), block_ids(&requests_b));
+    }
+
+    #[test]
+    fn orphaned_blocks_are_reset() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let peer_has = PieceSet::from_bools(&[true, false, false]);
+        let mut queue = DownloadQueue::new(None);
+
+        let requests_a = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_A, now);
+        assert_eq!(queue.in_flight_counts(), (2, 0));
+        // the block is still marked as in process, but nobody will ever send it
+        queue.in_flight.complete(BlockId {
+            piece_i: 0,
+            begin: 0,
+        });
+        assert_eq!(queue.in_flight_counts(), (1, 1));
+        assert!(
+            queue
+                .prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_B, now)
+                .is_empty()
+        );
+
+        queue.reset_in_flight();
+        assert_eq!(queue.in_flight_counts(), (0, 0));
+        let requests_b = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_B, now);
+        assert_eq!(block_ids(&requests_a), block_ids(&requests_b));
+    }
+
+    #[test]
+    fn rarest_piece_is_queued_first() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let mut queue = DownloadQueue::new(None);
+        queue
+            .rarity
+            .add_peer(&PieceSet::from_bools(&[true, true, true]));
+        queue
+            .rarity
+            .add_peer(&PieceSet::from_bools(&[true, false, true]));
+        let requests = queue.prepare_next_blocks(
+            2,
+            &i_have,
+            &PieceSet::from_bools(&[true; 3]),
+            &metainfo,
+            PEER_A,
+            now,
+        );
+        assert_eq!(block_ids(&requests), vec![(1, 0), (1, BLOCK_MAX)]);
+    }
+
+    #[test]
+    fn pipeline_spans_pieces() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let mut queue = DownloadQueue::new(None);
+        queue
+            .rarity
+            .add_peer(&PieceSet::from_bools(&[true, true, true]));
+        queue
+            .rarity
+            .add_peer(&PieceSet::from_bools(&[false, true, true]));
+        queue
+            .rarity
+            .add_peer(&PieceSet::from_bools(&[false, false, true]));
+
+        // one block of piece 0 is left for A, the rest comes from the next rarest ones
+        let first = queue.prepare_next_blocks(
+            1,
+            &i_have,
+            &PieceSet::from_bools(&[true; 3]),
+            &metainfo,
+            PEER_B,
+            now,
+        );
+        assert_eq!(block_ids(&first), vec![(0, 0)]);
+        let requests = queue.prepare_next_blocks(
+            4,
+            &i_have,
+            &PieceSet::from_bools(&[true; 3]),
+            &metainfo,
+            PEER_A,
+            now,
+        );
+        assert_eq!(
+            block_ids(&requests),
+            vec![(0, BLOCK_MAX), (1, 0), (1, BLOCK_MAX), (2, 0)]
+        );
+        // everything is handed out, the pipeline is as long as what's left
+        let requests = queue.prepare_next_blocks(
+            10,
+            &i_have,
+            &PieceSet::from_bools(&[true; 3]),
+            &metainfo,
+            PEER_B,
+            now,
+        );
+        assert_eq!(block_ids(&requests), vec![(2, BLOCK_MAX)]);
+        assert_eq!(queue.in_flight_counts(), (6, 0));
+    }
+
+    #[test]
+    fn short_bitfield_skips_the_queued_pieces_past_it() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let mut queue = DownloadQueue::new(None);
+        let requests = queue.prepare_next_blocks(
+            1,
+            &i_have,
+            &PieceSet::from_bools(&[false, false, true]),
+            &metainfo,
+            PEER_A,
+            now,
+        );
+        assert_eq!(block_ids(&requests), vec![(2, 0)]);
+        // B only sent a Have of piece 0
+        let requests = queue.prepare_next_blocks(
+            10,
+            &i_have,
+            &PieceSet::from_bools(&[true]),
+            &metainfo,
+            PEER_B,
+            now,
+        );
+        assert_eq!(block_ids(&requests), vec![(0, 0), (0, BLOCK_MAX)]);
+    }
+
+    #[test]
+    fn wanted_pieces_before_the_rarest() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let peer_has = PieceSet::from_bools(&[true, false, true]);
+        let mut queue = DownloadQueue::new(None);
+        // piece 1 is the rarest, but nobody waits for it
+        queue
+            .rarity
+            .add_peer(&PieceSet::from_bools(&[true, true, true]));
+        queue
+            .rarity
+            .add_peer(&PieceSet::from_bools(&[true, false, true]));
+        queue.wanted = HashMap::from([(0, None), (2, Some(now))]);
+
+        let requests = queue.prepare_next_blocks(
+            2,
+            &i_have,
+            &PieceSet::from_bools(&[true; 3]),
+            &metainfo,
+            PEER_A,
+            now,
+        );
+        assert_eq!(block_ids(&requests), vec![(2, 0), (2, BLOCK_MAX)]);
+        let requests = queue.prepare_next_blocks(2, &i_have, &peer_has, &metainfo, PEER_B, now);
+        assert_eq!(block_ids(&requests), vec![(0, 0), (0, BLOCK_MAX)]);
+    }
+
+    #[test]
+    fn peers_leave_web_seeded_pieces_alone() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let mut queue = DownloadQueue::new(None);
+        queue.web_seeded.insert(0);
+        let requests = queue.prepare_next_blocks(
+            10,
+            &i_have,
+            &PieceSet::from_bools(&[true, false, true]),
+            &metainfo,
+            PEER_A,
+            now,
+        );
+        assert_eq!(block_ids(&requests), vec![(2, 0), (2, BLOCK_MAX)]);
+    }
+
+    #[test]
+    fn buffers_stay_within_budget() {
+        let metainfo = metainfo();
+        let now = Instant::now();
+        let i_have = PieceSet::from_bools(&[false; 3]);
+        let peer_has = PieceSet::from_bools(&[true; 3]);
+        // a piece is 2 blocks, so only one piece fits
+        let budget = BLOCK_MAX as u64 * 3;
+        let mut queue = DownloadQueue::new(Some(budget));
+
+        let requests_a = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_A, now);
+        assert_eq!(requests_a.len(), 2);
+        let requests_b = queue.prepare_next_blocks(10, &i_have, &peer_has, &metainfo, PEER_B, now);
+        assert!(requests_b.is_empty());
+        assert_eq!(queue.pieces.len(), 1);
+        assert!(queue.buffered_bytes() <= budget);
+    }
+}
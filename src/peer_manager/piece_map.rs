@@ -0,0 +1,292 @@
+//! A compact snapshot of the state of every piece, e.g. for drawing a piece map.
+//! The PeerManager publishes it via a watch channel so readers never block the manager.
+//! Blocks and peers come and go all the time, so the map is rebuilt at most once per
+//! [`PROGRESS_INTERVAL`], and only if something changed.
+//!
+//! [`PROGRESS_INTERVAL`]: crate::peer_manager::progress::PROGRESS_INTERVAL
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::chunks::ChunkError,
+    peer_manager::{PeerManager, TorrentState, piece_manager::PieceManager},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PieceStatus {
+    Have,
+    /// the piece is in the download queue
+    Downloading,
+    Missing,
+    /// the last attempt to download the piece failed the hash check
+    Failed,
+}
+
+/// `len` consecutive pieces with the same status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceRun {
+    pub status: PieceStatus,
+    pub len: u32,
+}
+
+/// The run-length-encoded states of all pieces of a torrent, in order.
+/// It's empty as long as we don't have the metainfo.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PieceMap(pub Vec<PieceRun>);
+
+/// Some of the runs of a [`PieceMap`], see [`PieceMap::page`].
+/// Pieces that alternate between have and missing make a run each, so even the runs of a large
+/// torrent can be too many for one response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceMapPage {
+    /// the piece the first run starts at
+    pub first_piece: u32,
+    /// the pieces of the whole map
+    pub n_pieces: u32,
+    pub runs: Vec<PieceRun>,
+}
+
+impl PieceMapPage {
+    /// where the next page starts, None if this is the last one
+    pub fn next_piece(&self) -> Option<u32> {
+        let end = self.first_piece + self.runs.iter().map(|run| run.len).sum::<u32>();
+        (end < self.n_pieces).then_some(end)
+    }
+}
+
+impl PieceMap {
+    pub fn from_statuses(statuses: impl IntoIterator<Item = PieceStatus>) -> Self {
+        let mut runs: Vec<PieceRun> = Vec::new();
+        for status in statuses {
+            match runs.last_mut() {
+                Some(run) if run.status == status => run.len += 1,
+                _ => runs.push(PieceRun { status, len: 1 }),
+            }
+        }
+        Self(runs)
+    }
+
+    /// the number of pieces in the map
+    pub fn n_pieces(&self) -> u32 {
+        self.0.iter().map(|run| run.len).sum()
+    }
+
+    /// At most `max_runs` runs from `first_piece` on, a run it's in the middle of is cut.
+    pub fn page(&self, first_piece: u32, max_runs: usize) -> PieceMapPage {
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for run in &self.0 {
+            let end = start + run.len;
+            if end > first_piece {
+                runs.push(PieceRun {
+                    status: run.status,
+                    len: end - start.max(first_piece),
+                });
+                if runs.len() == max_runs.max(1) {
+                    break;
+                }
+            }
+            start = end;
+        }
+        PieceMapPage {
+            first_piece: first_piece.min(self.n_pieces()),
+            n_pieces: self.n_pieces(),
+            runs,
+        }
+    }
+
+    /// puts the pages back together, they have to come in order
+    pub fn from_pages(pages: impl IntoIterator<Item = PieceMapPage>) -> Result<Self, ChunkError> {
+        let mut map = PieceMap::default();
+        let mut total = None;
+        for page in pages {
+            let total = *total.get_or_insert(page.n_pieces);
+            if total != page.n_pieces {
+                return Err(ChunkError::LengthChanged(
+                    total as u64,
+                    page.n_pieces as u64,
+                ));
+            }
+            let expected = map.n_pieces();
+            if page.first_piece != expected {
+                return Err(ChunkError::OutOfOrder {
+                    expected: expected as u64,
+                    found: page.first_piece as u64,
+                });
+            }
+            for run in page.runs {
+                // a run may have been cut at the end of the last page
+                match map.0.last_mut() {
+                    Some(last) if last.status == run.status => last.len += run.len,
+                    _ => map.0.push(run),
+                }
+            }
+        }
+        let received = map.n_pieces();
+        match total {
+            Some(total) if total == received => Ok(map),
+            _ => Err(ChunkError::Incomplete {
+                received: received as u64,
+                total: total.unwrap_or(0) as u64,
+            }),
+        }
+    }
+
+    /// the number of pieces with the given status
+    pub fn count(&self, status: PieceStatus) -> u32 {
+        self.0
+            .iter()
+            .filter(|run| run.status == status)
+            .map(|run| run.len)
+            .sum()
+    }
+}
+
+impl PieceManager {
+    pub(super) fn piece_map(&self) -> PieceMap {
+        let queued: HashSet<u32> = self.queued_pieces().collect();
+        PieceMap::from_statuses(self.have.iter().enumerate().map(|(piece_i, have)| {
+            let piece_i = piece_i as u32;
+            if have {
+                PieceStatus::Have
+            } else if queued.contains(&piece_i) {
+                PieceStatus::Downloading
+            } else if self.failed.contains(&piece_i) {
+                PieceStatus::Failed
+            } else {
+                PieceStatus::Missing
+            }
+        }))
+    }
+}
+
+impl PeerManager {
+    /// returns a receiver that always holds the latest piece map of this torrent
+    pub fn subscribe_piece_map(&self) -> tokio::sync::watch::Receiver<PieceMap> {
+        self.piece_map.subscribe()
+    }
+
+    /// the map is published with the next progress, for the changes that come in droves
+    pub(super) fn piece_map_changed(&mut self) {
+        self.piece_map_stale = true;
+    }
+
+    /// recomputes the piece map and notifies the receivers if it changed
+    pub(super) fn publish_piece_map(&self) {
+        let map = match &self.torrent_state {
+            TorrentState::Downloading { piece_manager, .. }
+            | TorrentState::Seeding { piece_manager, .. } => piece_manager.piece_map(),
+            TorrentState::WaitingForMetadata { .. } | TorrentState::Stopped => return,
+        };
+        self.piece_map.send_if_modified(|current| {
+            let modified = *current != map;
+            *current = map;
+            modified
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_are_merged() {
+        use PieceStatus::*;
+        let map =
+            PieceMap::from_statuses([Have, Have, Downloading, Missing, Missing, Missing, Have]);
+        assert_eq!(
+            map.0,
+            vec![
+                PieceRun {
+                    status: Have,
+                    len: 2
+                },
+                PieceRun {
+                    status: Downloading,
+                    len: 1
+                },
+                PieceRun {
+                    status: Missing,
+                    len: 3
+                },
+                PieceRun {
+                    status: Have,
+                    len: 1
+                },
+            ]
+        );
+        assert_eq!(map.n_pieces(), 7);
+        assert_eq!(map.count(Have), 3);
+        assert_eq!(PieceMap::from_statuses([]), PieceMap::default());
+    }
+
+    #[test]
+    fn paged_map() {
+        use PieceStatus::*;
+        // every other piece, the worst case for the run-length encoding
+        let statuses = (0..1001).map(|i| if i % 2 == 0 { Have } else { Missing });
+        let map = PieceMap::from_statuses(statuses);
+        assert_eq!(map.0.len(), 1001);
+
+        let mut pages = Vec::new();
+        let mut next = Some(0);
+        while let Some(first_piece) = next {
+            let page = map.page(first_piece, 100);
+            assert!(page.runs.len() <= 100);
+            next = page.next_piece();
+            pages.push(page);
+        }
+        assert_eq!(pages.len(), 11);
+        assert_eq!(PieceMap::from_pages(pages.clone()).unwrap(), map);
+
+        assert!(matches!(
+            PieceMap::from_pages(pages[1..].to_vec()),
+            Err(ChunkError::OutOfOrder {
+                expected: 0,
+                found: 100
+            })
+        ));
+        assert!(matches!(
+            PieceMap::from_pages(pages[..1].to_vec()),
+            Err(ChunkError::Incomplete {
+                received: 100,
+                total: 1001
+            })
+        ));
+    }
+
+    #[test]
+    fn page_in_the_middle_of_a_run() {
+        use PieceStatus::*;
+        let map = PieceMap::from_statuses([Have, Have, Have, Missing, Missing]);
+        let page = map.page(1, 1);
+        assert_eq!(
+            page.runs,
+            [PieceRun {
+                status: Have,
+                len: 2
+            }]
+        );
+        assert_eq!(page.next_piece(), Some(3));
+        assert_eq!(map.page(3, 10).next_piece(), None);
+        // the cut run is merged again
+        let pieces = PieceMap::from_pages([
+            PieceMapPage {
+                first_piece: 0,
+                n_pieces: 5,
+                runs: vec![PieceRun {
+                    status: Have,
+                    len: 1,
+                }],
+            },
+            page,
+            map.page(3, 10),
+        ]);
+        assert_eq!(pieces.unwrap(), map);
+        let empty = PieceMap::from_pages([PieceMap::default().page(0, 10)]);
+        assert_eq!(empty.unwrap(), PieceMap::default());
+    }
+}
@@ -0,0 +1,211 @@
+//! The download progress with an estimate of the time that's left.
+//! Published via a watch channel like the piece map, so a CLI or TUI can poll it for free.
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::peer_manager::{PeerManager, TorrentState};
+
+/// how often the throughput is sampled and the progress is published
+pub(super) const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// the weight of the newest sample in the moving average
+const RATE_SMOOTHING: f64 = 0.2;
+/// slower than this (bytes/s) we don't dare to guess when we're done
+const STALLED_RATE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eta {
+    /// we don't know the size of the torrent yet
+    WaitingForMetadata,
+    /// nothing was verified in a while
+    Stalled,
+    Remaining(Duration),
+    Finished,
+}
+
+impl Eta {
+    fn new(left: Option<u64>, rate: f64) -> Self {
+        match left {
+            None => Eta::WaitingForMetadata,
+            Some(0) => Eta::Finished,
+            Some(_) if rate < STALLED_RATE => Eta::Stalled,
+            Some(left) => Eta::Remaining(Duration::from_secs_f64(left as f64 / rate)),
+        }
+    }
+}
+
+/// how far the hashing of the data on disk got, see [`TorrentControl::recheck`]
+///
+/// [`TorrentControl::recheck`]: crate::peer_manager::TorrentControl::recheck
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CheckProgress {
+    pub checked: u32,
+    pub total: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProgressSnapshot {
+    /// verified bytes
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// None while we're waiting for the metadata
+    pub left: Option<u64>,
+    /// the smoothed rate of verified bytes per second
+    pub download_rate: f64,
+    pub eta: Eta,
+    /// Some while the pieces on disk are hashed again
+    pub checking: Option<CheckProgress>,
+}
+
+impl Default for ProgressSnapshot {
+    fn default() -> Self {
+        Self {
+            downloaded: 0,
+            uploaded: 0,
+            left: None,
+            download_rate: 0.0,
+            eta: Eta::WaitingForMetadata,
+            checking: None,
+        }
+    }
+}
+
+impl fmt::Display for ProgressSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(CheckProgress { checked, total }) = self.checking {
+            return write!(f, "checked {checked} of {total} pieces");
+        }
+        write!(
+            f,
+            "{} KiB done, {:.1} KiB/s, ",
+            self.downloaded / 1024,
+            self.download_rate / 1024.0
+        )?;
+        match self.eta {
+            Eta::WaitingForMetadata => write!(f, "waiting for metadata"),
+            Eta::Stalled => write!(f, "stalled"),
+            Eta::Remaining(eta) => {
+                let secs = eta.as_secs();
+                write!(f, "{}m {:02}s left", secs / 60, secs % 60)
+            }
+            Eta::Finished => write!(f, "finished"),
+        }
+    }
+}
+
+/// An exponentially weighted moving average of the verified bytes per second.
+/// Only verified bytes count, so a peer sending garbage doesn't make us look faster.
+#[derive(Debug, Default)]
+pub(super) struct ThroughputEstimator {
+    last_sample: Option<(Instant, u64)>,
+    rate: f64,
+}
+
+impl ThroughputEstimator {
+    /// returns the smoothed rate after adding the sample
+    pub(super) fn sample(&mut self, now: Instant, verified: u64) -> f64 {
+        if let Some((at, last_verified)) = self.last_sample {
+            let elapsed = now.duration_since(at).as_secs_f64();
+            if elapsed > 0.0 {
+                let rate = verified.saturating_sub(last_verified) as f64 / elapsed;
+                self.rate = RATE_SMOOTHING * rate + (1.0 - RATE_SMOOTHING) * self.rate;
+            }
+            // otherwise the average only approaches zero and the snapshot would never settle
+            if self.rate < STALLED_RATE {
+                self.rate = 0.0;
+            }
+        }
+        self.last_sample = Some((now, verified));
+        self.rate
+    }
+}
+
+impl PeerManager {
+    /// returns a receiver that always holds the latest progress of this torrent
+    pub fn subscribe_progress(&self) -> tokio::sync::watch::Receiver<ProgressSnapshot> {
+        self.progress.subscribe()
+    }
+
+    /// samples the throughput and notifies the receivers if anything changed
+    pub(super) fn publish_progress(&mut self) {
+        let snapshot = match &self.torrent_state {
+            TorrentState::Downloading {
+                metainfo,
+                piece_manager,
+            }
+            | TorrentState::Seeding {
+                metainfo,
+                piece_manager,
+            } => {
+                let left = piece_manager.bytes_left(metainfo);
+                let download_rate = self
+                    .throughput
+                    .sample(self.clock.now(), piece_manager.downloaded);
+                ProgressSnapshot {
+                    downloaded: piece_manager.downloaded,
+                    uploaded: piece_manager.uploaded,
+                    left: Some(left),
+                    download_rate,
+                    eta: Eta::new(Some(left), download_rate),
+                    checking: None,
+                }
+            }
+            TorrentState::WaitingForMetadata { .. } => ProgressSnapshot::default(),
+            TorrentState::Stopped => return,
+        };
+        self.progress.send_if_modified(|current| {
+            let modified = *current != snapshot;
+            *current = snapshot;
+            modified
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_is_smoothed() {
+        let mut estimator = ThroughputEstimator::default();
+        let start = Instant::now();
+        assert_eq!(estimator.sample(start, 0), 0.0);
+        let rate = estimator.sample(start + Duration::from_secs(1), 1000);
+        assert!((rate - 200.0).abs() < 1e-9);
+        // a single slow second doesn't drop the rate to zero
+        let rate = estimator.sample(start + Duration::from_secs(2), 1000);
+        assert!((rate - 160.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn eta_cases() {
+        assert_eq!(Eta::new(None, 100.0), Eta::WaitingForMetadata);
+        assert_eq!(Eta::new(Some(0), 0.0), Eta::Finished);
+        assert_eq!(Eta::new(Some(1000), 0.1), Eta::Stalled);
+        assert_eq!(
+            Eta::new(Some(1000), 100.0),
+            Eta::Remaining(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn checking_replaces_the_rate() {
+        let mut snapshot = ProgressSnapshot {
+            downloaded: 2048,
+            ..ProgressSnapshot::default()
+        };
+        assert_eq!(
+            snapshot.to_string(),
+            "2 KiB done, 0.0 KiB/s, waiting for metadata"
+        );
+        snapshot.checking = Some(CheckProgress {
+            checked: 3,
+            total: 10,
+        });
+        assert_eq!(snapshot.to_string(), "checked 3 of 10 pieces");
+    }
+}
@@ -0,0 +1,246 @@
+//! One number for how much a peer is worth to us.
+//! It's made of how much the peer sent us, how fast it answers our requests and how often it sent
+//! data that failed the hash or broke the protocol. The better half of the peers gets longer block
+//! queues. When a peer leaves, its score stays with its address in the peer candidates, so the next
+//! run dials the good peers first.
+//! A peer we want data from that didn't send a block for [`SNUB_TIMEOUT`] is snubbed: it gets no
+//! blocks to request unless it's our optimistic unchoke, and only the optimistic slot.
+use std::{
+    collections::HashMap,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use crate::peer_manager::PeerManager;
+
+/// how much a new sample moves the smoothed round trip time, like TCP's SRTT
+const RTT_GAIN: f64 = 0.125;
+/// a piece that failed the hash outweighs 15 KiB/s of throughput
+const HASH_FAILURE_PENALTY: f64 = 4.0;
+const VIOLATION_PENALTY: f64 = 8.0;
+pub(super) const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct PeerQuality {
+    connected_at: Instant,
+    /// the bytes of the blocks the peer sent us
+    downloaded: u64,
+    /// when the last block arrived, or when the peer connected
+    last_block_at: Instant,
+    rtt: Option<Duration>,
+    /// when we handed out blocks the peer hasn't started sending yet
+    requested_at: Option<Instant>,
+    hash_failures: u32,
+    violations: u32,
+}
+
+impl PeerQuality {
+    fn new(now: Instant) -> Self {
+        Self {
+            connected_at: now,
+            downloaded: 0,
+            last_block_at: now,
+            rtt: None,
+            requested_at: None,
+            hash_failures: 0,
+            violations: 0,
+        }
+    }
+
+    fn score(&self, now: Instant) -> f64 {
+        // a peer that just connected isn't rated on its first block alone
+        let secs = now.duration_since(self.connected_at).as_secs_f64().max(1.0);
+        let kib_per_sec = self.downloaded as f64 / 1024.0 / secs;
+        // up to a point for answering quickly, half of it at 100ms
+        let latency = self
+            .rtt
+            .map_or(0.0, |rtt| 1.0 / (1.0 + 10.0 * rtt.as_secs_f64()));
+        (1.0 + kib_per_sec).log2() + latency
+            - HASH_FAILURE_PENALTY * self.hash_failures as f64
+            - VIOLATION_PENALTY * self.violations as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct PeerQualities(HashMap<[u8; 20], PeerQuality>);
+
+impl PeerQualities {
+    pub(super) fn connected(&mut self, peer_id: [u8; 20], now: Instant) {
+        self.0.insert(peer_id, PeerQuality::new(now));
+    }
+
+    /// The peer got blocks to request while it doesn't choke us,
+    /// the time until the first of them arrives is a round trip.
+    pub(super) fn requested(&mut self, peer_id: [u8; 20], now: Instant) {
+        if let Some(quality) = self.0.get_mut(&peer_id) {
+            quality.requested_at.get_or_insert(now);
+        }
+    }
+
+    pub(super) fn received(&mut self, peer_id: [u8; 20], bytes: u64, now: Instant) {
+        let Some(quality) = self.0.get_mut(&peer_id) else {
+            return;
+        };
+        quality.downloaded += bytes;
+        quality.last_block_at = now;
+        if let Some(requested_at) = quality.requested_at.take() {
+            let sample = now.duration_since(requested_at);
+            quality.rtt = Some(match quality.rtt {
+                Some(rtt) => rtt.mul_f64(1.0 - RTT_GAIN) + sample.mul_f64(RTT_GAIN),
+                None => sample,
+            });
+        }
+    }
+
+    /// we can't tell which of the peers that sent the blocks lied, so all of them are blamed
+    pub(super) fn corrupt_piece(&mut self, senders: &[[u8; 20]]) {
+        for peer_id in senders {
+            if let Some(quality) = self.0.get_mut(peer_id) {
+                quality.hash_failures += 1;
+            }
+        }
+    }
+
+    pub(super) fn violation(&mut self, peer_id: [u8; 20]) {
+        if let Some(quality) = self.0.get_mut(&peer_id) {
+            quality.violations += 1;
+        }
+    }
+
+    /// how long the peer didn't send us a block
+    fn idle(&self, peer_id: &[u8; 20], now: Instant) -> Duration {
+        self.0.get(peer_id).map_or(Duration::ZERO, |quality| {
+            now.saturating_duration_since(quality.last_block_at)
+        })
+    }
+
+    pub(super) fn score(&self, peer_id: &[u8; 20], now: Instant) -> Option<f64> {
+        Some(self.0.get(peer_id)?.score(now))
+    }
+
+    /// returns the last score of the peer
+    pub(super) fn remove_peer(&mut self, peer_id: &[u8; 20], now: Instant) -> Option<f64> {
+        Some(self.0.remove(peer_id)?.score(now))
+    }
+
+    /// the peer with the lowest score of those connected for at least `grace`
+    pub(super) fn worst(&self, now: Instant, grace: Duration) -> Option<[u8; 20]> {
+        self.0
+            .iter()
+            .filter(|(_, quality)| now.saturating_duration_since(quality.connected_at) >= grace)
+            .min_by(|a, b| a.1.score(now).total_cmp(&b.1.score(now)))
+            .map(|(peer_id, _)| *peer_id)
+    }
+
+    /// how many blocks the peer may request at once,
+    /// the peers in the worse half only get half as many so they hold up fewer blocks
+    pub(super) fn queue_len(&self, peer_id: &[u8; 20], max: usize, now: Instant) -> usize {
+        let Some(score) = self.score(peer_id, now) else {
+            return max;
+        };
+        let better = self.0.values().filter(|q| q.score(now) > score).count();
+        if better * 2 >= self.0.len() && better > 0 {
+            (max / 2).max(1)
+        } else {
+            max
+        }
+    }
+}
+
+impl PeerManager {
+    /// whether we want data from the peer but it didn't send any for [`SNUB_TIMEOUT`]
+    pub(super) fn is_snubbed(&self, peer_id: &[u8; 20], now: Instant) -> bool {
+        let interested = self
+            .peers
+            .get(peer_id)
+            .is_some_and(|conn| conn.identifier.0.am_interested.load(Ordering::Relaxed));
+        interested && self.quality.idle(peer_id, now) >= SNUB_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_add_up() {
+        let start = Instant::now();
+        let mut qualities = PeerQualities::default();
+        let (fast, slow, liar) = ([1; 20], [2; 20], [3; 20]);
+        for peer_id in [fast, slow, liar] {
+            qualities.connected(peer_id, start);
+            qualities.requested(peer_id, start);
+        }
+        qualities.received(fast, 1 << 20, start + Duration::from_millis(50));
+        qualities.received(slow, 1 << 20, start + Duration::from_millis(800));
+        qualities.received(liar, 1 << 20, start + Duration::from_millis(50));
+        qualities.corrupt_piece(&[liar]);
+
+        let now = start + Duration::from_secs(10);
+        let score = |qualities: &PeerQualities, peer_id| qualities.score(&peer_id, now).unwrap();
+        // the same throughput, the quicker answers win
+        assert!(score(&qualities, fast) > score(&qualities, slow));
+        assert!(score(&qualities, slow) > score(&qualities, liar));
+        assert_eq!(qualities.queue_len(&fast, 20, now), 20);
+        assert_eq!(qualities.queue_len(&slow, 20, now), 20);
+        assert_eq!(qualities.queue_len(&liar, 20, now), 10);
+
+        qualities.violation(fast);
+        let fast_score = score(&qualities, fast);
+        assert!(fast_score < score(&qualities, liar));
+        assert_eq!(qualities.remove_peer(&fast, now), Some(fast_score));
+        // unknown peers get the whole queue
+        assert_eq!(qualities.queue_len(&fast, 20, now), 20);
+    }
+
+    #[test]
+    fn smoothed_rtt() {
+        let start = Instant::now();
+        let mut qualities = PeerQualities::default();
+        qualities.connected([1; 20], start);
+        qualities.requested([1; 20], start);
+        qualities.received([1; 20], 16384, start + Duration::from_millis(100));
+        // only the first block after handing out a queue is a round trip
+        qualities.received([1; 20], 16384, start + Duration::from_secs(5));
+        assert_eq!(qualities.0[&[1; 20]].rtt, Some(Duration::from_millis(100)));
+
+        let later = start + Duration::from_secs(6);
+        qualities.requested([1; 20], later);
+        qualities.received([1; 20], 16384, later + Duration::from_millis(900));
+        assert_eq!(qualities.0[&[1; 20]].rtt, Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn idle_since_the_last_block() {
+        let start = Instant::now();
+        let mut qualities = PeerQualities::default();
+        qualities.connected([1; 20], start);
+        let later = start + SNUB_TIMEOUT;
+        assert_eq!(qualities.idle(&[1; 20], later), SNUB_TIMEOUT);
+        qualities.received([1; 20], 16384, later);
+        assert_eq!(qualities.idle(&[1; 20], later), Duration::ZERO);
+        assert_eq!(qualities.idle(&[2; 20], later), Duration::ZERO);
+    }
+
+    #[test]
+    fn worst_after_the_grace() {
+        let start = Instant::now();
+        let grace = Duration::from_secs(30);
+        let mut qualities = PeerQualities::default();
+        let (good, bad, new) = ([1; 20], [2; 20], [3; 20]);
+        qualities.connected(good, start);
+        qualities.connected(bad, start);
+        qualities.received(good, 1 << 20, start + Duration::from_secs(1));
+        assert_eq!(
+            qualities.worst(start + Duration::from_secs(10), grace),
+            None
+        );
+
+        let now = start + grace;
+        qualities.connected(new, now);
+        // the new peer has sent nothing either, but it didn't have the time yet
+        assert_eq!(qualities.worst(now, grace), Some(bad));
+        qualities.remove_peer(&bad, now);
+        assert_eq!(qualities.worst(now, grace), Some(good));
+    }
+}
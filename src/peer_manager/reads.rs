@@ -0,0 +1,171 @@
+//! Reads of verified data for library users, e.g. to stream a file while it downloads.
+//! A read is answered once every piece it covers passed the hash. Until then the peers download
+//! these pieces before the rarest ones, the pieces of the earliest deadline first.
+use std::{collections::HashMap, mem, ops::Range, time::Instant};
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::{
+    peer_manager::{PeerManager, TorrentState},
+    torrent::Metainfo,
+};
+
+#[derive(Debug, Error)]
+pub enum ReadError {
+    #[error("Bytes {start}..{end} aren't in the torrent, it's {total} bytes long")]
+    OutOfRange { start: u64, end: u64, total: u64 },
+    #[error("The pieces weren't downloaded before the deadline")]
+    DeadlineMissed,
+    #[error("The torrent stopped before the pieces were downloaded")]
+    Stopped,
+    #[error("Failed to read the pieces from disk: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ReadRange {
+    Bytes { offset: u64, len: u64 },
+    Piece(u32),
+}
+
+impl ReadRange {
+    /// where the bytes are in the torrent
+    fn resolve(self, metainfo: &Metainfo) -> Result<Range<u64>, ReadError> {
+        let total = metainfo.total_size();
+        let piece_length = metainfo.piece_length as u64;
+        let range = match self {
+            Self::Bytes { offset, len } => offset..offset.saturating_add(len),
+            Self::Piece(piece_i) if (piece_i as usize) < metainfo.pieces.len() => {
+                let start = piece_i as u64 * piece_length;
+                // the last piece may be shorter
+                start..(start + piece_length).min(total)
+            }
+            Self::Piece(piece_i) => {
+                let start = piece_i as u64 * piece_length;
+                start..start + piece_length
+            }
+        };
+        if range.end > total {
+            return Err(ReadError::OutOfRange {
+                start: range.start,
+                end: range.end,
+                total,
+            });
+        }
+        Ok(range)
+    }
+}
+
+/// A read that waits for its pieces, see [`crate::TorrentControl::read`].
+#[derive(Debug)]
+pub(super) struct ReadRequest {
+    pub(super) range: ReadRange,
+    pub(super) deadline: Option<Instant>,
+    pub(super) reply: oneshot::Sender<Result<Bytes, ReadError>>,
+}
+
+impl PeerManager {
+    /// Answers the reads whose pieces are verified, the others tell the PieceManager what to
+    /// download first.
+    pub(super) async fn serve_reads(&mut self) {
+        let (metainfo, piece_manager) = match &mut self.torrent_state {
+            TorrentState::Downloading {
+                metainfo,
+                piece_manager,
+            }
+            | TorrentState::Seeding {
+                metainfo,
+                piece_manager,
+            } => (metainfo, piece_manager),
+            TorrentState::WaitingForMetadata { .. } => {
+                self.reads.retain(|read| !read.reply.is_closed());
+                return;
+            }
+            TorrentState::Stopped => {
+                for read in self.reads.drain(..) {
+                    let _ = read.reply.send(Err(ReadError::Stopped));
+                }
+                return;
+            }
+        };
+
+        let mut wanted = HashMap::new();
+        for read in mem::take(&mut self.reads) {
+            // the reader gave up, e.g. because its deadline passed
+            if read.reply.is_closed() {
+                continue;
+            }
+            let range = match read.range.resolve(metainfo) {
+                Ok(range) => range,
+                Err(e) => {
+                    let _ = read.reply.send(Err(e));
+                    continue;
+                }
+            };
+            let missing = piece_manager.missing_pieces(&range, metainfo);
+            if missing.is_empty() {
+                let data = piece_manager.read(range).await.map_err(ReadError::from);
+                let _ = read.reply.send(data);
+                continue;
+            }
+            for piece_i in missing {
+                let deadline = wanted.entry(piece_i).or_insert(read.deadline);
+                *deadline = earliest(*deadline, read.deadline);
+            }
+            self.reads.push(read);
+        }
+        piece_manager.prioritize(wanted);
+    }
+}
+
+/// a read without a deadline waits as long as it takes
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+
+    #[test]
+    fn ranges_of_the_torrent() {
+        let metainfo = test_fixtures::metainfo(32769, 16384);
+
+        assert_eq!(
+            ReadRange::Piece(1).resolve(&metainfo).unwrap(),
+            16384..32768
+        );
+        assert_eq!(
+            ReadRange::Piece(2).resolve(&metainfo).unwrap(),
+            32768..32769
+        );
+        assert!(matches!(
+            ReadRange::Piece(3).resolve(&metainfo),
+            Err(ReadError::OutOfRange { start: 49152, .. })
+        ));
+        let bytes = ReadRange::Bytes {
+            offset: 100,
+            len: 32669,
+        };
+        assert_eq!(bytes.resolve(&metainfo).unwrap(), 100..32769);
+        let past_the_end = ReadRange::Bytes {
+            offset: 32769,
+            len: 1,
+        };
+        assert!(past_the_end.resolve(&metainfo).is_err());
+    }
+
+    #[test]
+    fn earliest_deadline() {
+        let now = Instant::now();
+        assert_eq!(earliest(None, Some(now)), Some(now));
+        assert_eq!(earliest(Some(now), None), Some(now));
+        assert_eq!(earliest(None, None), None);
+    }
+}
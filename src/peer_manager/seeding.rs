@@ -0,0 +1,58 @@
+//! The seeding goal: we stop uploading once the torrent reached the ratio of its ratio group.
+use crate::{
+    peer_manager::{PeerManager, ResMessage, TorrentState, error::PeerManagerError},
+    tracker::Event,
+};
+
+impl PeerManager {
+    /// Assigns the torrent to a ratio group of the config.
+    /// If we don't have the metainfo yet, the group is stored once the download starts.
+    pub async fn assign_ratio_group(
+        &mut self,
+        ratio_group: String,
+    ) -> Result<(), PeerManagerError> {
+        self.config.ratio_group(&ratio_group)?;
+        match &mut self.torrent_state {
+            TorrentState::Downloading { piece_manager, .. }
+            | TorrentState::Seeding { piece_manager, .. } => {
+                piece_manager.set_ratio_group(ratio_group).await?
+            }
+            TorrentState::WaitingForMetadata { .. } => self.pending_ratio_group = Some(ratio_group),
+            TorrentState::Stopped => {}
+        }
+        Ok(())
+    }
+
+    /// whether we are seeding and uploaded enough according to the torrent's ratio group
+    pub(super) fn seeding_goal_reached(&self) -> bool {
+        let TorrentState::Seeding {
+            metainfo,
+            piece_manager,
+        } = &self.torrent_state
+        else {
+            return false;
+        };
+        let Some(ratio_group) = piece_manager
+            .ratio_group
+            .as_ref()
+            .or(self.config.default_ratio_group.as_ref())
+            .and_then(|name| self.config.ratio_group(name).ok())
+        else {
+            return false;
+        };
+
+        ratio_group.is_reached(piece_manager.uploaded, metainfo.total_size())
+    }
+
+    /// persists the upload statistics and tells all peers to disconnect
+    pub(super) async fn stop_seeding(&mut self) -> Result<(), PeerManagerError> {
+        if let TorrentState::Seeding { piece_manager, .. } = &self.torrent_state {
+            piece_manager.persist_uploaded().await?;
+            piece_manager.flush_db().await?;
+        }
+        self.announce(Some(Event::Stopped));
+        self.torrent_state = TorrentState::Stopped;
+        eprintln!("Reached the seeding goal, stopping.");
+        self.broadcast_peers(ResMessage::Disconnect).await
+    }
+}
@@ -0,0 +1,237 @@
+//! Serves the block requests of the peers in deficit round robin order.
+//! Serving in arrival order lets a peer that requests aggressively take all of our upload,
+//! this way every peer with pending requests gets the same number of bytes per round.
+//! While we leech, peers that have pieces we lack get a bigger share since they can pay us back.
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{
+    BLOCK_MAX,
+    messages::payloads::RequestPiecePayload,
+    peer_manager::{PeerManager, ResMessage, TorrentState, error::PeerManagerError},
+};
+
+/// the bytes a peer may be served per round
+const QUANTUM: u32 = BLOCK_MAX;
+/// how many quanta a preferred peer gets per round
+const PREFERRED_QUANTA: u32 = 2;
+/// Requests longer than this are dropped, most clients refuse anything above 16KiB anyway.
+const MAX_REQUEST_LEN: u32 = 8 * BLOCK_MAX;
+
+#[derive(Debug, Default)]
+struct PeerRequests {
+    requests: VecDeque<RequestPiecePayload>,
+    deficit: u32,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct UploadQueue {
+    /// the peers with pending requests in the order they're served
+    round: VecDeque<[u8; 20]>,
+    peers: HashMap<[u8; 20], PeerRequests>,
+    /// see [`UploadQueue::prefer`]
+    preferred: HashSet<[u8; 20]>,
+}
+
+impl UploadQueue {
+    pub(super) fn push(&mut self, peer_id: [u8; 20], request: RequestPiecePayload) {
+        if request.length > MAX_REQUEST_LEN {
+            eprintln!(
+                "Ignoring a request for {} bytes, the limit is {MAX_REQUEST_LEN}.",
+                request.length
+            );
+            return;
+        }
+        let peer = self.peers.entry(peer_id).or_insert_with(|| {
+            self.round.push_back(peer_id);
+            PeerRequests::default()
+        });
+        peer.requests.push_back(request);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.round.is_empty()
+    }
+
+    /// these peers get [`PREFERRED_QUANTA`] per round from now on, the others one
+    pub(super) fn prefer(&mut self, peers: HashSet<[u8; 20]>) {
+        self.preferred = peers;
+    }
+
+    /// drops a pending request the peer cancelled
+    /// returns false if there's none, e.g. because it was served already
+    pub(super) fn cancel(&mut self, peer_id: &[u8; 20], request: &RequestPiecePayload) -> bool {
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return false;
+        };
+        let Some(i) = peer.requests.iter().position(|r| r == request) else {
+            return false;
+        };
+        peer.requests.remove(i);
+        if peer.requests.is_empty() {
+            self.remove_peer(peer_id);
+        }
+        true
+    }
+
+    /// drops the pending requests of a peer, e.g. because it disconnected
+    pub(super) fn remove_peer(&mut self, peer_id: &[u8; 20]) {
+        if self.peers.remove(peer_id).is_some() {
+            self.round.retain(|id| id != peer_id);
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.round.clear();
+        self.peers.clear();
+    }
+
+    /// returns the request that should be served next
+    pub(super) fn next(&mut self) -> Option<([u8; 20], RequestPiecePayload)> {
+        loop {
+            let peer_id = *self.round.front()?;
+            let peer = self
+                .peers
+                .get_mut(&peer_id)
+                .expect("Every peer in the round has an entry.");
+            let length = peer
+                .requests
+                .front()
+                .expect("Peers without requests are removed from the round.")
+                .length;
+            if peer.deficit < length {
+                // the peer used up its share of this round
+                peer.deficit += if self.preferred.contains(&peer_id) {
+                    PREFERRED_QUANTA * QUANTUM
+                } else {
+                    QUANTUM
+                };
+                self.round.rotate_left(1);
+                continue;
+            }
+            peer.deficit -= length;
+            let request = peer.requests.pop_front().expect("We just peeked at it.");
+            if peer.requests.is_empty() {
+                // an idle peer doesn't keep its deficit
+                self.remove_peer(&peer_id);
+            }
+            return Some((peer_id, request));
+        }
+    }
+}
+
+impl PeerManager {
+    /// serves the request that is next in line
+    /// returns true if the seeding goal was reached and the torrent stopped
+    pub(super) async fn serve_next_block(&mut self) -> Result<bool, PeerManagerError> {
+        let Some((peer_id, request)) = self.upload_queue.next() else {
+            return Ok(false);
+        };
+        let (TorrentState::Downloading {
+            metainfo,
+            piece_manager,
+        }
+        | TorrentState::Seeding {
+            metainfo,
+            piece_manager,
+        }) = &mut self.torrent_state
+        else {
+            return Ok(false);
+        };
+        // a recheck may have taken the piece from us since the request was queued
+        if !piece_manager.have.contains(request.index as usize) {
+            return Ok(false);
+        }
+        let block = piece_manager.get_block(request, metainfo).await;
+        if let Some(block) = &block {
+            piece_manager.uploaded += block.block.len() as u64;
+            self.choker.uploaded(peer_id, block.block.len() as u64);
+        }
+        self.send_peer(peer_id, ResMessage::Block(block)).await?;
+        if self.seeding_goal_reached() {
+            self.upload_queue.clear();
+            self.stop_seeding().await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(begin: u32) -> RequestPiecePayload {
+        RequestPiecePayload::new(0, begin, BLOCK_MAX)
+    }
+
+    #[test]
+    fn aggressive_peer_doesnt_dominate() {
+        let mut queue = UploadQueue::default();
+        for i in 0..10 {
+            queue.push([1; 20], request(i * BLOCK_MAX));
+        }
+        queue.push([2; 20], request(0));
+        queue.push([2; 20], request(BLOCK_MAX));
+
+        let order: Vec<u8> = std::iter::from_fn(|| queue.next())
+            .map(|(peer_id, _)| peer_id[0])
+            .take(5)
+            .collect();
+        assert_eq!(order, vec![1, 2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn small_requests_share_a_quantum() {
+        let mut queue = UploadQueue::default();
+        for i in 0..4 {
+            queue.push([1; 20], RequestPiecePayload::new(0, i * 4096, 4096));
+        }
+        queue.push([2; 20], request(0));
+        let order: Vec<u8> = std::iter::from_fn(|| queue.next())
+            .map(|(peer_id, _)| peer_id[0])
+            .collect();
+        assert_eq!(order, vec![1, 1, 1, 1, 2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn preferred_peer_gets_more() {
+        let mut queue = UploadQueue::default();
+        queue.prefer(HashSet::from([[2; 20]]));
+        for i in 0..6 {
+            queue.push([1; 20], request(i * BLOCK_MAX));
+            queue.push([2; 20], request(i * BLOCK_MAX));
+        }
+        let order: Vec<u8> = std::iter::from_fn(|| queue.next())
+            .map(|(peer_id, _)| peer_id[0])
+            .take(6)
+            .collect();
+        assert_eq!(order, vec![1, 2, 2, 1, 2, 2]);
+    }
+
+    #[test]
+    fn disconnected_peer_is_dropped() {
+        let mut queue = UploadQueue::default();
+        queue.push([1; 20], request(0));
+        queue.push([2; 20], request(0));
+        queue.remove_peer(&[1; 20]);
+        assert_eq!(queue.next().map(|(peer_id, _)| peer_id), Some([2; 20]));
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn cancelled_requests_are_dropped() {
+        let mut queue = UploadQueue::default();
+        queue.push([1; 20], request(0));
+        queue.push([1; 20], request(BLOCK_MAX));
+        assert!(queue.cancel(&[1; 20], &request(0)));
+        assert!(!queue.cancel(&[1; 20], &request(0)));
+        assert!(!queue.cancel(&[2; 20], &request(BLOCK_MAX)));
+        assert_eq!(queue.next(), Some(([1; 20], request(BLOCK_MAX))));
+
+        // a peer without requests leaves the round
+        queue.push([1; 20], request(0));
+        assert!(queue.cancel(&[1; 20], &request(0)));
+        assert!(queue.is_empty());
+    }
+}
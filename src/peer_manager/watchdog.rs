@@ -0,0 +1,203 @@
+//! Notices when a download hangs although there are peers that would send us data.
+//! A block that's assigned to a peer that never sends it, or that's marked as in process without
+//! being assigned at all, is never requested again, so the download stops without an error.
+//! When nothing was verified for [`Config::stall_timeout_secs`], the state is reported and the
+//! download is kicked: the blocks are handed out again, we reannounce and the worst peers are
+//! replaced.
+//!
+//! [`Config::stall_timeout_secs`]: crate::Config::stall_timeout_secs
+use std::{
+    fmt,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::peer_manager::{
+    EventKind, PeerManager, ResMessage, TorrentState, error::PeerManagerError,
+};
+
+/// how often the watchdog looks at the download
+pub(super) const WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+/// the share of the peers that's disconnected to make room for new ones
+const RECONNECT_DIVISOR: usize = 4;
+
+#[derive(Debug)]
+pub(super) struct Watchdog {
+    /// None if it's turned off
+    timeout: Option<Duration>,
+    verified: u64,
+    /// when we last verified something or had no peer to download from
+    progressed_at: Instant,
+    recoveries: u32,
+}
+
+impl Watchdog {
+    pub(super) fn new(timeout: Option<Duration>, now: Instant) -> Self {
+        Self {
+            timeout,
+            verified: 0,
+            progressed_at: now,
+            recoveries: 0,
+        }
+    }
+
+    /// Returns how long we're stalled if it's time to recover.
+    /// Without a peer that unchokes us there's nothing to recover, we're waiting for peers.
+    fn check(&mut self, now: Instant, verified: u64, can_download: bool) -> Option<Duration> {
+        if verified != self.verified || !can_download {
+            self.verified = verified;
+            self.progressed_at = now;
+            return None;
+        }
+        let stalled_for = now.duration_since(self.progressed_at);
+        if stalled_for < self.timeout? {
+            return None;
+        }
+        // the next recovery only if this one didn't help for another timeout
+        self.progressed_at = now;
+        self.recoveries += 1;
+        Some(stalled_for)
+    }
+}
+
+/// What the download looked like when the watchdog found it stalled,
+/// see [`PeerManager::subscribe_stalls`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StallReport {
+    pub stalled_for: Duration,
+    pub peers: usize,
+    /// the peers that don't choke us
+    pub unchoking_peers: usize,
+    pub pieces_in_progress: Vec<u32>,
+    /// the blocks a peer is downloading
+    pub blocks_in_flight: usize,
+    /// the blocks that are marked as in process but aren't assigned to any peer
+    pub orphaned_blocks: usize,
+    /// how often the watchdog had to step in for this torrent, this one included
+    pub recoveries: u32,
+    /// the peers we disconnected to make room for new ones
+    pub disconnected: usize,
+}
+
+impl fmt::Display for StallReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "nothing verified for {}s with {} peers, {} of them unchoking us, pieces {:?} in progress, \
+             {} blocks in flight, {} orphaned, recovery number {}, disconnected {} peers",
+            self.stalled_for.as_secs(),
+            self.peers,
+            self.unchoking_peers,
+            self.pieces_in_progress,
+            self.blocks_in_flight,
+            self.orphaned_blocks,
+            self.recoveries,
+            self.disconnected
+        )
+    }
+}
+
+impl PeerManager {
+    /// The report of the last stall the watchdog recovered from, None if there was none.
+    pub fn subscribe_stalls(&self) -> tokio::sync::watch::Receiver<Option<StallReport>> {
+        self.stalls.subscribe()
+    }
+
+    pub(super) async fn check_stall(&mut self) -> Result<(), PeerManagerError> {
+        let TorrentState::Downloading { piece_manager, .. } = &mut self.torrent_state else {
+            // the clock starts once we download
+            self.watchdog.check(self.clock.now(), 0, false);
+            return Ok(());
+        };
+        let unchoking_peers = self
+            .peers
+            .values()
+            .filter(|conn| !conn.identifier.0.peer_choking.load(Ordering::Relaxed))
+            .count();
+        let now = self.clock.now();
+        let Some(stalled_for) =
+            self.watchdog
+                .check(now, piece_manager.downloaded, unchoking_peers > 0)
+        else {
+            return Ok(());
+        };
+
+        let (blocks_in_flight, orphaned_blocks) = piece_manager.in_flight_counts();
+        let pieces_in_progress = piece_manager.pieces_in_progress();
+        piece_manager.reset_in_flight();
+
+        // the worst peers go, the reannounce finds new ones
+        let mut worst: Vec<_> = self
+            .peers
+            .keys()
+            .map(|peer_id| {
+                let score = self.quality.score(peer_id, now).unwrap_or(0.0);
+                (*peer_id, score)
+            })
+            .collect();
+        worst.sort_by(|a, b| a.1.total_cmp(&b.1));
+        worst.truncate(self.peers.len().div_ceil(RECONNECT_DIVISOR));
+
+        let report = StallReport {
+            stalled_for,
+            peers: self.peers.len(),
+            unchoking_peers,
+            pieces_in_progress,
+            blocks_in_flight,
+            orphaned_blocks,
+            recoveries: self.watchdog.recoveries,
+            disconnected: worst.len(),
+        };
+        eprintln!("The download is stalled: {report}");
+        self.events.record(EventKind::Stalled {
+            secs: report.stalled_for.as_secs(),
+        });
+        self.stalls.send_replace(Some(report));
+
+        for (peer_id, _) in worst {
+            // its connection may be gone already
+            let _ = self.send_peer(peer_id, ResMessage::Disconnect).await;
+            self.remove_peer(peer_id);
+        }
+        if let Some(announcer) = &self.announcer
+            && let Some(progress) = self.announce_progress()
+        {
+            announcer.force_announce(progress);
+        }
+        // the peers that are left ask for the blocks that were handed out again
+        self.broadcast_peers(ResMessage::StartDownload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalls_only_with_peers() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(300);
+        let mut watchdog = Watchdog::new(Some(timeout), start);
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(watchdog.check(at(200), 0, true), None);
+        // progress resets the clock
+        assert_eq!(watchdog.check(at(250), 100, true), None);
+        assert_eq!(watchdog.check(at(500), 100, true), None);
+        assert_eq!(
+            watchdog.check(at(550), 100, true),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(watchdog.recoveries, 1);
+        // the next recovery waits for another timeout
+        assert_eq!(watchdog.check(at(600), 100, true), None);
+        // nobody to download from isn't a stall
+        assert_eq!(watchdog.check(at(900), 100, false), None);
+        assert_eq!(watchdog.check(at(1100), 100, true), None);
+
+        let mut off = Watchdog::new(None, start);
+        assert_eq!(off.check(at(10_000), 0, true), None);
+    }
+}
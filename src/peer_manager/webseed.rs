@@ -0,0 +1,233 @@
+//! Downloads pieces over HTTP from the web seeds of the torrent (BEP 19).
+//! A web seed has every piece, but the pieces the peers have are better fetched from them,
+//! so a web seed gets the rarest missing piece, which is one no connected peer has if there is any.
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use reqwest::{StatusCode, header::RANGE};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::peer_manager::{
+    PeerManager, TorrentState, error::PeerManagerError, integrity::Contributor,
+};
+
+/// how often idle web seeds are given a new piece
+pub(super) const WEB_SEED_INTERVAL: Duration = Duration::from_secs(5);
+/// how long a web seed that failed is left alone
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// the web seed, the piece and its data
+pub(super) type Fetched = (usize, u32, Result<Bytes, WebSeedError>);
+
+#[derive(Debug, Error)]
+pub(super) enum WebSeedError {
+    #[error("The request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("The server answered with {0}")]
+    Status(StatusCode),
+    #[error("Expected {expected} bytes but got {got}")]
+    Length { expected: u64, got: u64 },
+}
+
+#[derive(Debug)]
+struct WebSeed {
+    url: url::Url,
+    /// the piece we're downloading from it
+    busy: Option<u32>,
+    /// set after a failure, it gets no pieces until then
+    retry_at: Option<Instant>,
+}
+
+impl WebSeed {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.busy.is_none() && self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct WebSeeds {
+    seeds: Vec<WebSeed>,
+    client: reqwest::Client,
+    fetched_tx: mpsc::Sender<Fetched>,
+    /// the pieces the web seeds finished, see [`PeerManager::on_web_seed_piece`]
+    pub(super) fetched: mpsc::Receiver<Fetched>,
+}
+
+impl WebSeeds {
+    pub(super) fn new(urls: impl IntoIterator<Item = url::Url>) -> Self {
+        let seeds: Vec<_> = urls
+            .into_iter()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .map(|url| WebSeed {
+                url,
+                busy: None,
+                retry_at: None,
+            })
+            .collect();
+        let (fetched_tx, fetched) = mpsc::channel(seeds.len().max(1));
+        Self {
+            seeds,
+            client: reqwest::Client::new(),
+            fetched_tx,
+            fetched,
+        }
+    }
+}
+
+impl PeerManager {
+    /// gives every idle web seed a piece
+    pub(super) fn fetch_from_web_seeds(&mut self) {
+        let TorrentState::Downloading {
+            metainfo,
+            piece_manager,
+        } = &mut self.torrent_state
+        else {
+            return;
+        };
+        let now = self.clock.now();
+        for (seed_i, seed) in self.web_seeds.seeds.iter_mut().enumerate() {
+            if !seed.is_idle(now) {
+                continue;
+            }
+            let Some((piece_i, range)) = piece_manager.claim_web_seed_piece(metainfo) else {
+                return;
+            };
+            seed.busy = Some(piece_i);
+            let url = file_url(&seed.url, &metainfo.name);
+            let client = self.web_seeds.client.clone();
+            let fetched = self.web_seeds.fetched_tx.clone();
+            tokio::spawn(async move {
+                let data = fetch(&client, url, range).await;
+                let _ = fetched.send((seed_i, piece_i, data)).await;
+            });
+        }
+    }
+
+    pub(super) async fn on_web_seed_piece(
+        &mut self,
+        (seed_i, piece_i, data): Fetched,
+    ) -> Result<(), PeerManagerError> {
+        self.web_seeds.seeds[seed_i].busy = None;
+        let url = self.web_seeds.seeds[seed_i].url.clone();
+        let TorrentState::Downloading {
+            metainfo,
+            piece_manager,
+        } = &mut self.torrent_state
+        else {
+            return Ok(());
+        };
+        let failed = match data {
+            Ok(data) => {
+                self.integrity_log
+                    .received(Contributor::WebSeed(url.to_string()), data.len() as u64);
+                let valid = piece_manager.write_piece(piece_i, data, metainfo).await?;
+                if valid {
+                    self.finish_piece(piece_i).await?;
+                } else {
+                    eprintln!("The web seed {url} sent a corrupt piece {piece_i}.");
+                    self.integrity_log.corrupt(piece_i, metainfo);
+                }
+                !valid
+            }
+            Err(e) => {
+                eprintln!("Failed to download piece {piece_i} from the web seed {url}: {e}");
+                piece_manager.release_web_seed_piece(piece_i);
+                true
+            }
+        };
+        if failed {
+            self.web_seeds.seeds[seed_i].retry_at = Some(self.clock.now() + RETRY_AFTER);
+        }
+        self.piece_map_changed();
+        self.fetch_from_web_seeds();
+        Ok(())
+    }
+}
+
+/// A url ending with a slash is the directory the file is in.
+fn file_url(url: &url::Url, name: &str) -> url::Url {
+    let mut url = url.clone();
+    if url.path().ends_with('/')
+        && let Ok(mut segments) = url.path_segments_mut()
+    {
+        segments.pop_if_empty().push(name);
+    }
+    url
+}
+
+async fn fetch(
+    client: &reqwest::Client,
+    url: url::Url,
+    range: Range<u64>,
+) -> Result<Bytes, WebSeedError> {
+    let response = client
+        .get(url)
+        .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(WebSeedError::Status(status));
+    }
+    let mut data = response.bytes().await?;
+    // a server that ignores the range sends the whole file
+    if status == StatusCode::OK && data.len() as u64 >= range.end {
+        data = data.slice(range.start as usize..range.end as usize);
+    }
+    if data.len() as u64 != range.end - range.start {
+        return Err(WebSeedError::Length {
+            expected: range.end - range.start,
+            got: data.len() as u64,
+        });
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn directory_urls_get_the_name() {
+        let url = |s| url::Url::parse(s).unwrap();
+        assert_eq!(
+            file_url(&url("http://seed.example/files/"), "a b.iso").as_str(),
+            "http://seed.example/files/a%20b.iso"
+        );
+        assert_eq!(
+            file_url(&url("http://seed.example/other.iso"), "a.iso").as_str(),
+            "http://seed.example/other.iso"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_the_range_of_the_piece() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let len = stream.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..len]).to_lowercase();
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 4\r\n\r\nabcd")
+                .await
+                .unwrap();
+            request
+        });
+
+        let url = url::Url::parse(&format!("http://{addr}/test")).unwrap();
+        let data = fetch(&reqwest::Client::new(), url, 2..6).await.unwrap();
+        assert_eq!(&data[..], b"abcd");
+        assert!(server.await.unwrap().contains("range: bytes=2-5"));
+    }
+}
@@ -0,0 +1,178 @@
+//! Decides which peers we may talk to.
+//! Every incoming connection and every peer we'd dial is checked before the handshake,
+//! so users that have to restrict their traffic never exchange a single byte with other peers.
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// where we learned about a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSource {
+    /// the peer connected to us
+    Incoming,
+    Tracker,
+    /// the `x.pe` parameter of a magnet link
+    MagnetLink,
+    Dht,
+    /// a connected peer told us about it (BEP 11)
+    Pex,
+    /// it announced itself in the local network (BEP 14)
+    Lsd,
+    /// one of the best peers of the last run
+    Cache,
+    /// a peer worth it that we lost, see [`PeerCandidates`](crate::peer_manager::PeerCandidates)
+    Reconnect,
+}
+
+/// Implement this to filter peers by anything the config rules can't express.
+pub trait ConnectionPolicy: fmt::Debug + Send + Sync {
+    fn allows(&self, addr: &SocketAddr, source: PeerSource) -> bool;
+}
+
+/// An IP range in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+/// A single address without a prefix length is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // IPv4 peers connecting to a dual-stack socket show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix_len) = match s.split_once('/') {
+            Some((ip, prefix_len)) => (ip, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = ip
+            .parse()
+            .map_err(|_| format!("`{ip}` is not an IP address"))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("`{prefix_len}` is not a valid prefix length"))?,
+            None => max_len,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> Self {
+        format!("{}/{}", range.network, range.prefix_len)
+    }
+}
+
+/// The rules of the config. A peer is allowed if nothing denies it.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct ConnectionRules {
+    /// If not empty, only peers in these ranges are allowed.
+    pub allow_ranges: Vec<IpRange>,
+    pub deny_ranges: Vec<IpRange>,
+    pub deny_ports: Vec<u16>,
+    /// e.g. `incoming` for users behind a firewall that must not accept connections
+    pub deny_sources: Vec<PeerSource>,
+}
+
+impl ConnectionPolicy for ConnectionRules {
+    fn allows(&self, addr: &SocketAddr, source: PeerSource) -> bool {
+        let ip = addr.ip();
+        (self.allow_ranges.is_empty() || self.allow_ranges.iter().any(|r| r.contains(&ip)))
+            && !self.deny_ranges.iter().any(|r| r.contains(&ip))
+            && !self.deny_ports.contains(&addr.port())
+            && !self.deny_sources.contains(&source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains(&"10.200.3.4".parse().unwrap()));
+        assert!(!range.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(range.contains(&"::ffff:10.0.0.1".parse().unwrap()));
+
+        let range: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(range.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!range.contains(&"2001:db9::1".parse().unwrap()));
+
+        let all: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"1.2.3.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("localhost/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn rules() {
+        let rules: ConnectionRules = serde_json::from_str(
+            r#"{
+                "allow_ranges": ["10.0.0.0/8"],
+                "deny_ranges": ["10.0.0.5"],
+                "deny_ports": [25],
+                "deny_sources": ["incoming"]
+            }"#,
+        )
+        .unwrap();
+        assert!(rules.allows(&addr("10.1.2.3:6881"), PeerSource::Tracker));
+        assert!(!rules.allows(&addr("192.168.1.1:6881"), PeerSource::Tracker));
+        assert!(!rules.allows(&addr("10.0.0.5:6881"), PeerSource::Tracker));
+        assert!(!rules.allows(&addr("10.1.2.3:25"), PeerSource::Tracker));
+        assert!(!rules.allows(&addr("10.1.2.3:6881"), PeerSource::Incoming));
+        assert!(ConnectionRules::default().allows(&addr("1.2.3.4:1"), PeerSource::Incoming));
+    }
+}
@@ -0,0 +1,25 @@
+//! The locks of the crate. Shared state mostly belongs to a single task that the others send
+//! messages to, e.g. the PeerManager. The few locks that are left are std locks held for a few
+//! statements, never across an await, so a task can't block the runtime or another task holding
+//! the lock. Clippy checks the awaits, see `clippy.toml`.
+//! In debug builds the locks record the order they're taken in and panic as soon as two of them
+//! are taken in both orders, which could deadlock. Release builds use the std locks as they are.
+pub(crate) use tracing_mutex::stdsync::{Mutex, MutexGuard};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic)]
+    fn lock_order_is_checked() {
+        let (first, second) = (Mutex::new(()), Mutex::new(()));
+        {
+            let _first = first.lock().unwrap();
+            let _second = second.lock().unwrap();
+        }
+        // the other order could deadlock with a task that takes them like above
+        let _second = second.lock().unwrap();
+        let _first = first.lock().unwrap();
+    }
+}
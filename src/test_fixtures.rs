@@ -0,0 +1,270 @@
+//! The sample torrents and golden outputs in `tests/fixtures`, the torrents are built by [`create`].
+//! With `UPDATE_GOLDEN=1` the golden files are written instead of compared.
+//! The tests that only need a torrent of a certain shape build it with [`metainfo`].
+use std::path::PathBuf;
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::{
+    Torrent,
+    core::bencode::{Dict, Value},
+    torrent::Metainfo,
+};
+
+/// every sample torrent, without the `.torrent`
+pub(crate) const TORRENTS: [&str; 6] = [
+    "single_file",
+    "multi_file",
+    "large_piece",
+    "private",
+    "announce_list",
+    "hybrid",
+];
+
+fn path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+pub(crate) fn torrent(name: &str) -> Torrent {
+    Torrent::read_from_file(&path(&format!("{name}.torrent"))).unwrap()
+}
+
+pub(crate) fn read(name: &str) -> Vec<u8> {
+    std::fs::read(path(name)).unwrap()
+}
+
+/// The info dict of a single file of `length` bytes in pieces of `piece_length`, the last piece
+/// is shorter if they don't add up. The hashes of the pieces are zeros.
+fn info_dict(length: u64, piece_length: u32) -> Vec<u8> {
+    let n_pieces = length.div_ceil(piece_length as u64) as usize;
+    let mut bencode = format!(
+        "d6:lengthi{length}e4:name4:test12:piece lengthi{piece_length}e6:pieces{}:",
+        n_pieces * 20
+    )
+    .into_bytes();
+    bencode.extend(vec![0_u8; n_pieces * 20]);
+    bencode.push(b'e');
+    bencode
+}
+
+pub(crate) fn metainfo(length: u64, piece_length: u32) -> Metainfo {
+    Metainfo::from_bytes(&info_dict(length, piece_length)).unwrap()
+}
+
+/// a torrent of [`metainfo`] without any trackers
+pub(crate) fn torrent_of(length: u64, piece_length: u32) -> Torrent {
+    let mut bencode = b"d4:info".to_vec();
+    bencode.extend(info_dict(length, piece_length));
+    bencode.push(b'e');
+    Torrent::from_bytes(&bencode).unwrap()
+}
+
+const BLOCK: usize = 16 * 1024;
+const TRACKER: &str = "http://tracker.example/announce";
+
+/// a fixed byte pattern, so the samples come out the same every time
+fn data(length: usize, seed: usize) -> Vec<u8> {
+    (0..length).map(|i| ((i * 31 + seed) % 251) as u8).collect()
+}
+
+fn pieces(data: &[u8], piece_length: usize) -> Value {
+    let hashes = data.chunks(piece_length).flat_map(Sha1::digest);
+    Value::Bytes(hashes.collect())
+}
+
+/// the root of the leaves, padded with zero hashes to a power of two
+fn merkle_root(mut leaves: Vec<[u8; 32]>) -> [u8; 32] {
+    leaves.resize(leaves.len().next_power_of_two(), [0; 32]);
+    while leaves.len() > 1 {
+        leaves = leaves
+            .chunks(2)
+            .map(|pair| {
+                Sha256::new()
+                    .chain_update(pair[0])
+                    .chain_update(pair[1])
+                    .finalize()
+                    .into()
+            })
+            .collect();
+    }
+    leaves[0]
+}
+
+fn string(s: &str) -> Value {
+    Value::Bytes(s.as_bytes().to_vec())
+}
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Dict {
+    let entries = entries
+        .into_iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value));
+    Dict(entries.collect())
+}
+
+fn single_file(name: &str, data: &[u8]) -> Dict {
+    dict([
+        ("length", Value::Int(data.len() as i64)),
+        ("name", string(name)),
+        ("piece length", Value::Int(BLOCK as i64)),
+        ("pieces", pieces(data, BLOCK)),
+    ])
+}
+
+/// The sample torrent of that name, with its keys sorted like the spec wants them.
+pub(crate) fn create(name: &str) -> Vec<u8> {
+    let single = data(40_000, 0);
+    let mut torrent = match name {
+        "single_file" => dict([
+            ("announce", string(TRACKER)),
+            ("info", Value::Dict(single_file("single.bin", &single))),
+        ]),
+        "multi_file" => {
+            let (a, b, c) = (data(10_000, 1), data(30_000, 2), data(2_000, 5));
+            let file = |data: &[u8], path: &[&str]| {
+                Value::Dict(dict([
+                    ("length", Value::Int(data.len() as i64)),
+                    (
+                        "path",
+                        Value::List(path.iter().map(|s| string(s)).collect()),
+                    ),
+                ]))
+            };
+            let files = vec![
+                file(&a, &["docs", "a.txt"]),
+                file(&b, &["b.bin"]),
+                file(&c, &["docs", "notes.md"]),
+            ];
+            let info = dict([
+                ("files", Value::List(files)),
+                ("name", string("multi")),
+                ("piece length", Value::Int(BLOCK as i64)),
+                ("pieces", pieces(&[a, b, c].concat(), BLOCK)),
+            ]);
+            dict([("announce", string(TRACKER)), ("info", Value::Dict(info))])
+        }
+        "large_piece" => {
+            let large = data(9_000_000, 3);
+            let info = dict([
+                ("length", Value::Int(large.len() as i64)),
+                ("name", string("large.bin")),
+                ("piece length", Value::Int(4 * 1024 * 1024)),
+                ("pieces", pieces(&large, 4 * 1024 * 1024)),
+            ]);
+            dict([("announce", string(TRACKER)), ("info", Value::Dict(info))])
+        }
+        "private" => {
+            let mut info = single_file("private.bin", &single);
+            info.insert("private", Value::Int(1));
+            info.insert("source", string("EXAMPLE"));
+            dict([
+                (
+                    "announce",
+                    string("https://private.example/announce?passkey=0123456789abcdef"),
+                ),
+                ("info", Value::Dict(info)),
+            ])
+        }
+        "announce_list" => {
+            let tiers = [
+                vec![TRACKER, "udp://tracker.example:6969/announce"],
+                vec!["wss://tracker.example/ws"],
+            ];
+            let tiers = tiers
+                .iter()
+                .map(|tier| Value::List(tier.iter().map(|s| string(s)).collect()));
+            dict([
+                ("announce", string(TRACKER)),
+                ("announce-list", Value::List(tiers.collect())),
+                (
+                    "url-list",
+                    Value::List(vec![string("http://seed.example/files/")]),
+                ),
+                ("info", Value::Dict(single_file("tiered.bin", &single))),
+            ])
+        }
+        "hybrid" => {
+            let hybrid = data(2 * BLOCK + 100, 4);
+            let leaves: Vec<[u8; 32]> = hybrid
+                .chunks(BLOCK)
+                .map(|block| Sha256::digest(block).into())
+                .collect();
+            let root = merkle_root(leaves.clone());
+            let mut info = single_file("hybrid.bin", &hybrid);
+            let file = dict([
+                ("length", Value::Int(hybrid.len() as i64)),
+                ("pieces root", Value::Bytes(root.to_vec())),
+            ]);
+            let tree = dict([("hybrid.bin", Value::Dict(dict([("", Value::Dict(file))])))]);
+            info.insert("file tree", Value::Dict(tree));
+            info.insert("meta version", Value::Int(2));
+            let layers = Dict(vec![(root.to_vec(), Value::Bytes(leaves.concat()))]);
+            dict([
+                ("announce", string(TRACKER)),
+                ("info", Value::Dict(info)),
+                ("piece layers", Value::Dict(layers)),
+            ])
+        }
+        _ => panic!("There is no sample torrent `{name}`"),
+    };
+    torrent.sort();
+    Value::Dict(torrent).encode()
+}
+
+pub(crate) fn assert_golden(name: &str, actual: &str) {
+    let path = path(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read `{}`: {e}", path.display()));
+    assert_eq!(
+        actual,
+        expected,
+        "The output differs from `{}`, UPDATE_GOLDEN=1 rewrites it.",
+        path.display()
+    );
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_what_create_builds() {
+        for name in TORRENTS {
+            let file = format!("{name}.torrent");
+            let created = create(name);
+            if std::env::var_os("UPDATE_GOLDEN").is_some() {
+                std::fs::write(path(&file), &created).unwrap();
+                continue;
+            }
+            assert!(
+                read(&file) == created,
+                "`{file}` differs from what `create` builds, UPDATE_GOLDEN=1 rewrites it."
+            );
+        }
+    }
+
+    #[test]
+    fn hybrid_has_piece_layers() {
+        let torrent = Value::decode(&read("hybrid.torrent")).unwrap();
+        let get = |value: &Value, key: &str| value.as_dict().unwrap().get(key.as_bytes()).cloned();
+        let mut file = get(&torrent, "info").unwrap();
+        for key in ["file tree", "hybrid.bin", ""] {
+            file = get(&file, key).unwrap();
+        }
+        let Some(Value::Bytes(root)) = get(&file, "pieces root") else {
+            panic!("The hybrid sample has no `pieces root`");
+        };
+        let layers = get(&torrent, "piece layers").unwrap();
+        let Some(Value::Bytes(layer)) = layers.as_dict().unwrap().get(&root) else {
+            panic!("The hybrid sample has no piece layer for its root");
+        };
+        // a hash per block
+        assert_eq!(layer.len(), 3 * 32);
+    }
+}
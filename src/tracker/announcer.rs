@@ -0,0 +1,750 @@
+//! Announces a torrent to its trackers whenever the PeerManager asks for it
+//! and hands the peers we haven't seen before to whoever dials them.
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    config::AnnounceSettings,
+    dht::{Dht, PeerLookup, SwarmEstimate},
+    lsd::{LSD_ANNOUNCE_INTERVAL, Lsd},
+    policy::PeerSource,
+    torrent::InfoHash,
+    tracker::{
+        AnnounceScheduler, Event, TrackerPeer, TrackerRequest, TrackerRequestError,
+        TrackerResponse, TrackerStatus, TrackerTiers,
+        resolver::{PeerAddr, PeerResolver},
+    },
+};
+
+/// The value we send as `left` if neither the metadata nor the magnet link tell us the length.
+/// It mustn't be 0, otherwise the trackers take us for a seeder and leave out the other seeders.
+const UNKNOWN_LEFT: u64 = 999;
+/// how often we look for peers in the DHT besides the announces the PeerManager asks for
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// a forced announce this soon after the last announce is skipped, the trackers would ban us
+const FORCED_ANNOUNCE_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// The statistics of a torrent at the time of the announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AnnounceProgress {
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    /// None while we're waiting for the metadata and don't know the length
+    pub(crate) left: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AnnounceRequest {
+    progress: AnnounceProgress,
+    event: Option<Event>,
+    /// the user asked for it, it ignores the backoff of failed trackers
+    forced: bool,
+}
+
+/// The PeerManager's end of the announcer.
+#[derive(Debug, Clone)]
+pub struct AnnounceHandle {
+    requests: mpsc::Sender<AnnounceRequest>,
+    /// peers the PeerManager learned about, e.g. through peer exchange
+    found_peers: mpsc::Sender<(SocketAddr, PeerSource)>,
+    /// straight to the dialer, the announcer only forwards a peer once
+    dialer: mpsc::Sender<(SocketAddr, PeerSource)>,
+}
+
+impl AnnounceHandle {
+    /// asks for an announce without waiting for it
+    /// the `started` event is added automatically to the first announce
+    pub(crate) fn announce(&self, progress: AnnounceProgress, event: Option<Event>) {
+        self.send(AnnounceRequest {
+            progress,
+            event,
+            forced: false,
+        });
+    }
+
+    /// announces right away unless we announced in the last minute
+    pub(crate) fn force_announce(&self, progress: AnnounceProgress) {
+        self.send(AnnounceRequest {
+            progress,
+            event: None,
+            forced: true,
+        });
+    }
+
+    fn send(&self, request: AnnounceRequest) {
+        if self.requests.try_send(request).is_err() {
+            eprintln!("The announcer is busy, skipping an announce.");
+        }
+    }
+
+    /// hands the peers to the dialer unless we know them already, drops them if the announcer is busy
+    pub(crate) fn add_peers(
+        &self,
+        peers: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) {
+        for peer in peers {
+            if self.found_peers.try_send((peer, source)).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// dials a peer we lost again after the delay, unless it's connected by then
+    pub(crate) fn redial(&self, peer: SocketAddr, after: Duration) {
+        let dialer = self.dialer.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(after).await;
+            let _ = dialer.send((peer, PeerSource::Reconnect)).await;
+        });
+    }
+}
+
+#[derive(Debug)]
+pub struct Announcer {
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    port: u16,
+    tiers: TrackerTiers,
+    scheduler: AnnounceScheduler,
+    settings: AnnounceSettings,
+    /// whether a tracker accepted our `started` event
+    started_sent: bool,
+    /// the `key` of every announce of this session
+    key: u32,
+    /// the IP the peers see us under, see [`Announcer::with_external_ip`]
+    external_ip: watch::Receiver<Option<IpAddr>>,
+    /// see [`Announcer::with_ipv6`]
+    ipv6: Option<Ipv6Addr>,
+    rx: mpsc::Receiver<AnnounceRequest>,
+    /// see [`AnnounceHandle::add_peers`], the local service discovery sends its peers here too
+    found_rx: mpsc::Receiver<(SocketAddr, PeerSource)>,
+    found_tx: mpsc::Sender<(SocketAddr, PeerSource)>,
+    peers_tx: mpsc::Sender<(SocketAddr, PeerSource)>,
+    known_peers: HashSet<PeerAddr>,
+    /// the ids of the peers from non-compact responses
+    known_peer_ids: HashSet<[u8; 20]>,
+    /// peers we know before the first announce, e.g. from the magnet link
+    initial_peers: Vec<(PeerAddr, PeerSource)>,
+    resolver: PeerResolver,
+    /// in the order of the tiers
+    status: watch::Sender<Vec<TrackerStatus>>,
+    /// the announce to repeat once the backoff of a tracker is over, if every tracker failed
+    retry: Option<(Instant, AnnounceRequest)>,
+    /// when we last tried to reach the trackers, see [`AnnounceHandle::force_announce`]
+    last_announce: Option<Instant>,
+    /// the progress of the last announce the PeerManager asked for,
+    /// the trackers are due on their own in the all-trackers mode
+    last_progress: Option<AnnounceProgress>,
+    /// see [`Announcer::with_dht`]
+    dht: Option<Dht>,
+    /// the DHT lookup that's running, it returns the peers it found
+    dht_lookup: Option<JoinHandle<PeerLookup>>,
+    /// the swarm size the last DHT lookup estimated
+    dht_swarm: watch::Sender<Option<SwarmEstimate>>,
+    /// whether the last announce had nothing left, the DHT learns it with our announce
+    seeding: bool,
+    /// see [`Announcer::with_lsd`]
+    lsd: Option<Lsd>,
+    /// see [`Announcer::with_clock`]
+    clock: Arc<dyn Clock>,
+}
+
+impl Announcer {
+    /// returns the announcer, the handle for the PeerManager and a receiver of new peers
+    pub fn new(
+        info_hash: InfoHash,
+        peer_id: [u8; 20],
+        port: u16,
+        tiers: TrackerTiers,
+        scheduler: AnnounceScheduler,
+    ) -> (
+        Self,
+        AnnounceHandle,
+        mpsc::Receiver<(SocketAddr, PeerSource)>,
+    ) {
+        let (tx, rx) = mpsc::channel(8);
+        let (found_tx, found_rx) = mpsc::channel(64);
+        let (peers_tx, peers_rx) = mpsc::channel(64);
+        let status = tiers
+            .iter()
+            .map(|(_, url)| TrackerStatus::new(url.clone()))
+            .collect();
+        let announcer = Self {
+            info_hash,
+            peer_id,
+            port,
+            tiers,
+            scheduler,
+            settings: AnnounceSettings::default(),
+            started_sent: false,
+            key: rand::random(),
+            external_ip: watch::Sender::new(None).subscribe(),
+            ipv6: None,
+            rx,
+            found_rx,
+            found_tx: found_tx.clone(),
+            peers_tx,
+            known_peers: HashSet::new(),
+            known_peer_ids: HashSet::new(),
+            initial_peers: Vec::new(),
+            resolver: PeerResolver::default(),
+            status: watch::Sender::new(status),
+            retry: None,
+            last_announce: None,
+            last_progress: None,
+            dht: None,
+            dht_lookup: None,
+            dht_swarm: watch::Sender::new(None),
+            seeding: false,
+            lsd: None,
+            clock: Arc::new(SystemClock),
+        };
+        let handle = AnnounceHandle {
+            requests: tx,
+            found_peers: found_tx,
+            dialer: announcer.peers_tx.clone(),
+        };
+        (announcer, handle, peers_rx)
+    }
+
+    /// adds the peers of the magnet link which are dialed as soon as the announcer runs
+    pub fn with_peers(mut self, peers: impl IntoIterator<Item = PeerAddr>) -> Self {
+        self.initial_peers
+            .extend(peers.into_iter().map(|peer| (peer, PeerSource::MagnetLink)));
+        self
+    }
+
+    /// adds the peers the last run rated best, they're dialed before the others
+    pub(crate) fn with_cached_peers(mut self, peers: impl IntoIterator<Item = SocketAddr>) -> Self {
+        let cached = peers
+            .into_iter()
+            .map(|addr| (PeerAddr::Ip(addr), PeerSource::Cache));
+        self.initial_peers.splice(0..0, cached);
+        self
+    }
+
+    pub fn with_settings(mut self, settings: AnnounceSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// sends the IP the PeerManager detected as `ip` in the announces
+    pub fn with_external_ip(mut self, external_ip: watch::Receiver<Option<IpAddr>>) -> Self {
+        self.external_ip = external_ip;
+        self
+    }
+
+    /// tells the trackers the IPv6 address we listen on too, None if we only have IPv4
+    pub(crate) fn with_ipv6(mut self, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+
+    /// looks for peers in the DHT whenever we announce and announces us there too
+    pub fn with_dht(mut self, dht: Option<Dht>) -> Self {
+        self.dht = dht;
+        self
+    }
+
+    /// announces us in the local network and dials the peers that announce themselves there
+    pub(crate) fn with_lsd(mut self, lsd: Option<Lsd>) -> Self {
+        self.lsd = lsd;
+        self
+    }
+
+    /// reads the time of the intervals, backoffs and retries from `clock` instead of the OS
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_std(self.clock.now())
+    }
+
+    /// returns a receiver that always holds the latest status of every tracker
+    pub fn subscribe_status(&self) -> watch::Receiver<Vec<TrackerStatus>> {
+        self.status.subscribe()
+    }
+
+    /// returns a receiver that holds the swarm size the DHT estimated last, see [`Dht::scrape`]
+    pub fn subscribe_dht_swarm(&self) -> watch::Receiver<Option<SwarmEstimate>> {
+        self.dht_swarm.subscribe()
+    }
+
+    /// runs until we announced `stopped` or the PeerManager or the receiver of the peers is dropped
+    pub async fn run(mut self) {
+        for (peer, source) in std::mem::take(&mut self.initial_peers) {
+            if self.forward(peer, source).await.is_err() {
+                return;
+            }
+        }
+        let mut dht_tick = tokio::time::interval(DHT_LOOKUP_INTERVAL);
+        let mut lsd_tick = tokio::time::interval(LSD_ANNOUNCE_INTERVAL);
+        if let Some(lsd) = &self.lsd {
+            lsd.subscribe(self.info_hash, self.found_tx.clone());
+        }
+        loop {
+            let now = self.now();
+            let retry_at = self.retry.map_or(now, |(at, _)| at);
+            // a retry of the whole announce covers the trackers that are due
+            let due = self
+                .next_due()
+                .zip(self.last_progress)
+                .filter(|_| self.retry.is_none());
+            let AnnounceRequest {
+                progress,
+                event,
+                forced,
+            } = tokio::select! {
+                request = self.rx.recv() => {
+                    let Some(mut request) = request else {
+                        return;
+                    };
+                    if request.forced && !self.may_force(self.now()) {
+                        eprintln!("We announced less than a minute ago, skipping the forced announce.");
+                        continue;
+                    }
+                    // a `completed` that didn't go through must not get lost
+                    if let Some((_, pending)) = self.retry.take() {
+                        request.event = request.event.or(pending.event);
+                    }
+                    request
+                }
+                _ = tokio::time::sleep_until(retry_at), if self.retry.is_some() => {
+                    self.retry.take().expect("The branch is only enabled with a retry.").1
+                }
+                _ = tokio::time::sleep_until(due.map_or(now, |(at, _)| at)), if due.is_some() => {
+                    let (_, progress) = due.expect("The branch is only enabled with a due tracker.");
+                    if self.announce_due(progress).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Some((peer, source)) = self.found_rx.recv() => {
+                    if self.forward(PeerAddr::Ip(peer), source).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                _ = lsd_tick.tick(), if self.lsd.is_some() => {
+                    if let Some(lsd) = &self.lsd {
+                        lsd.announce(self.info_hash).await;
+                    }
+                    continue;
+                }
+                _ = dht_tick.tick() => {
+                    self.start_dht_lookup();
+                    continue;
+                }
+                lookup = dht_lookup_done(&mut self.dht_lookup) => {
+                    self.dht_lookup = None;
+                    if let Some(lookup) = &lookup {
+                        self.dht_swarm.send_replace(Some(lookup.swarm));
+                    }
+                    for peer in lookup.map(|lookup| lookup.peers).unwrap_or_default() {
+                        if self.forward(PeerAddr::Ip(peer), PeerSource::Dht).await.is_err() {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+            };
+            self.seeding = progress.left == Some(0);
+            self.last_progress = Some(progress);
+            if event != Some(Event::Stopped) {
+                self.start_dht_lookup();
+            }
+            self.last_announce = Some(self.now());
+            let result = self.announce(progress, event, forced).await;
+            if event == Some(Event::Stopped) {
+                return;
+            }
+            let peers = match result {
+                Ok(peers) => peers,
+                Err(e) => {
+                    eprintln!("Failed to announce: {e}");
+                    self.retry = self.next_retry().map(|at| {
+                        (
+                            at,
+                            AnnounceRequest {
+                                progress,
+                                event,
+                                forced: false,
+                            },
+                        )
+                    });
+                    continue;
+                }
+            };
+            if self.forward_tracker_peers(peers).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// returns Err if nobody dials the peers anymore
+    async fn forward_tracker_peers(&mut self, peers: Vec<TrackerPeer>) -> Result<(), ()> {
+        for peer in peers {
+            if let Some(peer_id) = peer.peer_id
+                && (peer_id == self.peer_id || !self.known_peer_ids.insert(peer_id))
+            {
+                // that's us or a peer we know under another address
+                continue;
+            }
+            self.forward(peer.addr, PeerSource::Tracker).await?;
+        }
+        Ok(())
+    }
+
+    /// hands a peer we haven't seen before to the dialer
+    /// names are resolved in the background so they don't hold up the announces
+    /// returns Err if nobody dials the peers anymore
+    async fn forward(&mut self, peer: PeerAddr, source: PeerSource) -> Result<(), ()> {
+        if !self.known_peers.insert(peer.clone()) {
+            return Ok(());
+        }
+        match peer {
+            PeerAddr::Ip(addr) => self.peers_tx.send((addr, source)).await.map_err(|_| ()),
+            PeerAddr::Host { .. } => {
+                let resolver = self.resolver.clone();
+                let peers_tx = self.peers_tx.clone();
+                tokio::spawn(async move {
+                    // each address is a peer on its own
+                    for addr in resolver.resolve(peer).await {
+                        if peers_tx.send((addr, source)).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn may_force(&self, now: Instant) -> bool {
+        self.last_announce
+            .is_none_or(|last| now.duration_since(last) >= FORCED_ANNOUNCE_COOLDOWN)
+    }
+
+    /// unless one is running already
+    fn start_dht_lookup(&mut self) {
+        let Some(dht) = &self.dht else {
+            return;
+        };
+        if self.dht_lookup.is_none() {
+            let (dht, info_hash, port, seed) =
+                (dht.clone(), self.info_hash, self.port, self.seeding);
+            self.dht_lookup = Some(tokio::spawn(async move {
+                dht.get_peers(info_hash, Some(port), seed).await
+            }));
+        }
+    }
+
+    async fn announce(
+        &mut self,
+        progress: AnnounceProgress,
+        event: Option<Event>,
+        forced: bool,
+    ) -> Result<Vec<TrackerPeer>, TrackerRequestError> {
+        // trackerless torrents rely on the DHT
+        if self.tiers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (info_hash, peer_id) = (self.info_hash, self.peer_id);
+        let request = self.request(&info_hash, &peer_id, progress, event);
+        // a `stopped` is our last chance to reach the trackers
+        let ignore_backoff = forced || event == Some(Event::Stopped);
+        let peers = if self.settings.announce_to_all {
+            let now = self.now();
+            let trackers = self.trackers(|s| ignore_backoff || !s.is_backing_off(now));
+            self.announce_to_all(&request, trackers).await?
+        } else {
+            let (pos, response) = self.announce_to_tiers(&request, ignore_backoff).await?;
+            self.tiers.promote(pos);
+            response.into_peers()
+        };
+        self.started_sent = true;
+        Ok(peers)
+    }
+
+    /// announces to the trackers whose interval or backoff is over, see [`Announcer::next_due`]
+    /// returns Err if nobody dials the peers anymore
+    async fn announce_due(&mut self, progress: AnnounceProgress) -> Result<(), ()> {
+        let (info_hash, peer_id) = (self.info_hash, self.peer_id);
+        let request = self.request(&info_hash, &peer_id, progress, None);
+        let now = self.now();
+        let trackers = self.trackers(|s| s.due_at().is_some_and(|at| at <= now));
+        // the trackers that failed are in their status already
+        let peers = self
+            .announce_to_all(&request, trackers)
+            .await
+            .unwrap_or_default();
+        self.forward_tracker_peers(peers).await
+    }
+
+    /// when the first tracker is due in the all-trackers mode
+    fn next_due(&self) -> Option<Instant> {
+        if !self.settings.announce_to_all {
+            return None;
+        }
+        self.status.borrow().iter().filter_map(|s| s.due_at()).min()
+    }
+
+    fn request<'a>(
+        &self,
+        info_hash: &'a InfoHash,
+        peer_id: &'a [u8; 20],
+        progress: AnnounceProgress,
+        event: Option<Event>,
+    ) -> TrackerRequest<'a> {
+        let request = TrackerRequest::new(
+            info_hash,
+            peer_id,
+            self.port,
+            progress.left.unwrap_or(UNKNOWN_LEFT),
+        )
+        .with_stats(progress.uploaded, progress.downloaded)
+        .with_settings(&self.settings)
+        .with_key(self.key)
+        .with_ip(*self.external_ip.borrow())
+        .with_ipv6(self.ipv6);
+        match event.or((!self.started_sent).then_some(Event::Started)) {
+            Some(event) => request.with_event(event),
+            None => request,
+        }
+    }
+
+    /// the trackers of all tiers whose status matches
+    fn trackers(&self, filter: impl Fn(&TrackerStatus) -> bool) -> Vec<url::Url> {
+        self.status
+            .borrow()
+            .iter()
+            .filter(|s| filter(s))
+            .map(|s| s.url.clone())
+            .collect()
+    }
+
+    /// announces to all the trackers at once and merges their peers
+    /// fails only if none of them answered
+    async fn announce_to_all(
+        &mut self,
+        request: &TrackerRequest<'_>,
+        trackers: Vec<url::Url>,
+    ) -> Result<Vec<TrackerPeer>, TrackerRequestError> {
+        let announces = trackers.into_iter().map(|url| {
+            let request = request.clone().with_tracker_id(self.tracker_id(&url));
+            let scheduler = &self.scheduler;
+            async move {
+                let result = request.get_response([url.clone()], scheduler).await;
+                (url, result)
+            }
+        });
+        let results = futures_util::future::join_all(announces).await;
+
+        let mut peers = Vec::new();
+        let mut answered = false;
+        let mut last_err = TrackerRequestError::NoTracker;
+        for (url, result) in results {
+            self.record(&url, &result);
+            match result {
+                Ok(response) => {
+                    answered = true;
+                    peers.extend(response.into_peers());
+                }
+                Err(e) => last_err = e,
+            }
+        }
+        if answered { Ok(peers) } else { Err(last_err) }
+    }
+
+    fn tracker_id(&self, url: &url::Url) -> Option<String> {
+        self.status
+            .borrow()
+            .iter()
+            .find(|s| &s.url == url)
+            .and_then(|s| s.tracker_id.clone())
+    }
+
+    /// updates the status of the tracker and prints what went wrong
+    fn record(&self, url: &url::Url, result: &Result<TrackerResponse, TrackerRequestError>) {
+        let now = self.now();
+        self.status.send_modify(|status| {
+            let Some(status) = status.iter_mut().find(|s| &s.url == url) else {
+                return;
+            };
+            match result {
+                Ok(response) => status.succeeded(
+                    now,
+                    response.warning_message.clone(),
+                    response.peers.0.len() + response.peers6.0.len(),
+                    response.tracker_id.clone(),
+                    response.interval,
+                ),
+                Err(e) => status.failed(now, e.to_string()),
+            }
+        });
+        match result {
+            Ok(response) => {
+                if let Some(warning) = &response.warning_message {
+                    eprintln!("The tracker `{url}` warns: {warning}");
+                }
+            }
+            Err(e) => eprintln!("Failed to announce to `{url}`: {e}"),
+        }
+    }
+
+    /// tries one tracker after another until one answers
+    /// trackers that failed recently are skipped
+    async fn announce_to_tiers(
+        &mut self,
+        request: &TrackerRequest<'_>,
+        ignore_backoff: bool,
+    ) -> Result<((usize, usize), TrackerResponse), TrackerRequestError> {
+        let trackers: Vec<_> = self
+            .tiers
+            .iter()
+            .map(|(pos, url)| (pos, url.clone()))
+            .collect();
+        let mut last_err = TrackerRequestError::NoTracker;
+        for (pos, url) in trackers {
+            let Some(tracker_id) = self
+                .status
+                .borrow()
+                .iter()
+                .find(|s| s.url == url)
+                .filter(|s| ignore_backoff || !s.is_backing_off(self.now()))
+                .map(|s| s.tracker_id.clone())
+            else {
+                continue;
+            };
+            let result = request
+                .clone()
+                .with_tracker_id(tracker_id)
+                .get_response([url.clone()], &self.scheduler)
+                .await;
+            self.record(&url, &result);
+            match result {
+                Ok(response) => return Ok((pos, response)),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// the earliest time a tracker may be tried again
+    fn next_retry(&self) -> Option<Instant> {
+        self.status.borrow().iter().filter_map(|s| s.retry_at).min()
+    }
+}
+
+/// the result of the running lookup, None if it panicked, never returns if there's none
+async fn dht_lookup_done(lookup: &mut Option<JoinHandle<PeerLookup>>) -> Option<PeerLookup> {
+    match lookup {
+        Some(lookup) => lookup.await.ok(),
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::config::{TrackerCompat, TrackerTls};
+
+    use super::*;
+
+    /// answers one announce with the peer and returns the url to announce to
+    async fn tracker(peer: [u8; 6]) -> url::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!(
+            "http://{}/announce",
+            listener.local_addr().unwrap()
+        ));
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.read(&mut [0; 1024]).await.unwrap();
+            let mut body = b"d8:intervali900e5:peers6:".to_vec();
+            body.extend(peer);
+            body.push(b'e');
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        });
+        url.unwrap()
+    }
+
+    #[tokio::test]
+    async fn announce_to_all_merges_the_peers() {
+        let first = tracker([10, 0, 0, 1, 0x1a, 0xe1]).await;
+        let second = tracker([10, 0, 0, 2, 0x1a, 0xe1]).await;
+        let scheduler = AnnounceScheduler::new(
+            Duration::ZERO,
+            &TrackerTls::default(),
+            &TrackerCompat::default(),
+        )
+        .unwrap();
+        let tiers = TrackerTiers::single_tier(vec![first.clone(), second.clone()]);
+        let (mut announcer, _, _) =
+            Announcer::new(InfoHash([0; 20]), [0; 20], 6881, tiers, scheduler);
+        announcer.settings.announce_to_all = true;
+
+        let (info_hash, peer_id) = (announcer.info_hash, announcer.peer_id);
+        let progress = AnnounceProgress {
+            uploaded: 0,
+            downloaded: 0,
+            left: Some(1000),
+        };
+        let request = announcer.request(&info_hash, &peer_id, progress, None);
+        let mut peers: Vec<_> = announcer
+            .announce_to_all(&request, vec![first, second])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|peer| peer.addr)
+            .collect();
+        peers.sort_by_key(|addr| format!("{addr:?}"));
+        assert_eq!(
+            peers,
+            [
+                PeerAddr::Ip("10.0.0.1:6881".parse().unwrap()),
+                PeerAddr::Ip("10.0.0.2:6881".parse().unwrap()),
+            ]
+        );
+        // each tracker is due after its own interval
+        assert!(announcer.next_due().unwrap() > Instant::now() + Duration::from_secs(800));
+    }
+
+    #[test]
+    fn forced_announces_wait_for_the_cooldown() {
+        let scheduler = AnnounceScheduler::new(
+            Duration::ZERO,
+            &TrackerTls::default(),
+            &TrackerCompat::default(),
+        )
+        .unwrap();
+        let tiers = TrackerTiers::single_tier(Vec::new());
+        let (mut announcer, _, _) =
+            Announcer::new(InfoHash([0; 20]), [0; 20], 6881, tiers, scheduler);
+        let now = Instant::now();
+        assert!(announcer.may_force(now));
+        announcer.last_announce = Some(now);
+        assert!(!announcer.may_force(now + Duration::from_secs(59)));
+        assert!(announcer.may_force(now + FORCED_ANNOUNCE_COOLDOWN));
+    }
+}
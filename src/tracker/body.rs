@@ -0,0 +1,119 @@
+//! Reads the body of an HTTP tracker response.
+//! Misconfigured trackers surround the bencode with whitespace or answer with an HTML error page,
+//! the error then says what the tracker wrote instead of dumping the raw bytes.
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+
+use crate::tracker::TrackerRequestError;
+
+/// how much of a response that isn't bencode ends up in the error
+const SUMMARY_LEN: usize = 200;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// tries again without the surrounding whitespace if the body isn't bencode as is
+pub(super) fn parse<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, serde_bencode::Error> {
+    let error = match serde_bencode::from_bytes(bytes) {
+        Ok(value) => return Ok(value),
+        Err(error) => error,
+    };
+    let trimmed = bytes.trim_ascii();
+    if trimmed.len() == bytes.len() {
+        return Err(error);
+    }
+    serde_bencode::from_bytes(trimmed)
+}
+
+pub(super) fn invalid_response(
+    error: serde_bencode::Error,
+    response: Bytes,
+    url: &url::Url,
+) -> TrackerRequestError {
+    TrackerRequestError::InvalidResponse {
+        error,
+        summary: summarize(&response),
+        response,
+        url: url.to_string(),
+    }
+}
+
+/// what a human can make of a body that isn't bencode
+fn summarize(bytes: &[u8]) -> String {
+    if bytes.starts_with(GZIP_MAGIC) {
+        return "gzip compressed data without a `Content-Encoding` header".to_string();
+    }
+    if let Some(reason) = failure_reason(bytes) {
+        return reason;
+    }
+    let text = String::from_utf8_lossy(bytes);
+    let text = strip_tags(&text);
+    let mut summary = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if summary.is_empty() {
+        return format!("{} bytes that aren't text", bytes.len());
+    }
+    if let Some((end, _)) = summary.char_indices().nth(SUMMARY_LEN) {
+        summary.truncate(end);
+        summary.push('…');
+    }
+    summary
+}
+
+/// the `failure reason` of a dictionary that's broken elsewhere
+fn failure_reason(bytes: &[u8]) -> Option<String> {
+    const KEY: &[u8] = b"14:failure reason";
+    let start = bytes.windows(KEY.len()).position(|w| w == KEY)? + KEY.len();
+    let rest = &bytes[start..];
+    let colon = rest.iter().position(|b| *b == b':')?;
+    let len: usize = std::str::from_utf8(&rest[..colon]).ok()?.parse().ok()?;
+    let reason = rest.get(colon + 1..colon + 1 + len)?;
+    Some(String::from_utf8_lossy(reason).into_owned())
+}
+
+/// the text of an HTML page, the title and body run together
+fn strip_tags(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                out.push(' ');
+            }
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::TrackerResponse;
+
+    #[test]
+    fn whitespace_gets_a_second_chance() {
+        let response: TrackerResponse = parse(b"\r\n d8:intervali900e5:peers0:e\n").unwrap();
+        assert_eq!(response.interval, 900);
+        assert!(parse::<TrackerResponse>(b"<html>d8:intervali900e5:peers0:e").is_err());
+    }
+
+    #[test]
+    fn summaries() {
+        assert_eq!(
+            summarize(
+                b"<html><head><title>502 Bad Gateway</title></head>\n<body>nginx</body></html>"
+            ),
+            "502 Bad Gateway nginx"
+        );
+        assert_eq!(
+            summarize(b"d14:failure reason20:unregistered torrent8:interva"),
+            "unregistered torrent"
+        );
+        assert_eq!(
+            summarize(&[0x1f, 0x8b, 8, 0]),
+            "gzip compressed data without a `Content-Encoding` header"
+        );
+        assert_eq!(summarize(&[0xff; 500]).chars().count(), SUMMARY_LEN + 1);
+    }
+}
@@ -0,0 +1,733 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+use bytes::Bytes;
+use futures_util::future::select_ok;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    config::AnnounceSettings,
+    torrent::InfoHash,
+    tracker::peers::{PeerConnections, PeerConnections6},
+};
+
+pub use peers::TrackerPeer;
+
+mod announcer;
+mod body;
+mod resolver;
+mod scheduler;
+mod scrape;
+mod status;
+mod tiers;
+mod udp;
+mod websocket;
+
+pub(crate) use announcer::AnnounceProgress;
+pub use announcer::{AnnounceHandle, Announcer};
+pub use resolver::PeerAddr;
+pub(crate) use resolver::PeerResolver;
+pub use scheduler::AnnounceScheduler;
+pub use scrape::{ScrapeStats, scrape};
+pub use status::TrackerStatus;
+pub use tiers::TrackerTiers;
+pub(crate) use tiers::{merge_trackers, torrent_trackers};
+pub use udp::UdpTrackerError;
+pub use websocket::WebSocketTrackerError;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackerRequest<'a> {
+    /// the info hash of the torrent
+    info_hash: &'a InfoHash,
+    /// a unique identifier for your client
+    peer_id: &'a [u8; 20],
+    /// the port your client is listening on
+    port: u16,
+    /// the amount uploaded since the `started` announce
+    uploaded: u64,
+    /// the amount downloaded since the `started` announce
+    downloaded: u64,
+    /// the number of bytes left to download
+    left: u64,
+    /// whether the peer list should use the compact representation
+    /// The compact representation is more commonly used in the wild, the non-compact representation is mostly supported for backward-compatibility.
+    compact: bool,
+    /// whether the non-compact peer list should leave out the peer ids
+    no_peer_id: bool,
+    /// the number of peers we want, None leaves it to the tracker
+    numwant: Option<u32>,
+    /// None for the regular announces in between
+    event: Option<Event>,
+    /// random per session, lets the tracker recognize us if our IP changes
+    key: Option<u32>,
+    /// the `tracker id` the tracker sent us last time
+    tracker_id: Option<String>,
+    /// our external IP, if the tracker can't see it because of a proxy
+    ip: Option<IpAddr>,
+    /// our IPv6 address if we announce over IPv4, so the tracker hands it to IPv6 peers (BEP 7)
+    ipv6: Option<Ipv6Addr>,
+}
+
+/// The `event` parameter of an announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// the first announce of a download
+    Started,
+    /// the download finished while we were running
+    Completed,
+    /// we're shutting down gracefully
+    Stopped,
+}
+
+impl Event {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+impl<'a> TrackerRequest<'a> {
+    pub fn new(info_hash: &'a InfoHash, peer_id: &'a [u8; 20], port: u16, left: u64) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: true,
+            no_peer_id: false,
+            numwant: None,
+            event: None,
+            key: None,
+            tracker_id: None,
+            ip: None,
+            ipv6: None,
+        }
+    }
+
+    pub(crate) fn with_stats(mut self, uploaded: u64, downloaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+        self
+    }
+
+    pub(crate) fn with_event(mut self, event: Event) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    pub fn with_settings(mut self, settings: &AnnounceSettings) -> Self {
+        self.compact = settings.compact;
+        self.no_peer_id = settings.no_peer_id;
+        self.numwant = settings.numwant;
+        self
+    }
+
+    pub(crate) fn with_key(mut self, key: u32) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub(crate) fn with_ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    pub(crate) fn with_ipv6(mut self, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+
+    pub(crate) fn with_tracker_id(mut self, tracker_id: Option<String>) -> Self {
+        self.tracker_id = tracker_id;
+        self
+    }
+
+    fn to_url_encoded(&self) -> String {
+        let mut url_encoded = String::new();
+        url_encoded.push_str(&format!(
+            "info_hash={}",
+            escape_bytes_url(&self.info_hash.0)
+        ));
+        url_encoded.push_str(&format!("&peer_id={}", escape_bytes_url(self.peer_id)));
+        url_encoded.push_str(&format!("&port={}", self.port));
+        url_encoded.push_str(&format!("&uploaded={}", self.uploaded));
+        url_encoded.push_str(&format!("&downloaded={}", self.downloaded));
+        url_encoded.push_str(&format!("&left={}", self.left));
+        url_encoded.push_str(&format!("&compact={}", self.compact as u8));
+        if self.no_peer_id {
+            url_encoded.push_str("&no_peer_id=1");
+        }
+        if let Some(numwant) = self.numwant {
+            url_encoded.push_str(&format!("&numwant={numwant}"));
+        }
+        if let Some(event) = self.event {
+            url_encoded.push_str(&format!("&event={}", event.as_str()));
+        }
+        if let Some(key) = self.key {
+            url_encoded.push_str(&format!("&key={key:08x}"));
+        }
+        if let Some(ip) = self.ip {
+            let ip: String =
+                url::form_urlencoded::byte_serialize(ip.to_string().as_bytes()).collect();
+            url_encoded.push_str(&format!("&ip={ip}"));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            let ipv6: String =
+                url::form_urlencoded::byte_serialize(ipv6.to_string().as_bytes()).collect();
+            url_encoded.push_str(&format!("&ipv6={ipv6}"));
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            let tracker_id: String =
+                url::form_urlencoded::byte_serialize(tracker_id.as_bytes()).collect();
+            url_encoded.push_str(&format!("&trackerid={tracker_id}"));
+        }
+        url_encoded
+    }
+
+    pub async fn get_response(
+        &self,
+        announce_urls: impl IntoIterator<Item = url::Url>,
+        scheduler: &AnnounceScheduler,
+    ) -> Result<TrackerResponse, TrackerRequestError> {
+        let request_list: Vec<_> = announce_urls
+            .into_iter()
+            .map(|url| Box::pin(self.announce_to(url, scheduler)))
+            .collect();
+        if request_list.is_empty() {
+            return Err(TrackerRequestError::NoTracker);
+        }
+        let (response, _rem) = select_ok(request_list).await?;
+        Ok(response)
+    }
+
+    /// picks the protocol by the scheme of the url
+    async fn announce_to(
+        &self,
+        url: url::Url,
+        scheduler: &AnnounceScheduler,
+    ) -> Result<TrackerResponse, TrackerRequestError> {
+        let response = match url.scheme() {
+            "udp" => udp::announce(self, &url, scheduler).await?,
+            "ws" | "wss" => websocket::announce(self, &url, scheduler).await?,
+            _ => self.announce_http(url.clone(), scheduler).await?,
+        };
+        match response.failure_reason {
+            Some(reason) => Err(TrackerRequestError::Failure {
+                url: url.to_string(),
+                reason,
+            }),
+            None => Ok(response),
+        }
+    }
+
+    async fn announce_http(
+        &self,
+        mut url: url::Url,
+        scheduler: &AnnounceScheduler,
+    ) -> Result<TrackerResponse, TrackerRequestError> {
+        let quirks = scheduler.quirks(url.host_str().unwrap_or_default());
+        let query = if quirks.is_some_and(|quirks| quirks.no_compact) {
+            TrackerRequest {
+                compact: false,
+                ..self.clone()
+            }
+            .to_url_encoded()
+        } else {
+            self.to_url_encoded()
+        };
+        url.set_query(Some(&query));
+        let response = scheduler.get(url).await?;
+        let url = response.url().clone();
+        let response_bytes = Bytes::copy_from_slice(&response.bytes().await?);
+
+        body::parse(&response_bytes)
+            .map_err(|error| body::invalid_response(error, response_bytes, &url))
+    }
+}
+
+pub(super) fn escape_bytes_url(bytes: &[u8; 20]) -> String {
+    bytes
+        .iter()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() {
+                (*b as char).to_string()
+            } else {
+                format!("%{}", hex::encode([*b]))
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TrackerResponse {
+    /// An integer, indicating how often your client should make a request to the tracker, in seconds.
+    /// It's missing if the tracker refused the announce.
+    #[serde(default)]
+    pub interval: usize,
+    /// Why the tracker refused the announce, none of the other fields are set then.
+    #[serde(
+        rename = "failure reason",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub failure_reason: Option<String>,
+    /// The announce went through but the tracker has something to tell the user.
+    #[serde(
+        rename = "warning message",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub warning_message: Option<String>,
+    /// We have to send it back in the next announces to this tracker.
+    #[serde(
+        rename = "tracker id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tracker_id: Option<String>,
+    /// A string, which contains list of peers that your client can connect to.
+    /// Each peer is represented using 6 bytes.
+    /// The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    /// Old trackers send a list of dictionaries instead, see [`TrackerPeer`].
+    #[serde(default)]
+    pub peers: PeerConnections,
+    /// The same for IPv6 peers with 16 bytes for the address.
+    #[serde(default, skip_serializing_if = "PeerConnections6::is_empty")]
+    pub peers6: PeerConnections6,
+}
+
+impl TrackerResponse {
+    /// The IPv4 and IPv6 peers without duplicates.
+    /// Some trackers list IPv4 peers in `peers6` too, as IPv4-mapped addresses.
+    pub fn into_peers(self) -> Vec<TrackerPeer> {
+        let mut seen = HashSet::new();
+        self.peers
+            .0
+            .into_iter()
+            .chain(self.peers6.0.into_iter().map(|addr| TrackerPeer {
+                addr: PeerAddr::Ip(addr),
+                peer_id: None,
+            }))
+            .map(|mut peer| {
+                if let PeerAddr::Ip(addr) = &mut peer.addr {
+                    *addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                }
+                peer
+            })
+            .filter(|peer| seen.insert(peer.addr.clone()))
+            .collect()
+    }
+}
+
+mod peers {
+    use std::{
+        fmt,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    };
+
+    use serde::{
+        Deserialize, Deserializer, Serialize, Serializer,
+        de::{self, SeqAccess, Visitor},
+    };
+    use serde_bytes::ByteBuf;
+
+    use crate::tracker::PeerAddr;
+
+    /// a peer from the tracker response
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TrackerPeer {
+        pub addr: PeerAddr,
+        /// only the dictionary form contains the peer id
+        /// knowing it lets us skip peers we're already connected to before the handshake
+        pub peer_id: Option<[u8; 20]>,
+    }
+
+    /// the dictionary form of a peer
+    #[derive(Debug, Deserialize, Serialize)]
+    struct DictPeer {
+        #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+        peer_id: Option<ByteBuf>,
+        /// an IP or a DNS name
+        ip: String,
+        port: u16,
+    }
+
+    impl From<DictPeer> for TrackerPeer {
+        fn from(peer: DictPeer) -> Self {
+            let addr = match peer.ip.parse::<IpAddr>() {
+                Ok(ip) => PeerAddr::Ip(SocketAddr::new(ip, peer.port)),
+                Err(_) => PeerAddr::Host {
+                    name: peer.ip,
+                    port: peer.port,
+                },
+            };
+            let peer_id = peer
+                .peer_id
+                .and_then(|peer_id| <[u8; 20]>::try_from(peer_id.as_slice()).ok());
+            TrackerPeer { addr, peer_id }
+        }
+    }
+
+    /// the `peers` field, either compact with 6 bytes per peer or a list of dictionaries
+    #[derive(Debug, Clone, Default)]
+    pub struct PeerConnections(pub Vec<TrackerPeer>);
+    struct PeerListVisitor;
+    /// the compact `peers6` field (BEP 7), 18 bytes per peer
+    #[derive(Debug, Clone, Default)]
+    pub struct PeerConnections6(pub Vec<SocketAddr>);
+    /// `entry_len` is the length of the IP plus 2 bytes for the port
+    struct PeersVisitor {
+        entry_len: usize,
+    }
+
+    fn to_bytes(peers: &[SocketAddr]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for peer in peers {
+            match peer.ip() {
+                IpAddr::V4(ip) => bytes.extend(&ip.octets()),
+                IpAddr::V6(ip) => bytes.extend(&ip.octets()),
+            }
+            bytes.extend(&peer.port().to_be_bytes());
+        }
+        bytes
+    }
+
+    impl Serialize for PeerConnections {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            // the compact form can't hold peer ids and names
+            let compact: Option<Vec<SocketAddr>> = self
+                .0
+                .iter()
+                .map(|peer| match peer.addr {
+                    PeerAddr::Ip(addr @ SocketAddr::V4(_)) if peer.peer_id.is_none() => Some(addr),
+                    _ => None,
+                })
+                .collect();
+            if let Some(compact) = compact {
+                return serializer.serialize_bytes(&to_bytes(&compact));
+            }
+            let dicts: Vec<DictPeer> = self
+                .0
+                .iter()
+                .map(|peer| {
+                    let (ip, port) = match &peer.addr {
+                        PeerAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+                        PeerAddr::Host { name, port } => (name.clone(), *port),
+                    };
+                    DictPeer {
+                        peer_id: peer.peer_id.map(|id| ByteBuf::from(id.to_vec())),
+                        ip,
+                        port,
+                    }
+                })
+                .collect();
+            dicts.serialize(serializer)
+        }
+    }
+
+    impl Serialize for PeerConnections6 {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_bytes(&to_bytes(&self.0))
+        }
+    }
+
+    impl PeerConnections6 {
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    impl<'de> Visitor<'de> for PeersVisitor {
+        type Value = Vec<SocketAddr>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                formatter,
+                "A string of multiples of {} bytes",
+                self.entry_len
+            )
+        }
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            if v.len() % self.entry_len != 0 {
+                return Err(de::Error::custom(format!(
+                    "Bytes which length is a multiple of {}. Got {:?}",
+                    self.entry_len,
+                    v.len()
+                )));
+            }
+            Ok(v.chunks_exact(self.entry_len)
+                .map(|chunk| {
+                    let (ip, port) = chunk.split_at(self.entry_len - 2);
+                    let ip = match <[u8; 4]>::try_from(ip) {
+                        Ok(ip) => IpAddr::V4(Ipv4Addr::from(ip)),
+                        Err(_) => IpAddr::V6(Ipv6Addr::from(
+                            <[u8; 16]>::try_from(ip).expect("entries are either 6 or 18 bytes"),
+                        )),
+                    };
+                    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+                })
+                .collect())
+        }
+    }
+
+    impl<'de> Visitor<'de> for PeerListVisitor {
+        type Value = PeerConnections;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("A string of multiples of 6 bytes or a list of dictionaries")
+        }
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            let addrs = PeersVisitor { entry_len: 6 }.visit_bytes(v)?;
+            Ok(PeerConnections(
+                addrs
+                    .into_iter()
+                    .map(|addr| TrackerPeer {
+                        addr: PeerAddr::Ip(addr),
+                        peer_id: None,
+                    })
+                    .collect(),
+            ))
+        }
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(peer) = seq.next_element::<DictPeer>()? {
+                peers.push(peer.into());
+            }
+            Ok(PeerConnections(peers))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PeerConnections {
+        fn deserialize<D>(deserializer: D) -> Result<PeerConnections, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(PeerListVisitor)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PeerConnections6 {
+        fn deserialize<D>(deserializer: D) -> Result<PeerConnections6, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer
+                .deserialize_bytes(PeersVisitor { entry_len: 18 })
+                .map(PeerConnections6)
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TrackerRequestError {
+    #[error("Failed to parse announce url: `{0}`")]
+    InvalidUrl(#[from] url::ParseError),
+    #[error("The tracker `{url}` sent an invalid response (`{error}`): {summary}")]
+    InvalidResponse {
+        error: serde_bencode::Error,
+        /// the readable part of the response, e.g. the text of an HTML error page
+        summary: String,
+        response: bytes::Bytes,
+        url: String,
+    },
+    #[error("Something failed with requesting the tracker-response: `{0}`")]
+    ReqwestError(#[from] reqwest::Error),
+    #[error("There's no tracker to announce to")]
+    NoTracker,
+    #[error("The tracker `{url}` refused the announce: `{reason}`")]
+    Failure { url: String, reason: String },
+    #[error("The tracker `{0}` doesn't support scraping")]
+    ScrapeUnsupported(String),
+    #[error("The scrape response of `{0}` doesn't contain the torrent")]
+    NotInScrape(String),
+    #[error(transparent)]
+    Udp(#[from] UdpTrackerError),
+    #[error(transparent)]
+    WebSocket(#[from] WebSocketTrackerError),
+    #[error("Failed to read the certificate `{path}`: `{error}`")]
+    Certificate {
+        path: std::path::PathBuf,
+        error: std::io::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn peers6_are_parsed() {
+        let mut bytes = b"d8:intervali1800e5:peers6:".to_vec();
+        bytes.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        bytes.extend_from_slice(b"6:peers618:");
+        bytes.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        bytes.extend_from_slice(&[0x1a, 0xe2]);
+        bytes.push(b'e');
+        let response: TrackerResponse = serde_bencode::from_bytes(&bytes).unwrap();
+        let addrs: Vec<PeerAddr> = response
+            .into_peers()
+            .into_iter()
+            .map(|peer| peer.addr)
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                PeerAddr::Ip("10.0.0.1:6881".parse().unwrap()),
+                PeerAddr::Ip("[::1]:6882".parse().unwrap()),
+            ]
+        );
+    }
+
+    /// the layout opentracker answers with to a dual-stack client
+    #[test]
+    fn mixed_peers_are_deduplicated() {
+        let mut bytes =
+            b"d8:completei3e10:incompletei1e8:intervali1800e12:min intervali900e5:peers18:"
+                .to_vec();
+        bytes.extend_from_slice(&[203, 0, 113, 7, 0xc8, 0xd5]);
+        bytes.extend_from_slice(&[198, 51, 100, 20, 0x1a, 0xe1]);
+        bytes.extend_from_slice(&[203, 0, 113, 7, 0xc8, 0xd5]);
+        bytes.extend_from_slice(b"6:peers654:");
+        let v6: Ipv6Addr = "2001:db8::42".parse().unwrap();
+        bytes.extend_from_slice(&v6.octets());
+        bytes.extend_from_slice(&[0x1a, 0xe1]);
+        // the first IPv4 peer again, mapped to IPv6
+        bytes.extend_from_slice(&Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets());
+        bytes.extend_from_slice(&[0xc8, 0xd5]);
+        bytes.extend_from_slice(&v6.octets());
+        bytes.extend_from_slice(&[0x1a, 0xe1]);
+        bytes.push(b'e');
+
+        let response: TrackerResponse = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(response.peers.0.len(), 3);
+        assert_eq!(response.peers6.0.len(), 3);
+        let addrs: Vec<PeerAddr> = response
+            .into_peers()
+            .into_iter()
+            .map(|peer| peer.addr)
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                PeerAddr::Ip("203.0.113.7:51413".parse().unwrap()),
+                PeerAddr::Ip("198.51.100.20:6881".parse().unwrap()),
+                PeerAddr::Ip("[2001:db8::42]:6881".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn dict_peers_are_parsed() {
+        let mut bytes = b"d8:intervali1800e5:peersld2:ip8:10.0.0.17:peer id20:".to_vec();
+        bytes.extend_from_slice(&[b'p'; 20]);
+        bytes.extend_from_slice(b"4:porti6881eed2:ip16:peer.example.org4:porti51413eeee");
+        let response: TrackerResponse = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            response.into_peers(),
+            vec![
+                TrackerPeer {
+                    addr: PeerAddr::Ip("10.0.0.1:6881".parse().unwrap()),
+                    peer_id: Some([b'p'; 20]),
+                },
+                TrackerPeer {
+                    addr: PeerAddr::Host {
+                        name: "peer.example.org".to_string(),
+                        port: 51413,
+                    },
+                    peer_id: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn failure_reason_is_parsed() {
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d14:failure reason17:torrent not founde").unwrap();
+        assert_eq!(
+            response.failure_reason.as_deref(),
+            Some("torrent not found")
+        );
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali900e5:peers0:15:warning message4:slowe")
+                .unwrap();
+        assert_eq!(response.interval, 900);
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+    }
+
+    #[test]
+    fn url_encoded_with_event() {
+        let info_hash = InfoHash([b'a'; 20]);
+        let request = TrackerRequest::new(&info_hash, &[b'b'; 20], 6881, 5_000_000_000)
+            .with_stats(1, 2)
+            .with_event(Event::Started);
+        assert_eq!(
+            request.to_url_encoded(),
+            format!(
+                "info_hash={}&peer_id={}&port=6881&uploaded=1&downloaded=2&left=5000000000&compact=1&event=started",
+                "a".repeat(20),
+                "b".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn url_encoded_with_settings() {
+        let info_hash = InfoHash([b'a'; 20]);
+        let settings = AnnounceSettings {
+            numwant: Some(200),
+            compact: false,
+            no_peer_id: true,
+            announce_to_all: false,
+        };
+        let request =
+            TrackerRequest::new(&info_hash, &[b'b'; 20], 6881, 0).with_settings(&settings);
+        assert!(
+            request
+                .to_url_encoded()
+                .ends_with("&left=0&compact=0&no_peer_id=1&numwant=200")
+        );
+    }
+
+    #[test]
+    fn url_encoded_with_key_and_tracker_id() {
+        let info_hash = InfoHash([b'a'; 20]);
+        let request = TrackerRequest::new(&info_hash, &[b'b'; 20], 6881, 0)
+            .with_key(0xbeef)
+            .with_ip(Some("203.0.113.7".parse().unwrap()))
+            .with_ipv6(Some("2001:db8::1".parse().unwrap()))
+            .with_tracker_id(Some("id 1&2".to_string()));
+        assert!(request.to_url_encoded().ends_with(
+            "&compact=1&key=0000beef&ip=203.0.113.7&ipv6=2001%3Adb8%3A%3A1&trackerid=id+1%262"
+        ));
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali900e10:tracker id3:abc5:peers0:e").unwrap();
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+    }
+}
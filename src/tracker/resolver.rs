@@ -0,0 +1,144 @@
+//! Peers may be given as DNS names instead of IPs, e.g. in magnet links or non-compact tracker responses.
+//! They are resolved in the announcer's task so the PeerManager never waits for a lookup.
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::sync::Mutex;
+
+/// how long a lookup is cached
+const DNS_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// A name that resolves to more addresses is most likely not a single peer,
+/// so we only take the first few.
+const MAX_ADDRS_PER_NAME: usize = 4;
+
+/// the address of a peer before it's resolved
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Host { name: String, port: u16 },
+}
+
+impl FromStr for PeerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = SocketAddr::from_str(s) {
+            return Ok(PeerAddr::Ip(addr));
+        }
+        let (name, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("`{s}` has no port"))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("`{port}` is not a valid port"))?;
+        if name.is_empty() {
+            return Err(format!("`{s}` has no host"));
+        }
+        Ok(PeerAddr::Host {
+            name: name.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Ip(addr) => write!(f, "{addr}"),
+            PeerAddr::Host { name, port } => write!(f, "{name}:{port}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedLookup {
+    at: Instant,
+    addrs: Vec<SocketAddr>,
+}
+
+/// This is cheap to clone, all clones share the same cache.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerResolver(Arc<Mutex<HashMap<(String, u16), CachedLookup>>>);
+
+impl PeerResolver {
+    /// returns every address of the peer that we can dial
+    /// a failed lookup just returns no addresses
+    pub(crate) async fn resolve(&self, addr: PeerAddr) -> Vec<SocketAddr> {
+        let (name, port) = match addr {
+            PeerAddr::Ip(addr) => return vec![addr],
+            PeerAddr::Host { name, port } => (name, port),
+        };
+        let key = (name, port);
+        if let Some(cached) = self.0.lock().unwrap().get(&key)
+            && cached.at.elapsed() < DNS_CACHE_TTL
+        {
+            return cached.addrs.clone();
+        }
+
+        let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((key.0.as_str(), port)).await {
+            Ok(addrs) => addrs.take(MAX_ADDRS_PER_NAME).collect(),
+            Err(e) => {
+                eprintln!("Failed to resolve the peer `{}`: {e}", key.0);
+                Vec::new()
+            }
+        };
+        self.0.lock().unwrap().insert(
+            key,
+            CachedLookup {
+                at: Instant::now(),
+                addrs: addrs.clone(),
+            },
+        );
+        addrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+
+    #[test]
+    fn parse_peer_addr() {
+        assert_eq!(
+            PeerAddr::from_str("10.0.0.1:6881"),
+            Ok(PeerAddr::Ip(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(10, 0, 0, 1),
+                6881
+            ))))
+        );
+        assert_eq!(
+            PeerAddr::from_str("[2001:db8::1]:6881"),
+            Ok(PeerAddr::Ip("[2001:db8::1]:6881".parse().unwrap()))
+        );
+        assert_eq!(
+            PeerAddr::from_str("peer.example.org:51413"),
+            Ok(PeerAddr::Host {
+                name: "peer.example.org".to_string(),
+                port: 51413
+            })
+        );
+        assert!(PeerAddr::from_str("peer.example.org").is_err());
+        assert!(PeerAddr::from_str(":6881").is_err());
+    }
+
+    #[tokio::test]
+    async fn lookups_are_cached() {
+        let resolver = PeerResolver::default();
+        let addrs = resolver
+            .resolve(PeerAddr::Host {
+                name: "localhost".to_string(),
+                port: 6881,
+            })
+            .await;
+        assert!(addrs.len() <= MAX_ADDRS_PER_NAME);
+        assert_eq!(resolver.0.lock().unwrap().len(), 1);
+    }
+}
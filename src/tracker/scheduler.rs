@@ -0,0 +1,165 @@
+//! Spaces out the announces to the same tracker host.
+//! Users with many torrents on the same tracker would otherwise send their announces in bursts
+//! which some trackers punish with rate-limit bans.
+//! It also applies the [`TrackerQuirks`] of the host to every HTTP request.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{
+    config::{TrackerCompat, TrackerQuirks, TrackerTls},
+    sync::Mutex,
+    tracker::TrackerRequestError,
+};
+
+/// sent if the config doesn't name another one, some trackers flag clients that pose as a browser
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// This is cheap to clone, all clones share the same schedule and HTTP client.
+#[derive(Debug, Clone)]
+pub struct AnnounceScheduler(Arc<SchedulerInner>);
+
+#[derive(Debug)]
+struct SchedulerInner {
+    /// reused for every announce so connections to the same host are kept alive
+    client: reqwest::Client,
+    /// the minimum time between two requests to the same host
+    min_gap: Duration,
+    /// maps the host to the earliest time the next request may be sent
+    next_slot: Mutex<HashMap<String, Instant>>,
+    compat: TrackerCompat,
+}
+
+impl AnnounceScheduler {
+    pub fn new(
+        min_gap: Duration,
+        tls: &TrackerTls,
+        compat: &TrackerCompat,
+    ) -> Result<Self, TrackerRequestError> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(compat.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT));
+        for path in tls.root_certificates.iter() {
+            for certificate in read_certificates(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if tls.accept_invalid_certs {
+            eprintln!("Warning: the certificates of HTTPS trackers aren't verified.");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let client = builder.build()?;
+        Ok(Self(Arc::new(SchedulerInner {
+            client,
+            min_gap,
+            next_slot: Mutex::new(HashMap::new()),
+            compat: compat.clone(),
+        })))
+    }
+
+    pub(super) fn quirks(&self, host: &str) -> Option<&TrackerQuirks> {
+        self.0.compat.quirks_for(host)
+    }
+
+    /// sends a GET request to the url as soon as the host's schedule allows it
+    pub(super) async fn get(&self, url: url::Url) -> Result<reqwest::Response, reqwest::Error> {
+        let host = url.host_str().unwrap_or_default().to_string();
+        self.wait_for_slot(&host).await;
+        let mut request = self.0.client.get(url);
+        if let Some(quirks) = self.quirks(&host) {
+            if let Some(user_agent) = &quirks.user_agent {
+                request = request.header(reqwest::header::USER_AGENT, user_agent);
+            }
+            if quirks.no_keepalive {
+                request = request.header(reqwest::header::CONNECTION, "close");
+            }
+            for (name, value) in quirks.headers.iter() {
+                request = request.header(name, value);
+            }
+        }
+        request.send().await
+    }
+
+    /// waits until the host's schedule allows the next request
+    pub(super) async fn wait_for_slot(&self, host: &str) {
+        let slot = self.reserve_slot(host, Instant::now());
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// returns the time at which we may send the request and books the slot after it
+    fn reserve_slot(&self, host: &str, now: Instant) -> Instant {
+        let mut next_slot = self.0.next_slot.lock().unwrap();
+        let slot = next_slot
+            .get(host)
+            .copied()
+            .filter(|slot| *slot > now)
+            .unwrap_or(now);
+        next_slot.insert(host.to_string(), slot + self.0.min_gap);
+        slot
+    }
+}
+
+/// a PEM file may contain a whole chain, a DER file only a single certificate
+fn read_certificates(
+    path: &std::path::Path,
+) -> Result<Vec<reqwest::Certificate>, TrackerRequestError> {
+    let bytes = std::fs::read(path).map_err(|error| TrackerRequestError::Certificate {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    if bytes.starts_with(b"-----BEGIN") {
+        Ok(reqwest::Certificate::from_pem_bundle(&bytes)?)
+    } else {
+        Ok(vec![reqwest::Certificate::from_der(&bytes)?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_host_is_spaced() {
+        let scheduler = AnnounceScheduler::new(
+            Duration::from_secs(2),
+            &TrackerTls::default(),
+            &TrackerCompat::default(),
+        )
+        .unwrap();
+        let now = Instant::now();
+        assert_eq!(scheduler.reserve_slot("tracker.example", now), now);
+        assert_eq!(
+            scheduler.reserve_slot("tracker.example", now),
+            now + Duration::from_secs(2)
+        );
+        assert_eq!(
+            scheduler.reserve_slot("tracker.example", now),
+            now + Duration::from_secs(4)
+        );
+        // other hosts are independent
+        assert_eq!(scheduler.reserve_slot("other.example", now), now);
+        // once the gap has passed, the request may be sent immediately
+        let later = now + Duration::from_secs(10);
+        assert_eq!(scheduler.reserve_slot("tracker.example", later), later);
+    }
+
+    #[test]
+    fn tls_settings() {
+        let insecure = TrackerTls {
+            accept_invalid_certs: true,
+            ..Default::default()
+        };
+        let compat = TrackerCompat::default();
+        assert!(AnnounceScheduler::new(Duration::ZERO, &insecure, &compat).is_ok());
+
+        let dir = tempfile::tempdir().unwrap();
+        let garbage = dir.path().join("garbage.pem");
+        std::fs::write(&garbage, "-----BEGIN CERTIFICATE-----\nnot base64\n").unwrap();
+        for path in [garbage, dir.path().join("missing.pem")] {
+            let tls = TrackerTls {
+                root_certificates: vec![path],
+                ..Default::default()
+            };
+            assert!(AnnounceScheduler::new(Duration::ZERO, &tls, &compat).is_err());
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Asks a tracker about the health of a swarm without joining it.
+//! Only HTTP trackers are supported, the scrape url is derived from the announce url
+//! by the convention most trackers follow.
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    torrent::InfoHash,
+    tracker::{AnnounceScheduler, TrackerRequestError, TrackerTiers, body, escape_bytes_url},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ScrapeStats {
+    /// the number of seeders
+    pub complete: u64,
+    /// the number of leechers
+    pub incomplete: u64,
+    /// how often the torrent was downloaded completely
+    pub downloaded: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    /// maps the raw info hash to its stats
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// `.../announce?x=y` becomes `.../scrape?x=y`
+/// returns None if the tracker doesn't follow the convention
+fn scrape_url(announce: &url::Url) -> Option<url::Url> {
+    if !matches!(announce.scheme(), "http" | "https") {
+        return None;
+    }
+    let (dir, last) = announce.path().rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    let mut url = announce.clone();
+    url.set_path(&format!("{dir}/scrape{rest}"));
+    Some(url)
+}
+
+/// asks the trackers one after another until one knows the torrent
+pub async fn scrape(
+    info_hash: &InfoHash,
+    tiers: &TrackerTiers,
+    scheduler: &AnnounceScheduler,
+) -> Result<ScrapeStats, TrackerRequestError> {
+    let mut last_err = TrackerRequestError::NoTracker;
+    for (_, announce) in tiers.iter() {
+        let Some(url) = scrape_url(announce) else {
+            last_err = TrackerRequestError::ScrapeUnsupported(announce.to_string());
+            continue;
+        };
+        match scrape_tracker(info_hash, url, scheduler).await {
+            Ok(stats) => return Ok(stats),
+            Err(e) => {
+                eprintln!("Failed to scrape `{announce}`: {e}");
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+async fn scrape_tracker(
+    info_hash: &InfoHash,
+    mut url: url::Url,
+    scheduler: &AnnounceScheduler,
+) -> Result<ScrapeStats, TrackerRequestError> {
+    // the existing query, e.g. a passkey, has to stay
+    let query = match url.query() {
+        Some(query) => format!("{query}&info_hash={}", escape_bytes_url(&info_hash.0)),
+        None => format!("info_hash={}", escape_bytes_url(&info_hash.0)),
+    };
+    url.set_query(Some(&query));
+    let response = scheduler.get(url.clone()).await?;
+    let response_bytes = Bytes::copy_from_slice(&response.bytes().await?);
+    parse_response(info_hash, &response_bytes).map_err(|error| match error {
+        Some(error) => body::invalid_response(error, response_bytes, &url),
+        None => TrackerRequestError::NotInScrape(url.to_string()),
+    })
+}
+
+/// returns None as the error if the tracker doesn't know the torrent
+fn parse_response(
+    info_hash: &InfoHash,
+    bytes: &[u8],
+) -> Result<ScrapeStats, Option<serde_bencode::Error>> {
+    let response = body::parse::<ScrapeResponse>(bytes).map_err(Some)?;
+    response
+        .files
+        .get(&ByteBuf::from(info_hash.0.to_vec()))
+        .copied()
+        .ok_or(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_scrape_url() {
+        let url = |s: &str| url::Url::parse(s).unwrap();
+        assert_eq!(
+            scrape_url(&url("http://tracker.example/announce")),
+            Some(url("http://tracker.example/scrape"))
+        );
+        assert_eq!(
+            scrape_url(&url("https://tracker.example/x/announce.php?passkey=abc")),
+            Some(url("https://tracker.example/x/scrape.php?passkey=abc"))
+        );
+        assert_eq!(scrape_url(&url("http://tracker.example/a")), None);
+        assert_eq!(
+            scrape_url(&url("udp://tracker.example:1337/announce")),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_files() {
+        let info_hash = InfoHash([b'x'; 20]);
+        let mut bytes = b"d5:filesd20:".to_vec();
+        bytes.extend_from_slice(&info_hash.0);
+        bytes.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        assert_eq!(
+            parse_response(&info_hash, &bytes).unwrap(),
+            ScrapeStats {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50,
+            }
+        );
+        assert!(matches!(
+            parse_response(&InfoHash([b'y'; 20]), &bytes),
+            Err(None)
+        ));
+    }
+}
@@ -0,0 +1,138 @@
+//! What we know about each tracker of a torrent.
+//! A tracker that failed is skipped until its backoff is over, so a dead tracker
+//! doesn't delay every announce until it times out.
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// the wait after the first failure, it doubles with every further one
+const BACKOFF_BASE: Duration = Duration::from_secs(15);
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+/// for the trackers that don't send an interval
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// a shorter interval of a tracker is raised to this
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerStatus {
+    pub url: url::Url,
+    /// failed announces in a row
+    pub failures: u32,
+    /// the `failure reason` of the tracker or why we couldn't reach it
+    pub last_error: Option<String>,
+    /// the `warning message` of the last response
+    pub warning: Option<String>,
+    pub last_success: Option<Instant>,
+    /// we don't announce to the tracker before this
+    pub retry_at: Option<Instant>,
+    /// the number of peers in the last response
+    pub peers: usize,
+    /// the `tracker id` we echo in every announce to this tracker
+    pub tracker_id: Option<String>,
+    /// when the interval of the tracker is over, see [`AnnounceSettings::announce_to_all`]
+    ///
+    /// [`AnnounceSettings::announce_to_all`]: crate::config::AnnounceSettings::announce_to_all
+    pub next_announce: Option<Instant>,
+}
+
+impl TrackerStatus {
+    pub(super) fn new(url: url::Url) -> Self {
+        Self {
+            url,
+            failures: 0,
+            last_error: None,
+            warning: None,
+            last_success: None,
+            retry_at: None,
+            peers: 0,
+            tracker_id: None,
+            next_announce: None,
+        }
+    }
+
+    /// when we should announce to the tracker again, after a failure that's the end of the backoff
+    pub(super) fn due_at(&self) -> Option<Instant> {
+        self.retry_at.or(self.next_announce)
+    }
+
+    pub(super) fn is_backing_off(&self, now: Instant) -> bool {
+        self.retry_at.is_some_and(|retry_at| retry_at > now)
+    }
+
+    pub(super) fn succeeded(
+        &mut self,
+        now: Instant,
+        warning: Option<String>,
+        peers: usize,
+        tracker_id: Option<String>,
+        interval: usize,
+    ) {
+        // trackers only send the id when it changes
+        if tracker_id.is_some() {
+            self.tracker_id = tracker_id;
+        }
+        self.failures = 0;
+        self.last_error = None;
+        self.warning = warning;
+        self.last_success = Some(now);
+        self.retry_at = None;
+        self.peers = peers;
+        let interval = match interval {
+            0 => DEFAULT_INTERVAL,
+            secs => Duration::from_secs(secs as u64).max(MIN_INTERVAL),
+        };
+        self.next_announce = Some(now + interval);
+    }
+
+    pub(super) fn failed(&mut self, now: Instant, error: String) {
+        self.failures += 1;
+        self.last_error = Some(error);
+        self.retry_at = Some(now + backoff(self.failures));
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(15));
+        assert_eq!(backoff(2), Duration::from_secs(30));
+        assert_eq!(backoff(4), Duration::from_secs(120));
+        assert_eq!(backoff(100), BACKOFF_MAX);
+
+        let now = Instant::now();
+        let mut status = TrackerStatus::new(url::Url::parse("http://t.example/announce").unwrap());
+        status.failed(now, "timeout".to_string());
+        assert!(status.is_backing_off(now + Duration::from_secs(14)));
+        assert!(!status.is_backing_off(now + Duration::from_secs(15)));
+        status.succeeded(now, None, 3, Some("abc".to_string()), 1800);
+        assert_eq!(status.failures, 0);
+        status.succeeded(now, None, 3, None, 1800);
+        assert_eq!(status.tracker_id.as_deref(), Some("abc"));
+        assert!(!status.is_backing_off(now));
+    }
+
+    #[test]
+    fn own_interval() {
+        let now = Instant::now();
+        let mut status = TrackerStatus::new(url::Url::parse("http://t.example/announce").unwrap());
+        assert_eq!(status.due_at(), None);
+        status.succeeded(now, None, 0, None, 900);
+        assert_eq!(status.due_at(), Some(now + Duration::from_secs(900)));
+        status.succeeded(now, None, 0, None, 5);
+        assert_eq!(status.due_at(), Some(now + MIN_INTERVAL));
+        status.succeeded(now, None, 0, None, 0);
+        assert_eq!(status.due_at(), Some(now + DEFAULT_INTERVAL));
+        // after a failure it's due once the backoff is over
+        status.failed(now, "timeout".to_string());
+        assert_eq!(status.due_at(), Some(now + BACKOFF_BASE));
+    }
+}
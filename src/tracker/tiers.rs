@@ -0,0 +1,187 @@
+//! The trackers of a torrent grouped into tiers (BEP 12).
+//! We try the tiers in order and the trackers of a tier one after another.
+//! A tracker that answers is moved to the front of its tier so it's tried first next time.
+use rand::seq::SliceRandom;
+
+use crate::Torrent;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerTiers(Vec<Vec<url::Url>>);
+
+impl TrackerTiers {
+    /// uses the `announce-list` if there is one, otherwise the `announce` url
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self::from_tiers(torrent_trackers(torrent))
+    }
+
+    /// e.g. the trackers the DB kept, see [`merge_trackers`]
+    pub fn from_tiers(tiers: Vec<Vec<url::Url>>) -> Self {
+        let tiers: Vec<_> = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        if tiers.is_empty() {
+            Self::single_tier(Vec::new())
+        } else {
+            Self::shuffled(tiers)
+        }
+    }
+
+    /// e.g. for the trackers of a magnet link which don't have tiers
+    pub fn single_tier(urls: Vec<url::Url>) -> Self {
+        Self::shuffled(vec![urls])
+    }
+
+    /// the BEP wants the trackers in a tier to be tried in random order
+    fn shuffled(mut tiers: Vec<Vec<url::Url>>) -> Self {
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rand::rng());
+        }
+        Self(tiers)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(Vec::is_empty)
+    }
+
+    /// every tracker once, in the order they're tried
+    pub(crate) fn urls(&self) -> Vec<url::Url> {
+        self.iter().map(|(_, url)| url.clone()).collect()
+    }
+
+    /// all trackers in the order they should be tried, with their position
+    pub(super) fn iter(&self) -> impl Iterator<Item = ((usize, usize), &url::Url)> {
+        self.0.iter().enumerate().flat_map(|(tier_i, tier)| {
+            tier.iter()
+                .enumerate()
+                .map(move |(i, url)| ((tier_i, i), url))
+        })
+    }
+
+    /// moves the tracker that answered to the front of its tier
+    pub(super) fn promote(&mut self, (tier_i, i): (usize, usize)) {
+        let url = self.0[tier_i].remove(i);
+        self.0[tier_i].insert(0, url);
+    }
+}
+
+/// The trackers of the torrent in the order of the file, the `announce-list` if there is one,
+/// otherwise the `announce` url.
+pub(crate) fn torrent_trackers(torrent: &Torrent) -> Vec<Vec<url::Url>> {
+    let tiers: Vec<Vec<url::Url>> = torrent
+        .announce_list
+        .iter()
+        .flatten()
+        .map(|tier| {
+            tier.iter()
+                .filter_map(|url| url::Url::parse(url).ok())
+                .collect::<Vec<_>>()
+        })
+        .filter(|tier| !tier.is_empty())
+        .collect();
+    if tiers.is_empty() {
+        torrent
+            .announce
+            .iter()
+            .map(|url| vec![url.clone()])
+            .collect()
+    } else {
+        tiers
+    }
+}
+
+/// Adds the trackers of `new` that aren't in any tier of `trackers` yet, each to the tier it has
+/// in `new` or to a new last tier. Returns whether one was added.
+pub(crate) fn merge_trackers(trackers: &mut Vec<Vec<url::Url>>, new: Vec<Vec<url::Url>>) -> bool {
+    let mut added = false;
+    for (tier_i, tier) in new.into_iter().enumerate() {
+        for url in tier {
+            if trackers.iter().flatten().any(|known| *known == url) {
+                continue;
+            }
+            let tier_i = tier_i.min(trackers.len());
+            if tier_i == trackers.len() {
+                trackers.push(Vec::new());
+            }
+            trackers[tier_i].push(url);
+            added = true;
+        }
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> url::Url {
+        url::Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn tiers_keep_their_order() {
+        let tiers = TrackerTiers::shuffled(vec![
+            vec![
+                url("http://a.example/announce"),
+                url("http://b.example/announce"),
+            ],
+            vec![url("http://c.example/announce")],
+        ]);
+        let order: Vec<_> = tiers.iter().map(|(pos, _)| pos).collect();
+        assert_eq!(order, vec![(0, 0), (0, 1), (1, 0)]);
+        // shuffling happens only inside of a tier
+        assert_eq!(tiers.0[1], vec![url("http://c.example/announce")]);
+    }
+
+    #[test]
+    fn working_tracker_is_promoted() {
+        let mut tiers = TrackerTiers(vec![
+            vec![
+                url("http://a.example/announce"),
+                url("http://b.example/announce"),
+                url("http://c.example/announce"),
+            ],
+            vec![url("http://d.example/announce")],
+        ]);
+        tiers.promote((0, 2));
+        assert_eq!(
+            tiers.0[0],
+            vec![
+                url("http://c.example/announce"),
+                url("http://a.example/announce"),
+                url("http://b.example/announce"),
+            ]
+        );
+        tiers.promote((1, 0));
+        assert_eq!(tiers.0[1], vec![url("http://d.example/announce")]);
+    }
+
+    #[test]
+    fn merged_trackers_keep_their_tier() {
+        let mut trackers = vec![vec![url("http://a.example/announce")]];
+        // the same magnet link again
+        assert!(!merge_trackers(
+            &mut trackers,
+            vec![vec![url("http://a.example/announce")]]
+        ));
+        assert!(merge_trackers(
+            &mut trackers,
+            vec![
+                vec![
+                    url("http://b.example/announce"),
+                    url("http://a.example/announce"),
+                ],
+                vec![],
+                vec![url("udp://c.example:6969")],
+            ]
+        ));
+        assert_eq!(
+            trackers,
+            vec![
+                vec![
+                    url("http://a.example/announce"),
+                    url("http://b.example/announce"),
+                ],
+                vec![url("udp://c.example:6969")],
+            ]
+        );
+        assert!(TrackerTiers::from_tiers(vec![vec![]]).is_empty());
+    }
+}
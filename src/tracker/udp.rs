@@ -0,0 +1,290 @@
+//! Announces to `udp://` trackers (BEP 15).
+//! A connect exchange gets us a connection id which the announce has to carry.
+//! UDP loses packets, so a request is sent again if the tracker doesn't answer in time.
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+use crate::tracker::{
+    AnnounceScheduler, Event, PeerAddr, TrackerPeer, TrackerRequest, TrackerResponse,
+    peers::{PeerConnections, PeerConnections6},
+};
+
+/// the magic constant of the connect request
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+/// the timeout of the first try, it doubles with every retransmission
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+/// The BEP retransmits up to 8 times which takes over an hour.
+/// By then the next tracker of the tiers is the better bet.
+const MAX_RETRANSMISSIONS: u32 = 2;
+/// the largest payload a UDP packet can carry
+const MAX_PACKET_LEN: usize = 65_507;
+
+#[derive(Debug, Error)]
+pub enum UdpTrackerError {
+    #[error("The UDP tracker url `{0}` has no host or port")]
+    NoAddress(String),
+    #[error("Failed to talk to the UDP tracker: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("The UDP tracker didn't answer")]
+    Timeout,
+    #[error("The UDP tracker sent a response of {0} bytes which is too short")]
+    Truncated(usize),
+    #[error("The UDP tracker answered with the unknown action {0}")]
+    UnexpectedAction(u32),
+}
+
+/// connects, announces and returns the response like an HTTP tracker would have sent it
+pub(super) async fn announce(
+    request: &TrackerRequest<'_>,
+    url: &url::Url,
+    scheduler: &AnnounceScheduler,
+) -> Result<TrackerResponse, UdpTrackerError> {
+    let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+        return Err(UdpTrackerError::NoAddress(url.to_string()));
+    };
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| UdpTrackerError::NoAddress(url.to_string()))?;
+    scheduler.wait_for_slot(host).await;
+
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    let transaction_id = rand::random();
+    let response = match exchange(&socket, &connect_packet(transaction_id), transaction_id).await? {
+        Ok(response) => response,
+        Err(reason) => return Ok(refusal(reason)),
+    };
+    let connection_id = u64::from_be_bytes(read(&response, 8)?);
+
+    let transaction_id = rand::random();
+    let packet = announce_packet(request, connection_id, transaction_id);
+    match exchange(&socket, &packet, transaction_id).await? {
+        Ok(response) => parse_announce(&response, addr.is_ipv6()),
+        Err(reason) => Ok(refusal(reason)),
+    }
+}
+
+/// Sends the packet until the tracker answers it.
+/// Returns the response or the error message of the tracker.
+async fn exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    transaction_id: u32,
+) -> Result<Result<Vec<u8>, String>, UdpTrackerError> {
+    let mut buf = vec![0; MAX_PACKET_LEN];
+    for n in 0..=MAX_RETRANSMISSIONS {
+        socket.send(packet).await?;
+        let answer = async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                // late answers to an earlier try don't count
+                if len >= 8 && buf[4..8] == transaction_id.to_be_bytes() {
+                    return Ok::<_, std::io::Error>(len);
+                }
+            }
+        };
+        let Ok(len) = tokio::time::timeout(BASE_TIMEOUT * 2u32.pow(n), answer).await else {
+            continue;
+        };
+        let response = buf[..len?].to_vec();
+        return match u32::from_be_bytes(read(&response, 0)?) {
+            ACTION_ERROR => Ok(Err(String::from_utf8_lossy(&response[8..]).into_owned())),
+            ACTION_CONNECT | ACTION_ANNOUNCE => Ok(Ok(response)),
+            action => Err(UdpTrackerError::UnexpectedAction(action)),
+        };
+    }
+    Err(UdpTrackerError::Timeout)
+}
+
+fn connect_packet(transaction_id: u32) -> [u8; 16] {
+    let mut packet = [0; 16];
+    packet[0..8].copy_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet[8..12].copy_from_slice(&ACTION_CONNECT.to_be_bytes());
+    packet[12..16].copy_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+fn announce_packet(
+    request: &TrackerRequest<'_>,
+    connection_id: u64,
+    transaction_id: u32,
+) -> Vec<u8> {
+    let event: u32 = match request.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    };
+    // only IPv4 fits, for IPv6 the tracker takes the address of the packet
+    let ip = match request.ip {
+        Some(IpAddr::V4(ip)) => ip.octets(),
+        _ => [0; 4],
+    };
+    let numwant = request
+        .numwant
+        .map_or(-1, |numwant| numwant.min(i32::MAX as u32) as i32);
+
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet.extend_from_slice(&request.info_hash.0);
+    packet.extend_from_slice(request.peer_id);
+    packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    packet.extend_from_slice(&event.to_be_bytes());
+    packet.extend_from_slice(&ip);
+    packet.extend_from_slice(&request.key.unwrap_or(0).to_be_bytes());
+    packet.extend_from_slice(&numwant.to_be_bytes());
+    packet.extend_from_slice(&request.port.to_be_bytes());
+    packet
+}
+
+/// the peers are 18 bytes long if we talk to the tracker over IPv6, 6 bytes otherwise
+fn parse_announce(response: &[u8], ipv6: bool) -> Result<TrackerResponse, UdpTrackerError> {
+    let interval = u32::from_be_bytes(read(response, 8)?);
+    // the leechers and seeders are the next 8 bytes, we don't use them
+    let peers = response
+        .get(20..)
+        .ok_or(UdpTrackerError::Truncated(response.len()))?;
+    let entry_len = if ipv6 { 18 } else { 6 };
+    let addrs = peers.chunks_exact(entry_len).map(|chunk| {
+        let (ip, port) = chunk.split_at(entry_len - 2);
+        let ip = match <[u8; 4]>::try_from(ip) {
+            Ok(ip) => IpAddr::from(ip),
+            Err(_) => IpAddr::from(<[u8; 16]>::try_from(ip).expect("entries are 6 or 18 bytes")),
+        };
+        SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+    });
+    let (peers, peers6): (Vec<_>, Vec<_>) = addrs.partition(SocketAddr::is_ipv4);
+    Ok(TrackerResponse {
+        interval: interval as usize,
+        failure_reason: None,
+        warning_message: None,
+        tracker_id: None,
+        peers: PeerConnections(
+            peers
+                .into_iter()
+                .map(|addr| TrackerPeer {
+                    addr: PeerAddr::Ip(addr),
+                    peer_id: None,
+                })
+                .collect(),
+        ),
+        peers6: PeerConnections6(peers6),
+    })
+}
+
+/// an error packet of the tracker means the same as `failure reason`
+fn refusal(reason: String) -> TrackerResponse {
+    TrackerResponse {
+        interval: 0,
+        failure_reason: Some(reason),
+        warning_message: None,
+        tracker_id: None,
+        peers: PeerConnections::default(),
+        peers6: PeerConnections6::default(),
+    }
+}
+
+fn read<const N: usize>(response: &[u8], at: usize) -> Result<[u8; N], UdpTrackerError> {
+    response
+        .get(at..at + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(UdpTrackerError::Truncated(response.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{TrackerCompat, TrackerTls},
+        torrent::InfoHash,
+    };
+
+    use super::*;
+
+    #[test]
+    fn announce_layout() {
+        let info_hash = InfoHash([b'a'; 20]);
+        let request = TrackerRequest::new(&info_hash, &[b'b'; 20], 6881, 1000)
+            .with_stats(3, 2)
+            .with_event(Event::Completed)
+            .with_key(0xbeef)
+            .with_ip(Some(IpAddr::from([203, 0, 113, 7])));
+        let packet = announce_packet(&request, 0x1122334455667788, 42);
+        assert_eq!(packet.len(), 98);
+        assert_eq!(packet[0..8], 0x1122334455667788u64.to_be_bytes());
+        assert_eq!(packet[8..16], [0, 0, 0, 1, 0, 0, 0, 42]);
+        assert_eq!(packet[56..64], 2u64.to_be_bytes());
+        assert_eq!(packet[64..72], 1000u64.to_be_bytes());
+        assert_eq!(packet[72..80], 3u64.to_be_bytes());
+        assert_eq!(packet[80..84], 1u32.to_be_bytes());
+        assert_eq!(packet[84..88], [203, 0, 113, 7]);
+        assert_eq!(packet[88..92], 0xbeefu32.to_be_bytes());
+        assert_eq!(packet[92..96], (-1i32).to_be_bytes());
+        assert_eq!(packet[96..98], 6881u16.to_be_bytes());
+    }
+
+    /// answers a connect and an announce like a tracker with a single peer
+    async fn fake_tracker(socket: UdpSocket) {
+        let mut buf = [0; 1024];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 16);
+        let mut response = vec![0, 0, 0, 0];
+        response.extend_from_slice(&buf[12..16]);
+        response.extend_from_slice(&7u64.to_be_bytes());
+        socket.send_to(&response, from).await.unwrap();
+
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 98);
+        assert_eq!(buf[0..8], 7u64.to_be_bytes());
+        let mut response = vec![0, 0, 0, 1];
+        response.extend_from_slice(&buf[12..16]);
+        response.extend_from_slice(&1800u32.to_be_bytes());
+        response.extend_from_slice(&[0; 8]);
+        response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        socket.send_to(&response, from).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn announce_to_local_tracker() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        let tracker = tokio::spawn(fake_tracker(socket));
+
+        let info_hash = InfoHash([b'a'; 20]);
+        let request = TrackerRequest::new(&info_hash, &[b'b'; 20], 6881, 1000);
+        let scheduler = AnnounceScheduler::new(
+            Duration::ZERO,
+            &TrackerTls::default(),
+            &TrackerCompat::default(),
+        )
+        .unwrap();
+        let response = request.get_response([url], &scheduler).await.unwrap();
+        tracker.await.unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(
+            response.into_peers(),
+            vec![TrackerPeer {
+                addr: PeerAddr::Ip("10.0.0.1:6881".parse().unwrap()),
+                peer_id: None,
+            }]
+        );
+    }
+}
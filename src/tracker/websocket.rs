@@ -0,0 +1,218 @@
+//! The WebSocket trackers of WebTorrent (`ws://` and `wss://`).
+//! Their peers connect to each other over WebRTC, which we don't speak, so the announce gets us
+//! no peers but the interval. The hybrid clients that are in both swarms are found through the
+//! other trackers, the DHT and PEX.
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::tracker::{
+    AnnounceScheduler, Event, TrackerRequest, TrackerResponse,
+    peers::{PeerConnections, PeerConnections6},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Error)]
+pub enum WebSocketTrackerError {
+    #[error("Failed to talk to the WebSocket tracker: `{0}`")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    #[error("The WebSocket tracker didn't answer")]
+    Timeout,
+    #[error("The WebSocket tracker closed the connection without answering")]
+    Closed,
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for WebSocketTrackerError {
+    fn from(value: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(value))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AnnounceMsg {
+    action: &'static str,
+    /// the bytes as the chars of the same value, like JavaScript's binary strings
+    info_hash: String,
+    peer_id: String,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<Event>,
+    /// the number of WebRTC offers we send along
+    numwant: u32,
+    offers: Vec<()>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnswerMsg {
+    action: Option<String>,
+    info_hash: Option<String>,
+    #[serde(default)]
+    interval: usize,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    /// an offer of another peer the tracker relays to us
+    offer: Option<serde_json::Value>,
+}
+
+pub(super) async fn announce(
+    request: &TrackerRequest<'_>,
+    url: &url::Url,
+    scheduler: &AnnounceScheduler,
+) -> Result<TrackerResponse, WebSocketTrackerError> {
+    scheduler
+        .wait_for_slot(url.host_str().unwrap_or_default())
+        .await;
+    tokio::time::timeout(TIMEOUT, exchange(request, url))
+        .await
+        .map_err(|_| WebSocketTrackerError::Timeout)?
+}
+
+async fn exchange(
+    request: &TrackerRequest<'_>,
+    url: &url::Url,
+) -> Result<TrackerResponse, WebSocketTrackerError> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str()).await?;
+    socket
+        .send(Message::Text(announce_message(request)))
+        .await?;
+    let info_hash = binary_string(&request.info_hash.0);
+    while let Some(msg) = socket.next().await {
+        let Message::Text(text) = msg? else {
+            continue;
+        };
+        if let Some(response) = parse_answer(&text, &info_hash) {
+            let _ = socket.close(None).await;
+            return Ok(response);
+        }
+    }
+    Err(WebSocketTrackerError::Closed)
+}
+
+fn announce_message(request: &TrackerRequest<'_>) -> String {
+    let msg = AnnounceMsg {
+        action: "announce",
+        info_hash: binary_string(&request.info_hash.0),
+        peer_id: binary_string(request.peer_id),
+        uploaded: request.uploaded,
+        downloaded: request.downloaded,
+        left: request.left,
+        event: request.event,
+        numwant: 0,
+        offers: Vec::new(),
+    };
+    serde_json::to_string(&msg).expect("the message only holds strings and numbers")
+}
+
+/// The answer to our announce like an HTTP tracker would have sent it,
+/// None for the messages that aren't meant for it.
+fn parse_answer(text: &str, info_hash: &str) -> Option<TrackerResponse> {
+    let answer: AnswerMsg = serde_json::from_str(text).ok()?;
+    let is_ours = answer.action.as_deref() == Some("announce")
+        && answer
+            .info_hash
+            .as_deref()
+            .is_none_or(|hash| hash == info_hash)
+        && answer.offer.is_none();
+    if !is_ours && answer.failure_reason.is_none() {
+        return None;
+    }
+    Some(TrackerResponse {
+        interval: answer.interval,
+        failure_reason: answer.failure_reason,
+        warning_message: answer.warning_message,
+        tracker_id: None,
+        peers: PeerConnections::default(),
+        peers6: PeerConnections6::default(),
+    })
+}
+
+fn binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::{
+        config::{TrackerCompat, TrackerTls},
+        torrent::InfoHash,
+    };
+
+    use super::*;
+
+    #[test]
+    fn announce_layout() {
+        let info_hash = InfoHash([0xff; 20]);
+        let request =
+            TrackerRequest::new(&info_hash, &[b'b'; 20], 6881, 1000).with_event(Event::Started);
+        let msg: serde_json::Value = serde_json::from_str(&announce_message(&request)).unwrap();
+        assert_eq!(msg["action"], "announce");
+        assert_eq!(msg["info_hash"], "\u{ff}".repeat(20));
+        assert_eq!(msg["peer_id"], "b".repeat(20));
+        assert_eq!(msg["left"], 1000);
+        assert_eq!(msg["event"], "started");
+        assert_eq!(msg["offers"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn relayed_offers_are_skipped() {
+        let ours = "a".repeat(20);
+        let offer = format!(r#"{{"action":"announce","info_hash":"{ours}","offer":{{}}}}"#);
+        assert!(parse_answer(&offer, &ours).is_none());
+        let other = format!(
+            r#"{{"action":"announce","info_hash":"{}"}}"#,
+            "b".repeat(20)
+        );
+        assert!(parse_answer(&other, &ours).is_none());
+        let refused = parse_answer(r#"{"failure reason":"unknown torrent"}"#, &ours).unwrap();
+        assert_eq!(refused.failure_reason.as_deref(), Some("unknown torrent"));
+    }
+
+    #[tokio::test]
+    async fn announce_to_local_tracker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let tracker = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let Some(Ok(Message::Text(announce))) = socket.next().await else {
+                panic!("expected the announce");
+            };
+            let announce: serde_json::Value = serde_json::from_str(&announce).unwrap();
+            let answer = serde_json::json!({
+                "action": "announce",
+                "info_hash": announce["info_hash"],
+                "interval": 120,
+                "complete": 1,
+                "incomplete": 0,
+            });
+            socket
+                .send(Message::Text(answer.to_string()))
+                .await
+                .unwrap();
+        });
+
+        let info_hash = InfoHash([0xab; 20]);
+        let request = TrackerRequest::new(&info_hash, &[b'b'; 20], 6881, 1000);
+        let scheduler = AnnounceScheduler::new(
+            Duration::ZERO,
+            &TrackerTls::default(),
+            &TrackerCompat::default(),
+        )
+        .unwrap();
+        let response = request.get_response([url], &scheduler).await.unwrap();
+        tracker.await.unwrap();
+
+        assert_eq!(response.interval, 120);
+        assert!(response.into_peers().is_empty());
+    }
+}